@@ -1,4 +1,6 @@
+use jtag_taps::bits::BitVec;
 use jtag_taps::cable;
+use jtag_taps::idcode::IdCode;
 use jtag_taps::statemachine::JtagSM;
 use jtag_taps::taps::Taps;
 
@@ -6,16 +8,17 @@ fn main() {
     let cable = cable::new_from_string("jlink", 1 << 20).expect("cable");
     let jtag = JtagSM::new(cable);
     let mut taps = Taps::new(jtag);
-    taps.detect();
+    for tap in taps.detect().expect("detect") {
+        match tap.idcode {
+            Some(idcode) => println!("{}: {}", tap, IdCode(idcode).describe(taps.database())),
+            None => println!("{}", tap),
+        }
+    }
 
-    let ir = vec![235, 0];
+    let ir = BitVec::from_u64(235, 10);
     taps.select_tap(0, &ir);
     let readback = taps.read_ir();
-    print!("ir: ");
-    for x in readback {
-        print!("{:x} ", x);
-    }
-    println!();
+    println!("ir: {:x}", readback);
 
     taps.write_ir(&ir);
     let buf = vec![
@@ -24,11 +27,7 @@ fn main() {
         0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,
         0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,
     ];
-    taps.write_dr(&buf, 8);
+    taps.write_dr(&BitVec::from_bytes(&buf, 256));
     let dr = taps.read_dr(256);
-    print!("dr: ");
-    for x in dr {
-        print!("{:x} ", x);
-    }
-    println!();
+    println!("dr: {:x}", dr);
 }
@@ -0,0 +1,278 @@
+//! `BitVec` holds the value of a JTAG register along with its length in bits.  Bit 0 is the first
+//! bit shifted in or out of the chain, which is also the least significant bit when the register
+//! is treated as a number.  Hex and binary strings are written most significant bit first, the
+//! way data sheets and SVF files write register values.
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Clone,PartialEq,Eq,Hash,Default)]
+pub struct BitVec {
+    // Packed least significant bit first.  Bits past `len` in the last byte are always zero.
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitVec {
+    /// Create an empty `BitVec`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a `BitVec` of `len` zeros
+    pub fn zeros(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Create a `BitVec` of `len` ones
+    pub fn ones(len: usize) -> Self {
+        let mut bits = Self {
+            bytes: vec![0xff; len.div_ceil(8)],
+            len,
+        };
+        bits.mask_last();
+        bits
+    }
+
+    /// Create a `BitVec` from the first `len` bits of `bytes`, least significant bit of the first
+    /// byte first.  Panics if `bytes` is too short.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Self {
+        assert!(bytes.len() * 8 >= len, "{} bytes don't hold {} bits", bytes.len(), len);
+        let mut bits = Self {
+            bytes: bytes[..len.div_ceil(8)].to_vec(),
+            len,
+        };
+        bits.mask_last();
+        bits
+    }
+
+    /// Create a `BitVec` of `len` bits holding `value`.  Panics if `value` doesn't fit.
+    pub fn from_u64(value: u64, len: usize) -> Self {
+        assert!(len >= 64 || value >> len == 0, "{:#x} doesn't fit in {} bits", value, len);
+        let mut bytes = value.to_le_bytes().to_vec();
+        bytes.resize(std::cmp::max(8, len.div_ceil(8)), 0);
+        bytes.truncate(len.div_ceil(8));
+        Self {
+            bytes,
+            len,
+        }
+    }
+
+    /// The value as an integer, or None if it is longer than 64 bits
+    pub fn to_u64(&self) -> Option<u64> {
+        if self.len > 64 {
+            return None;
+        }
+        let mut bytes = [0; 8];
+        bytes[..self.bytes.len()].copy_from_slice(&self.bytes);
+        Some(u64::from_le_bytes(bytes))
+    }
+
+    /// Parse `len` bits from a hex string, most significant digit first.  Underscores and
+    /// whitespace are ignored, as is a leading "0x".  Digits beyond `len` must be zero.
+    pub fn from_hex(s: &str, len: usize) -> Result<Self, String> {
+        let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+        let mut bits = Self::zeros(len);
+        let mut pos = 0;
+        for c in digits.chars().rev() {
+            if c == '_' || c.is_whitespace() {
+                continue;
+            }
+            let digit = c.to_digit(16).ok_or_else(|| format!("invalid hex digit '{}' in \"{}\"", c, s))?;
+            for i in 0..4 {
+                if digit & (1 << i) != 0 {
+                    if pos + i >= len {
+                        return Err(format!("\"{}\" doesn't fit in {} bits", s, len));
+                    }
+                    bits.set(pos + i, true);
+                }
+            }
+            pos += 4;
+        }
+        Ok(bits)
+    }
+
+    /// Parse a binary string, most significant bit first.  The length is the number of digits.
+    /// Underscores and whitespace are ignored, as is a leading "0b".
+    pub fn from_bin(s: &str) -> Result<Self, String> {
+        let digits = s.strip_prefix("0b").or_else(|| s.strip_prefix("0B")).unwrap_or(s);
+        let mut bits = Self::new();
+        for c in digits.chars().rev() {
+            match c {
+                '0' => bits.push(false),
+                '1' => bits.push(true),
+                '_' => {}
+                c if c.is_whitespace() => {}
+                c => return Err(format!("invalid binary digit '{}' in \"{}\"", c, s)),
+            }
+        }
+        Ok(bits)
+    }
+
+    /// The value as a hex string, most significant digit first, with enough digits to hold every
+    /// bit
+    pub fn to_hex(&self) -> String {
+        let digits = self.len.div_ceil(4);
+        (0..digits).rev().map(|d| {
+            let mut digit = 0;
+            for i in 0..4 {
+                if d * 4 + i < self.len && self.get(d * 4 + i) {
+                    digit |= 1 << i;
+                }
+            }
+            std::char::from_digit(digit, 16).unwrap()
+        }).collect()
+    }
+
+    /// The value as a binary string, most significant bit first
+    pub fn to_bin(&self) -> String {
+        (0..self.len).rev().map(|i| if self.get(i) { '1' } else { '0' }).collect()
+    }
+
+    /// Number of bits
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The value of bit `i`.  Panics if `i` is out of range.
+    pub fn get(&self, i: usize) -> bool {
+        assert!(i < self.len, "bit {} out of range for {} bits", i, self.len);
+        self.bytes[i / 8] & (1 << (i % 8)) != 0
+    }
+
+    /// Set bit `i` to `value`.  Panics if `i` is out of range.
+    pub fn set(&mut self, i: usize, value: bool) {
+        assert!(i < self.len, "bit {} out of range for {} bits", i, self.len);
+        if value {
+            self.bytes[i / 8] |= 1 << (i % 8);
+        } else {
+            self.bytes[i / 8] &= !(1 << (i % 8));
+        }
+    }
+
+    /// Add a bit after the current last bit
+    pub fn push(&mut self, value: bool) {
+        if self.len.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        self.len += 1;
+        self.set(self.len - 1, value);
+    }
+
+    /// Copy out the bits in `range`
+    pub fn slice(&self, range: Range<usize>) -> Self {
+        assert!(range.start <= range.end && range.end <= self.len,
+                "range {:?} out of range for {} bits", range, self.len);
+        range.map(|i| self.get(i)).collect()
+    }
+
+    /// Add the bits of `other` after the current last bit
+    pub fn append(&mut self, other: &BitVec) {
+        if self.len.is_multiple_of(8) {
+            self.bytes.extend_from_slice(&other.bytes);
+            self.len += other.len;
+        } else {
+            for bit in other.iter() {
+                self.push(bit);
+            }
+        }
+    }
+
+    /// A new `BitVec` holding the bits of `self` followed by the bits of `other`.  Since bit 0 is
+    /// shifted first, `other` ends up closer to TDI.
+    pub fn concat(&self, other: &BitVec) -> Self {
+        let mut bits = self.clone();
+        bits.append(other);
+        bits
+    }
+
+    /// Iterate over the bits, starting with bit 0
+    pub fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.len).map(|i| self.get(i))
+    }
+
+    /// Number of bits that are set
+    pub fn count_ones(&self) -> usize {
+        self.bytes.iter().map(|x| x.count_ones() as usize).sum()
+    }
+
+    /// Whether the bits equal those of `expected` wherever `mask` is set, like the TDO and MASK
+    /// of an SVF scan.  Bits past the end of the shortest of the three aren't compared.
+    pub fn masked_eq(&self, expected: &BitVec, mask: &BitVec) -> bool {
+        let len = self.len.min(expected.len).min(mask.len);
+        let whole = len / 8;
+        let differ = |i: usize| (self.bytes[i] ^ expected.bytes[i]) & mask.bytes[i];
+        (0..whole).all(|i| differ(i) == 0) &&
+            (len.is_multiple_of(8) || differ(whole) & ((1 << (len % 8)) - 1) == 0)
+    }
+
+    /// The positions of the bits that differ from `expected` where `mask` is set, starting from
+    /// bit 0
+    pub fn masked_diff(&self, expected: &BitVec, mask: &BitVec) -> Vec<usize> {
+        let len = self.len.min(expected.len).min(mask.len);
+        (0..len).filter(|&i| mask.get(i) && self.get(i) != expected.get(i)).collect()
+    }
+
+    /// The bits packed into bytes, least significant bit of the first byte first.  The unused bits
+    /// of the last byte are zero.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Number of bits used in the last byte of `as_bytes()`, from 1 to 8
+    pub(crate) fn last_bits(&self) -> u8 {
+        assert!(self.len > 0);
+        (((self.len - 1) % 8) + 1) as u8
+    }
+
+    fn mask_last(&mut self) {
+        if !self.len.is_multiple_of(8) {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] &= (1 << (self.len % 8)) - 1;
+        }
+    }
+}
+
+impl FromIterator<bool> for BitVec {
+    fn from_iter<I: IntoIterator<Item = bool>>(iter: I) -> Self {
+        let mut bits = Self::new();
+        for bit in iter {
+            bits.push(bit);
+        }
+        bits
+    }
+}
+
+impl fmt::Debug for BitVec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BitVec({}, {})", self.len, self.to_hex())
+    }
+}
+
+impl fmt::Display for BitVec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::LowerHex for BitVec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Binary for BitVec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_bin())
+    }
+}
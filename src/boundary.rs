@@ -0,0 +1,260 @@
+//! Pin access through a device's boundary register.  `BoundaryScan` keeps the value of every
+//! boundary cell of one TAP, starting from the safe values in its BSDL description.  Pins are read
+//! with SAMPLE, and driven or tri-stated with EXTEST after the values have been loaded with
+//! PRELOAD, so that nothing the caller hasn't asked for is ever driven with anything but its safe
+//! value.
+//!
+//! `BoundaryScan` doesn't own the `Taps` it shifts through, so several devices on one chain can
+//! each have their own.
+use crate::bits::BitVec;
+use crate::bsdl::{Bsdl, BoundaryCell, CellFunction};
+use crate::cable::Cable;
+use crate::taps::Taps;
+
+pub struct BoundaryScan {
+    bsdl: Bsdl,
+    tap: usize,
+    // Value of each cell for the next scan, indexed by cell number
+    cells: BitVec,
+    // What each cell captured in the last scan
+    captured: Option<BitVec>,
+    // The instruction last loaded into the TAP
+    loaded: Option<String>,
+}
+
+impl BoundaryScan {
+    /// Create a `BoundaryScan` for TAP number `tap` of the chain, described by `bsdl`.  All cells
+    /// start with their safe values.  Cells whose safe value is X start with the value that
+    /// disables the outputs they control, or 0.
+    pub fn new(bsdl: Bsdl, tap: usize) -> Result<Self, String> {
+        if bsdl.instruction("SAMPLE").or(bsdl.instruction("PRELOAD")).is_none() {
+            return Err(format!("{} has no SAMPLE instruction", bsdl.entity));
+        }
+        if bsdl.instruction("EXTEST").is_none() {
+            return Err(format!("{} has no EXTEST instruction", bsdl.entity));
+        }
+
+        let mut cells = BitVec::zeros(bsdl.boundary_length);
+        for cell in &bsdl.boundary {
+            if let Some(control) = cell.control {
+                if bsdl.boundary.iter().any(|c| c.number == control.cell && c.safe.is_none()) {
+                    cells.set(control.cell, control.disable_value);
+                }
+            }
+        }
+        for cell in &bsdl.boundary {
+            if let Some(safe) = cell.safe {
+                cells.set(cell.number, safe);
+            }
+        }
+
+        Ok(Self {
+            bsdl,
+            tap,
+            cells,
+            captured: None,
+            loaded: None,
+        })
+    }
+
+    pub fn bsdl(&self) -> &Bsdl {
+        &self.bsdl
+    }
+
+    /// Position of the TAP in the chain
+    pub fn tap(&self) -> usize {
+        self.tap
+    }
+
+    /// The values that the next scan will shift into the boundary register, indexed by cell
+    /// number
+    pub fn cells(&self) -> &BitVec {
+        &self.cells
+    }
+
+    /// What the boundary register captured in the last scan, indexed by cell number
+    pub fn captured(&self) -> Option<&BitVec> {
+        self.captured.as_ref()
+    }
+
+    /// True once EXTEST has been loaded, meaning the device pins are driven from the boundary
+    /// register.  False again after anything that `taps` knows could have changed the instruction,
+    /// such as a reset or TRST.
+    pub fn in_extest<T, U>(&self, taps: &Taps<T>) -> bool
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        self.loaded.as_deref() == Some("EXTEST") &&
+            taps.current_ir(self.tap) == self.opcode("EXTEST").ok().as_ref()
+    }
+
+    fn cells_for<'a>(&'a self, port: &'a str) -> impl Iterator<Item = &'a BoundaryCell> + 'a {
+        self.bsdl.boundary.iter()
+            .filter(move |c| c.port.as_deref().is_some_and(|p| p.eq_ignore_ascii_case(port)))
+    }
+
+    pub(crate) fn output_cell<'a>(&'a self, port: &'a str) -> Result<&'a BoundaryCell, String> {
+        if self.cells_for(port).next().is_none() {
+            return Err(format!("{} has no boundary cell for {}", self.bsdl.entity, port));
+        }
+        self.cells_for(port)
+            .find(|c| matches!(c.function, CellFunction::Output2 | CellFunction::Output3 | CellFunction::Bidir))
+            .ok_or_else(|| format!("{} is input-only on {}", port, self.bsdl.entity))
+    }
+
+    pub(crate) fn input_cell<'a>(&'a self, port: &'a str) -> Result<&'a BoundaryCell, String> {
+        if self.cells_for(port).next().is_none() {
+            return Err(format!("{} has no boundary cell for {}", self.bsdl.entity, port));
+        }
+        // An output3 cell captures what is being driven rather than the pin, so prefer a cell
+        // that watches the pin itself
+        self.cells_for(port)
+            .find(|c| matches!(c.function, CellFunction::Input | CellFunction::Clock |
+                               CellFunction::ObserveOnly | CellFunction::Bidir))
+            .or_else(|| self.cells_for(port).find(|c| c.function == CellFunction::Output2))
+            .ok_or_else(|| format!("{} can't be read on {}", port, self.bsdl.entity))
+    }
+
+    /// Drive `port` to `value` on the next `apply`.  Fails if the port doesn't have an output
+    /// cell.
+    pub fn drive(&mut self, port: &str, value: bool) -> Result<(), String> {
+        let cell = self.output_cell(port)?.clone();
+        self.cells.set(cell.number, value);
+        if let Some(control) = cell.control {
+            self.cells.set(control.cell, !control.disable_value);
+        }
+        Ok(())
+    }
+
+    /// Stop driving `port` on the next `apply`.  Fails if the port's output can't be disabled.
+    pub fn tristate(&mut self, port: &str) -> Result<(), String> {
+        let cell = self.output_cell(port)?;
+        let control = cell.control.ok_or_else(|| format!("{} is always driven", port))?;
+        self.cells.set(control.cell, control.disable_value);
+        Ok(())
+    }
+
+    /// Put the cells for `port` back to their safe values
+    pub fn release(&mut self, port: &str) -> Result<(), String> {
+        let mut safe = vec![];
+        for cell in self.cells_for(port) {
+            safe.push((cell.number, cell.safe));
+            if let Some(control) = cell.control {
+                let control_safe = self.bsdl.boundary.iter()
+                    .find(|c| c.number == control.cell)
+                    .and_then(|c| c.safe)
+                    .unwrap_or(control.disable_value);
+                safe.push((control.cell, Some(control_safe)));
+            }
+        }
+        if safe.is_empty() {
+            return Err(format!("{} has no boundary cell for {}", self.bsdl.entity, port));
+        }
+        for (number, value) in safe {
+            self.cells.set(number, value.unwrap_or(false));
+        }
+        Ok(())
+    }
+
+    /// The state of `port` captured by the last scan
+    pub fn pin(&self, port: &str) -> Result<bool, String> {
+        let cell = self.input_cell(port)?;
+        let captured = self.captured.as_ref().ok_or_else(|| "no scan has been done".to_string())?;
+        Ok(captured.get(cell.number))
+    }
+
+    pub(crate) fn opcode(&self, name: &str) -> Result<BitVec, String> {
+        self.bsdl.instruction(name)
+            .or_else(|| if name == "SAMPLE" { self.bsdl.instruction("PRELOAD") } else { None })
+            .cloned()
+            .ok_or_else(|| format!("{} has no {} instruction", self.bsdl.entity, name))
+    }
+
+    // For scans of the whole chain done outside of `BoundaryScan`
+    pub(crate) fn set_scanned(&mut self, captured: BitVec, loaded: Option<&str>) {
+        self.captured = Some(captured);
+        self.loaded = loaded.map(|s| s.to_string());
+    }
+
+    pub(crate) fn set_cells(&mut self, cells: BitVec) {
+        assert_eq!(cells.len(), self.cells.len());
+        self.cells = cells;
+    }
+
+    // Load `name` into the instruction register, unless `taps` knows that the TAP is selected and
+    // holds it already.  Once an instruction has been loaded, the next one goes in with `write_ir`
+    // while the TAP is still selected, so that `SelectMode::Reset` doesn't reset the chain between
+    // PRELOAD and EXTEST.
+    fn load<T, U>(&mut self, taps: &mut Taps<T>, name: &str) -> Result<(), String>
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        let opcode = self.opcode(name)?;
+        let tap = taps.taps().get(self.tap).ok_or_else(|| format!("no TAP {} in the chain", self.tap))?;
+        if tap.irlen != self.bsdl.irlen {
+            return Err(format!("TAP {} has a {} bit IR, but {} has {}", self.tap, tap.irlen,
+                               self.bsdl.entity, self.bsdl.irlen));
+        }
+
+        if taps.active() == self.tap && taps.current_ir(self.tap) == Some(&opcode) {
+            self.loaded = Some(name.to_string());
+            return Ok(());
+        }
+        if self.loaded.is_some() && taps.active() == self.tap {
+            taps.write_ir(&opcode);
+        } else {
+            taps.select_tap(self.tap, &opcode);
+        }
+        self.loaded = Some(name.to_string());
+        Ok(())
+    }
+
+    fn scan<T, U>(&mut self, taps: &mut Taps<T>)
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        self.captured = Some(taps.read_write_dr(&self.cells));
+    }
+
+    /// Capture the state of every pin, which can then be read with `pin`.  This uses SAMPLE
+    /// unless EXTEST is already loaded, in which case the pins stay under boundary scan control.
+    pub fn sample<T, U>(&mut self, taps: &mut Taps<T>) -> Result<(), String>
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        if !self.in_extest(taps) {
+            self.load(taps, "SAMPLE")?;
+        }
+        self.scan(taps);
+        Ok(())
+    }
+
+    /// Load the current cell values into the boundary register with PRELOAD, without driving
+    /// them onto the pins
+    pub fn preload<T, U>(&mut self, taps: &mut Taps<T>) -> Result<(), String>
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        if self.in_extest(taps) {
+            return Err("PRELOAD would take the pins out of EXTEST".to_string());
+        }
+        self.load(taps, "SAMPLE")?;
+        self.scan(taps);
+        Ok(())
+    }
+
+    /// Drive the current cell values onto the pins with EXTEST, capturing the pin states at the
+    /// same time.  The values are preloaded first when EXTEST isn't loaded yet, so the pins go
+    /// straight from normal operation to the requested values.
+    pub fn apply<T, U>(&mut self, taps: &mut Taps<T>) -> Result<(), String>
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        if !self.in_extest(taps) {
+            self.preload(taps)?;
+            self.load(taps, "EXTEST")?;
+        }
+        self.scan(taps);
+        Ok(())
+    }
+}
@@ -0,0 +1,603 @@
+//! A parser for IEEE 1149.1 Boundary Scan Description Language files.  BSDL is a subset of VHDL;
+//! only the parts describing the test logic are read: the instruction register, the IDCODE, which
+//! registers each instruction selects, the boundary register cells and the physical pin map.
+//! Everything else, such as the port list and the compliance enable pins, is skipped.
+use crate::bits::BitVec;
+
+use std::path::Path;
+
+/// A value where some bits are don't-cares, such as "0XXX0011" in `IDCODE_REGISTER`
+#[derive(Clone,Debug,PartialEq)]
+pub struct Pattern {
+    /// The expected bits, with don't-cares set to 0
+    pub value: BitVec,
+    /// 1 for each bit that must match `value`
+    pub mask: BitVec,
+}
+
+impl Pattern {
+    /// Parse a string of 0, 1 and X, most significant bit first
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut value = BitVec::new();
+        let mut mask = BitVec::new();
+        for c in s.chars().rev() {
+            match c.to_ascii_uppercase() {
+                '0' => { value.push(false); mask.push(true); }
+                '1' => { value.push(true); mask.push(true); }
+                'X' => { value.push(false); mask.push(false); }
+                c if c.is_whitespace() || c == '_' => {}
+                c => return Err(format!("invalid pattern character '{}' in \"{}\"", c, s)),
+            }
+        }
+        Ok(Self { value, mask })
+    }
+
+    pub fn len(&self) -> usize {
+        self.value.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+
+    /// True if `bits` has the same length and agrees in every bit that isn't a don't-care
+    pub fn matches(&self, bits: &BitVec) -> bool {
+        bits.len() == self.len() && (0..self.len()).all(|i| !self.mask.get(i) || bits.get(i) == self.value.get(i))
+    }
+}
+
+/// An entry in `INSTRUCTION_OPCODE`.  Some instructions have more than one opcode.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Instruction {
+    pub name: String,
+    pub opcodes: Vec<BitVec>,
+}
+
+/// An entry in `REGISTER_ACCESS`, naming the register that each instruction puts between TDI and
+/// TDO
+#[derive(Clone,Debug,PartialEq)]
+pub struct RegisterAccess {
+    /// BOUNDARY, BYPASS, DEVICE_ID or a design-specific name
+    pub register: String,
+    /// The length given in brackets after the register name, if any
+    pub length: Option<usize>,
+    pub instructions: Vec<String>,
+}
+
+/// What a boundary register cell does
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum CellFunction {
+    Input,
+    Clock,
+    Output2,
+    Output3,
+    Control,
+    ControlR,
+    Internal,
+    Bidir,
+    ObserveOnly,
+}
+
+/// What an output does while its control cell holds the disable value
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum DisableResult {
+    Z,
+    Weak0,
+    Weak1,
+    Pull0,
+    Pull1,
+    Keeper,
+}
+
+/// The control cell for an output or bidirectional cell
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct ControlCell {
+    /// Number of the control cell in the boundary register
+    pub cell: usize,
+    /// The value that disables the output
+    pub disable_value: bool,
+    pub disable_result: DisableResult,
+}
+
+/// A cell of the boundary register.  Cell 0 is closest to TDO.
+#[derive(Clone,Debug,PartialEq)]
+pub struct BoundaryCell {
+    pub number: usize,
+    /// The cell design, such as BC_1
+    pub cell_type: String,
+    /// The port the cell is attached to, such as "PA(3)", or None for "*"
+    pub port: Option<String>,
+    pub function: CellFunction,
+    /// The value to load when the cell isn't otherwise being used, or None for "X"
+    pub safe: Option<bool>,
+    pub control: Option<ControlCell>,
+}
+
+/// The test logic described by a BSDL file
+#[derive(Clone,Debug,PartialEq)]
+pub struct Bsdl {
+    pub entity: String,
+    /// `INSTRUCTION_LENGTH`
+    pub irlen: usize,
+    pub instructions: Vec<Instruction>,
+    /// `INSTRUCTION_CAPTURE`
+    pub ir_capture: Option<Pattern>,
+    /// `IDCODE_REGISTER`
+    pub idcode: Option<Pattern>,
+    /// `USERCODE_REGISTER`
+    pub usercode: Option<Pattern>,
+    pub register_access: Vec<RegisterAccess>,
+    /// `BOUNDARY_LENGTH`
+    pub boundary_length: usize,
+    /// `BOUNDARY_REGISTER`, sorted by cell number.  A cell that does two jobs, such as a control
+    /// cell that also observes an input, has two entries.
+    pub boundary: Vec<BoundaryCell>,
+    /// Physical pins for each port, from the PIN_MAP_STRING chosen by PHYSICAL_PIN_MAP.  Ports
+    /// that are vectors list one pin per element.
+    pub pin_map: Vec<(String, Vec<String>)>,
+}
+
+impl Bsdl {
+    /// Read and parse a BSDL file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Parse the text of a BSDL file
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tokens = lex_vhdl(text)?;
+        let decls = Declarations::find(&tokens)?;
+
+        let number = |name: &str| -> Result<usize, String> {
+            let value = decls.attribute(name).ok_or_else(|| format!("missing {}", name))?;
+            value.parse().map_err(|_| format!("{} of \"{}\" isn't a number", name, value))
+        };
+        let irlen = number("INSTRUCTION_LENGTH")?;
+        let boundary_length = number("BOUNDARY_LENGTH")?;
+
+        let instructions = parse_instructions(decls.attribute("INSTRUCTION_OPCODE").unwrap_or(""))?;
+        for inst in &instructions {
+            if let Some(op) = inst.opcodes.iter().find(|op| op.len() != irlen) {
+                return Err(format!("opcode {:b} for {} isn't {} bits", op, inst.name, irlen));
+            }
+        }
+
+        let pattern = |name: &str, len: usize| -> Result<Option<Pattern>, String> {
+            let Some(value) = decls.attribute(name) else {
+                return Ok(None);
+            };
+            let pattern = Pattern::parse(value)?;
+            if pattern.len() != len {
+                return Err(format!("{} \"{}\" isn't {} bits", name, value, len));
+            }
+            Ok(Some(pattern))
+        };
+        let ir_capture = pattern("INSTRUCTION_CAPTURE", irlen)?;
+        let idcode = pattern("IDCODE_REGISTER", 32)?;
+        let usercode = pattern("USERCODE_REGISTER", 32)?;
+
+        let register_access = parse_register_access(decls.attribute("REGISTER_ACCESS").unwrap_or(""))?;
+        let boundary = parse_boundary(decls.attribute("BOUNDARY_REGISTER").unwrap_or(""), boundary_length)?;
+        let pin_map = match decls.pin_map() {
+            Some(map) => parse_pin_map(map)?,
+            None => vec![],
+        };
+
+        Ok(Self {
+            entity: decls.entity,
+            irlen,
+            instructions,
+            ir_capture,
+            idcode,
+            usercode,
+            register_access,
+            boundary_length,
+            boundary,
+            pin_map,
+        })
+    }
+
+    /// The first opcode of the instruction called `name`, ignoring case
+    pub fn instruction(&self, name: &str) -> Option<&BitVec> {
+        self.instructions.iter()
+            .find(|inst| inst.name.eq_ignore_ascii_case(name))
+            .and_then(|inst| inst.opcodes.first())
+    }
+
+    /// The register that the instruction called `name` selects, ignoring case
+    pub fn register_for(&self, instruction: &str) -> Option<&RegisterAccess> {
+        self.register_access.iter()
+            .find(|reg| reg.instructions.iter().any(|i| i.eq_ignore_ascii_case(instruction)))
+    }
+
+    /// The physical pins of `port`, ignoring case
+    pub fn pins(&self, port: &str) -> Option<&[String]> {
+        self.pin_map.iter()
+            .find(|(p, _)| p.eq_ignore_ascii_case(port))
+            .map(|(_, pins)| pins.as_slice())
+    }
+}
+
+#[derive(Clone,Debug,PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Punct(char),
+}
+
+// Split VHDL into identifiers, numbers, string literals and punctuation, dropping comments
+fn lex_vhdl(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c == '-' && chars.peek() == Some(&'-') {
+            for c in chars.by_ref() {
+                if c == '\n' {
+                    break;
+                }
+            }
+        } else if c == '"' {
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => s.push(c),
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let mut s = c.to_string();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+                    break;
+                }
+                s.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(s));
+        } else {
+            tokens.push(Token::Punct(c));
+        }
+    }
+    Ok(tokens)
+}
+
+fn is_word(token: Option<&Token>, word: &str) -> bool {
+    matches!(token, Some(Token::Word(w)) if w.eq_ignore_ascii_case(word))
+}
+
+// The parts of the VHDL that matter here
+struct Declarations {
+    entity: String,
+    // Attribute names in upper case, with string values concatenated
+    attributes: Vec<(String, String)>,
+    // The default value of the PHYSICAL_PIN_MAP generic
+    physical_pin_map: Option<String>,
+    // PIN_MAP_STRING constants
+    pin_maps: Vec<(String, String)>,
+}
+
+impl Declarations {
+    fn find(tokens: &[Token]) -> Result<Self, String> {
+        let mut decls = Self {
+            entity: String::new(),
+            attributes: vec![],
+            physical_pin_map: None,
+            pin_maps: vec![],
+        };
+
+        let mut i = 0;
+        while i < tokens.len() {
+            if is_word(tokens.get(i), "entity") && decls.entity.is_empty() {
+                if let Some(Token::Word(name)) = tokens.get(i + 1) {
+                    decls.entity = name.clone();
+                }
+                i += 2;
+            } else if is_word(tokens.get(i), "attribute") && is_word(tokens.get(i + 2), "of") {
+                // attribute NAME of TARGET : CLASS is VALUE ;
+                let name = match &tokens[i + 1] {
+                    Token::Word(name) => name.to_ascii_uppercase(),
+                    _ => return Err("expected attribute name".to_string()),
+                };
+                let start = (i..tokens.len()).find(|j| is_word(tokens.get(*j), "is"))
+                    .ok_or_else(|| format!("attribute {} has no value", name))?;
+                let (value, end) = statement_value(tokens, start + 1)?;
+                decls.attributes.push((name, value));
+                i = end;
+            } else if is_word(tokens.get(i), "constant") {
+                // constant NAME : PIN_MAP_STRING := VALUE ;
+                let name = match tokens.get(i + 1) {
+                    Some(Token::Word(name)) => name.clone(),
+                    _ => return Err("expected constant name".to_string()),
+                };
+                let assign = (i..tokens.len()).find(|j| tokens[*j] == Token::Punct('=') &&
+                                                    tokens[*j - 1] == Token::Punct(':'))
+                    .ok_or_else(|| format!("constant {} has no value", name))?;
+                let is_pin_map = is_word(tokens.get(i + 3), "PIN_MAP_STRING");
+                let (value, end) = statement_value(tokens, assign + 1)?;
+                if is_pin_map {
+                    decls.pin_maps.push((name, value));
+                }
+                i = end;
+            } else if is_word(tokens.get(i), "PHYSICAL_PIN_MAP") && tokens.get(i + 1) == Some(&Token::Punct(':')) {
+                // generic (PHYSICAL_PIN_MAP : string := "DEFAULT");
+                if let Some(Token::Str(s)) = tokens.iter().skip(i).take(8).find(|t| matches!(t, Token::Str(_))) {
+                    decls.physical_pin_map = Some(s.clone());
+                }
+                i += 1;
+            } else {
+                i += 1;
+            }
+        }
+
+        if decls.entity.is_empty() {
+            return Err("no entity found".to_string());
+        }
+        Ok(decls)
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    fn pin_map(&self) -> Option<&str> {
+        let chosen = self.physical_pin_map.as_ref().and_then(|name| {
+            self.pin_maps.iter().find(|(n, _)| n.eq_ignore_ascii_case(name))
+        });
+        chosen.or(self.pin_maps.first()).map(|(_, v)| v.as_str())
+    }
+}
+
+// Collect the value of a statement starting at `start`, up to the terminating semicolon.  String
+// literals joined with & are concatenated, otherwise the words are joined with spaces.  Returns
+// the value and the index after the semicolon.
+fn statement_value(tokens: &[Token], start: usize) -> Result<(String, usize), String> {
+    let mut value = String::new();
+    let mut i = start;
+    loop {
+        match tokens.get(i) {
+            None => return Err("missing ;".to_string()),
+            Some(Token::Punct(';')) => return Ok((value, i + 1)),
+            Some(Token::Str(s)) => value.push_str(s),
+            Some(Token::Word(w)) => {
+                if !value.is_empty() {
+                    value.push(' ');
+                }
+                value.push_str(w);
+            }
+            Some(Token::Punct(_)) => {}
+        }
+        i += 1;
+    }
+}
+
+// Split the contents of a BSDL string attribute into words and punctuation
+fn lex_attribute(s: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut word = String::new();
+    for c in s.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '*' || c == '.' || c == '-' {
+            word.push(c);
+            continue;
+        }
+        if !word.is_empty() {
+            tokens.push(Token::Word(std::mem::take(&mut word)));
+        }
+        if !c.is_whitespace() {
+            tokens.push(Token::Punct(c));
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(Token::Word(word));
+    }
+    tokens
+}
+
+// Parse "NAME (a, b, c), NAME (d)" where the fields may themselves contain parentheses, as in
+// "PA(3)".  Returns what comes before each opening parenthesis along with the fields, each with
+// its tokens joined back together.
+fn parse_groups(s: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+    let tokens = lex_attribute(s);
+    let mut groups = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        let mut head = String::new();
+        while i < tokens.len() && tokens[i] != Token::Punct('(') {
+            match &tokens[i] {
+                Token::Word(w) => head.push_str(w),
+                Token::Punct(',') if head.is_empty() => {}
+                Token::Punct(c) => head.push(*c),
+                Token::Str(_) => {}
+            }
+            i += 1;
+        }
+        if i == tokens.len() {
+            if head.is_empty() {
+                break;
+            }
+            return Err(format!("expected ( after \"{}\"", head));
+        }
+        i += 1;
+
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut depth = 0;
+        loop {
+            match tokens.get(i) {
+                None => return Err(format!("missing ) after \"{}\"", head)),
+                Some(Token::Punct(')')) if depth == 0 => {
+                    fields.push(field);
+                    i += 1;
+                    break;
+                }
+                Some(Token::Punct(',')) if depth == 0 => fields.push(std::mem::take(&mut field)),
+                Some(Token::Punct(c)) => {
+                    if *c == '(' {
+                        depth += 1;
+                    } else if *c == ')' {
+                        depth -= 1;
+                    }
+                    field.push(*c);
+                }
+                Some(Token::Word(w)) => field.push_str(w),
+                Some(Token::Str(_)) => {}
+            }
+            i += 1;
+        }
+        groups.push((head, fields));
+    }
+    Ok(groups)
+}
+
+fn parse_instructions(s: &str) -> Result<Vec<Instruction>, String> {
+    parse_groups(s)?.into_iter().map(|(name, fields)| {
+        let opcodes = fields.iter()
+            .map(|op| BitVec::from_bin(op).map_err(|e| format!("{}: {}", name, e)))
+            .collect::<Result<_, _>>()?;
+        Ok(Instruction { name, opcodes })
+    }).collect()
+}
+
+fn parse_register_access(s: &str) -> Result<Vec<RegisterAccess>, String> {
+    parse_groups(s)?.into_iter().map(|(head, instructions)| {
+        let (register, length) = match head.split_once('[') {
+            Some((register, len)) => {
+                let len = len.trim_end_matches(']');
+                let len = len.parse().map_err(|_| format!("bad register length in \"{}\"", head))?;
+                (register.to_string(), Some(len))
+            }
+            None => (head, None),
+        };
+        Ok(RegisterAccess { register, length, instructions })
+    }).collect()
+}
+
+fn parse_function(s: &str) -> Result<CellFunction, String> {
+    Ok(match s.to_ascii_uppercase().as_str() {
+        "INPUT" => CellFunction::Input,
+        "CLOCK" => CellFunction::Clock,
+        "OUTPUT2" => CellFunction::Output2,
+        "OUTPUT3" => CellFunction::Output3,
+        "CONTROL" => CellFunction::Control,
+        "CONTROLR" => CellFunction::ControlR,
+        "INTERNAL" => CellFunction::Internal,
+        "BIDIR" => CellFunction::Bidir,
+        "OBSERVE_ONLY" => CellFunction::ObserveOnly,
+        _ => return Err(format!("unknown cell function {}", s)),
+    })
+}
+
+fn parse_disable_result(s: &str) -> Result<DisableResult, String> {
+    Ok(match s.to_ascii_uppercase().as_str() {
+        "Z" => DisableResult::Z,
+        "WEAK0" => DisableResult::Weak0,
+        "WEAK1" => DisableResult::Weak1,
+        "PULL0" => DisableResult::Pull0,
+        "PULL1" => DisableResult::Pull1,
+        "KEEPER" => DisableResult::Keeper,
+        _ => return Err(format!("unknown disable result {}", s)),
+    })
+}
+
+fn parse_bit(s: &str) -> Result<Option<bool>, String> {
+    match s {
+        "0" => Ok(Some(false)),
+        "1" => Ok(Some(true)),
+        "X" | "x" => Ok(None),
+        _ => Err(format!("expected 0, 1 or X, found {}", s)),
+    }
+}
+
+fn parse_boundary(s: &str, length: usize) -> Result<Vec<BoundaryCell>, String> {
+    let mut cells = vec![];
+    for (number, fields) in parse_groups(s)? {
+        let number: usize = number.parse().map_err(|_| format!("bad boundary cell number \"{}\"", number))?;
+        let err = |e: String| format!("boundary cell {}: {}", number, e);
+        if fields.len() != 4 && fields.len() != 7 {
+            return Err(err(format!("expected 4 or 7 fields, found {}", fields.len())));
+        }
+
+        let control = if fields.len() == 7 {
+            let cell = fields[4].parse().map_err(|_| err(format!("bad control cell \"{}\"", fields[4])))?;
+            let disable_value = parse_bit(&fields[5]).map_err(err)?
+                .ok_or_else(|| err("disable value can't be X".to_string()))?;
+            let disable_result = parse_disable_result(&fields[6]).map_err(err)?;
+            Some(ControlCell { cell, disable_value, disable_result })
+        } else {
+            None
+        };
+
+        cells.push(BoundaryCell {
+            number,
+            cell_type: fields[0].clone(),
+            port: if fields[1] == "*" { None } else { Some(fields[1].clone()) },
+            function: parse_function(&fields[2]).map_err(err)?,
+            safe: parse_bit(&fields[3]).map_err(err)?,
+            control,
+        });
+    }
+
+    // A cell may be listed twice when it does two jobs, such as a control cell that also observes
+    // an input pin
+    cells.sort_by_key(|c| c.number);
+    let mut numbers: Vec<_> = cells.iter().map(|c| c.number).collect();
+    numbers.dedup();
+    if numbers.len() != length || numbers.iter().enumerate().any(|(i, n)| *n != i) {
+        return Err(format!("BOUNDARY_REGISTER doesn't describe cells 0 to {}", length as isize - 1));
+    }
+    for cell in &cells {
+        if let Some(control) = cell.control {
+            if control.cell >= length {
+                return Err(format!("boundary cell {} has control cell {} out of range", cell.number,
+                                   control.cell));
+            }
+        }
+    }
+    Ok(cells)
+}
+
+fn parse_pin_map(s: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+    // PORT : PIN, or PORT : (PIN, PIN, ...), separated by commas
+    let tokens = lex_attribute(s);
+    let mut map = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        let port = match &tokens[i] {
+            Token::Word(w) => w.clone(),
+            Token::Punct(',') => {
+                i += 1;
+                continue;
+            }
+            t => return Err(format!("unexpected {:?} in pin map", t)),
+        };
+        if tokens.get(i + 1) != Some(&Token::Punct(':')) {
+            return Err(format!("expected : after {} in pin map", port));
+        }
+        i += 2;
+
+        let mut pins = vec![];
+        if tokens.get(i) == Some(&Token::Punct('(')) {
+            i += 1;
+            while i < tokens.len() && tokens[i] != Token::Punct(')') {
+                if let Token::Word(w) = &tokens[i] {
+                    pins.push(w.clone());
+                }
+                i += 1;
+            }
+            i += 1;
+        } else if let Some(Token::Word(w)) = tokens.get(i) {
+            pins.push(w.clone());
+            i += 1;
+        } else {
+            return Err(format!("missing pin for {} in pin map", port));
+        }
+        map.push((port, pins));
+    }
+    Ok(map)
+}
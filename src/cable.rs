@@ -4,6 +4,12 @@ pub mod mpsse;
 pub mod ft232r;
 pub mod usbblaster;
 pub mod jlink;
+pub mod sim;
+
+use crate::bits::BitVec;
+use crate::statemachine::TapModel;
+
+use std::time::Duration;
 
 pub trait Cable {
     /// Clock out a series of TMS values to change the state of the JTAG chain.  Each element of
@@ -13,17 +19,18 @@ pub trait Cable {
     /// Shift in bits from the TDO line.  `bits` is the total number of bits to read.  Should be
     /// called with state = ShiftIR or ShiftDR, and will remain in that state.  Should clock out
     /// all ones.
-    fn read_data(&mut self, bits: usize) -> Vec<u8>;
-    /// Shift out bits on the TDI line.  `bits` is the number of bits to send from the last byte.
-    /// Should be called with state = ShiftIR or ShiftDR.  State won't change unless `pause_after`
-    /// is true, in which case it will be PauseIR or PauseDR on exit.
-    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool);
-
-    /// Shift out bits on the TDI line.  `bits` is the number of bits to send from the last byte.
-    /// Should be called with state = ShiftIR or ShiftDR.  State won't change unless `pause_after`
-    /// is true, in which case it will be PauseIR or PauseDR on exit.  Also captures and returns
-    /// the bits that were shifted in from TDO
-    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Vec<u8>;
+    fn read_data(&mut self, bits: usize) -> BitVec;
+    /// Shift out the bits of `data` on the TDI line, which must not be empty.  Should be called
+    /// with state = ShiftIR or ShiftDR.  `exit` is the sequence of TMS values
+    /// that leaves the shift state: the first is clocked along with the last bit of data and the
+    /// rest after it.  State won't change if `exit` is empty, and `&[1, 0]` ends in PauseIR or
+    /// PauseDR.
+    fn write_data(&mut self, data: &BitVec, exit: &[usize]);
+
+    /// Shift out the bits of `data` on the TDI line, which must not be empty.  Should be called
+    /// with state = ShiftIR or ShiftDR, and leaves it by clocking `exit` as for `write_data()`.
+    /// Also captures and returns the bits that were shifted in from TDO
+    fn read_write_data(&mut self, data: &BitVec, exit: &[usize]) -> BitVec;
 
     /// If the cable implements any queueing, flush to hardware.
     fn flush(&mut self) {
@@ -34,18 +41,81 @@ pub trait Cable {
     /// adapter doesn't have any more queue space.
     fn queue_read(&mut self, bits: usize) -> bool;
 
-    /// Shift out bits on the TDI line.  `bits` is the number of bits to send from the last byte.
-    /// Should be called with state = ShiftIR or ShiftDR.  State won't change unless `pause_after`
-    /// is true, in which case it will be PauseIR or PauseDR on exit.  Also captures
-    /// the bits that were shifted in from TDO, which can be retrieved with a queue to
-    /// `finish_read()`.  Returns false if the adapter doesn't have any more queue space.
-    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> bool;
+    /// Shift out the bits of `data` on the TDI line, which must not be empty.  Should be called
+    /// with state = ShiftIR or ShiftDR, and leaves it by clocking `exit` as for `write_data()`.
+    /// Also captures the bits that were shifted in from TDO, which can be
+    /// retrieved with a queue to `finish_read()`.  Returns false if the adapter doesn't have any
+    /// more queue space.
+    fn queue_read_write(&mut self, data: &BitVec, exit: &[usize]) -> bool;
 
     /// Return the data from a previously queued read.  `bits` must exactly match the corresponding
     /// call to `queue_read()`, otherwise the behavior is undefined.  Once you finish a read, you
     /// must finish all the queued reads by calling `finish_read()` as many times as `queue_read()`
     /// was called.
-    fn finish_read(&mut self, bits: usize) -> Vec<u8>;
+    fn finish_read(&mut self, bits: usize) -> BitVec;
+
+    /// Clock out arbitrary TMS and TDI values, capturing TDO.  `tms` and `tdi` must be the same
+    /// length, which is the number of clocks.  Unlike the other methods, this places no
+    /// restrictions on the state of the chain: it is up to the caller to know where the TMS
+    /// values leave it.  Returns the bits captured from TDO.
+    fn shift_vectors(&mut self, tms: &BitVec, tdi: &BitVec) -> BitVec;
+
+    /// Pulse the TRST line, resetting every TAP to Test-Logic-Reset, and flush.  Returns false if
+    /// the cable doesn't have a TRST line.
+    fn trst(&mut self) -> bool {
+        false
+    }
+
+    /// The frequency of TCK in hertz, if the cable knows it.
+    fn frequency(&self) -> Option<u32> {
+        None
+    }
+
+    /// Wait for at least `duration` before clocking out anything queued after this call.  Should
+    /// only be called in a stable state (Reset, Idle, PauseDR or PauseIR): cables that know their
+    /// TCK frequency implement the wait by clocking TCK with TMS held at its current level, which
+    /// keeps the wait ordered with the rest of the queue.  The default flushes the queue and
+    /// sleeps.
+    fn delay(&mut self, duration: Duration) {
+        self.flush();
+        std::thread::sleep(duration);
+    }
+
+    /// The TAP state implied by the TMS values this cable has actually clocked, for cables that
+    /// keep track.  Drivers only trace TMS in debug builds, where `JtagSM` checks the trace after
+    /// every operation.
+    fn tms_trace(&self) -> Option<TapModel> {
+        None
+    }
+}
+
+/// Number of TCK cycles at `frequency` hertz needed to cover at least `duration`.
+pub(crate) fn cycles_for(duration: Duration, frequency: u32) -> usize {
+    (duration.as_nanos() * frequency as u128).div_ceil(1_000_000_000) as usize
+}
+
+/// Append bits `src_skip..src_bits` of `src` to `dst`, which currently holds `dst_bits` bits.
+pub(crate) fn bit_append(dst: &mut Vec<u8>, mut dst_bits: usize, src: &[u8], src_bits: usize, src_skip: usize) {
+    let mut byte = if !dst.is_empty() && !dst_bits.is_multiple_of(8) {
+        dst.pop().unwrap()
+    } else {
+        0
+    };
+
+    for i in src_skip..src_bits {
+        if src[i / 8] & (1 << (i % 8)) != 0{
+            byte |= 1 << (dst_bits % 8);
+        }
+
+        dst_bits += 1;
+        if dst_bits.is_multiple_of(8) {
+            dst.push(byte);
+            byte = 0;
+        }
+    }
+    if !dst_bits.is_multiple_of(8) {
+        dst.push(byte);
+    }
 }
 
 /// Helper function for constructing a cable from a string.  This is expected to be used by CLI
@@ -1,5 +1,7 @@
 //! Implement the `Cable` trait for FTDI RS232R-based adapters
+use crate::bits::BitVec;
 use crate::cable::Cable;
+use crate::statemachine::TapModel;
 
 use libftd2xx::{Ftdi, FtdiCommon, BitMode};
 
@@ -9,7 +11,9 @@ pub struct Ft232r {
     tdo: u8,
     tms: u8,
     clk: u8,
-    read_queue: Vec<Vec<u8>>,
+    read_queue: Vec<BitVec>,
+    // TMS values clocked so far
+    trace: TapModel,
 }
 
 impl Ft232r {
@@ -35,42 +39,26 @@ impl Ft232r {
             tms,
             clk,
             read_queue: vec![],
+            trace: TapModel::new(),
         }
     }
 
-    fn select_bit(mut recv: Vec<u8>, tdi: u8) -> Vec<u8> {
-        let mut recv_bits = vec![];
-        let mut byte = 0_u8;
-        let mut bit = 0;
-
-        // convert bytes to bits
-        loop {
-            // Second byte sampled when CLK was high
-            recv.remove(0);
-            // First byte sampled when CLK was low
-            let x = recv.remove(0);
-
-            if x & (1 << tdi) != 0 {
-                byte |= 1 << bit;
-            }
-            bit += 1;
-
-            if bit == 8 {
-                recv_bits.push(byte);
-                byte = 0;
-                bit = 0;
-            }
-
-            if recv.is_empty() {
-                break;
+    // Follow the TMS values in a buffer of pin states in debug builds.  TMS is sampled on the rising
+    // edge, i.e. in the entries with the clock high.
+    fn trace_pins(&mut self, buf: &[u8]) {
+        if cfg!(debug_assertions) {
+            for x in buf {
+                if x & (1 << self.clk) != 0 {
+                    self.trace.clock(x & (1 << self.tms) != 0);
+                }
             }
         }
+    }
 
-        if bit > 0 {
-            recv_bits.push(byte);
-        }
-
-        recv_bits
+    fn select_bit(recv: Vec<u8>, tdi: u8) -> BitVec {
+        // Each bit is clocked by a pair of entries.  The first was sampled when CLK was high, the
+        // second when CLK was low.
+        recv.chunks(2).map(|x| x[x.len() - 1] & (1 << tdi) != 0).collect()
     }
 }
 
@@ -93,11 +81,12 @@ impl Cable for Ft232r {
             buf.push(x << self.tms | tdo << self.tdo | 1 << self.clk);
         }
         let mut recv = vec![0; buf.len()];
+        self.trace_pins(&buf);
         self.ft.write(&buf).expect("send");
         self.ft.read(&mut recv).expect("send");
     }
 
-    fn read_data(&mut self, bits: usize) -> Vec<u8>
+    fn read_data(&mut self, bits: usize) -> BitVec
     {
         let mut buf = vec![];
         for _ in 0..bits {
@@ -106,45 +95,50 @@ impl Cable for Ft232r {
         }
 
         let mut recv = vec![0; buf.len()];
+        self.trace_pins(&buf);
         self.ft.write(&buf).expect("send");
         self.ft.read(&mut recv).expect("send");
         Self::select_bit(recv, self.tdi)
     }
 
-    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool)
+    fn write_data(&mut self, data: &BitVec, exit: &[usize])
     {
-        self.read_write_data(data, bits, pause_after);
+        self.read_write_data(data, exit);
     }
 
-    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Vec<u8> {
+    fn read_write_data(&mut self, data: &BitVec, exit: &[usize]) -> BitVec {
         let mut buf = vec![];
 
-        assert!(bits <= 8);
-        assert!(bits != 0);
+        assert!(!data.is_empty());
 
-        for x in &data[0..data.len()-1] {
-            for bit in 0..8 {
-                let tdo = (x >> bit) & 1;
-                buf.push(tdo << self.tdo);
-                buf.push(tdo << self.tdo | 1 << self.clk);
-            }
+        // the last bit is clocked with the first TMS value of the exit sequence
+        for (i, bit) in data.iter().enumerate() {
+            let tdo = bit as u8;
+            let tms = if i == data.len()-1 && exit.first().is_some_and(|x| *x != 0) {
+                1
+            } else {
+                0
+            };
+            buf.push(tms << self.tms | tdo << self.tdo);
+            buf.push(tms << self.tms | tdo << self.tdo | 1 << self.clk);
         }
+        let data_len = buf.len();
 
-        // handle last byte
-        let x = data[data.len()-1];
-        for bit in 0..bits {
-            let tdo = (x >> bit) & 1;
-            if bit == bits-1 && pause_after {
-                buf.push(1 << self.tms | tdo << self.tdo);
-                buf.push(1 << self.tms | tdo << self.tdo | 1 << self.clk);
-            }
-            buf.push(tdo << self.tdo);
-            buf.push(tdo << self.tdo | 1 << self.clk);
+        for x in exit.iter().skip(1) {
+            let x = if *x != 0 {
+                1
+            } else {
+                0
+            };
+            buf.push(x << self.tms | 1 << self.tdo);
+            buf.push(x << self.tms | 1 << self.tdo | 1 << self.clk);
         }
 
         let mut recv = vec![0; buf.len()];
+        self.trace_pins(&buf);
         self.ft.write(&buf).expect("send");
         self.ft.read(&mut recv).expect("send");
+        recv.truncate(data_len);
         Self::select_bit(recv, self.tdi)
     }
 
@@ -154,13 +148,42 @@ impl Cable for Ft232r {
         true
     }
 
-    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> bool {
-        let result = self.read_write_data(data, bits, pause_after);
+    fn queue_read_write(&mut self, data: &BitVec, exit: &[usize]) -> bool {
+        let result = self.read_write_data(data, exit);
         self.read_queue.push(result);
         true
     }
 
-    fn finish_read(&mut self, _bits: usize) -> Vec<u8> {
+    fn finish_read(&mut self, _bits: usize) -> BitVec {
         self.read_queue.remove(0)
     }
+
+    fn tms_trace(&self) -> Option<TapModel> {
+        if cfg!(debug_assertions) {
+            Some(self.trace)
+        } else {
+            None
+        }
+    }
+
+    fn shift_vectors(&mut self, tms: &BitVec, tdi: &BitVec) -> BitVec {
+        let mut buf = vec![];
+        assert_eq!(tms.len(), tdi.len());
+        if tms.is_empty() {
+            return BitVec::new();
+        }
+
+        for (x, tdo) in tms.iter().zip(tdi.iter()) {
+            let x = x as u8;
+            let tdo = tdo as u8;
+            buf.push(x << self.tms | tdo << self.tdo);
+            buf.push(x << self.tms | tdo << self.tdo | 1 << self.clk);
+        }
+
+        let mut recv = vec![0; buf.len()];
+        self.trace_pins(&buf);
+        self.ft.write(&buf).expect("send");
+        self.ft.read(&mut recv).expect("send");
+        Self::select_bit(recv, self.tdi)
+    }
 }
@@ -1,5 +1,7 @@
 //! Implement the `Cable` trait for "jlink" compatible hardware adapters
-use crate::cable::Cable;
+use crate::bits::BitVec;
+use crate::cable::{Cable, bit_append, cycles_for};
+use crate::statemachine::{JtagState, TapModel};
 
 use std::time::Duration;
 
@@ -7,9 +9,30 @@ use rusb::{DeviceHandle, Direction, GlobalContext};
 use rusb::constants::*;
 
 const TAP_SEQUENCE_MAX: usize = 390;
+// Number of bits shift_vectors() puts in a single EMU_CMD_HW_JTAG3 command
+const VECTOR_CHUNK_BITS: usize = TAP_SEQUENCE_MAX / 2 * 8;
+// Number of bits change_mode() and delay() put in a single EMU_CMD_HW_JTAG3 command
+const SEQUENCE_CHUNK_BITS: usize = (TAP_SEQUENCE_MAX - 1) * 8;
+
+/// The USB bulk transfers used to talk to the adapter.  Implemented for libusb device handles, and
+/// by anything else that can stand in for the adapter, such as a model of it for testing.
+pub trait BulkTransfer {
+    fn read_bulk(&mut self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize, rusb::Error>;
+    fn write_bulk(&mut self, endpoint: u8, buf: &[u8], timeout: Duration) -> Result<usize, rusb::Error>;
+}
+
+impl BulkTransfer for DeviceHandle<GlobalContext> {
+    fn read_bulk(&mut self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize, rusb::Error> {
+        DeviceHandle::read_bulk(self, endpoint, buf, timeout)
+    }
+
+    fn write_bulk(&mut self, endpoint: u8, buf: &[u8], timeout: Duration) -> Result<usize, rusb::Error> {
+        DeviceHandle::write_bulk(self, endpoint, buf, timeout)
+    }
+}
 
-pub struct JLink {
-    device: DeviceHandle<GlobalContext>,
+pub struct JLink<D = DeviceHandle<GlobalContext>> {
+    device: D,
     // queued bytes to send
     buffer: Vec<u8>,
     // number of bytes we'll receive after sending the above
@@ -26,33 +49,15 @@ pub struct JLink {
     tdo_buf: Vec<u8>,
     // number of bits in the above
     send_bits: usize,
+    // level TMS was left at by the last queued bit
+    tms_level: bool,
+    // TMS values queued so far
+    trace: TapModel,
+    clock: u32,
     read_endpoint: u8,
     write_endpoint: u8,
 }
 
-fn bit_append (dst: &mut Vec<u8>, mut dst_bits: usize, src: &[u8], src_bits: usize, src_skip: usize) {
-    let mut byte = if !dst.is_empty() && dst_bits % 8 != 0 {
-        dst.pop().unwrap()
-    } else {
-        0
-    };
-
-    for i in src_skip..src_bits {
-        if src[i / 8] & (1 << (i % 8)) != 0{
-            byte |= 1 << (dst_bits % 8);
-        }
-
-        dst_bits += 1;
-        if dst_bits % 8 == 0 {
-            dst.push(byte);
-            byte = 0;
-        }
-    }
-    if dst_bits % 8 != 0 {
-        dst.push(byte);
-    }
-}
-
 impl JLink {
     pub fn new(clock: u32) -> Self {
         let device = rusb::open_device_with_vid_pid(0x1366, 0x0105).expect("no jlink attached");
@@ -84,31 +89,41 @@ impl JLink {
                 let mut buf = [0; 2];
                 let _ = device.read_bulk(read_endpoint, &mut buf, Duration::from_millis(10));
 
-                let mut jlink = Self {
-                    device,
-                    buffer: vec![],
-                    tms_buf: vec![],
-                    tdo_buf: vec![],
-                    queued_reads: vec![],
-                    queued_read_offsets: vec![],
-                    queued_send_bits: vec![],
-                    send_bits: 0,
-                    recv_bytes: 0,
-                    read_endpoint,
-                    write_endpoint,
-                };
-
-                jlink.get_status();
-                jlink.set_clock(clock);
-                jlink.set_interface(0);
-                jlink.deassert_trst();
-                jlink.deassert_srst();
-
-                return jlink;
+                return Self::from_device(device, read_endpoint, write_endpoint, clock);
             }
         }
         panic!("no jlink attached");
     }
+}
+
+impl<D: BulkTransfer> JLink<D> {
+    /// Use an adapter that has already been opened, talking to it through the given bulk
+    /// endpoints
+    pub fn from_device(device: D, read_endpoint: u8, write_endpoint: u8, clock: u32) -> Self {
+        let mut jlink = Self {
+            device,
+            buffer: vec![],
+            tms_buf: vec![],
+            tdo_buf: vec![],
+            queued_reads: vec![],
+            queued_read_offsets: vec![],
+            queued_send_bits: vec![],
+            send_bits: 0,
+            tms_level: false,
+            trace: TapModel::new(),
+            clock: 0,
+            recv_bytes: 0,
+            read_endpoint,
+            write_endpoint,
+        };
+
+        jlink.get_status();
+        jlink.set_clock(clock);
+        jlink.set_interface(0);
+        jlink.deassert_trst();
+        jlink.deassert_srst();
+        jlink
+    }
 
     fn send_command(&mut self, cmd: u8, mut data: Vec<u8>) {
         self.flush_tap_sequence();
@@ -195,6 +210,7 @@ impl JLink {
     }
 
     pub fn set_clock(&mut self, mut clock: u32) {
+        self.clock = clock;
         clock /= 1000;
         let buf = vec![(clock & 0xff) as u8, ((clock >> 8) & 0xff) as u8];
         self.send_command(0x5, buf);
@@ -224,13 +240,20 @@ impl JLink {
 
     fn tap_sequence(&mut self, tms: Vec<u8>, tdo: Vec<u8>, bits: usize) {
         assert_eq!(tms.len(), tdo.len());
-        if self.tms_buf.len() + tms.len() > TAP_SEQUENCE_MAX {
+        assert!(bits.div_ceil(8) < TAP_SEQUENCE_MAX);
+        if (self.send_bits + bits).div_ceil(8) >= TAP_SEQUENCE_MAX {
             self.flush_tap_sequence();
         }
 
         bit_append(&mut self.tms_buf, self.send_bits, &tms, bits, 0);
         bit_append(&mut self.tdo_buf, self.send_bits, &tdo, bits, 0);
         self.send_bits += bits;
+        if bits > 0 {
+            self.tms_level = tms[(bits - 1) / 8] & (1 << ((bits - 1) % 8)) != 0;
+        }
+        if cfg!(debug_assertions) {
+            self.trace.clock_bits(&tms, bits);
+        }
     }
 
     fn flush_tap_sequence(&mut self) {
@@ -249,104 +272,186 @@ impl JLink {
         }
     }
 
-    fn send_tdo(&mut self, data: &[u8], bits: u8, pause_after: bool) {
-        let mut total_bits = (data.len()-1) * 8 + (bits as usize);
+    fn send_tdo(&mut self, data: &[u8], bits: u8, exit: &[usize]) {
+        let total_bits = (data.len()-1) * 8 + (bits as usize);
 
         let mut tms = vec![0; data.len()];
         let mut data = data.to_vec();
+        let len = data.len();
+        if bits < 8 {
+            // Clear the unused bits so the rest of the exit sequence can be appended
+            data[len-1] &= (1 << bits) - 1;
+        }
 
-        if pause_after {
-            let len = tms.len();
+        if exit.first().is_some_and(|x| *x != 0) {
             tms[len-1] |= 1 << (bits-1);
+        }
 
-            // Add an extra clock for the transition to pause state
-            if total_bits % 8 == 0 {
-                data.push(0xff);
-                tms.push(0);
+        // Add extra clocks for the rest of the exit sequence
+        let mut extra = vec![];
+        let mut byte = 0u8;
+        for (i, x) in exit.iter().skip(1).enumerate() {
+            if *x != 0 {
+                byte |= 1 << (i % 8);
+            }
+            if i % 8 == 7 {
+                extra.push(byte);
+                byte = 0;
             }
-            total_bits += 1;
         }
+        extra.push(byte);
+        let extra_bits = exit.len().saturating_sub(1);
+        bit_append(&mut tms, total_bits, &extra, extra_bits, 0);
+        bit_append(&mut data, total_bits, &vec![0xff; extra.len()], extra_bits, 0);
 
-        self.tap_sequence(tms, data, total_bits);
+        self.tap_sequence(tms, data, total_bits + extra_bits);
     }
 
-    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> bool {
-        if self.tms_buf.len() + data.len() + (bits as usize + 7) / 8 + 1 >= TAP_SEQUENCE_MAX {
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, exit: &[usize]) -> bool {
+        if self.tms_buf.len() + data.len() + (bits as usize).div_ceil(8) + 1 >= TAP_SEQUENCE_MAX {
             return false;
         }
         self.queued_read_offsets.push(self.recv_bytes);
         self.queued_send_bits.push(self.send_bits);
 
-        self.send_tdo(data, bits, pause_after);
+        self.send_tdo(data, bits, exit);
         true
     }
 
+    fn shift_vectors(&mut self, tms: &[u8], tdi: &[u8], bits: usize) -> Result<Vec<u8>, rusb::Error> {
+        assert!(self.queued_read_offsets.is_empty());
+
+        let mut tdo = vec![];
+        let mut start = 0;
+        while start < bits {
+            let len = std::cmp::min(bits - start, VECTOR_CHUNK_BITS);
+            let mut tms_chunk = vec![];
+            bit_append(&mut tms_chunk, 0, tms, start + len, start);
+            let mut tdi_chunk = vec![];
+            bit_append(&mut tdi_chunk, 0, tdi, start + len, start);
+
+            // Make sure the sequence doesn't get flushed out from under the offsets we record
+            if self.tms_buf.len() + tms_chunk.len() + 1 >= TAP_SEQUENCE_MAX {
+                self.flush_tap_sequence();
+            }
+            self.queued_read_offsets.push(self.recv_bytes);
+            self.queued_send_bits.push(self.send_bits);
+            self.tap_sequence(tms_chunk, tdi_chunk, len);
+
+            let data = self.finish_read(len)?;
+            bit_append(&mut tdo, start, &data, len, 0);
+            start += len;
+        }
+        Ok(tdo)
+    }
 }
 
-impl Cable for JLink {
+impl<D: BulkTransfer> Cable for JLink<D> {
     fn change_mode(&mut self, tms: &[usize], tdo: bool) {
-        let mut buf = vec![];
-        let mut byte = 0u8;
-        for (i, x) in tms.iter().enumerate() {
-            if *x != 0 {
-                byte |= 1 << (i % 8);
+        // Long runs of clocks, such as from JtagSM::run_test(), take more than one command
+        for tms in tms.chunks(SEQUENCE_CHUNK_BITS) {
+            let mut buf = vec![];
+            let mut byte = 0u8;
+            for (i, x) in tms.iter().enumerate() {
+                if *x != 0 {
+                    byte |= 1 << (i % 8);
+                }
+                if i % 8 == 7 {
+                    buf.push(byte);
+                    byte = 0;
+                }
             }
-            if i % 8 == 7 {
+
+            // Push the last byte for cases when we don't have a multiple of 8
+            // transitions.
+            if !tms.len().is_multiple_of(8) {
                 buf.push(byte);
-                byte = 0;
             }
-        }
-
-        // Push the last byte for cases when we don't have a multiple of 8
-        // transitions.
-        if tms.len() % 8 != 0 {
-            buf.push(byte);
-        }
 
-        let tdo_bytes = if tdo {
-            vec![0xff; buf.len()]
-        } else {
-            vec![0; buf.len()]
-        };
+            let tdo_bytes = if tdo {
+                vec![0xff; buf.len()]
+            } else {
+                vec![0; buf.len()]
+            };
 
-        self.tap_sequence(buf, tdo_bytes, tms.len());
+            self.tap_sequence(buf, tdo_bytes, tms.len());
+        }
     }
 
     fn queue_read(&mut self, mut bits: usize) -> bool {
-        let bytes = (bits + 7) / 8;
+        let bytes = bits.div_ceil(8);
         let buf = vec![0xff; bytes];
 
         bits %= 8;
         if bits == 0 {
             bits = 8;
         }
-        self.queue_read_write(&buf, bits as u8, false)
+        self.queue_read_write(&buf, bits as u8, &[])
     }
 
-    fn read_data(&mut self, bits: usize) -> Vec<u8> {
+    fn read_data(&mut self, bits: usize) -> BitVec {
         self.queue_read(bits);
         Cable::finish_read(self, bits)
     }
 
-    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) {
-        self.send_tdo(data, bits, pause_after);
+    fn write_data(&mut self, data: &BitVec, exit: &[usize]) {
+        self.send_tdo(data.as_bytes(), data.last_bits(), exit);
     }
 
-    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Vec<u8> {
-        self.queue_read_write(data, bits, pause_after);
-        let total_bits = (data.len()-1) * 8 + (bits as usize);
-        Cable::finish_read(self, total_bits)
+    fn read_write_data(&mut self, data: &BitVec, exit: &[usize]) -> BitVec {
+        self.queue_read_write(data.as_bytes(), data.last_bits(), exit);
+        Cable::finish_read(self, data.len())
     }
 
-    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> bool {
-        self.queue_read_write(data, bits, pause_after)
+    fn queue_read_write(&mut self, data: &BitVec, exit: &[usize]) -> bool {
+        self.queue_read_write(data.as_bytes(), data.last_bits(), exit)
     }
 
-    fn finish_read(&mut self, bits: usize) -> Vec<u8> {
-        self.finish_read(bits).expect("finish_read")
+    fn finish_read(&mut self, bits: usize) -> BitVec {
+        let data = self.finish_read(bits).expect("finish_read");
+        BitVec::from_bytes(&data, bits)
     }
 
     fn flush(&mut self) {
         self.read_data(0).expect("flush");
     }
+
+    fn shift_vectors(&mut self, tms: &BitVec, tdi: &BitVec) -> BitVec {
+        assert_eq!(tms.len(), tdi.len());
+        let tdo = self.shift_vectors(tms.as_bytes(), tdi.as_bytes(), tms.len()).expect("shift_vectors");
+        BitVec::from_bytes(&tdo, tms.len())
+    }
+
+    fn frequency(&self) -> Option<u32> {
+        Some(self.clock)
+    }
+
+    fn tms_trace(&self) -> Option<TapModel> {
+        if cfg!(debug_assertions) {
+            Some(self.trace)
+        } else {
+            None
+        }
+    }
+
+    fn trst(&mut self) -> bool {
+        Cable::flush(self);
+        self.assert_trst();
+        self.deassert_trst();
+        self.trace = TapModel::at(JtagState::Reset);
+        true
+    }
+
+    fn delay(&mut self, duration: Duration) {
+        // The adapter has no wait command, so keep clocking with TMS where it is
+        let mut cycles = cycles_for(duration, self.clock);
+        let tms = if self.tms_level { 0xff } else { 0 };
+
+        while cycles > 0 {
+            let len = std::cmp::min(cycles, SEQUENCE_CHUNK_BITS);
+            let bytes = len.div_ceil(8);
+            self.tap_sequence(vec![tms; bytes], vec![0xff; bytes], len);
+            cycles -= len;
+        }
+    }
 }
@@ -1,5 +1,7 @@
 //! Implement the `Cable` trait for "jtagkey" compatible hardware adapters like the Bus Blaster
-use crate::cable::Cable;
+use crate::bits::BitVec;
+use crate::cable::{Cable, bit_append, cycles_for};
+use crate::statemachine::{JtagState, TapModel};
 
 use std::time::Duration;
 
@@ -8,15 +10,97 @@ use ftdi_mpsse::{ClockTMSOut, ClockTMS};
 use libftd2xx::{ClockData, ClockDataOut, ClockBits, ClockBitsOut};
 
 const MAX_BUFFER_SIZE: usize = 4096;
+// Number of bits shift_vectors() sends per transfer.  Worst case is one 3-byte clock_tms command
+// per bit, which keeps the commands within MAX_BUFFER_SIZE.
+const VECTOR_CHUNK_BITS: usize = 1024;
+
+// Clock TCK without transferring data, leaving TMS and TDI alone.  Not available on the FT2232D.
+const CLOCK_BITS_NO_DATA: u8 = 0x8e;
+const CLOCK_BYTES_NO_DATA: u8 = 0x8f;
+
+/// Clock the last bit of a shift with a TMS command that also carries as much of `exit` as will
+/// fit, then clock out the rest of `exit`.  If `read` is true, TDO is captured by the first
+/// command.  Returns the number of TMS bits in the first command.
+fn exit_shift(mut builder: MpsseCmdBuilder, last_bit: bool, exit: &[usize], read: bool)
+    -> (MpsseCmdBuilder, usize)
+{
+    let mut first = 0;
+    let mut first_len = 0;
+    for x in exit.iter().take(7) {
+        if *x != 0 {
+            first |= 1 << first_len;
+        }
+        first_len += 1;
+    }
+    // Stay in the shift state if there is nowhere to go
+    let first_len = std::cmp::max(first_len, 1);
+
+    if read {
+        builder = builder.clock_tms(ClockTMS::NegTMSPosTDO, first, last_bit, first_len as u8);
+    } else {
+        builder = builder.clock_tms_out(ClockTMSOut::NegEdge, first, last_bit, first_len as u8);
+    }
+
+    for chunk in exit[std::cmp::min(exit.len(), 7)..].chunks(7) {
+        let mut buf = 0;
+        for (i, x) in chunk.iter().enumerate() {
+            if *x != 0 {
+                buf |= 1 << i;
+            }
+        }
+        builder = builder.clock_tms_out(ClockTMSOut::NegEdge, buf, last_bit, chunk.len() as u8);
+    }
+    (builder, first_len)
+}
+
+/// Follow the TMS values clocked by a sequence of MPSSE commands.  Only understands the commands
+/// that `Mpsse` queues: everything other than the TMS commands holds TMS at its last level.
+fn trace_commands(trace: &mut TapModel, tms_level: &mut bool, commands: &[u8]) {
+    let mut i = 0;
+    while i < commands.len() {
+        let cmd = commands[i];
+        // Commands that write TDI carry data after the length
+        let write = cmd & 0x10 != 0;
+        if cmd == CLOCK_BITS_NO_DATA {
+            trace.clock_held(*tms_level, commands[i+1] as usize + 1);
+            i += 2;
+        } else if cmd == CLOCK_BYTES_NO_DATA {
+            let len = (commands[i+1] as usize | (commands[i+2] as usize) << 8) + 1;
+            trace.clock_held(*tms_level, len * 8);
+            i += 3;
+        } else if cmd & 0x40 != 0 {
+            // TMS command: length, then TMS bits with TDI in the top bit
+            let len = commands[i+1] as usize + 1;
+            for bit in 0..len {
+                *tms_level = commands[i+2] & (1 << bit) != 0;
+                trace.clock(*tms_level);
+            }
+            i += 3;
+        } else if cmd & 0x02 != 0 {
+            // Bit command: length, then one byte of data
+            trace.clock_held(*tms_level, commands[i+1] as usize + 1);
+            i += if write { 3 } else { 2 };
+        } else {
+            // Byte command: 16-bit length, then the data
+            let len = (commands[i+1] as usize | (commands[i+2] as usize) << 8) + 1;
+            trace.clock_held(*tms_level, len * 8);
+            i += if write { 3 + len } else { 3 };
+        }
+    }
+}
 
 pub struct Mpsse<T> {
     ft: T,
+    clock: u32,
     // Data to send to the adapter
     buffer: Vec<u8>,
     // Data we have read from the adapter and not yet returned
     queued_reads: Vec<u8>,
-    // (bits, bytes, write, pause)
-    queued_read_state: Vec<(usize, usize, bool, bool)>,
+    // TMS values queued so far, and the level the last one left TMS at
+    trace: TapModel,
+    tms_level: bool,
+    // (bits, bytes, TMS bits clocked by the command carrying the last bit or 0 for plain reads)
+    queued_read_state: Vec<(usize, usize, usize)>,
 }
 
 impl<T: FtdiMpsse + MpsseCmdExecutor> Mpsse<T>
@@ -34,11 +118,22 @@ impl<T: FtdiMpsse + MpsseCmdExecutor> Mpsse<T>
 
         Self {
             ft,
+            clock,
+            trace: TapModel::new(),
+            tms_level: true,
             buffer: vec![],
             queued_reads: vec![],
             queued_read_state: vec![],
         }
     }
+
+    // Queue commands to send to the adapter, following their TMS values in debug builds
+    fn append_commands(&mut self, commands: &[u8]) {
+        if cfg!(debug_assertions) {
+            trace_commands(&mut self.trace, &mut self.tms_level, commands);
+        }
+        self.buffer.extend_from_slice(commands);
+    }
 }
 
 impl<T: FtdiMpsse + MpsseCmdExecutor> Cable for Mpsse<T>
@@ -66,7 +161,7 @@ impl<T: FtdiMpsse + MpsseCmdExecutor> Cable for Mpsse<T>
         if len + self.buffer.len() > MAX_BUFFER_SIZE {
             self.flush();
         }
-        self.buffer.append(&mut builder.as_slice().to_vec());
+        self.append_commands(builder.as_slice());
     }
 
     fn queue_read(&mut self, mut bits: usize) -> bool
@@ -91,21 +186,21 @@ impl<T: FtdiMpsse + MpsseCmdExecutor> Cable for Mpsse<T>
         }
 
         let total_bytes = bytes + self.queued_read_state.iter()
-            .map(|x| (x.0 + 7) / 8)
+            .map(|x| x.0.div_ceil(8))
             .sum::<usize>();
 
         if total_bytes < MAX_BUFFER_SIZE {
-            self.queued_read_state.push((orig_bits, bytes, false, false));
-            self.buffer.append(&mut builder.as_slice().to_vec());
+            self.queued_read_state.push((orig_bits, bytes, 0));
+            self.append_commands(builder.as_slice());
             true
         } else {
             false
         }
     }
 
-    fn finish_read(&mut self, mut bits: usize) -> Vec<u8>
+    fn finish_read(&mut self, mut bits: usize) -> BitVec
     {
-        let (orig_bits, bytes, pause_after, write) = self.queued_read_state.remove(0);
+        let (orig_bits, bytes, tms_bits) = self.queued_read_state.remove(0);
         assert_eq!(bits, orig_bits);
 
         if self.queued_reads.is_empty() {
@@ -122,45 +217,41 @@ impl<T: FtdiMpsse + MpsseCmdExecutor> Cable for Mpsse<T>
         // split_off returns the second half of the vec, but we want the first half
         std::mem::swap(&mut buf, &mut self.queued_reads);
 
-        if pause_after {
-            buf.pop();
-        }
-
-        if write {
-            let len = buf.len();
-            buf[len-1] >>= 7;
+        if tms_bits > 0 {
+            // The last bit came from clock_tms, and is the first of the bits it shifted in from
+            // the top of the byte
+            let last_recv = (buf.pop().unwrap() >> (8 - tms_bits)) & 1;
 
             bits -= 1;
-            if bits >= 1 {
-                // Shift the bits from clock_bits
-                buf[len-2] >>= 8 - (bits % 8);
-
-                // Need to repack the bit from clock_tms into the bits from clock_bits
-                let last_recv = buf[len-1] & 1;
-                buf[len-2] |=  last_recv << (bits % 8);
-                buf.pop();
+            if bits.is_multiple_of(8) {
+                buf.push(last_recv);
+            } else {
+                // Shift the bits from clock_bits and repack the bit from clock_tms into them
+                let last_idx = buf.len()-1;
+                buf[last_idx] >>= 8 - (bits % 8);
+                buf[last_idx] |= last_recv << (bits % 8);
             }
         } else {
-            if bits % 8 != 0 {
+            if !bits.is_multiple_of(8) {
                 let last_idx = buf.len()-1;
                 buf[last_idx] >>= 8 - (bits % 8);
             }
         }
-        buf
+        BitVec::from_bytes(&buf, orig_bits)
     }
 
-    fn read_data(&mut self, bits: usize) -> Vec<u8>
+    fn read_data(&mut self, bits: usize) -> BitVec
     {
         assert!(self.queued_read_state.is_empty());
         self.queue_read(bits);
         self.finish_read(bits)
     }
 
-    fn write_data(&mut self, data: &[u8], mut bits: u8, pause_after: bool)
+    fn write_data(&mut self, data: &BitVec, exit: &[usize])
     {
         let mut builder = MpsseCmdBuilder::new();
-        assert!(bits <= 8);
-        assert!(bits != 0);
+        let mut bits = data.last_bits();
+        let data = data.as_bytes();
 
         // We will send the last bit using clock_tms
         bits -= 1;
@@ -173,28 +264,22 @@ impl<T: FtdiMpsse + MpsseCmdExecutor> Cable for Mpsse<T>
             builder = builder.clock_bits_out(ClockBitsOut::LsbNeg, last_byte, bits);
         }
         let last_bit = last_byte & (1 << bits) != 0;
-        // Change to pause state
-        if pause_after {
-            builder = builder.clock_tms_out(ClockTMSOut::NegEdge, 1, last_bit, 2);
-        } else {
-            builder = builder.clock_tms_out(ClockTMSOut::NegEdge, 0, last_bit, 1);
-        }
+        let (builder, _) = exit_shift(builder, last_bit, exit, false);
 
         let len = builder.as_slice().len();
         if len + self.buffer.len() > MAX_BUFFER_SIZE {
             self.flush();
         }
-        self.buffer.append(&mut builder.as_slice().to_vec());
+        self.append_commands(builder.as_slice());
     }
 
-    fn queue_read_write(&mut self, data: &[u8], mut bits: u8, pause_after: bool) -> bool {
-        let total_bits = (data.len()-1) * 8 + bits as usize;
+    fn queue_read_write(&mut self, data: &BitVec, exit: &[usize]) -> bool {
+        let total_bits = data.len();
+        let mut bits = data.last_bits();
+        let data = data.as_bytes();
         let mut read_bytes = 1;
         let mut builder = MpsseCmdBuilder::new();
 
-        assert!(bits <= 8);
-        assert!(bits != 0);
-
         // We will send the last bit using clock_tms
         bits -= 1;
 
@@ -208,13 +293,7 @@ impl<T: FtdiMpsse + MpsseCmdExecutor> Cable for Mpsse<T>
             read_bytes += 1;
         }
         let last_bit = last_byte & (1 << bits) != 0;
-
-        // Change to pause state
-        if pause_after {
-            builder = builder.clock_tms(ClockTMS::NegTMSPosTDO, 1, last_bit, 1);
-            read_bytes += 1;
-        }
-        builder = builder.clock_tms(ClockTMS::NegTMSPosTDO, 0, last_bit, 1);
+        let (builder, tms_bits) = exit_shift(builder, last_bit, exit, true);
 
         let len = builder.as_slice().len();
         if len + self.buffer.len() > MAX_BUFFER_SIZE {
@@ -226,25 +305,137 @@ impl<T: FtdiMpsse + MpsseCmdExecutor> Cable for Mpsse<T>
             .sum::<usize>();
 
         if total_bytes < MAX_BUFFER_SIZE {
-            self.queued_read_state.push((total_bits, read_bytes, true, pause_after));
-            self.buffer.append(&mut builder.as_slice().to_vec());
+            self.queued_read_state.push((total_bits, read_bytes, tms_bits));
+            self.append_commands(builder.as_slice());
             true
         } else {
             false
         }
     }
 
-    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Vec<u8> {
+    fn read_write_data(&mut self, data: &BitVec, exit: &[usize]) -> BitVec {
         assert!(self.queued_read_state.is_empty());
-        self.queue_read_write(data, bits, pause_after);
-        let total_bits = (data.len()-1) * 8 + bits as usize;
-        self.finish_read(total_bits)
+        self.queue_read_write(data, exit);
+        self.finish_read(data.len())
     }
 
     fn flush(&mut self) {
         self.ft.send(&self.buffer).expect("flush");
         self.buffer.clear();
     }
+
+    fn frequency(&self) -> Option<u32> {
+        Some(self.clock)
+    }
+
+    fn tms_trace(&self) -> Option<TapModel> {
+        if cfg!(debug_assertions) {
+            Some(self.trace)
+        } else {
+            None
+        }
+    }
+
+    fn delay(&mut self, duration: Duration) {
+        let mut cycles = cycles_for(duration, self.clock);
+        let mut builder = MpsseCmdBuilder::new();
+
+        while cycles >= 8 {
+            let bytes = std::cmp::min(cycles / 8, 0x10000);
+            let len = bytes - 1;
+            builder.0.extend_from_slice(&[CLOCK_BYTES_NO_DATA, (len & 0xff) as u8, ((len >> 8) & 0xff) as u8]);
+            cycles -= bytes * 8;
+        }
+        if cycles > 0 {
+            builder.0.extend_from_slice(&[CLOCK_BITS_NO_DATA, (cycles - 1) as u8]);
+        }
+
+        let len = builder.as_slice().len();
+        if len + self.buffer.len() > MAX_BUFFER_SIZE {
+            self.flush();
+        }
+        self.append_commands(builder.as_slice());
+    }
+
+    fn shift_vectors(&mut self, tms: &BitVec, tdi: &BitVec) -> BitVec {
+        assert!(self.queued_read_state.is_empty());
+        assert_eq!(tms.len(), tdi.len());
+        let bits = tms.len();
+        let tms = tms.as_bytes();
+        let tdi = tdi.as_bytes();
+        let bit = |buf: &[u8], i: usize| buf[i / 8] & (1 << (i % 8)) != 0;
+
+        let mut tdo = vec![];
+        let mut start = 0;
+        while start < bits {
+            let end = std::cmp::min(start + VECTOR_CHUNK_BITS, bits);
+            let mut builder = MpsseCmdBuilder::new();
+            // (bits, bytes) returned by each command
+            let mut segments = vec![];
+            // The data commands leave TMS wherever the last TMS command put it, so they can only be
+            // used once we know it is low.
+            let mut tms_low = false;
+
+            let mut i = start;
+            while i < end {
+                if tms_low && !bit(tms, i) {
+                    let mut len = 0;
+                    while i + len < end && !bit(tms, i + len) {
+                        len += 1;
+                    }
+
+                    let mut data = vec![];
+                    bit_append(&mut data, 0, tdi, i + len, i);
+                    let bytes = len / 8;
+                    if bytes > 0 {
+                        builder = builder.clock_data(ClockData::LsbPosIn, &data[..bytes]);
+                        segments.push((bytes * 8, bytes));
+                    }
+                    if !len.is_multiple_of(8) {
+                        builder = builder.clock_bits(ClockBits::LsbPosIn, data[bytes], (len % 8) as u8);
+                        segments.push((len % 8, 1));
+                    }
+                    i += len;
+                } else {
+                    // clock_tms holds TDI constant, so split whenever TDI changes
+                    let level = bit(tdi, i);
+                    let mut len = 0;
+                    let mut buf = 0;
+                    while i + len < end && len < 7 && bit(tdi, i + len) == level {
+                        if bit(tms, i + len) {
+                            buf |= 1 << len;
+                        }
+                        len += 1;
+                    }
+                    builder = builder.clock_tms(ClockTMS::NegTMSPosTDO, buf, level, len as u8);
+                    segments.push((len, 1));
+                    tms_low = buf & (1 << (len - 1)) == 0;
+                    i += len;
+                }
+            }
+
+            let read_bytes = segments.iter().map(|x| x.1).sum::<usize>();
+            let mut recv = vec![0; read_bytes];
+            self.append_commands(builder.as_slice());
+            self.ft.xfer(&self.buffer, &mut recv).expect("send");
+            self.buffer.clear();
+
+            let mut offset = 0;
+            let mut tdo_bits = start;
+            for (seg_bits, seg_bytes) in segments {
+                let mut data = recv[offset..offset + seg_bytes].to_vec();
+                if seg_bits < 8 {
+                    // Partial bytes are shifted in from the top
+                    data[0] >>= 8 - seg_bits;
+                }
+                bit_append(&mut tdo, tdo_bits, &data, seg_bits, 0);
+                tdo_bits += seg_bits;
+                offset += seg_bytes;
+            }
+            start = end;
+        }
+        BitVec::from_bytes(&tdo, bits)
+    }
 }
 
 // Lower pins
@@ -307,20 +498,20 @@ impl Cable for JtagKey {
         self.ft.change_mode(tms, tdo)
     }
 
-    fn read_data(&mut self, bits: usize) -> Vec<u8> {
+    fn read_data(&mut self, bits: usize) -> BitVec {
         self.ft.read_data(bits)
     }
 
-    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) {
-        self.ft.write_data(data, bits, pause_after)
+    fn write_data(&mut self, data: &BitVec, exit: &[usize]) {
+        self.ft.write_data(data, exit)
     }
 
-    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Vec<u8> {
-        self.ft.read_write_data(data, bits, pause_after)
+    fn read_write_data(&mut self, data: &BitVec, exit: &[usize]) -> BitVec {
+        self.ft.read_write_data(data, exit)
     }
 
-    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> bool {
-        self.ft.queue_read_write(data, bits, pause_after)
+    fn queue_read_write(&mut self, data: &BitVec, exit: &[usize]) -> bool {
+        self.ft.queue_read_write(data, exit)
     }
 
     fn flush(&mut self) {
@@ -331,7 +522,31 @@ impl Cable for JtagKey {
         self.ft.queue_read(bits)
     }
 
-    fn finish_read(&mut self, bits: usize) -> Vec<u8> {
+    fn finish_read(&mut self, bits: usize) -> BitVec {
         self.ft.finish_read(bits)
     }
+
+    fn shift_vectors(&mut self, tms: &BitVec, tdi: &BitVec) -> BitVec {
+        self.ft.shift_vectors(tms, tdi)
+    }
+
+    fn frequency(&self) -> Option<u32> {
+        self.ft.frequency()
+    }
+
+    fn tms_trace(&self) -> Option<TapModel> {
+        self.ft.tms_trace()
+    }
+
+    fn trst(&mut self) -> bool {
+        self.ft.flush();
+        self.ft.ft.set_gpio_upper(PIN_N_SRST, UPPER_OUTPUT_PINS).expect("pins");
+        self.ft.ft.set_gpio_upper(PIN_N_TRST | PIN_N_SRST, UPPER_OUTPUT_PINS).expect("pins");
+        self.ft.trace = TapModel::at(JtagState::Reset);
+        true
+    }
+
+    fn delay(&mut self, duration: Duration) {
+        self.ft.delay(duration)
+    }
 }
@@ -0,0 +1,457 @@
+//! A simulated scan chain that implements the `Cable` trait, for testing code that uses the crate
+//! without hardware.  Each TAP has an instruction register, BYPASS, an optional IDCODE and an
+//! optional boundary register described by BSDL.  Boundary register pins can be wired together
+//! into nets, and faults can be injected into the board: open pins, nets stuck at a value, shorts
+//! between nets and a stuck TDO.
+//!
+//! Shorted nets behave as a wired-AND, and pins that nothing drives read as 1, as if pulled up.
+use crate::bits::BitVec;
+use crate::bsdl::{Bsdl, BoundaryCell, CellFunction};
+use crate::cable::Cable;
+use crate::statemachine::{JtagState, TapModel};
+
+/// The data register an instruction selects
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum SimRegister {
+    Bypass,
+    Idcode,
+    /// The boundary register, with the pins under normal control (SAMPLE/PRELOAD)
+    Sample,
+    /// The boundary register, with the pins driven from it (EXTEST)
+    Extest,
+}
+
+/// A fault on the simulated board
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum SimFault {
+    /// The pin `port` of TAP `tap` is disconnected from its net
+    Open { tap: usize, port: String },
+    /// The net is stuck at `value` no matter what drives it
+    StuckAt { net: String, value: bool },
+    /// The two nets are shorted together
+    Short(String, String),
+    /// TDO always reads as `value`
+    TdoStuck(bool),
+}
+
+/// One simulated TAP
+#[derive(Clone,Debug)]
+pub struct SimTap {
+    irlen: usize,
+    ir_capture: BitVec,
+    idcode: Option<u32>,
+    instructions: Vec<(BitVec, SimRegister)>,
+    boundary_length: usize,
+    cells: Vec<BoundaryCell>,
+
+    ir: BitVec,
+    selected: SimRegister,
+    ir_shift: BitVec,
+    dr_shift: BitVec,
+    // Update stage of the boundary register
+    update: BitVec,
+}
+
+impl SimTap {
+    /// Create a TAP with an `irlen` bit instruction register that captures 01.  It selects IDCODE
+    /// at reset if `idcode` is given, and BYPASS otherwise.  Every instruction selects BYPASS until
+    /// others are added with `with_instruction`.
+    pub fn new(irlen: usize, idcode: Option<u32>) -> Self {
+        assert!(irlen >= 2);
+        let mut tap = Self {
+            irlen,
+            ir_capture: BitVec::from_u64(1, irlen),
+            idcode,
+            instructions: vec![],
+            boundary_length: 0,
+            cells: vec![],
+            ir: BitVec::ones(irlen),
+            selected: SimRegister::Bypass,
+            ir_shift: BitVec::zeros(irlen),
+            dr_shift: BitVec::zeros(1),
+            update: BitVec::new(),
+        };
+        tap.reset();
+        tap
+    }
+
+    /// Create a TAP that behaves as described by `bsdl`, with its IDCODE, instruction capture
+    /// value, instructions and boundary register.  Don't-care bits are 0.
+    pub fn from_bsdl(bsdl: &Bsdl) -> Self {
+        let idcode = bsdl.idcode.as_ref().map(|p| p.value.to_u64().unwrap() as u32);
+        let mut tap = Self::new(bsdl.irlen, idcode);
+        if let Some(capture) = &bsdl.ir_capture {
+            tap.ir_capture = capture.value.clone();
+        }
+        for inst in &bsdl.instructions {
+            let register = match inst.name.to_ascii_uppercase().as_str() {
+                "IDCODE" => SimRegister::Idcode,
+                "SAMPLE" | "PRELOAD" => SimRegister::Sample,
+                "EXTEST" => SimRegister::Extest,
+                _ => SimRegister::Bypass,
+            };
+            for opcode in &inst.opcodes {
+                tap.instructions.push((opcode.clone(), register));
+            }
+        }
+        tap.boundary_length = bsdl.boundary_length;
+        tap.cells = bsdl.boundary.clone();
+        tap.update = BitVec::zeros(bsdl.boundary_length);
+        tap.reset();
+        tap
+    }
+
+    /// Make `opcode` select `register`
+    pub fn with_instruction(mut self, opcode: BitVec, register: SimRegister) -> Self {
+        assert_eq!(opcode.len(), self.irlen);
+        self.instructions.push((opcode, register));
+        self.reset();
+        self
+    }
+
+    /// Change the value loaded into the instruction register in Capture-IR
+    pub fn with_ir_capture(mut self, capture: BitVec) -> Self {
+        assert_eq!(capture.len(), self.irlen);
+        self.ir_capture = capture;
+        self
+    }
+
+    /// The instruction currently in effect
+    pub fn ir(&self) -> &BitVec {
+        &self.ir
+    }
+
+    /// The data register currently selected
+    pub fn selected(&self) -> SimRegister {
+        self.selected
+    }
+
+    fn decode(&self, ir: &BitVec) -> SimRegister {
+        self.instructions.iter()
+            .find(|(op, _)| op == ir)
+            .map(|(_, reg)| *reg)
+            .unwrap_or(SimRegister::Bypass)
+    }
+
+    fn reset(&mut self) {
+        // IDCODE is selected even if it has no opcode
+        let idcode_op = self.instructions.iter().find(|(_, reg)| *reg == SimRegister::Idcode);
+        self.ir = match idcode_op {
+            Some((op, _)) if self.idcode.is_some() => op.clone(),
+            _ => BitVec::ones(self.irlen),
+        };
+        self.selected = if self.idcode.is_some() {
+            SimRegister::Idcode
+        } else {
+            SimRegister::Bypass
+        };
+    }
+
+    // The value that `port` is driven to, or None if it isn't being driven
+    fn driven(&self, port: &str) -> Option<bool> {
+        if self.selected != SimRegister::Extest {
+            return None;
+        }
+        self.cells.iter()
+            .filter(|c| matches!(c.function, CellFunction::Output2 | CellFunction::Output3 | CellFunction::Bidir))
+            .filter(|c| c.port.as_deref().is_some_and(|p| p.eq_ignore_ascii_case(port)))
+            .find_map(|c| {
+                let enabled = match c.control {
+                    Some(control) => self.update.get(control.cell) != control.disable_value,
+                    None => true,
+                };
+                enabled.then(|| self.update.get(c.number))
+            })
+    }
+}
+
+/// A simulated scan chain.  TAP 0 is the one closest to TDI.
+pub struct SimChain {
+    taps: Vec<SimTap>,
+    state: JtagState,
+    nets: Vec<(String, Vec<(usize, String)>)>,
+    faults: Vec<SimFault>,
+    read_queue: Vec<BitVec>,
+    queue_limit: Option<usize>,
+    clocks: usize,
+    resets: usize,
+}
+
+impl SimChain {
+    pub fn new(taps: Vec<SimTap>) -> Self {
+        Self {
+            taps,
+            state: JtagState::Reset,
+            nets: vec![],
+            faults: vec![],
+            read_queue: vec![],
+            queue_limit: None,
+            clocks: 0,
+            resets: 0,
+        }
+    }
+
+    /// Connect the pins listed in `pins` (TAP and port name) together as a net called `name`
+    pub fn add_net(&mut self, name: &str, pins: &[(usize, &str)]) {
+        let pins = pins.iter().map(|(tap, port)| (*tap, port.to_string())).collect();
+        self.nets.push((name.to_string(), pins));
+    }
+
+    pub fn inject(&mut self, fault: SimFault) {
+        self.faults.push(fault);
+    }
+
+    pub fn clear_faults(&mut self) {
+        self.faults.clear();
+    }
+
+    pub fn taps(&self) -> &[SimTap] {
+        &self.taps
+    }
+
+    /// The state of the TAP controllers
+    pub fn state(&self) -> JtagState {
+        self.state
+    }
+
+    /// Make `queue_read` and `queue_read_write` refuse reads once `limit` are queued, like a
+    /// cable whose buffer is full.  None, the default, has no limit.
+    pub fn set_queue_limit(&mut self, limit: Option<usize>) {
+        self.queue_limit = limit;
+    }
+
+    fn queue_full(&self) -> bool {
+        self.queue_limit.is_some_and(|limit| self.read_queue.len() >= limit)
+    }
+
+    /// Number of TCK cycles so far
+    pub fn clocks(&self) -> usize {
+        self.clocks
+    }
+
+    /// Number of times the TAPs have entered Test-Logic-Reset, through TMS or TRST
+    pub fn resets(&self) -> usize {
+        self.resets
+    }
+
+    fn is_open(&self, tap: usize, port: &str) -> bool {
+        self.faults.iter().any(|f| matches!(f, SimFault::Open { tap: t, port: p }
+                                            if *t == tap && p.eq_ignore_ascii_case(port)))
+    }
+
+    // The nets connected to net `net`, including itself, through shorts
+    fn shorted(&self, net: usize) -> Vec<usize> {
+        let mut group = vec![net];
+        let mut i = 0;
+        while i < group.len() {
+            let name = &self.nets[group[i]].0;
+            for fault in &self.faults {
+                if let SimFault::Short(a, b) = fault {
+                    let other = if a == name { b } else if b == name { a } else { continue };
+                    if let Some(n) = self.nets.iter().position(|(n, _)| n == other) {
+                        if !group.contains(&n) {
+                            group.push(n);
+                        }
+                    }
+                }
+            }
+            i += 1;
+        }
+        group
+    }
+
+    fn net_value(&self, net: usize) -> bool {
+        let group = self.shorted(net);
+        for fault in &self.faults {
+            if let SimFault::StuckAt { net: name, value } = fault {
+                if group.iter().any(|n| self.nets[*n].0 == *name) {
+                    return *value;
+                }
+            }
+        }
+
+        let mut value = true;
+        for n in group {
+            for (tap, port) in &self.nets[n].1 {
+                if !self.is_open(*tap, port) {
+                    value &= self.taps[*tap].driven(port).unwrap_or(true);
+                }
+            }
+        }
+        value
+    }
+
+    fn pin_value(&self, tap: usize, port: &str) -> bool {
+        if !self.is_open(tap, port) {
+            let net = self.nets.iter().position(|(_, pins)| {
+                pins.iter().any(|(t, p)| *t == tap && p.eq_ignore_ascii_case(port))
+            });
+            if let Some(net) = net {
+                return self.net_value(net);
+            }
+        }
+        self.taps[tap].driven(port).unwrap_or(true)
+    }
+
+    fn capture_dr(&self, tap: usize) -> BitVec {
+        let t = &self.taps[tap];
+        match t.selected {
+            SimRegister::Idcode if t.idcode.is_some() => BitVec::from_u64(t.idcode.unwrap() as u64, 32),
+            SimRegister::Sample | SimRegister::Extest if t.boundary_length > 0 => {
+                let mut captured = t.update.clone();
+                for cell in &t.cells {
+                    let Some(port) = &cell.port else {
+                        continue;
+                    };
+                    if matches!(cell.function, CellFunction::Input | CellFunction::Clock |
+                                CellFunction::ObserveOnly | CellFunction::Bidir) {
+                        captured.set(cell.number, self.pin_value(tap, port));
+                    }
+                }
+                captured
+            }
+            _ => BitVec::zeros(1),
+        }
+    }
+
+    fn shift(&mut self, ir: bool, tdi: bool) -> bool {
+        let mut carry = tdi;
+        for tap in &mut self.taps {
+            let reg = if ir {
+                &mut tap.ir_shift
+            } else {
+                &mut tap.dr_shift
+            };
+            let out = reg.get(0);
+            let mut next = reg.slice(1..reg.len());
+            next.push(carry);
+            *reg = next;
+            carry = out;
+        }
+        carry
+    }
+
+    // Clock TCK once, returning the value of TDO
+    fn clock(&mut self, tms: bool, tdi: bool) -> bool {
+        self.clocks += 1;
+        let tdo = match self.state {
+            JtagState::ShiftIR => self.shift(true, tdi),
+            JtagState::ShiftDR => self.shift(false, tdi),
+            _ => true,
+        };
+
+        let prev = self.state;
+        self.state = self.state.next(tms);
+        if self.state == JtagState::Reset && prev != JtagState::Reset {
+            self.resets += 1;
+        }
+        match self.state {
+            JtagState::Reset => {
+                for tap in &mut self.taps {
+                    tap.reset();
+                }
+            }
+            JtagState::CaptureIR => {
+                for tap in &mut self.taps {
+                    tap.ir_shift = tap.ir_capture.clone();
+                }
+            }
+            JtagState::UpdateIR => {
+                for tap in &mut self.taps {
+                    tap.ir = tap.ir_shift.clone();
+                    tap.selected = tap.decode(&tap.ir);
+                }
+            }
+            JtagState::CaptureDR => {
+                for i in 0..self.taps.len() {
+                    self.taps[i].dr_shift = self.capture_dr(i);
+                }
+            }
+            JtagState::UpdateDR => {
+                for tap in &mut self.taps {
+                    if matches!(tap.selected, SimRegister::Sample | SimRegister::Extest) &&
+                        tap.dr_shift.len() == tap.boundary_length {
+                        tap.update = tap.dr_shift.clone();
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        match self.faults.iter().find_map(|f| if let SimFault::TdoStuck(v) = f { Some(*v) } else { None }) {
+            Some(stuck) => stuck,
+            None => tdo,
+        }
+    }
+}
+
+impl Cable for SimChain {
+    fn change_mode(&mut self, tms: &[usize], tdo: bool) {
+        for x in tms {
+            self.clock(*x != 0, tdo);
+        }
+    }
+
+    fn read_data(&mut self, bits: usize) -> BitVec {
+        (0..bits).map(|_| self.clock(false, true)).collect()
+    }
+
+    fn write_data(&mut self, data: &BitVec, exit: &[usize]) {
+        self.read_write_data(data, exit);
+    }
+
+    fn read_write_data(&mut self, data: &BitVec, exit: &[usize]) -> BitVec {
+        assert!(!data.is_empty());
+        let last = data.len() - 1;
+        let out = data.iter().enumerate().map(|(i, bit)| {
+            let tms = i == last && exit.first().is_some_and(|x| *x != 0);
+            self.clock(tms, bit)
+        }).collect();
+        for x in exit.iter().skip(1) {
+            self.clock(*x != 0, true);
+        }
+        out
+    }
+
+    fn queue_read(&mut self, bits: usize) -> bool {
+        if self.queue_full() {
+            return false;
+        }
+        let data = self.read_data(bits);
+        self.read_queue.push(data);
+        true
+    }
+
+    fn queue_read_write(&mut self, data: &BitVec, exit: &[usize]) -> bool {
+        if self.queue_full() {
+            return false;
+        }
+        let data = self.read_write_data(data, exit);
+        self.read_queue.push(data);
+        true
+    }
+
+    fn finish_read(&mut self, bits: usize) -> BitVec {
+        let data = self.read_queue.remove(0);
+        assert_eq!(data.len(), bits);
+        data
+    }
+
+    fn shift_vectors(&mut self, tms: &BitVec, tdi: &BitVec) -> BitVec {
+        assert_eq!(tms.len(), tdi.len());
+        tms.iter().zip(tdi.iter()).map(|(tms, tdi)| self.clock(tms, tdi)).collect()
+    }
+
+    fn trst(&mut self) -> bool {
+        self.state = JtagState::Reset;
+        self.resets += 1;
+        for tap in &mut self.taps {
+            tap.reset();
+        }
+        true
+    }
+
+    fn tms_trace(&self) -> Option<TapModel> {
+        Some(TapModel::at(self.state))
+    }
+}
@@ -0,0 +1,306 @@
+//! Scan chain descriptions read from TOML or JSON files, so that the setup of a board's chain can
+//! be shared between tools rather than repeated in each of them.  A description names the cable
+//! and its clock, how `Taps::select_tap` should treat the TAPs that aren't selected, and lists the
+//! TAPs starting from the one closest to TDI:
+//!
+//! ```toml
+//! cable = "jtagkey"
+//! clock = 6000000
+//! select = "bypass"   # or "preserve" or "reset", see taps::SelectMode
+//!
+//! [[tap]]
+//! name = "mcu"
+//! bsdl = "stm32f103_lqfp48.bsd"   # relative to the description file
+//!
+//! [[tap]]
+//! name = "fpga"
+//! irlen = 6
+//! idcode = 0x0362d093
+//! idcode_mask = 0x0fffffff
+//! park = "BYPASS"     # instruction to leave it with when select = "preserve"
+//! instructions = { USER1 = 0x02, USER2 = { opcode = "000011", dr_len = 32 } }
+//! ```
+//!
+//! JSON descriptions have the same fields, with the TAPs in a `taps` array.  Numbers can be given
+//! as integers, or as strings that are hex with a leading "0x" or else binary, most significant
+//! bit first.  JSON has no hex integers, so that is the only way to write them there.
+//!
+//! The IR length and expected IDCODE are taken from the BSDL file when they aren't given, and the
+//! instruction table starts with the BSDL file's instructions, or those of the device in the
+//! IDCODE database, with `instructions` added on top.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::bits::BitVec;
+use crate::bsdl::Bsdl;
+use crate::cable::{self, Cable};
+use crate::instruction::InstructionSet;
+use crate::statemachine::JtagSM;
+use crate::taps::{ExpectedId, SelectMode, Taps};
+
+/// TCK frequency used when a description doesn't give one
+pub const DEFAULT_CLOCK: u32 = 1_000_000;
+
+/// A number that can be written as an integer or a string
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+#[serde(untagged)]
+pub enum Number {
+    Int(u64),
+    Text(String),
+}
+
+impl Number {
+    fn to_u32(&self, what: &str) -> Result<u32, String> {
+        let bits = self.to_bits(32, what)?;
+        Ok(bits.to_u64().unwrap() as u32)
+    }
+
+    fn to_bits(&self, len: usize, what: &str) -> Result<BitVec, String> {
+        match self {
+            Number::Int(value) => {
+                if len < 64 && value >> len != 0 {
+                    return Err(format!("{} {:#x} doesn't fit in {} bits", what, value, len));
+                }
+                Ok(BitVec::from_u64(*value, len))
+            }
+            Number::Text(text) if text.starts_with("0x") || text.starts_with("0X") => {
+                BitVec::from_hex(text, len).map_err(|e| format!("{}: {}", what, e))
+            }
+            Number::Text(text) => {
+                let bits = BitVec::from_bin(text).map_err(|e| format!("{}: {}", what, e))?;
+                if bits.len() != len {
+                    return Err(format!("{} {} is {} bits, not {}", what, text, bits.len(), len));
+                }
+                Ok(bits)
+            }
+        }
+    }
+}
+
+/// An instruction, given either as just its opcode or with the length of its data register
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+#[serde(untagged)]
+pub enum InstructionConfig {
+    Opcode(Number),
+    Full { opcode: Number, dr_len: Option<usize> },
+}
+
+/// Description of one TAP
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TapConfig {
+    pub name: String,
+    pub irlen: Option<usize>,
+    pub idcode: Option<Number>,
+    /// Bits of `idcode` that have to match.  Defaults to all of them.
+    pub idcode_mask: Option<Number>,
+    pub bsdl: Option<PathBuf>,
+    #[serde(default)]
+    pub instructions: BTreeMap<String, InstructionConfig>,
+    /// Instruction to leave the TAP with while others are selected in `SelectMode::Preserve`
+    pub park: Option<String>,
+}
+
+/// Description of a scan chain
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ChainConfig {
+    /// Cable name as accepted by `cable::new_from_string`
+    pub cable: Option<String>,
+    /// TCK frequency in hertz
+    pub clock: Option<u32>,
+    /// "bypass", "preserve" or "reset"
+    pub select: Option<String>,
+    #[serde(alias = "tap", default)]
+    pub taps: Vec<TapConfig>,
+    /// Directory that BSDL paths are relative to
+    #[serde(skip)]
+    pub base_dir: Option<PathBuf>,
+}
+
+// A TAP description with the BSDL file loaded and everything worked out
+struct Resolved {
+    irlen: usize,
+    idcode: Option<ExpectedId>,
+    instructions: Option<InstructionSet>,
+}
+
+impl ChainConfig {
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| e.to_string())
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, String> {
+        serde_json::from_str(text).map_err(|e| e.to_string())
+    }
+
+    /// Read a description from a file, which is JSON if the name ends in ".json" and TOML
+    /// otherwise.  BSDL paths in the file are relative to the directory it is in.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut config = if path.extension().is_some_and(|ext| ext == "json") {
+            Self::from_json(&text)
+        } else {
+            Self::from_toml(&text)
+        }.map_err(|e| format!("{}: {}", path.display(), e))?;
+        config.base_dir = path.parent().map(|dir| dir.to_path_buf());
+        Ok(config)
+    }
+
+    pub fn select_mode(&self) -> Result<SelectMode, String> {
+        match self.select.as_deref() {
+            None | Some("bypass") => Ok(SelectMode::Bypass),
+            Some("preserve") => Ok(SelectMode::Preserve),
+            Some("reset") => Ok(SelectMode::Reset),
+            Some(other) => Err(format!("unknown select mode {}", other)),
+        }
+    }
+
+    /// Open the cable and build a `Taps` for the chain with `configure`
+    pub fn open(&self) -> Result<Taps<Box<dyn Cable>>, String> {
+        let name = self.cable.as_deref().ok_or_else(|| "no cable given".to_string())?;
+        let cable = cable::new_from_string(name, self.clock.unwrap_or(DEFAULT_CLOCK))?;
+        let mut taps = Taps::new(JtagSM::new(cable));
+        self.configure(&mut taps)?;
+        Ok(taps)
+    }
+
+    /// Detect the TAPs on the chain, check that they match the description, and give them their
+    /// names and instructions
+    pub fn configure<T, U>(&self, taps: &mut Taps<T>) -> Result<(), String>
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        let resolved = self.resolve()?;
+        let found = taps.detect()?;
+        if found.len() != self.taps.len() {
+            return Err(format!("expected {} TAPs, but found {}", self.taps.len(), found.len()));
+        }
+        for ((config, want), tap) in self.taps.iter().zip(&resolved).zip(&found) {
+            if tap.irlen != want.irlen {
+                return Err(format!("TAP {} ({}) has a {} bit IR, but expected {}", tap.position,
+                                   config.name, tap.irlen, want.irlen));
+            }
+            if let Some(idcode) = want.idcode {
+                if !idcode.matches(tap.idcode) {
+                    let found = match tap.idcode {
+                        Some(found) => format!("IDCODE {:08x}", found),
+                        None => "no IDCODE".to_string(),
+                    };
+                    return Err(format!("TAP {} ({}) has {}, but expected {}", tap.position,
+                                       config.name, found, idcode));
+                }
+            }
+        }
+        self.apply(taps, resolved)
+    }
+
+    /// Add the TAPs to `taps` as described, without looking at the chain.  For chains that can't
+    /// be detected, for example because some TAPs don't have IDCODEs and the IR capture values are
+    /// ambiguous.
+    pub fn declare<T, U>(&self, taps: &mut Taps<T>) -> Result<(), String>
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        if !taps.taps().is_empty() {
+            return Err("TAPs have already been added".to_string());
+        }
+        let resolved = self.resolve()?;
+        for tap in &resolved {
+            taps.add_tap(tap.irlen);
+        }
+        self.apply(taps, resolved)
+    }
+
+    fn resolve(&self) -> Result<Vec<Resolved>, String> {
+        self.taps.iter().map(|tap| self.resolve_tap(tap).map_err(|e| format!("TAP {}: {}", tap.name, e))).collect()
+    }
+
+    fn resolve_tap(&self, tap: &TapConfig) -> Result<Resolved, String> {
+        let bsdl = match &tap.bsdl {
+            Some(path) => {
+                let path = match &self.base_dir {
+                    Some(dir) => dir.join(path),
+                    None => path.clone(),
+                };
+                Some(Bsdl::from_file(path)?)
+            }
+            None => None,
+        };
+
+        let irlen = match (tap.irlen, &bsdl) {
+            (Some(irlen), Some(bsdl)) if irlen != bsdl.irlen => {
+                return Err(format!("IR length {} doesn't match {}'s {}", irlen, bsdl.entity, bsdl.irlen));
+            }
+            (Some(irlen), _) => irlen,
+            (None, Some(bsdl)) => bsdl.irlen,
+            (None, None) => return Err("no IR length or BSDL file".to_string()),
+        };
+
+        let idcode = match (&tap.idcode, &tap.idcode_mask) {
+            (Some(idcode), mask) => {
+                let mask = match mask {
+                    Some(mask) => mask.to_u32("IDCODE mask")?,
+                    None => 0xffff_ffff,
+                };
+                Some(ExpectedId::Masked { value: idcode.to_u32("IDCODE")?, mask })
+            }
+            (None, Some(_)) => return Err("IDCODE mask without an IDCODE".to_string()),
+            (None, None) => bsdl.as_ref().and_then(|bsdl| bsdl.idcode.as_ref()).map(|pattern| {
+                ExpectedId::Masked {
+                    value: pattern.value.to_u64().unwrap() as u32,
+                    mask: pattern.mask.to_u64().unwrap() as u32,
+                }
+            }),
+        };
+
+        let mut instructions = bsdl.as_ref().map(InstructionSet::from_bsdl);
+        if !tap.instructions.is_empty() {
+            let set = instructions.get_or_insert_with(|| InstructionSet::new(irlen));
+            for (name, inst) in &tap.instructions {
+                let (opcode, dr_len) = match inst {
+                    InstructionConfig::Opcode(opcode) => (opcode, None),
+                    InstructionConfig::Full { opcode, dr_len } => (opcode, *dr_len),
+                };
+                set.add(name, opcode.to_bits(irlen, name)?, dr_len)?;
+            }
+        }
+
+        Ok(Resolved {
+            irlen,
+            idcode,
+            instructions,
+        })
+    }
+
+    fn apply<T, U>(&self, taps: &mut Taps<T>, resolved: Vec<Resolved>) -> Result<(), String>
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        taps.set_select_mode(self.select_mode()?);
+        for (i, (config, tap)) in self.taps.iter().zip(resolved).enumerate() {
+            taps.set_name(i, &config.name);
+            if let Some(mut instructions) = tap.instructions {
+                // Keep anything from the IDCODE database that the description doesn't override
+                if config.bsdl.is_none() {
+                    let mut merged = taps.taps()[i].instructions.clone();
+                    for inst in instructions.iter() {
+                        merged.add(&inst.name, inst.opcode.clone(), inst.dr_len)?;
+                    }
+                    instructions = merged;
+                }
+                taps.set_instructions(i, instructions)?;
+            }
+            if let Some(park) = &config.park {
+                let ir = taps.taps()[i].instructions.opcode(park)
+                    .map_err(|e| format!("TAP {}: {}", config.name, e))?
+                    .clone();
+                taps.set_parked_ir(i, Some(ir));
+            }
+        }
+        Ok(())
+    }
+}
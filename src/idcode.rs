@@ -0,0 +1,200 @@
+//! Decoding of the 32-bit IDCODE that most TAPs load into their data register at reset, plus a
+//! database of known devices.  An IDCODE is made up of a 4-bit version, a 16-bit part number, an
+//! 11-bit JEP106 manufacturer code and a 1 in the least significant bit.
+use std::fmt;
+
+/// A 32-bit IDCODE
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub struct IdCode(pub u32);
+
+impl IdCode {
+    /// Version number, from the top 4 bits
+    pub fn version(&self) -> u8 {
+        (self.0 >> 28) as u8
+    }
+
+    /// Part number assigned by the manufacturer
+    pub fn part(&self) -> u16 {
+        (self.0 >> 12) as u16
+    }
+
+    /// JEP106 continuation code, i.e. which bank the manufacturer ID is in (bank 1 is 0)
+    pub fn bank(&self) -> u8 {
+        ((self.0 >> 8) & 0xf) as u8
+    }
+
+    /// JEP106 manufacturer ID within the bank, without the parity bit
+    pub fn manufacturer_id(&self) -> u8 {
+        ((self.0 >> 1) & 0x7f) as u8
+    }
+
+    /// Name of the manufacturer from the JEP106 table
+    pub fn manufacturer(&self) -> Option<&'static str> {
+        jep106::JEP106Code::new(self.bank(), self.manufacturer_id()).get()
+    }
+
+    /// True if this could be a real IDCODE.  The least significant bit must be 1, and the
+    /// manufacturer ID can't be 0x7f, which JEP106 uses as the continuation code.  1149.1 relies
+    /// on this so that an IDCODE can't be confused with the ones shifted in from TDI.
+    pub fn is_valid(&self) -> bool {
+        self.0 & 1 == 1 && self.manufacturer_id() != 0x7f
+    }
+
+    /// Describe the IDCODE, using `db` to look up the device name.  For example "Xilinx XC7A35T
+    /// rev 3".
+    pub fn describe(&self, db: &Database) -> String {
+        let manufacturer = self.manufacturer().unwrap_or("Unknown manufacturer");
+        match db.lookup(*self) {
+            Some(device) => format!("{} {} rev {}", manufacturer, device.name, self.version()),
+            None => format!("{} part {:04x} rev {}", manufacturer, self.part(), self.version()),
+        }
+    }
+}
+
+impl fmt::Display for IdCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+/// A known device.  An IDCODE matches if it equals `idcode` in all the bits set in `mask`.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Device {
+    pub name: String,
+    pub idcode: u32,
+    pub mask: u32,
+    /// Length of the instruction register in bits
+    pub irlen: usize,
+    /// Names and opcodes of the instructions the device supports
+    pub instructions: Vec<(String, u64)>,
+}
+
+impl Device {
+    /// Create a device description.  `instructions` lists names and opcodes.
+    pub fn new(name: &str, idcode: u32, mask: u32, irlen: usize, instructions: &[(&str, u64)]) -> Self {
+        Self {
+            name: name.to_string(),
+            idcode,
+            mask,
+            irlen,
+            instructions: instructions.iter().map(|(name, op)| (name.to_string(), *op)).collect(),
+        }
+    }
+
+    /// True if `idcode` belongs to this device
+    pub fn matches(&self, idcode: IdCode) -> bool {
+        (idcode.0 ^ self.idcode) & self.mask == 0
+    }
+
+    /// Opcode of the instruction called `name`
+    pub fn instruction(&self, name: &str) -> Option<u64> {
+        self.instructions.iter().find(|(n, _)| n == name).map(|(_, op)| *op)
+    }
+}
+
+/// A set of known devices that IDCODEs can be looked up in
+#[derive(Clone,Debug,Default)]
+pub struct Database {
+    devices: Vec<Device>,
+}
+
+// Ignore the version nibble
+const ANY_VERSION: u32 = 0x0fff_ffff;
+
+const XILINX_7SERIES: &[(&str, u64)] = &[
+    ("EXTEST", 0x26), ("SAMPLE", 0x01), ("PRELOAD", 0x01), ("USER1", 0x02), ("USER2", 0x03),
+    ("USER3", 0x22), ("USER4", 0x23), ("CFG_OUT", 0x04), ("CFG_IN", 0x05), ("USERCODE", 0x08),
+    ("IDCODE", 0x09), ("HIGHZ", 0x0a), ("JPROGRAM", 0x0b), ("JSTART", 0x0c),
+    ("JSHUTDOWN", 0x0d), ("ISC_NOOP", 0x14), ("BYPASS", 0x3f),
+];
+
+const XILINX_XC9500XL: &[(&str, u64)] = &[
+    ("EXTEST", 0x00), ("SAMPLE", 0x01), ("PRELOAD", 0x01), ("INTEST", 0x02), ("CLAMP", 0xfa),
+    ("HIGHZ", 0xfc), ("USERCODE", 0xfd), ("IDCODE", 0xfe), ("BYPASS", 0xff),
+];
+
+const ALTERA: &[(&str, u64)] = &[
+    ("SAMPLE", 0x005), ("PRELOAD", 0x005), ("EXTEST", 0x00f), ("IDCODE", 0x006),
+    ("USERCODE", 0x007), ("CLAMP", 0x00a), ("HIGHZ", 0x00b), ("USER0", 0x00c),
+    ("USER1", 0x00e), ("BYPASS", 0x3ff),
+];
+
+const ARM_JTAG_DP: &[(&str, u64)] = &[
+    ("ABORT", 0x8), ("DPACC", 0xa), ("APACC", 0xb), ("IDCODE", 0xe), ("BYPASS", 0xf),
+];
+
+const LATTICE_ECP5: &[(&str, u64)] = &[
+    ("EXTEST", 0x15), ("SAMPLE", 0x1c), ("PRELOAD", 0x1c), ("HIGHZ", 0x18), ("USERCODE", 0xc0),
+    ("IDCODE", 0xe0), ("BYPASS", 0xff),
+];
+
+const STM32_BOUNDARY: &[(&str, u64)] = &[
+    ("EXTEST", 0x00), ("IDCODE", 0x01), ("SAMPLE", 0x02), ("PRELOAD", 0x02), ("BYPASS", 0x1f),
+];
+
+impl Database {
+    /// Create an empty database
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a database of the devices this crate knows about
+    pub fn builtin() -> Self {
+        let mut db = Self::new();
+
+        for (name, idcode) in [
+            ("XC7A35T", 0x0362d093), ("XC7A50T", 0x0362c093), ("XC7A100T", 0x03631093),
+            ("XC7A200T", 0x03636093), ("XC7K325T", 0x03651093), ("XC7Z010", 0x03722093),
+            ("XC7Z020", 0x03727093),
+        ] {
+            db.add(Device::new(name, idcode, ANY_VERSION, 6, XILINX_7SERIES));
+        }
+
+        for (name, idcode) in [
+            ("XC9536XL", 0x09602093), ("XC9572XL", 0x09604093), ("XC95144XL", 0x09608093),
+            ("XC95288XL", 0x09616093),
+        ] {
+            db.add(Device::new(name, idcode, ANY_VERSION, 8, XILINX_XC9500XL));
+        }
+
+        for (name, idcode) in [
+            ("EPM240", 0x020a10dd), ("EPM570", 0x020a20dd), ("EP4CE10", 0x020f10dd),
+            ("EP4CE22", 0x020f30dd), ("10M08", 0x031820dd), ("10M50", 0x031050dd),
+        ] {
+            db.add(Device::new(name, idcode, ANY_VERSION, 10, ALTERA));
+        }
+
+        db.add(Device::new("ARM JTAG-DP", 0x0ba00477, ANY_VERSION, 4, ARM_JTAG_DP));
+
+        // The version nibble tells the LFE5U parts from the LFE5UM parts, which add SERDES
+        for (name, idcode) in [
+            ("LFE5U-25F", 0x41111043), ("LFE5U-45F", 0x41112043), ("LFE5U-85F", 0x41113043),
+            ("LFE5UM-25F", 0x01111043), ("LFE5UM-45F", 0x01112043), ("LFE5UM-85F", 0x01113043),
+        ] {
+            db.add(Device::new(name, idcode, 0xffff_ffff, 8, LATTICE_ECP5));
+        }
+
+        for (name, idcode) in [
+            ("STM32F1 medium density", 0x06410041), ("STM32F1 high density", 0x06414041),
+            ("STM32F405/407", 0x06413041), ("STM32F42x/43x", 0x06419041),
+        ] {
+            db.add(Device::new(name, idcode, ANY_VERSION, 5, STM32_BOUNDARY));
+        }
+
+        db
+    }
+
+    /// Add a device.  Devices added later take priority when more than one matches.
+    pub fn add(&mut self, device: Device) {
+        self.devices.push(device);
+    }
+
+    /// Find the device that `idcode` belongs to
+    pub fn lookup(&self, idcode: IdCode) -> Option<&Device> {
+        self.devices.iter().rev().find(|d| d.matches(idcode))
+    }
+
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+}
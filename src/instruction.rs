@@ -0,0 +1,128 @@
+//! Tables of named instructions for a TAP, so that instructions can be loaded by name rather than
+//! by opcode.  A table can be filled in from a BSDL file, from a device in the IDCODE database, or
+//! by hand.  The data register lengths of the standard IEEE 1149.1 instructions are known without
+//! being told.
+use crate::bits::BitVec;
+use crate::bsdl::Bsdl;
+use crate::idcode::Device;
+
+/// One instruction of a TAP
+#[derive(Clone,Debug,PartialEq)]
+pub struct InstructionInfo {
+    pub name: String,
+    pub opcode: BitVec,
+    /// Length of the data register the instruction selects, if known
+    pub dr_len: Option<usize>,
+}
+
+/// Length of the data register selected by the standard instruction `name`, if it has a fixed
+/// length.  The boundary register used by SAMPLE/PRELOAD and EXTEST is different for every device.
+pub fn standard_dr_len(name: &str) -> Option<usize> {
+    match name.to_ascii_uppercase().as_str() {
+        "BYPASS" | "HIGHZ" | "CLAMP" => Some(1),
+        "IDCODE" | "USERCODE" => Some(32),
+        _ => None,
+    }
+}
+
+/// The instructions of a TAP with an `irlen` bit instruction register.  Names are compared
+/// ignoring case.
+#[derive(Clone,Debug,PartialEq)]
+pub struct InstructionSet {
+    irlen: usize,
+    instructions: Vec<InstructionInfo>,
+}
+
+impl InstructionSet {
+    /// Create a table holding only BYPASS, which 1149.1 requires to be all ones
+    pub fn new(irlen: usize) -> Self {
+        let mut set = Self {
+            irlen,
+            instructions: vec![],
+        };
+        set.add("BYPASS", BitVec::ones(irlen), None).unwrap();
+        set
+    }
+
+    /// Create a table from the instructions in a BSDL file.  Instructions with more than one
+    /// opcode use the first.
+    pub fn from_bsdl(bsdl: &Bsdl) -> Self {
+        let mut set = Self::new(bsdl.irlen);
+        for inst in &bsdl.instructions {
+            let Some(opcode) = inst.opcodes.first() else {
+                continue;
+            };
+            let dr_len = match bsdl.register_for(&inst.name) {
+                Some(reg) => match reg.register.to_ascii_uppercase().as_str() {
+                    "BYPASS" => Some(1),
+                    "DEVICE_ID" => Some(32),
+                    "BOUNDARY" => Some(bsdl.boundary_length),
+                    _ => reg.length,
+                },
+                None => match inst.name.to_ascii_uppercase().as_str() {
+                    "SAMPLE" | "PRELOAD" | "EXTEST" => Some(bsdl.boundary_length),
+                    _ => None,
+                },
+            };
+            // The BSDL parser already checked the opcode lengths
+            set.add(&inst.name, opcode.clone(), dr_len).unwrap();
+        }
+        set
+    }
+
+    /// Create a table from a device in the IDCODE database
+    pub fn from_device(device: &Device) -> Self {
+        let mut set = Self::new(device.irlen);
+        for (name, opcode) in &device.instructions {
+            set.add(name, BitVec::from_u64(*opcode, device.irlen), None).unwrap();
+        }
+        set
+    }
+
+    pub fn irlen(&self) -> usize {
+        self.irlen
+    }
+
+    /// Add an instruction, replacing any with the same name.  `dr_len` can be left as None for
+    /// the standard instructions.  Fails if `opcode` isn't `irlen` bits long.
+    pub fn add(&mut self, name: &str, opcode: BitVec, dr_len: Option<usize>) -> Result<(), String> {
+        if opcode.len() != self.irlen {
+            return Err(format!("opcode {:b} for {} is {} bits, but the IR is {} bits", opcode, name,
+                               opcode.len(), self.irlen));
+        }
+        let info = InstructionInfo {
+            name: name.to_string(),
+            opcode,
+            dr_len: dr_len.or_else(|| standard_dr_len(name)),
+        };
+        match self.instructions.iter_mut().find(|i| i.name.eq_ignore_ascii_case(name)) {
+            Some(existing) => *existing = info,
+            None => self.instructions.push(info),
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&InstructionInfo> {
+        self.instructions.iter().find(|i| i.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The opcode of `name`, or an error naming the instructions that do exist
+    pub fn opcode(&self, name: &str) -> Result<&BitVec, String> {
+        match self.get(name) {
+            Some(info) => Ok(&info.opcode),
+            None => {
+                let names: Vec<&str> = self.instructions.iter().map(|i| i.name.as_str()).collect();
+                Err(format!("no instruction {} (have {})", name, names.join(", ")))
+            }
+        }
+    }
+
+    /// The name of the instruction with `opcode`, if there is one
+    pub fn name_of(&self, opcode: &BitVec) -> Option<&str> {
+        self.instructions.iter().find(|i| i.opcode == *opcode).map(|i| i.name.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &InstructionInfo> {
+        self.instructions.iter()
+    }
+}
@@ -0,0 +1,380 @@
+//! Interconnect testing of a board with several boundary scan devices.  A `Netlist` describes
+//! which device pins are wired together.  `run` drives one pin of each net from its boundary
+//! register with EXTEST, captures what the other pins on the net see, and diagnoses any opens,
+//! shorts and stuck-at faults down to the names of the nets.
+//!
+//! An open that cuts off a net's driver, or its only receiver, can't be told apart from a net
+//! stuck at the value the inputs float to.  Shorts are only found between nets that are tested,
+//! and are assumed to resolve as a wired-AND or a wired-OR.
+use std::fmt;
+
+use crate::bits::BitVec;
+use crate::boundary::BoundaryScan;
+use crate::cable::Cable;
+use crate::taps::Taps;
+
+/// A device pin: the position of its TAP in the chain and the port name from the BSDL
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Pin {
+    pub tap: usize,
+    pub port: String,
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} on TAP {}", self.port, self.tap)
+    }
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Net {
+    pub name: String,
+    pub pins: Vec<Pin>,
+}
+
+/// The nets of a board.  Pins of devices that aren't under test can be listed, and are ignored.
+#[derive(Clone,Debug,Default)]
+pub struct Netlist {
+    nets: Vec<Net>,
+}
+
+impl Netlist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a net called `name` connecting `pins`, given as TAP position and port name
+    pub fn add_net(&mut self, name: &str, pins: &[(usize, &str)]) {
+        self.nets.push(Net {
+            name: name.to_string(),
+            pins: pins.iter().map(|(tap, port)| Pin { tap: *tap, port: port.to_string() }).collect(),
+        });
+    }
+
+    pub fn nets(&self) -> &[Net] {
+        &self.nets
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Algorithm {
+    /// Give every net a unique code, and drive each bit of the code followed by its complement.
+    /// Needs twice as many vectors as there are bits in the number of nets.
+    CountingSequence,
+    /// Drive one net high at a time with the rest low.  Needs one vector per net, but any short
+    /// is found between exactly the nets involved.
+    WalkingOnes,
+}
+
+/// Generate the test vectors for `nets` nets.  Bit i of each vector is the value to drive onto
+/// net i.
+pub fn vectors(algorithm: Algorithm, nets: usize) -> Vec<BitVec> {
+    match algorithm {
+        Algorithm::CountingSequence => {
+            // Codes start at 1, so that no net is driven with a constant
+            let bits = (usize::BITS - nets.leading_zeros()) as usize;
+            let mut vectors: Vec<BitVec> = (0..bits)
+                .map(|bit| (0..nets).map(|net| (net + 1) >> bit & 1 == 1).collect())
+                .collect();
+            for bit in 0..bits {
+                let complement = vectors[bit].iter().map(|x| !x).collect();
+                vectors.push(complement);
+            }
+            vectors
+        }
+        Algorithm::WalkingOnes => {
+            (0..nets).map(|high| (0..nets).map(|net| net == high).collect()).collect()
+        }
+    }
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Fault {
+    /// Every receiver on the net saw `value` throughout the test
+    StuckAt { net: String, value: bool },
+    /// The net was received correctly except at `pins`
+    Open { net: String, pins: Vec<Pin> },
+    /// The nets are shorted together
+    Short { nets: Vec<String> },
+    /// `pin` saw something that doesn't fit any of the other faults
+    Mismatch { net: String, pin: Pin, expected: BitVec, observed: BitVec },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::StuckAt { net, value } => write!(f, "{} stuck at {}", net, *value as u8),
+            Fault::Open { net, pins } => {
+                let pins: Vec<String> = pins.iter().map(|p| p.to_string()).collect();
+                write!(f, "{} open at {}", net, pins.join(", "))
+            }
+            Fault::Short { nets } => write!(f, "{} shorted together", nets.join(", ")),
+            Fault::Mismatch { net, pin, expected, observed } => {
+                write!(f, "{} at {}: expected {}, got {}", net, pin, expected.to_bin(), observed.to_bin())
+            }
+        }
+    }
+}
+
+/// Result of an interconnect test
+#[derive(Clone,Debug,PartialEq)]
+pub struct Report {
+    /// Number of test vectors applied
+    pub vectors: usize,
+    pub faults: Vec<Fault>,
+    /// Nets that couldn't be tested because they don't have both a pin that can be driven and
+    /// another that can be read
+    pub untested: Vec<String>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.faults.is_empty()
+    }
+}
+
+struct TestNet {
+    name: String,
+    // Index into devices and port name
+    driver: (usize, String),
+    receivers: Vec<(usize, Pin)>,
+}
+
+fn device_for(devices: &[BoundaryScan], tap: usize) -> Option<usize> {
+    devices.iter().position(|d| d.tap() == tap)
+}
+
+// Decide which pin drives each net and which receive it, and tri-state every other output on the
+// tested nets
+fn plan(devices: &mut [BoundaryScan], netlist: &Netlist) -> Result<(Vec<TestNet>, Vec<String>), String> {
+    let mut tested = vec![];
+    let mut untested = vec![];
+    for net in netlist.nets() {
+        let mut pins = vec![];
+        for pin in &net.pins {
+            let Some(dev) = device_for(devices, pin.tap) else {
+                continue;
+            };
+            let bsdl = devices[dev].bsdl();
+            if !bsdl.boundary.iter().any(|c| c.port.as_deref().is_some_and(|p| p.eq_ignore_ascii_case(&pin.port))) {
+                return Err(format!("{} has no boundary cell for {} in net {}", bsdl.entity, pin.port, net.name));
+            }
+            pins.push((dev, pin));
+        }
+
+        let driver = pins.iter().position(|(dev, pin)| devices[*dev].output_cell(&pin.port).is_ok());
+        let receivers: Vec<(usize, Pin)> = pins.iter().enumerate()
+            .filter(|(i, (dev, pin))| Some(*i) != driver && devices[*dev].input_cell(&pin.port).is_ok())
+            .map(|(_, (dev, pin))| (*dev, (*pin).clone()))
+            .collect();
+        let Some(driver) = driver.filter(|_| !receivers.is_empty()) else {
+            untested.push(net.name.clone());
+            continue;
+        };
+
+        for (i, (dev, pin)) in pins.iter().enumerate() {
+            if i != driver && devices[*dev].output_cell(&pin.port).is_ok() {
+                devices[*dev].tristate(&pin.port)
+                    .map_err(|e| format!("{} has more than one driver: {}", net.name, e))?;
+            }
+        }
+        let (dev, pin) = pins[driver];
+        tested.push(TestNet {
+            name: net.name.clone(),
+            driver: (dev, pin.port.clone()),
+            receivers,
+        });
+    }
+    Ok((tested, untested))
+}
+
+fn set_vector(devices: &mut [BoundaryScan], nets: &[TestNet], vector: &BitVec) -> Result<(), String> {
+    for (i, net) in nets.iter().enumerate() {
+        let (dev, port) = &net.driver;
+        devices[*dev].drive(port, vector.get(i))?;
+    }
+    Ok(())
+}
+
+// Instructions for every TAP in the chain: `name` for the devices, and BYPASS for everything else
+fn chain_ir<T, U>(taps: &Taps<T>, devices: &[BoundaryScan], name: &str) -> Result<Vec<BitVec>, String>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    taps.taps().iter().map(|t| match device_for(devices, t.position) {
+        Some(dev) => devices[dev].opcode(name),
+        None => Ok(BitVec::ones(t.irlen)),
+    }).collect()
+}
+
+fn chain_dr<T, U>(taps: &Taps<T>, devices: &[BoundaryScan]) -> Vec<BitVec>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    taps.taps().iter().map(|t| match device_for(devices, t.position) {
+        Some(dev) => devices[dev].cells().clone(),
+        None => BitVec::zeros(1),
+    }).collect()
+}
+
+fn is_constant(bits: &BitVec) -> Option<bool> {
+    let ones = bits.count_ones();
+    if ones == 0 {
+        Some(false)
+    } else if ones == bits.len() {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+fn diagnose(nets: &[TestNet], expected: &[BitVec], observed: &[Vec<BitVec>]) -> Vec<Fault> {
+    let mut faults = vec![];
+    // Nets where every receiver saw the same wrong sequence
+    let mut wrong = vec![];
+    for (i, net) in nets.iter().enumerate() {
+        let seen = &observed[i];
+        if seen.iter().all(|o| *o == expected[i]) {
+            continue;
+        }
+        if seen.iter().all(|o| *o == seen[0]) {
+            wrong.push(i);
+        } else if seen.iter().any(|o| *o == expected[i]) {
+            let pins = net.receivers.iter().zip(seen)
+                .filter(|(_, o)| **o != expected[i])
+                .map(|((_, pin), _)| pin.clone())
+                .collect();
+            faults.push(Fault::Open { net: net.name.clone(), pins });
+        } else {
+            for ((_, pin), o) in net.receivers.iter().zip(seen) {
+                faults.push(Fault::Mismatch {
+                    net: net.name.clone(),
+                    pin: pin.clone(),
+                    expected: expected[i].clone(),
+                    observed: o.clone(),
+                });
+            }
+        }
+    }
+
+    let mut shorted = vec![false; wrong.len()];
+    for a in 0..wrong.len() {
+        if shorted[a] {
+            continue;
+        }
+        let seen = &observed[wrong[a]][0];
+        let group: Vec<usize> = (a..wrong.len()).filter(|b| observed[wrong[*b]][0] == *seen).collect();
+        if group.len() < 2 {
+            continue;
+        }
+        let and: BitVec = (0..seen.len()).map(|j| group.iter().all(|b| expected[wrong[*b]].get(j))).collect();
+        let or: BitVec = (0..seen.len()).map(|j| group.iter().any(|b| expected[wrong[*b]].get(j))).collect();
+        if *seen == and || *seen == or {
+            for b in &group {
+                shorted[*b] = true;
+            }
+            faults.push(Fault::Short { nets: group.iter().map(|b| nets[wrong[*b]].name.clone()).collect() });
+        }
+    }
+
+    for (a, i) in wrong.iter().enumerate() {
+        if shorted[a] {
+            continue;
+        }
+        let seen = &observed[*i][0];
+        match is_constant(seen) {
+            Some(value) => faults.push(Fault::StuckAt { net: nets[*i].name.clone(), value }),
+            None => {
+                for (_, pin) in &nets[*i].receivers {
+                    faults.push(Fault::Mismatch {
+                        net: nets[*i].name.clone(),
+                        pin: pin.clone(),
+                        expected: expected[*i].clone(),
+                        observed: seen.clone(),
+                    });
+                }
+            }
+        }
+    }
+    faults
+}
+
+/// Test the nets in `netlist` using the boundary registers of `devices`, which must each be for a
+/// different TAP in `taps`.  Other TAPs are put in BYPASS.  The first pin of each net that can be
+/// driven is the driver, and every other output on the net is tri-stated.  The cells of `devices`
+/// are put back to what they were before, and the chain is reset at the end, taking the pins out
+/// of EXTEST.
+pub fn run<T, U>(taps: &mut Taps<T>, devices: &mut [BoundaryScan], netlist: &Netlist, algorithm: Algorithm)
+    -> Result<Report, String>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    for (i, device) in devices.iter().enumerate() {
+        let tap = taps.taps().get(device.tap()).ok_or_else(|| format!("no TAP {} in the chain", device.tap()))?;
+        if tap.irlen != device.bsdl().irlen {
+            return Err(format!("TAP {} has a {} bit IR, but {} has {}", device.tap(), tap.irlen,
+                               device.bsdl().entity, device.bsdl().irlen));
+        }
+        if devices[..i].iter().any(|d| d.tap() == device.tap()) {
+            return Err(format!("more than one device for TAP {}", device.tap()));
+        }
+    }
+
+    let saved: Vec<BitVec> = devices.iter().map(|d| d.cells().clone()).collect();
+    let result = plan(devices, netlist).and_then(|(nets, untested)| {
+        let vectors = vectors(algorithm, nets.len());
+        let mut responses = vec![];
+        if !vectors.is_empty() {
+            // Preload the first vector so that EXTEST starts out driving it
+            set_vector(devices, &nets, &vectors[0])?;
+            let sample = chain_ir(taps, devices, "SAMPLE")?;
+            let extest = chain_ir(taps, devices, "EXTEST")?;
+            taps.sm.mode_reset();
+            taps.write_ir_all(&sample);
+            taps.read_write_dr_all(&chain_dr(taps, devices));
+            taps.write_ir_all(&extest);
+
+            // Each scan captures the response to the vector before it, and the last one puts the
+            // cells back to their safe values
+            for v in 1..=vectors.len() {
+                if v < vectors.len() {
+                    set_vector(devices, &nets, &vectors[v])?;
+                } else {
+                    for (device, cells) in devices.iter_mut().zip(&saved) {
+                        device.set_cells(cells.clone());
+                    }
+                }
+                responses.push(taps.read_write_dr_all(&chain_dr(taps, devices)));
+            }
+            taps.sm.mode_reset();
+            for device in devices.iter_mut() {
+                let captured = responses[responses.len() - 1][device.tap()].clone();
+                device.set_scanned(captured, None);
+            }
+        }
+
+        let expected: Vec<BitVec> = (0..nets.len())
+            .map(|i| vectors.iter().map(|v| v.get(i)).collect())
+            .collect();
+        let mut observed = vec![];
+        for net in &nets {
+            let mut seen: Vec<BitVec> = vec![];
+            for (dev, pin) in &net.receivers {
+                let cell = devices[*dev].input_cell(&pin.port)?.number;
+                seen.push(responses.iter().map(|r| r[pin.tap].get(cell)).collect());
+            }
+            observed.push(seen);
+        }
+
+        Ok(Report {
+            vectors: vectors.len(),
+            faults: diagnose(&nets, &expected, &observed),
+            untested,
+        })
+    });
+
+    for (device, cells) in devices.iter_mut().zip(saved) {
+        device.set_cells(cells);
+    }
+    result
+}
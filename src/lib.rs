@@ -16,26 +16,41 @@
 //! instruction and data registers for that one TAP, and it will take care of
 //! putting other TAPs in BYPASS and shifting data in and out as appropriate.  Taps
 //! also has some support for automatically detecting the IR lengths and ID codes
-//! of the TAPs.
+//! of the TAPs.  Each TAP can have a name and a table of instructions, taken from
+//! BSDL or the IDCODE database, so that instructions can be loaded by name.
+//! 
+//! Register values are passed around as `bits::BitVec`, which carries its length in bits so that
+//! registers that aren't a multiple of 8 bits long don't need a separate bit count.
 //! 
 //! # Example
-//! ```
-//! use jtag_taps::cable::jtagkey::JtagKey;
+//! ```no_run
+//! use jtag_taps::bits::BitVec;
+//! use jtag_taps::cable::mpsse::JtagKey;
 //! use jtag_taps::statemachine::JtagSM;
 //! use jtag_taps::taps::Taps;
-//! let cable = JtagKey::new("Dual RS232-HS A", 1 << 20);
+//! let cable = JtagKey::new(1 << 20, true);
 //! let jtag = JtagSM::new(Box::new(cable));
 //! let mut taps = Taps::new(jtag);
-//! taps.detect();
+//! taps.detect().expect("detect");
 //! 
-//! let ir = vec![235, 0];
+//! let ir = BitVec::from_u64(235, 10);
 //! taps.select_tap(0, &ir);
 //! let buf = vec![
 //!     0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,
 //! ];
-//! taps.write_dr(&buf, 8);
+//! taps.write_dr(&BitVec::from_bytes(&buf, 64));
 //! ```
 
+pub mod bits;
+pub mod boundary;
+pub mod bsdl;
+pub mod config;
 pub mod cable;
+pub mod idcode;
+pub mod instruction;
+pub mod interconnect;
+pub mod stapl;
 pub mod statemachine;
+pub mod svf;
 pub mod taps;
+pub mod xsvf;
@@ -0,0 +1,797 @@
+//! Playing STAPL (JESD71, also known as Jam) programs, the language Altera and others used for
+//! in-system programming.  `Program::parse` reads the source of a .jam file, and a `Player` runs
+//! one of its ACTIONs, or the whole program if it has none, against a `JtagSM`.
+//!
+//! Array literals are written most significant bit first: `$` is followed by hex and `#` by
+//! binary, the same way `BitVec::from_hex` and `BitVec::from_bin` read them.  `@` is followed by
+//! an array compressed with the Altera compression algorithm, which is six bits per character
+//! packed least significant bit first, then decompressed with an LZ77 variant.  A slice
+//! `a[hi..lo]` has `a[lo]` as its bit 0, which is the first shifted in a scan.
+//!
+//! PREIR, POSTIR, PREDR and POSTDR work like the headers and trailers of SVF: the PRE data is
+//! shifted first, so it ends up in the devices closest to TDO.  Unless given, IR padding is all
+//! ones and DR padding all zeros.
+//!
+//! Every variable is global, so USES is ignored, and CRC statements aren't checked.  VECTOR and
+//! VMAP aren't supported.
+//!
+//! `Program::from_bytecode` loads the byte-code (.jbc) form of STAPL instead, and `load` takes
+//! either.  A `Player` runs byte-code the same way, except that it has no statements to see.
+mod jbc;
+mod parse;
+
+use std::collections::HashMap;
+
+pub use parse::{Action, Inclusion, Program};
+use parse::{BinOp, Expr, Func, Init, Lvalue, PrintItem, Stmt, UnOp};
+
+use crate::bits::BitVec;
+use crate::cable::Cable;
+use crate::statemachine::{JtagSM, JtagState, Register};
+use crate::svf;
+
+/// Result of running a STAPL program
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Report {
+    /// The value given to EXIT, or 0 if the program ran to the end
+    pub exit_code: i64,
+    /// The lines written with PRINT
+    pub output: Vec<String>,
+    /// The keys and values given to EXPORT.  Arrays are exported as hex.
+    pub exports: Vec<(String, String)>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.exit_code == 0
+    }
+}
+
+#[derive(Clone,Debug)]
+enum Var {
+    Int { value: i64, boolean: bool },
+    IntArray(Vec<i64>),
+    BoolArray(BitVec),
+}
+
+// What to do after a statement
+enum Flow {
+    Next,
+    Jump(usize),
+    Call(usize),
+    Return,
+    Exit(i64),
+}
+
+struct Loop {
+    var: String,
+    end: i64,
+    step: i64,
+    body: usize,
+}
+
+/// Runs STAPL programs, with the choice of which optional procedures to run
+#[derive(Clone,Debug,Default)]
+pub struct Player {
+    enabled: HashMap<String, bool>,
+}
+
+impl Player {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether to run `procedure` when it is listed in an action as OPTIONAL or RECOMMENDED.
+    /// Optional procedures are skipped and recommended ones run unless this says otherwise.
+    pub fn enable(&mut self, procedure: &str, enabled: bool) {
+        self.enabled.insert(procedure.to_ascii_uppercase(), enabled);
+    }
+
+    /// Run `action` from `program`, or with None, the statements of a program without actions
+    /// from the top.  Returns an error, with the line number, if a statement can't be executed;
+    /// the program reports failures itself with its exit code.
+    pub fn run<T, U>(&self, sm: &mut JtagSM<T>, program: &Program, action: Option<&str>) -> Result<Report, String>
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        if let Some(code) = &program.bytecode {
+            return jbc::run(self, sm, program, code, action);
+        }
+        let mut machine = Machine {
+            chain: Chain::new(sm),
+            program,
+            vars: HashMap::new(),
+            stack: vec![],
+            calls: vec![],
+            loops: vec![],
+            report: Report::default(),
+        };
+        let exit = match action {
+            None => machine.run(0)?,
+            Some(name) => {
+                let action = program.actions().iter().find(|a| a.name.eq_ignore_ascii_case(name))
+                    .ok_or_else(|| format!("no action {}", name))?;
+                machine.init_data()?;
+                let mut exit = None;
+                for (procedure, inclusion) in &action.procedures {
+                    if self.runs(procedure, *inclusion) {
+                        exit = machine.run(program.procedures[procedure])?;
+                        if exit.is_some() {
+                            break;
+                        }
+                    }
+                }
+                exit
+            }
+        };
+        machine.chain.sm.cable.flush();
+        let mut report = machine.report;
+        report.exit_code = exit.unwrap_or(0);
+        Ok(report)
+    }
+
+    // Whether a procedure listed in an action with `inclusion` is to be run
+    fn runs(&self, procedure: &str, inclusion: Inclusion) -> bool {
+        match inclusion {
+            Inclusion::Required => true,
+            Inclusion::Optional => self.enabled.get(procedure).copied().unwrap_or(false),
+            Inclusion::Recommended => self.enabled.get(procedure).copied().unwrap_or(true),
+        }
+    }
+}
+
+// The scan chain as a program sees it, with the padding and end states it has set
+struct Chain<'a, T> {
+    sm: &'a mut JtagSM<T>,
+    // PRE and POST data for the IR and then the DR
+    padding: [[BitVec; 2]; 2],
+    stops: [JtagState; 2],
+}
+
+impl<'a, T, U> Chain<'a, T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    fn new(sm: &'a mut JtagSM<T>) -> Self {
+        Self {
+            sm,
+            padding: Default::default(),
+            stops: [JtagState::Idle, JtagState::Idle],
+        }
+    }
+
+    // Shift `data` into `reg` between the padding, returning what it captured if `read` is set
+    fn scan(&mut self, reg: Register, data: &BitVec, read: bool) -> Option<BitVec> {
+        let [pre, post] = &self.padding[reg_index(reg)];
+        let offset = pre.len();
+        let tdi = pre.concat(data).concat(post);
+        let end = self.stops[reg_index(reg)];
+
+        // Scans always go through Capture, but JtagSM goes straight from a pause state back to the
+        // shift state
+        if matches!(self.sm.state(), JtagState::PauseDR | JtagState::PauseIR) {
+            self.sm.change_mode(JtagState::SelectDR);
+        }
+        if !read {
+            self.sm.write_reg_end(reg, &tdi, end);
+            return None;
+        }
+        Some(self.sm.read_write_reg_end(reg, &tdi, end).slice(offset..offset + data.len()))
+    }
+
+    // Set the PRE or POST data of `reg`
+    fn set_padding(&mut self, reg: Register, pre: bool, bits: BitVec) {
+        self.padding[reg_index(reg)][if pre { 0 } else { 1 }] = bits;
+    }
+
+    // Set the state that scans of `reg` end in
+    fn set_stop(&mut self, reg: Register, state: JtagState) -> Result<(), String> {
+        if !is_stable(state) {
+            return Err(format!("{} is not a stable state", svf::state_name(state)));
+        }
+        self.stops[reg_index(reg)] = state;
+        Ok(())
+    }
+
+    // Going to Reset always clocks TMS high, since the program may be relying on it to get the
+    // chain into a known state
+    fn go_to(&mut self, state: JtagState) {
+        if state == JtagState::Reset {
+            self.sm.mode_reset();
+        } else {
+            self.sm.change_mode(state);
+        }
+    }
+
+    // Wait in `state` for at least `cycles` clocks and `usec` microseconds, then go to `end`
+    fn wait(&mut self, state: JtagState, cycles: u64, usec: f64, end: JtagState) -> Result<(), String> {
+        if !is_stable(state) {
+            return Err(format!("can't wait in {}", svf::state_name(state)));
+        }
+        self.go_to(state);
+        svf::wait(self.sm, state, cycles, usec / 1e6);
+        self.go_to(end);
+        Ok(())
+    }
+}
+
+// The state of a running program
+struct Machine<'a, T> {
+    chain: Chain<'a, T>,
+    program: &'a Program,
+    vars: HashMap<String, Var>,
+    stack: Vec<i64>,
+    // Where to return to, and the number of loops open at the call
+    calls: Vec<(usize, usize)>,
+    loops: Vec<Loop>,
+    report: Report,
+}
+
+fn reg_index(reg: Register) -> usize {
+    match reg {
+        Register::Instruction => 0,
+        Register::Data => 1,
+    }
+}
+
+impl<T, U> Machine<'_, T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    // Execute from `pc` until the end of the program, or the end of the procedure it is in.
+    // Returns the exit code if the program stopped with EXIT.
+    fn run(&mut self, mut pc: usize) -> Result<Option<i64>, String> {
+        let base = self.calls.len();
+        while let Some(line) = self.program.lines.get(pc) {
+            let flow = self.execute(pc, &line.stmt).map_err(|e| format!("line {}: {}", line.line, e))?;
+            match flow {
+                Flow::Next => pc += 1,
+                Flow::Jump(to) => pc = to,
+                Flow::Call(to) => {
+                    self.calls.push((pc + 1, self.loops.len()));
+                    pc = to;
+                }
+                Flow::Return => {
+                    if self.calls.len() == base {
+                        return Ok(None);
+                    }
+                    let (ret, loops) = self.calls.pop().unwrap();
+                    self.loops.truncate(loops);
+                    pc = ret;
+                }
+                Flow::Exit(code) => return Ok(Some(code)),
+            }
+        }
+        Ok(None)
+    }
+
+    // Declare the variables in the DATA blocks, before running an action
+    fn init_data(&mut self) -> Result<(), String> {
+        let program = self.program;
+        for (i, line) in program.lines.iter().enumerate() {
+            if !matches!(line.stmt, Stmt::Data(_)) {
+                continue;
+            }
+            for pc in i + 1..program.block_end[&i] {
+                let line = &program.lines[pc];
+                let flow = match line.stmt {
+                    Stmt::Boolean { .. } | Stmt::Integer { .. } => self.execute(pc, &line.stmt),
+                    _ => Err("DATA blocks can only declare variables".to_string()),
+                };
+                flow.map_err(|e| format!("line {}: {}", line.line, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, pc: usize, stmt: &Stmt) -> Result<Flow, String> {
+        match stmt {
+            Stmt::Action(_) | Stmt::Data(_) | Stmt::EndData | Stmt::Note(..) | Stmt::Crc => {}
+            // Procedures are only run by CALL or an action
+            Stmt::Procedure(_) => return Ok(Flow::Jump(self.program.block_end[&pc] + 1)),
+            Stmt::EndProc => return Ok(Flow::Return),
+            Stmt::Boolean { name, size, init } => {
+                let var = match size {
+                    None => {
+                        let value = match init {
+                            Some(Init::Value(expr)) => self.eval(expr)? != 0,
+                            Some(Init::List(_)) => return Err(format!("{} isn't an array", name)),
+                            None => false,
+                        };
+                        Var::Int { value: value as i64, boolean: true }
+                    }
+                    Some(size) => {
+                        let len = self.eval_len(size)?;
+                        match init {
+                            Some(Init::Value(expr)) => Var::BoolArray(self.eval_bits(expr, len)?),
+                            Some(Init::List(_)) => return Err(format!("{} must be given as one array", name)),
+                            None => Var::BoolArray(BitVec::zeros(len)),
+                        }
+                    }
+                };
+                self.vars.insert(name.clone(), var);
+            }
+            Stmt::Integer { name, size, init } => {
+                let var = match size {
+                    None => {
+                        let value = match init {
+                            Some(Init::Value(expr)) => self.eval(expr)?,
+                            Some(Init::List(_)) => return Err(format!("{} isn't an array", name)),
+                            None => 0,
+                        };
+                        Var::Int { value, boolean: false }
+                    }
+                    Some(size) => {
+                        let len = self.eval_len(size)?;
+                        let values = match init {
+                            Some(Init::Value(expr)) => vec![self.eval(expr)?],
+                            Some(Init::List(list)) => list.iter().map(|e| self.eval(e)).collect::<Result<_, _>>()?,
+                            None => vec![],
+                        };
+                        if values.len() > len {
+                            return Err(format!("{} values for the {} elements of {}", values.len(), len, name));
+                        }
+                        let mut array = vec![0; len];
+                        array[..values.len()].copy_from_slice(&values);
+                        Var::IntArray(array)
+                    }
+                };
+                self.vars.insert(name.clone(), var);
+            }
+            Stmt::Let(lvalue, expr) => self.assign(lvalue, expr)?,
+            Stmt::If(cond, stmt) => {
+                if self.eval(cond)? != 0 {
+                    return self.execute(pc, stmt);
+                }
+            }
+            Stmt::For { var, start, end, step } => {
+                let start = self.eval(start)?;
+                let end = self.eval(end)?;
+                let step = match step {
+                    Some(step) => self.eval(step)?,
+                    None => 1,
+                };
+                if step == 0 {
+                    return Err("FOR with a STEP of 0".to_string());
+                }
+                self.set_int(var, start)?;
+                if (step > 0 && start > end) || (step < 0 && start < end) {
+                    return Ok(Flow::Jump(self.program.block_end[&pc] + 1));
+                }
+                self.loops.push(Loop { var: var.clone(), end, step, body: pc + 1 });
+            }
+            Stmt::Next(var) => {
+                // Loops left with GOTO are dropped here
+                while self.loops.last().is_some_and(|l| l.var != *var) {
+                    self.loops.pop();
+                }
+                let (end, step, body) = match self.loops.last() {
+                    Some(l) => (l.end, l.step, l.body),
+                    None => return Err(format!("NEXT {} outside its loop", var)),
+                };
+                let value = self.get_int(var)?.wrapping_add(step);
+                self.set_int(var, value)?;
+                if (step > 0 && value <= end) || (step < 0 && value >= end) {
+                    return Ok(Flow::Jump(body));
+                }
+                self.loops.pop();
+            }
+            Stmt::Goto(label) => {
+                let to = self.program.labels.get(label).ok_or_else(|| format!("no label {}", label))?;
+                return Ok(Flow::Jump(*to));
+            }
+            Stmt::Call(name) => {
+                let to = self.program.procedures.get(name).ok_or_else(|| format!("no procedure {}", name))?;
+                return Ok(Flow::Call(*to));
+            }
+            Stmt::Exit(code) => return Ok(Flow::Exit(self.eval(code)?)),
+            Stmt::Push(expr) => {
+                let value = self.eval(expr)?;
+                self.stack.push(value);
+            }
+            Stmt::Pop(lvalue) => {
+                let value = self.stack.pop().ok_or_else(|| "POP with nothing pushed".to_string())?;
+                self.assign(lvalue, &Expr::Int(value))?;
+            }
+            Stmt::Print(items) => {
+                let mut text = String::new();
+                for item in items {
+                    match item {
+                        PrintItem::Str(s) => text.push_str(s),
+                        PrintItem::Expr(expr) => text.push_str(&self.eval(expr)?.to_string()),
+                    }
+                }
+                self.report.output.push(text);
+            }
+            Stmt::Export(key, expr) => {
+                let value = match expr {
+                    Expr::Var(name) if matches!(self.vars.get(name), Some(Var::BoolArray(_))) => {
+                        let Some(Var::BoolArray(bits)) = self.vars.get(name) else { unreachable!() };
+                        bits.to_hex()
+                    }
+                    _ => self.eval(expr)?.to_string(),
+                };
+                self.report.exports.push((key.clone(), value));
+            }
+            Stmt::Scan { reg, len, data, capture, compare } => {
+                let len = self.eval_len(len)?;
+                let data = self.eval_bits(data, len)?;
+                self.scan(*reg, data, capture.as_ref(), compare.as_deref())?;
+            }
+            Stmt::Padding { reg, pre, len, data } => {
+                let len = self.eval_len(len)?;
+                let bits = match data {
+                    Some(data) => self.eval_bits(data, len)?,
+                    None if *reg == Register::Instruction => BitVec::ones(len),
+                    None => BitVec::zeros(len),
+                };
+                self.chain.set_padding(*reg, *pre, bits);
+            }
+            Stmt::Stop(reg, state) => self.chain.set_stop(*reg, *state)?,
+            Stmt::State(states) => {
+                for state in states {
+                    self.chain.go_to(*state);
+                }
+            }
+            Stmt::Wait { state, cycles, usec, end } => {
+                let state = state.unwrap_or(JtagState::Idle);
+                let cycles = match cycles {
+                    Some(cycles) => self.eval_len(cycles)? as u64,
+                    None => 0,
+                };
+                let usec = match usec {
+                    Some(usec) => self.eval_len(usec)? as f64,
+                    None => 0.0,
+                };
+                self.chain.wait(state, cycles, usec, end.unwrap_or(state))?;
+            }
+            // The cable's frequency can't be changed
+            Stmt::Frequency(_) => {}
+        }
+        Ok(Flow::Next)
+    }
+
+    fn scan(&mut self, reg: Register, data: BitVec, capture: Option<&Lvalue>,
+            compare: Option<&(Expr, Expr, Lvalue)>) -> Result<(), String> {
+        let len = data.len();
+        let Some(captured) = self.chain.scan(reg, &data, capture.is_some() || compare.is_some()) else {
+            return Ok(());
+        };
+
+        if let Some(lvalue) = capture {
+            self.assign_bits(lvalue, &captured)?;
+        }
+        if let Some((expected, mask, result)) = compare {
+            let expected = self.eval_bits(expected, len)?;
+            let mask = self.eval_bits(mask, len)?;
+            let equal = captured.masked_eq(&expected, &mask);
+            self.assign(result, &Expr::Int(equal as i64))?;
+        }
+        Ok(())
+    }
+
+    fn var(&self, name: &str) -> Result<&Var, String> {
+        self.vars.get(name).ok_or_else(|| format!("unknown variable {}", name))
+    }
+
+    fn var_mut(&mut self, name: &str) -> Result<&mut Var, String> {
+        self.vars.get_mut(name).ok_or_else(|| format!("unknown variable {}", name))
+    }
+
+    fn get_int(&self, name: &str) -> Result<i64, String> {
+        match self.var(name)? {
+            Var::Int { value, .. } => Ok(*value),
+            Var::BoolArray(bits) => bits_to_int(bits),
+            Var::IntArray(_) => Err(format!("{} is an array", name)),
+        }
+    }
+
+    fn set_int(&mut self, name: &str, new: i64) -> Result<(), String> {
+        match self.var_mut(name)? {
+            Var::Int { value, boolean } => {
+                *value = if *boolean { (new != 0) as i64 } else { new };
+                Ok(())
+            }
+            _ => Err(format!("{} is an array", name)),
+        }
+    }
+
+    fn assign(&mut self, lvalue: &Lvalue, expr: &Expr) -> Result<(), String> {
+        match lvalue {
+            Lvalue::Var(name) => match self.var(name)? {
+                Var::Int { .. } => {
+                    let value = self.eval(expr)?;
+                    self.set_int(name, value)
+                }
+                Var::BoolArray(bits) => {
+                    let bits = self.eval_bits(expr, bits.len())?;
+                    self.assign_bits(lvalue, &bits)
+                }
+                Var::IntArray(_) => Err(format!("{} is an array", name)),
+            },
+            Lvalue::Index(name, index) => {
+                let index = self.eval(index)?;
+                let value = self.eval(expr)?;
+                match self.var_mut(name)? {
+                    Var::IntArray(array) => {
+                        let len = array.len();
+                        *array.get_mut(checked_index(index, len, name)?).unwrap() = value;
+                    }
+                    Var::BoolArray(bits) => bits.set(checked_index(index, bits.len(), name)?, value != 0),
+                    Var::Int { .. } => return Err(format!("{} isn't an array", name)),
+                }
+                Ok(())
+            }
+            Lvalue::Slice(name, msb, lsb) => {
+                let (msb, lsb) = (self.eval(msb)?, self.eval(lsb)?);
+                let len = msb.abs_diff(lsb) as usize + 1;
+                let bits = self.eval_bits(expr, len)?;
+                self.assign_bits(&Lvalue::Slice(name.clone(), Expr::Int(msb), Expr::Int(lsb)), &bits)
+            }
+        }
+    }
+
+    // Store `bits` in a BOOLEAN array or a slice of one
+    fn assign_bits(&mut self, lvalue: &Lvalue, bits: &BitVec) -> Result<(), String> {
+        let (name, range) = match lvalue {
+            Lvalue::Var(name) => (name, None),
+            Lvalue::Index(name, index) => (name, Some((self.eval(index)?, self.eval(index)?))),
+            Lvalue::Slice(name, msb, lsb) => (name, Some((self.eval(msb)?, self.eval(lsb)?))),
+        };
+        let Var::BoolArray(array) = self.var_mut(name)? else {
+            return Err(format!("{} isn't a BOOLEAN array", name));
+        };
+        let indices = match range {
+            None => slice_indices(array.len() as i64 - 1, 0, array.len(), name)?,
+            Some((msb, lsb)) => slice_indices(msb, lsb, array.len(), name)?,
+        };
+        if indices.len() != bits.len() {
+            return Err(format!("{} bits don't fit in {} bits of {}", bits.len(), indices.len(), name));
+        }
+        for (i, bit) in indices.into_iter().zip(bits.iter()) {
+            array.set(i, bit);
+        }
+        Ok(())
+    }
+
+    fn slice(&self, name: &str, msb: &Expr, lsb: &Expr) -> Result<BitVec, String> {
+        let (msb, lsb) = (self.eval(msb)?, self.eval(lsb)?);
+        let Var::BoolArray(array) = self.var(name)? else {
+            return Err(format!("{} isn't a BOOLEAN array", name));
+        };
+        let mut bits = BitVec::new();
+        for i in slice_indices(msb, lsb, array.len(), name)? {
+            bits.push(array.get(i));
+        }
+        Ok(bits)
+    }
+
+    fn eval_len(&self, expr: &Expr) -> Result<usize, String> {
+        let value = self.eval(expr)?;
+        usize::try_from(value).map_err(|_| format!("{} can't be negative", value))
+    }
+
+    // Evaluate `expr` as a number
+    fn eval(&self, expr: &Expr) -> Result<i64, String> {
+        Ok(match expr {
+            Expr::Int(value) => *value,
+            Expr::Hex(digits) => bits_to_int(&BitVec::from_hex(digits, digits.len() * 4)?)?,
+            Expr::Bin(digits) => bits_to_int(&BitVec::from_bin(digits)?)?,
+            Expr::Aca(_) => return Err("compressed arrays can't be used as numbers".to_string()),
+            Expr::Var(name) => self.get_int(name)?,
+            Expr::Index(name, index) => {
+                let index = self.eval(index)?;
+                match self.var(name)? {
+                    Var::IntArray(array) => array[checked_index(index, array.len(), name)?],
+                    Var::BoolArray(bits) => bits.get(checked_index(index, bits.len(), name)?) as i64,
+                    Var::Int { .. } => return Err(format!("{} isn't an array", name)),
+                }
+            }
+            Expr::Slice(name, msb, lsb) => bits_to_int(&self.slice(name, msb, lsb)?)?,
+            Expr::Unary(op, arg) => {
+                let arg = self.eval(arg)?;
+                match op {
+                    UnOp::Neg => arg.wrapping_neg(),
+                    UnOp::Not => (arg == 0) as i64,
+                    UnOp::Invert => !arg,
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                // && and || don't evaluate the right hand side if they don't need to
+                match op {
+                    BinOp::And if lhs == 0 => return Ok(0),
+                    BinOp::Or if lhs != 0 => return Ok(1),
+                    _ => {}
+                }
+                let rhs = self.eval(rhs)?;
+                let shift = || u32::try_from(rhs).ok().filter(|s| *s < 64).ok_or_else(|| format!("can't shift by {}", rhs));
+                match op {
+                    BinOp::Or | BinOp::And => (rhs != 0) as i64,
+                    BinOp::BitOr => lhs | rhs,
+                    BinOp::BitXor => lhs ^ rhs,
+                    BinOp::BitAnd => lhs & rhs,
+                    BinOp::Eq => (lhs == rhs) as i64,
+                    BinOp::Ne => (lhs != rhs) as i64,
+                    BinOp::Lt => (lhs < rhs) as i64,
+                    BinOp::Le => (lhs <= rhs) as i64,
+                    BinOp::Gt => (lhs > rhs) as i64,
+                    BinOp::Ge => (lhs >= rhs) as i64,
+                    BinOp::Shl => lhs << shift()?,
+                    BinOp::Shr => lhs >> shift()?,
+                    BinOp::Add => lhs.wrapping_add(rhs),
+                    BinOp::Sub => lhs.wrapping_sub(rhs),
+                    BinOp::Mul => lhs.wrapping_mul(rhs),
+                    BinOp::Div | BinOp::Rem if rhs == 0 => return Err("division by zero".to_string()),
+                    BinOp::Div => lhs.wrapping_div(rhs),
+                    BinOp::Rem => lhs.wrapping_rem(rhs),
+                }
+            }
+            Expr::Call(func, arg) => {
+                let arg = self.eval(arg)?;
+                match func {
+                    Func::Abs => arg.wrapping_abs(),
+                    Func::Int => arg,
+                    // Rounded up
+                    Func::Log2 if arg > 0 => (64 - (arg - 1).leading_zeros()) as i64,
+                    Func::Sqrt if arg >= 0 => arg.isqrt(),
+                    _ => return Err(format!("{:?}({}) is undefined", func, arg)),
+                }
+            }
+        })
+    }
+
+    // Evaluate `expr` as an array of `len` bits
+    fn eval_bits(&self, expr: &Expr, len: usize) -> Result<BitVec, String> {
+        let bits = match expr {
+            Expr::Hex(digits) => return BitVec::from_hex(digits, len),
+            Expr::Bin(digits) => {
+                let bits = BitVec::from_bin(digits)?;
+                if bits.iter().skip(len).any(|bit| bit) {
+                    return Err(format!("#{} doesn't fit in {} bits", digits, len));
+                }
+                let mut fitted = bits.slice(0..bits.len().min(len));
+                fitted.append(&BitVec::zeros(len - fitted.len()));
+                return Ok(fitted);
+            }
+            Expr::Aca(chars) => {
+                let bytes = aca_expand(&aca_unpack(chars)?, ACA_WINDOW)?;
+                if bytes.len() * 8 < len {
+                    return Err(format!("compressed array has {} bits, not {}", bytes.len() * 8, len));
+                }
+                return Ok(BitVec::from_bytes(&bytes, len));
+            }
+            Expr::Var(name) if matches!(self.vars.get(name), Some(Var::BoolArray(_))) => {
+                let Some(Var::BoolArray(bits)) = self.vars.get(name) else { unreachable!() };
+                bits.clone()
+            }
+            Expr::Slice(name, msb, lsb) => self.slice(name, msb, lsb)?,
+            _ => {
+                // Negative numbers are two's complement
+                let value = self.eval(expr)?;
+                let fits = len >= 64 || value >> len == 0 || (len > 0 && value >> (len - 1) == -1);
+                if !fits {
+                    return Err(format!("{} doesn't fit in {} bits", value, len));
+                }
+                let low = if len < 64 { value as u64 & ((1 << len) - 1) } else { value as u64 };
+                let mut bits = BitVec::from_u64(low, len.min(64));
+                if len > 64 {
+                    let fill = if value < 0 { BitVec::ones(len - 64) } else { BitVec::zeros(len - 64) };
+                    bits.append(&fill);
+                }
+                return Ok(bits);
+            }
+        };
+        // A longer array gives its low bits
+        if bits.len() < len {
+            return Err(format!("{} bits given where {} are needed", bits.len(), len));
+        }
+        Ok(bits.slice(0..len))
+    }
+}
+
+fn is_stable(state: JtagState) -> bool {
+    matches!(state, JtagState::Reset | JtagState::Idle | JtagState::PauseDR | JtagState::PauseIR)
+}
+
+fn bits_to_int(bits: &BitVec) -> Result<i64, String> {
+    bits.to_u64().map(|value| value as i64)
+        .ok_or_else(|| format!("{} bit array used as a number", bits.len()))
+}
+
+fn checked_index(index: i64, len: usize, name: &str) -> Result<usize, String> {
+    usize::try_from(index).ok().filter(|i| *i < len)
+        .ok_or_else(|| format!("index {} is outside {}, which has {} elements", index, name, len))
+}
+
+// The indices of `msb..lsb` in an array, from the least significant bit
+fn slice_indices(msb: i64, lsb: i64, len: usize, name: &str) -> Result<Vec<usize>, String> {
+    let msb = checked_index(msb, len, name)?;
+    let lsb = checked_index(lsb, len, name)?;
+    Ok(if msb >= lsb {
+        (lsb..=msb).collect()
+    } else {
+        (msb..=lsb).rev().collect()
+    })
+}
+
+// Longest distance back a match can refer to.  Version 0 of the byte-code allows one more.
+const ACA_WINDOW: usize = 8191;
+
+// The bits of an array compressed with the Altera compression algorithm, from the six bits of
+// each character
+fn aca_unpack(chars: &str) -> Result<BitVec, String> {
+    let mut packed = BitVec::new();
+    for c in chars.chars() {
+        let value = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'A'..='Z' => c as u32 - 'A' as u32 + 10,
+            'a'..='z' => c as u32 - 'a' as u32 + 36,
+            '_' => 62,
+            '@' => 63,
+            _ => return Err(format!("invalid character '{}' in a compressed array", c)),
+        };
+        for i in 0..6 {
+            packed.push(value & (1 << i) != 0);
+        }
+    }
+    Ok(packed)
+}
+
+// Decompress an array compressed with the Altera compression algorithm: a 32 bit length in bytes,
+// then blocks that are either three literal bytes or a distance and length to copy from what has
+// already been decompressed.  The distance takes as many bits as the largest one possible, up to
+// `window`.
+fn aca_expand(packed: &BitVec, window: usize) -> Result<Vec<u8>, String> {
+    let mut pos = 0;
+    let mut read = |bits: usize| -> Result<usize, String> {
+        if pos + bits > packed.len() {
+            return Err("compressed array ends too soon".to_string());
+        }
+        let value = (0..bits).filter(|i| packed.get(pos + i)).map(|i| 1 << i).sum();
+        pos += bits;
+        Ok(value)
+    };
+    let len = read(32)?;
+    let mut out: Vec<u8> = Vec::with_capacity(len);
+    while out.len() < len {
+        if read(1)? == 0 {
+            for _ in 0..3 {
+                if out.len() < len {
+                    out.push(read(8)? as u8);
+                }
+            }
+        } else {
+            let longest = out.len().min(window);
+            let distance = read((usize::BITS - longest.leading_zeros()).max(1) as usize)?;
+            let count = read(8)?;
+            if distance == 0 || distance > out.len() {
+                return Err(format!("compressed array refers back {} bytes from byte {}", distance, out.len()));
+            }
+            for _ in 0..count {
+                if out.len() < len {
+                    out.push(out[out.len() - distance]);
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Load a STAPL program from the contents of a file, which may be source (.jam) or byte-code
+/// (.jbc)
+pub fn load(contents: &[u8]) -> Result<Program, String> {
+    if contents.starts_with(b"JAM\0") || contents.starts_with(b"JAM\x01") {
+        return Program::from_bytecode(contents);
+    }
+    let text = std::str::from_utf8(contents).map_err(|e| e.to_string())?;
+    Program::parse(text)
+}
+
+/// Parse and run the text of a STAPL program with a new `Player`
+pub fn play<T, U>(sm: &mut JtagSM<T>, text: &str, action: Option<&str>) -> Result<Report, String>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    Player::new().run(sm, &Program::parse(text)?, action)
+}
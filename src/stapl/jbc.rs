@@ -0,0 +1,800 @@
+//! Loader and interpreter for STAPL byte-code (.jbc), the form Altera's compiler turns .jam source
+//! into.  A file starts with "JAM" and a version byte.  Version 0 (JBC 1.0) has no actions and runs
+//! from the start of its code, and version 1 (JBC 2.0) has actions and procedures as the source
+//! does.  The code runs on a stack of 32 bit integers, with the variables numbered by the symbol
+//! table.
+//!
+//! In version 1, ranges of BOOLEAN arrays are given by their left and right indices, as in the
+//! source: bit 0 of the range is the element at the right index.  Version 0 gives a start index
+//! and a count instead.
+use super::{aca_expand, slice_indices, Action, Chain, Inclusion, Player, Program, Report, ACA_WINDOW};
+use crate::bits::BitVec;
+use crate::cable::Cable;
+use crate::statemachine::{JtagSM, JtagState, Register, STATES};
+
+const OP_NOP: u8 = 0x00;
+const OP_DUP: u8 = 0x01;
+const OP_SWP: u8 = 0x02;
+const OP_ADD: u8 = 0x03;
+const OP_SUB: u8 = 0x04;
+const OP_MULT: u8 = 0x05;
+const OP_DIV: u8 = 0x06;
+const OP_MOD: u8 = 0x07;
+const OP_SHL: u8 = 0x08;
+const OP_SHR: u8 = 0x09;
+const OP_NOT: u8 = 0x0a;
+const OP_AND: u8 = 0x0b;
+const OP_OR: u8 = 0x0c;
+const OP_XOR: u8 = 0x0d;
+const OP_INV: u8 = 0x0e;
+const OP_GT: u8 = 0x0f;
+const OP_LT: u8 = 0x10;
+const OP_RET: u8 = 0x11;
+const OP_CMPS: u8 = 0x12;
+const OP_PINT: u8 = 0x13;
+const OP_PRNT: u8 = 0x14;
+const OP_DSS: u8 = 0x15;
+const OP_DSSC: u8 = 0x16;
+const OP_ISS: u8 = 0x17;
+const OP_ISSC: u8 = 0x18;
+const OP_DPR: u8 = 0x1a;
+const OP_DPRL: u8 = 0x1b;
+const OP_DPO: u8 = 0x1c;
+const OP_DPOL: u8 = 0x1d;
+const OP_IPR: u8 = 0x1e;
+const OP_IPRL: u8 = 0x1f;
+const OP_IPO: u8 = 0x20;
+const OP_IPOL: u8 = 0x21;
+const OP_PCHR: u8 = 0x22;
+const OP_EXIT: u8 = 0x23;
+const OP_EQU: u8 = 0x24;
+const OP_POPT: u8 = 0x25;
+const OP_ABS: u8 = 0x29;
+const OP_BCH0: u8 = 0x2a;
+const OP_PSH0: u8 = 0x2b;
+const OP_PSHL: u8 = 0x40;
+const OP_PSHV: u8 = 0x41;
+const OP_JMP: u8 = 0x42;
+const OP_CALL: u8 = 0x43;
+const OP_NEXT: u8 = 0x44;
+const OP_PSTR: u8 = 0x45;
+const OP_SINT: u8 = 0x47;
+const OP_ST: u8 = 0x48;
+const OP_ISTP: u8 = 0x49;
+const OP_DSTP: u8 = 0x4a;
+const OP_SWPN: u8 = 0x4b;
+const OP_DUPN: u8 = 0x4c;
+const OP_POPV: u8 = 0x4d;
+const OP_POPE: u8 = 0x4e;
+const OP_POPA: u8 = 0x4f;
+const OP_JMPZ: u8 = 0x50;
+const OP_DS: u8 = 0x51;
+const OP_IS: u8 = 0x52;
+const OP_DPRA: u8 = 0x53;
+const OP_DPOA: u8 = 0x54;
+const OP_IPRA: u8 = 0x55;
+const OP_IPOA: u8 = 0x56;
+const OP_EXPT: u8 = 0x57;
+const OP_PSHE: u8 = 0x58;
+const OP_PSHA: u8 = 0x59;
+const OP_DYNA: u8 = 0x5a;
+const OP_EXPV: u8 = 0x5c;
+const OP_COPY: u8 = 0x80;
+const OP_DSC: u8 = 0x82;
+const OP_ISC: u8 = 0x83;
+const OP_WAIT: u8 = 0x84;
+const OP_CMPA: u8 = 0xc0;
+
+// Symbol table attribute bits
+const ATTR_COMPRESSED: u8 = 0x02;
+const ATTR_INITIALIZED: u8 = 0x04;
+const ATTR_ARRAY: u8 = 0x08;
+const ATTR_INTEGER: u8 = 0x10;
+
+// Procedure attribute bits
+const PROC_OPTIONAL: u8 = 0x01;
+const PROC_RECOMMENDED: u8 = 0x02;
+
+#[derive(Clone,Debug)]
+enum Value {
+    Int(i32),
+    IntArray(Vec<i32>),
+    BoolArray(BitVec),
+}
+
+/// The parts of a byte-code file needed to run it
+#[derive(Clone,Debug)]
+pub(super) struct ByteCode {
+    data: Vec<u8>,
+    version: u8,
+    strings: usize,
+    // Where the code starts and ends.  Jumps are relative to the start.
+    code: usize,
+    code_end: usize,
+    // The variables as they are before the program runs
+    init: Vec<Value>,
+    // For each action, where each of its procedures starts
+    actions: Vec<Vec<usize>>,
+}
+
+fn be32(data: &[u8], offset: usize) -> Result<u32, String> {
+    offset.checked_add(4).and_then(|end| data.get(offset..end))
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| format!("byte-code ends before offset {:#x}", offset + 4))
+}
+
+fn string(data: &[u8], offset: usize) -> Result<String, String> {
+    let rest = data.get(offset..).ok_or_else(|| format!("no string at offset {:#x}", offset))?;
+    let len = rest.iter().position(|b| *b == 0)
+        .ok_or_else(|| format!("string at offset {:#x} isn't terminated", offset))?;
+    Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+}
+
+fn state(code: u32) -> Result<JtagState, String> {
+    STATES.get(code as usize).copied().ok_or_else(|| format!("invalid state {}", code))
+}
+
+// The indices of `count` bits from `start`, for version 0
+fn run_indices(start: i32, count: i32, len: usize, id: u32) -> Result<Vec<usize>, String> {
+    if count < 1 {
+        return Err(format!("count of {} bits", count));
+    }
+    slice_indices(start as i64 + count as i64 - 1, start as i64, len, &format!("variable {}", id))
+}
+
+// The indices of a range given by its left and right indices, for version 1
+fn range_indices(left: i32, right: i32, len: usize, id: u32) -> Result<Vec<usize>, String> {
+    slice_indices(left as i64, right as i64, len, &format!("variable {}", id))
+}
+
+fn register(data: bool) -> Register {
+    if data { Register::Data } else { Register::Instruction }
+}
+
+fn element_error(index: i32, id: u32, len: usize) -> String {
+    format!("index {} is outside variable {}, which has {} elements", index, id, len)
+}
+
+// Bits 0 to `count` of `value`
+fn literal(value: i32, count: i32) -> Result<BitVec, String> {
+    let count = usize::try_from(count).ok().filter(|c| *c <= 32)
+        .ok_or_else(|| format!("{} bits of a literal", count))?;
+    Ok(BitVec::from_u64(value as u32 as u64, 32).slice(0..count))
+}
+
+impl Program {
+    /// Load STAPL byte-code, the contents of a .jbc file.  The byte-code's actions, their
+    /// procedures and its notes are available as for source, but it has no statements.
+    pub fn from_bytecode(data: &[u8]) -> Result<Self, String> {
+        let version = match data.get(..4) {
+            Some(b"JAM\0") => 0,
+            Some(b"JAM\x01") => 1,
+            _ => return Err("not STAPL byte-code".to_string()),
+        };
+        let word = |offset| be32(data, offset).map(|value| value as usize);
+        let delta = version as usize * 8;
+        let strings = word(4 + delta)?;
+        let note_strings = word(8 + delta)?;
+        let note_table = word(12 + delta)?;
+        let symbols = word(16 + delta)?;
+        let data_section = word(20 + delta)?;
+        let code = word(24 + delta)?;
+        let debug = word(28 + delta)?;
+        let note_count = word(44 + 2 * delta)?;
+        let symbol_count = word(48 + 2 * delta)?;
+        let code_end = if debug > code { debug.min(data.len()) } else { data.len() };
+
+        let mut notes = vec![];
+        for i in 0..note_count {
+            let entry = note_table + 8 * i;
+            let key = string(data, note_strings + word(entry)?)?;
+            notes.push((key, string(data, note_strings + word(entry + 4)?)?));
+        }
+
+        let window = if version == 0 { ACA_WINDOW + 1 } else { ACA_WINDOW };
+        let mut init = Vec::with_capacity(symbol_count.min(data.len()));
+        for i in 0..symbol_count {
+            let entry = symbols + (11 + delta) * i;
+            let attr = *data.get(entry).ok_or_else(|| format!("symbol {} is past the end", i))?;
+            let value = word(entry + 3 + delta)?;
+            let size = word(entry + 7 + delta)?;
+            let contents = data.get(data_section + value..).unwrap_or(&[]);
+            let err = |what: &str| format!("{} of symbol {} is past the end", what, i);
+            let var = if attr & (ATTR_INITIALIZED | ATTR_ARRAY) == ATTR_INITIALIZED {
+                Value::Int(value as i32)
+            } else if attr & ATTR_ARRAY == 0 {
+                Value::Int(0)
+            } else if attr & ATTR_INTEGER != 0 {
+                let mut array = vec![0; size];
+                if attr & ATTR_INITIALIZED != 0 {
+                    for (j, element) in array.iter_mut().enumerate() {
+                        *element = be32(contents, 4 * j).map_err(|_| err("data"))? as i32;
+                    }
+                }
+                Value::IntArray(array)
+            } else if attr & ATTR_INITIALIZED == 0 {
+                Value::BoolArray(BitVec::zeros(size))
+            } else if attr & ATTR_COMPRESSED != 0 {
+                let bytes = aca_expand(&BitVec::from_bytes(contents, contents.len() * 8), window)
+                    .map_err(|e| format!("symbol {}: {}", i, e))?;
+                Value::BoolArray(BitVec::from_bytes(&bytes, bytes.len() * 8))
+            } else {
+                let bytes = contents.get(..size.div_ceil(8)).ok_or_else(|| err("data"))?;
+                Value::BoolArray(BitVec::from_bytes(bytes, size))
+            };
+            init.push(var);
+        }
+
+        let mut actions = vec![];
+        let mut starts = vec![];
+        if version > 0 {
+            let action_table = word(4)?;
+            let procedure_table = word(8)?;
+            let action_count = word(40 + delta)?;
+            let procedure_count = word(44 + delta)?;
+            for i in 0..action_count {
+                let entry = action_table + 12 * i;
+                let description = word(entry + 4)?;
+                let description = if description < note_strings.saturating_sub(strings) {
+                    Some(string(data, strings + description)?)
+                } else {
+                    None
+                };
+                let mut action = Action {
+                    name: string(data, strings + word(entry)?)?.to_ascii_uppercase(),
+                    description,
+                    procedures: vec![],
+                };
+                let mut procedures = vec![];
+                // The procedures are a list linked through the procedure table, ending at 0
+                let mut procedure = word(entry + 8)?;
+                loop {
+                    if procedure >= procedure_count || procedures.len() == procedure_count {
+                        return Err(format!("action {} has a bad list of procedures", action.name));
+                    }
+                    let entry = procedure_table + 13 * procedure;
+                    let attr = *data.get(entry + 8)
+                        .ok_or_else(|| format!("procedure {} is past the end", procedure))?;
+                    let inclusion = if attr & PROC_RECOMMENDED != 0 {
+                        Inclusion::Recommended
+                    } else if attr & PROC_OPTIONAL != 0 {
+                        Inclusion::Optional
+                    } else {
+                        Inclusion::Required
+                    };
+                    let name = string(data, strings + word(entry)?)?.to_ascii_uppercase();
+                    action.procedures.push((name, inclusion));
+                    procedures.push(code + word(entry + 9)?);
+                    procedure = word(entry + 4)?;
+                    if procedure == 0 {
+                        break;
+                    }
+                }
+                actions.push(action);
+                starts.push(procedures);
+            }
+        }
+
+        Ok(Program {
+            lines: vec![],
+            labels: Default::default(),
+            procedures: Default::default(),
+            block_end: Default::default(),
+            notes,
+            actions,
+            bytecode: Some(ByteCode {
+                data: data.to_vec(),
+                version,
+                strings,
+                code,
+                code_end,
+                init,
+                actions: starts,
+            }),
+        })
+    }
+}
+
+// Run `action` of the byte-code `code` loaded into `program`, or for version 0, which has no
+// actions, the whole of it
+pub(super) fn run<T, U>(player: &Player, sm: &mut JtagSM<T>, program: &Program, code: &ByteCode,
+                        action: Option<&str>) -> Result<Report, String>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    let mut machine = Machine {
+        chain: Chain::new(sm),
+        code,
+        vars: code.init.clone(),
+        stack: vec![],
+        message: String::new(),
+        report: Report::default(),
+    };
+    let exit = match action {
+        None if code.version == 0 => machine.run(code.code)?,
+        None => return Err("the byte-code has actions, so one has to be chosen".to_string()),
+        Some(name) => {
+            let i = program.actions.iter().position(|a| a.name.eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("no action {}", name))?;
+            let mut exit = None;
+            let procedures = program.actions[i].procedures.iter().zip(&code.actions[i]);
+            for ((procedure, inclusion), start) in procedures {
+                if player.runs(procedure, *inclusion) {
+                    exit = machine.run(*start)?;
+                    if exit.is_some() {
+                        break;
+                    }
+                }
+            }
+            exit
+        }
+    };
+    machine.chain.sm.cable.flush();
+    let mut report = machine.report;
+    report.exit_code = exit.unwrap_or(0);
+    Ok(report)
+}
+
+// What to do after an instruction
+enum Step {
+    Next,
+    Return,
+    Exit(i32),
+}
+
+// The state of running byte-code
+struct Machine<'a, T> {
+    chain: Chain<'a, T>,
+    code: &'a ByteCode,
+    vars: Vec<Value>,
+    stack: Vec<i32>,
+    // The PRINT being put together
+    message: String,
+    report: Report,
+}
+
+impl<T, U> Machine<'_, T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    // Execute from offset `pc` of the file until the procedure returns or the program exits.
+    // Returns the exit code if it exits.
+    fn run(&mut self, mut pc: usize) -> Result<Option<i64>, String> {
+        loop {
+            if pc < self.code.code || pc >= self.code.code_end {
+                return Err(format!("offset {:#x} is outside the code", pc));
+            }
+            let start = pc;
+            let op = self.code.data[pc];
+            pc += 1;
+            let mut args = [0; 3];
+            for arg in args.iter_mut().take((op >> 6) as usize) {
+                *arg = be32(&self.code.data, pc)?;
+                pc += 4;
+            }
+            let step = self.execute(op, &args, &mut pc)
+                .map_err(|e| format!("opcode {:#04x} at offset {:#x}: {}", op, start, e))?;
+            match step {
+                Step::Next => {}
+                Step::Return => return Ok(None),
+                Step::Exit(code) => return Ok(Some(code as i64)),
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Result<i32, String> {
+        self.stack.pop().ok_or_else(|| "stack underflow".to_string())
+    }
+
+    fn top(&mut self) -> Result<&mut i32, String> {
+        self.stack.last_mut().ok_or_else(|| "stack underflow".to_string())
+    }
+
+    fn push(&mut self, value: i32) {
+        self.stack.push(value);
+    }
+
+    // Swap the top of the stack with the element `n` below it
+    fn swap(&mut self, n: u32) -> Result<(), String> {
+        let len = self.stack.len();
+        let other = len.checked_sub(n as usize + 1).ok_or_else(|| "stack underflow".to_string())?;
+        self.stack.swap(other, len - 1);
+        Ok(())
+    }
+
+    // Push the element `n` below the top of the stack
+    fn dup(&mut self, n: u32) -> Result<(), String> {
+        let len = self.stack.len();
+        let index = len.checked_sub(n as usize + 1).ok_or_else(|| "stack underflow".to_string())?;
+        self.push(self.stack[index]);
+        Ok(())
+    }
+
+    fn binary(&mut self, f: impl FnOnce(i32, i32) -> Result<i32, String>) -> Result<(), String> {
+        let rhs = self.pop()?;
+        let lhs = self.top()?;
+        *lhs = f(*lhs, rhs)?;
+        Ok(())
+    }
+
+    // The code offset `to`, checked to be in the code
+    fn jump(&self, to: u32) -> Result<usize, String> {
+        let pc = self.code.code + to as usize;
+        if pc >= self.code.code_end {
+            return Err(format!("jump to {:#x} is outside the code", to));
+        }
+        Ok(pc)
+    }
+
+    fn var(&self, id: u32) -> Result<&Value, String> {
+        self.vars.get(id as usize).ok_or_else(|| format!("no variable {}", id))
+    }
+
+    fn var_mut(&mut self, id: u32) -> Result<&mut Value, String> {
+        self.vars.get_mut(id as usize).ok_or_else(|| format!("no variable {}", id))
+    }
+
+    fn bool_array(&self, id: u32) -> Result<&BitVec, String> {
+        match self.var(id)? {
+            Value::BoolArray(bits) => Ok(bits),
+            _ => Err(format!("variable {} isn't a BOOLEAN array", id)),
+        }
+    }
+
+    fn bool_array_mut(&mut self, id: u32) -> Result<&mut BitVec, String> {
+        match self.var_mut(id)? {
+            Value::BoolArray(bits) => Ok(bits),
+            _ => Err(format!("variable {} isn't a BOOLEAN array", id)),
+        }
+    }
+
+    fn int_array_mut(&mut self, id: u32) -> Result<&mut Vec<i32>, String> {
+        match self.var_mut(id)? {
+            Value::IntArray(array) => Ok(array),
+            _ => Err(format!("variable {} isn't an INTEGER array", id)),
+        }
+    }
+
+    // Pop a range of the BOOLEAN array `id`, given the way most instructions do it: the index and
+    // then the count in version 0, or the right and then the left index in version 1
+    fn pop_range(&mut self, id: u32) -> Result<Vec<usize>, String> {
+        let len = self.bool_array(id)?.len();
+        let (first, second) = (self.pop()?, self.pop()?);
+        if self.code.version == 0 {
+            run_indices(first, second, len, id)
+        } else {
+            range_indices(second, first, len, id)
+        }
+    }
+
+    fn gather(&self, id: u32, indices: &[usize]) -> Result<BitVec, String> {
+        let array = self.bool_array(id)?;
+        let mut bits = BitVec::new();
+        for i in indices {
+            bits.push(array.get(*i));
+        }
+        Ok(bits)
+    }
+
+    fn scatter(&mut self, id: u32, indices: &[usize], bits: &BitVec) -> Result<(), String> {
+        let array = self.bool_array_mut(id)?;
+        for (i, bit) in indices.iter().zip(bits.iter()) {
+            array.set(*i, bit);
+        }
+        Ok(())
+    }
+
+    fn string(&self, id: u32) -> Result<String, String> {
+        string(&self.code.data, self.code.strings + id as usize)
+    }
+
+    fn execute(&mut self, op: u8, args: &[u32; 3], pc: &mut usize) -> Result<Step, String> {
+        let version = self.code.version;
+        match op {
+            OP_NOP => {}
+            OP_DUP => self.dup(0)?,
+            OP_SWP => self.swap(1)?,
+            OP_ADD => self.binary(|a, b| Ok(a.wrapping_add(b)))?,
+            OP_SUB => self.binary(|a, b| Ok(a.wrapping_sub(b)))?,
+            OP_MULT => self.binary(|a, b| Ok(a.wrapping_mul(b)))?,
+            OP_DIV | OP_MOD => self.binary(|a, b| match b {
+                0 => Err("division by zero".to_string()),
+                _ if op == OP_DIV => Ok(a.wrapping_div(b)),
+                _ => Ok(a.wrapping_rem(b)),
+            })?,
+            OP_SHL | OP_SHR => self.binary(|a, b| match u32::try_from(b).ok().filter(|b| *b < 32) {
+                None => Err(format!("can't shift by {}", b)),
+                Some(b) if op == OP_SHL => Ok(a << b),
+                Some(b) => Ok(a >> b),
+            })?,
+            OP_NOT => {
+                let top = self.top()?;
+                *top = (*top == 0) as i32;
+            }
+            OP_AND => self.binary(|a, b| Ok(a & b))?,
+            OP_OR => self.binary(|a, b| Ok(a | b))?,
+            OP_XOR => self.binary(|a, b| Ok(a ^ b))?,
+            OP_INV => {
+                let top = self.top()?;
+                *top = !*top;
+            }
+            OP_GT => self.binary(|a, b| Ok((a > b) as i32))?,
+            OP_LT => self.binary(|a, b| Ok((a < b) as i32))?,
+            OP_EQU => self.binary(|a, b| Ok((a == b) as i32))?,
+            OP_ABS => {
+                let top = self.top()?;
+                *top = top.wrapping_abs();
+            }
+            OP_RET => {
+                // An empty stack means the end of a procedure listed in the action
+                let Some(ret) = self.stack.pop() else {
+                    return Ok(Step::Return);
+                };
+                *pc = ret as u32 as usize;
+            }
+            OP_CMPS => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                let mask = self.pop()?;
+                let count = self.top()?;
+                if !(1..=32).contains(count) {
+                    return Err(format!("count of {} bits", count));
+                }
+                let mask = (mask as u32 & (u32::MAX >> (32 - *count))) as i32;
+                *count = (a & mask == b & mask) as i32;
+            }
+            OP_PINT => {
+                let value = self.pop()?;
+                self.message.push_str(&value.to_string());
+            }
+            OP_PSTR => {
+                let text = self.string(args[0])?;
+                self.message.push_str(&text);
+            }
+            OP_PCHR => {
+                let c = self.pop()?;
+                self.message.push(c as u8 as char);
+            }
+            OP_PRNT => self.report.output.push(std::mem::take(&mut self.message)),
+            OP_DSS | OP_ISS | OP_DSSC | OP_ISSC => {
+                let reg = register(op == OP_DSS || op == OP_DSSC);
+                let value = self.pop()?;
+                if op == OP_DSS || op == OP_ISS {
+                    let count = self.pop()?;
+                    self.chain.scan(reg, &literal(value, count)?, false);
+                } else {
+                    let count = *self.top()?;
+                    let captured = self.chain.scan(reg, &literal(value, count)?, true).unwrap();
+                    *self.top()? = captured.to_u64().unwrap() as u32 as i32;
+                }
+            }
+            OP_DPR | OP_DPO | OP_IPR | OP_IPO => {
+                let count = self.pop()?;
+                let count = usize::try_from(count).map_err(|_| format!("count of {} bits", count))?;
+                let (reg, bits) = match op {
+                    OP_DPR | OP_DPO => (Register::Data, BitVec::zeros(count)),
+                    _ => (Register::Instruction, BitVec::ones(count)),
+                };
+                self.chain.set_padding(reg, op == OP_DPR || op == OP_IPR, bits);
+            }
+            OP_DPRL | OP_DPOL | OP_IPRL | OP_IPOL => {
+                let count = self.pop()?;
+                let value = self.pop()?;
+                let reg = register(op == OP_DPRL || op == OP_DPOL);
+                self.chain.set_padding(reg, op == OP_DPRL || op == OP_IPRL, literal(value, count)?);
+            }
+            OP_DPRA | OP_DPOA | OP_IPRA | OP_IPOA => {
+                let indices = self.pop_range(args[0])?;
+                let bits = self.gather(args[0], &indices)?;
+                let reg = register(op == OP_DPRA || op == OP_DPOA);
+                self.chain.set_padding(reg, op == OP_DPRA || op == OP_IPRA, bits);
+            }
+            OP_EXIT => return Ok(Step::Exit(self.pop()?)),
+            OP_POPT => {
+                self.pop()?;
+            }
+            OP_BCH0 => {
+                self.swap(1)?;
+                self.swap(7)?;
+                self.swap(1)?;
+                self.swap(6)?;
+                self.dup(8)?;
+                self.swap(2)?;
+                self.swap(1)?;
+                self.dup(6)?;
+                self.dup(6)?;
+            }
+            OP_PSH0 => self.push(0),
+            OP_PSHL => self.push(args[0] as i32),
+            OP_PSHV => match self.var(args[0])? {
+                Value::Int(value) => self.push(*value),
+                _ => return Err(format!("variable {} is an array", args[0])),
+            },
+            OP_JMP => *pc = self.jump(args[0])?,
+            OP_JMPZ => {
+                if self.pop()? == 0 {
+                    *pc = self.jump(args[0])?;
+                }
+            }
+            OP_CALL => {
+                self.push(*pc as i32);
+                *pc = self.jump(args[0])?;
+            }
+            OP_NEXT => {
+                // The step, end and the start of the loop body are left on the stack by FOR
+                let len = self.stack.len();
+                if len < 3 {
+                    return Err("stack underflow".to_string());
+                }
+                let [top, end, step] = self.stack[len - 3..] else { unreachable!() };
+                let Value::Int(var) = self.var_mut(args[0])? else {
+                    return Err(format!("variable {} is an array", args[0]));
+                };
+                if (step < 0 && *var <= end) || (step >= 0 && *var >= end) {
+                    self.stack.truncate(len - 3);
+                } else {
+                    *var = var.wrapping_add(step);
+                    *pc = self.jump(top as u32)?;
+                }
+            }
+            OP_SINT | OP_ST => self.chain.go_to(state(args[0])?),
+            OP_ISTP => self.chain.set_stop(Register::Instruction, state(args[0])?)?,
+            OP_DSTP => self.chain.set_stop(Register::Data, state(args[0])?)?,
+            OP_SWPN => self.swap(args[0] + 1)?,
+            OP_DUPN => self.dup(args[0] + 1)?,
+            OP_POPV => {
+                let value = self.pop()?;
+                match self.var_mut(args[0])? {
+                    Value::Int(var) => *var = value,
+                    _ => return Err(format!("variable {} is an array", args[0])),
+                }
+            }
+            OP_POPE => {
+                let index = self.pop()?;
+                let value = self.pop()?;
+                let array = self.int_array_mut(args[0])?;
+                let len = array.len();
+                let element = usize::try_from(index).ok().and_then(|i| array.get_mut(i))
+                    .ok_or_else(|| element_error(index, args[0], len))?;
+                *element = value;
+            }
+            OP_PSHE => {
+                let index = *self.top()?;
+                let Value::IntArray(array) = self.var(args[0])? else {
+                    return Err(format!("variable {} isn't an INTEGER array", args[0]));
+                };
+                let value = usize::try_from(index).ok().and_then(|i| array.get(i)).copied()
+                    .ok_or_else(|| element_error(index, args[0], array.len()))?;
+                *self.top()? = value;
+            }
+            OP_PSHA | OP_POPA => {
+                // These take the count or left index first
+                let len = self.bool_array(args[0])?.len();
+                let first = self.pop()?;
+                let second = if op == OP_PSHA { *self.top()? } else { self.pop()? };
+                let indices = if version == 0 {
+                    run_indices(second, first, len, args[0])?
+                } else {
+                    range_indices(first, second, len, args[0])?
+                };
+                if indices.len() > 32 {
+                    return Err(format!("{} bits don't fit in an integer", indices.len()));
+                }
+                if op == OP_PSHA {
+                    let bits = self.gather(args[0], &indices)?;
+                    *self.top()? = bits.to_u64().unwrap() as u32 as i32;
+                } else {
+                    let value = self.pop()?;
+                    let bits = BitVec::from_u64(value as u32 as u64, 32).slice(0..indices.len());
+                    self.scatter(args[0], &indices, &bits)?;
+                }
+            }
+            OP_DS | OP_IS => {
+                let reg = register(op == OP_DS);
+                let indices = self.pop_range(args[0])?;
+                let count = if version == 0 { indices.len() as i32 } else { self.pop()? };
+                let count = usize::try_from(count).ok().filter(|c| *c <= indices.len())
+                    .ok_or_else(|| format!("scan of {} bits from {}", count, indices.len()))?;
+                let data = self.gather(args[0], &indices[..count])?;
+                self.chain.scan(reg, &data, false);
+            }
+            OP_DSC | OP_ISC => {
+                let reg = register(op == OP_DSC);
+                let (capture, scan) = if version == 0 {
+                    let capture = self.pop()?;
+                    let scan = self.pop()?;
+                    let count = *self.top()?;
+                    (run_indices(capture, count, self.bool_array(args[1])?.len(), args[1])?,
+                     run_indices(scan, count, self.bool_array(args[0])?.len(), args[0])?)
+                } else {
+                    (self.pop_range(args[1])?, self.pop_range(args[0])?)
+                };
+                let count = self.pop()?;
+                let count = usize::try_from(count).ok()
+                    .filter(|c| *c <= capture.len() && *c <= scan.len())
+                    .ok_or_else(|| format!("scan of {} bits", count))?;
+                let data = self.gather(args[0], &scan[..count])?;
+                let captured = self.chain.scan(reg, &data, true).unwrap();
+                self.scatter(args[1], &capture[..count], &captured)?;
+            }
+            OP_EXPT => {
+                let key = self.string(args[0])?;
+                let value = self.pop()?;
+                self.report.exports.push((key, value.to_string()));
+            }
+            OP_EXPV => {
+                if version == 0 {
+                    return Err("not in version 0 of the byte-code".to_string());
+                }
+                let key = self.string(args[0])?;
+                let id = self.pop()? as u32;
+                let indices = self.pop_range(id)?;
+                let bits = self.gather(id, &indices)?;
+                self.report.exports.push((key, bits.to_hex()));
+            }
+            OP_DYNA => {
+                let size = self.pop()?;
+                let size = usize::try_from(size).map_err(|_| format!("size of {}", size))?;
+                // Arrays only grow, and come back cleared
+                match self.var_mut(args[0])? {
+                    Value::BoolArray(bits) if size > bits.len() => *bits = BitVec::zeros(size),
+                    Value::IntArray(array) if size > array.len() => *array = vec![0; size],
+                    Value::Int(_) => return Err(format!("variable {} isn't an array", args[0])),
+                    _ => {}
+                }
+            }
+            OP_COPY => {
+                let (source, dest) = if version == 0 {
+                    let count = self.pop()?;
+                    let source = self.pop()?;
+                    let dest = self.pop()?;
+                    (run_indices(source, count, self.bool_array(args[0])?.len(), args[0])?,
+                     run_indices(dest, count, self.bool_array(args[1])?.len(), args[1])?)
+                } else {
+                    (self.pop_range(args[0])?, self.pop_range(args[1])?)
+                };
+                let count = source.len().min(dest.len());
+                // Reversed ranges line up at their ends, so they have to be the same length
+                let reversed = |r: &[usize]| r.len() > 1 && r[0] > r[1];
+                if source.len() != dest.len() && (reversed(&source) || reversed(&dest)) {
+                    return Err(format!("copy of {} bits into {} with a range reversed",
+                                       source.len(), dest.len()));
+                }
+                let bits = self.gather(args[0], &source[..count])?;
+                self.scatter(args[1], &dest[..count], &bits)?;
+            }
+            OP_WAIT => {
+                let cycles = self.pop()?;
+                let usec = self.pop()?;
+                if version > 0 {
+                    // The maximum cycles and microseconds
+                    self.pop()?;
+                    self.pop()?;
+                }
+                let cycles = u64::try_from(cycles).map_err(|_| format!("wait of {} cycles", cycles))?;
+                let usec = u64::try_from(usec).map_err(|_| format!("wait of {} microseconds", usec))?;
+                self.chain.wait(state(args[0])?, cycles, usec as f64, state(args[1])?)?;
+            }
+            OP_CMPA => {
+                let (first, second, mask) = if version == 0 {
+                    let first = self.pop()?;
+                    let second = self.pop()?;
+                    let mask = self.pop()?;
+                    let count = self.pop()?;
+                    (run_indices(first, count, self.bool_array(args[0])?.len(), args[0])?,
+                     run_indices(second, count, self.bool_array(args[1])?.len(), args[1])?,
+                     run_indices(mask, count, self.bool_array(args[2])?.len(), args[2])?)
+                } else {
+                    (self.pop_range(args[0])?, self.pop_range(args[1])?, self.pop_range(args[2])?)
+                };
+                let count = first.len().min(second.len()).min(mask.len());
+                let first = self.gather(args[0], &first[..count])?;
+                let second = self.gather(args[1], &second[..count])?;
+                let mask = self.gather(args[2], &mask[..count])?;
+                self.push(first.masked_eq(&second, &mask) as i32);
+            }
+            _ => return Err("unknown or unsupported opcode".to_string()),
+        }
+        Ok(Step::Next)
+    }
+}
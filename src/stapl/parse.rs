@@ -0,0 +1,720 @@
+//! Lexer and parser for STAPL (.jam) source.  Statements are kept in a flat list, the way the
+//! language is laid out, and blocks are matched up afterwards so that the interpreter can jump
+//! over them.
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use super::jbc::ByteCode;
+use crate::statemachine::{JtagState, Register};
+use crate::svf;
+
+#[derive(Clone,Debug,PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Str(String),
+    // Array literals: the digits after $, # or @
+    Hex(String),
+    Bin(String),
+    Aca(String),
+    // Punctuation and operators
+    Sym(&'static str),
+}
+
+const SYMBOLS: [&str; 29] = [
+    "..", "&&", "||", "==", "!=", "<=", ">=", "<<", ">>",
+    ";", ",", ":", "=", "(", ")", "[", "]", "+", "-", "*", "/", "%", "&", "|", "^", "~", "!", "<", ">",
+];
+
+fn take_while(chars: &mut Peekable<CharIndices>, f: &dyn Fn(char) -> bool) -> String {
+    let mut s = String::new();
+    while let Some((_, c)) = chars.next_if(|(_, c)| f(*c)) {
+        s.push(c);
+    }
+    s
+}
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, String> {
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let token = match c {
+            '\n' => {
+                line += 1;
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            '\'' => {
+                take_while(&mut chars, &|c| c != '\n');
+                continue;
+            }
+            '"' => {
+                let s = take_while(&mut chars, &|c| c != '"' && c != '\n');
+                if chars.next().is_none_or(|(_, c)| c != '"') {
+                    return Err(format!("line {}: unterminated string", line));
+                }
+                Token::Str(s)
+            }
+            '$' => Token::Hex(take_while(&mut chars, &|c| c.is_ascii_hexdigit())),
+            '#' => Token::Bin(take_while(&mut chars, &|c| c == '0' || c == '1')),
+            '@' if chars.peek().is_some_and(|(_, c)| c.is_ascii_alphanumeric() || *c == '_' || *c == '@') => {
+                Token::Aca(take_while(&mut chars, &|c| c.is_ascii_alphanumeric() || c == '_' || c == '@'))
+            }
+            c if c.is_ascii_digit() => {
+                let digits = c.to_string() + &take_while(&mut chars, &|c| c.is_ascii_digit());
+                Token::Number(digits.parse().map_err(|_| format!("line {}: number {} is too big", line, digits))?)
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let rest = take_while(&mut chars, &|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+                Token::Ident((c.to_string() + &rest).to_ascii_uppercase())
+            }
+            _ => {
+                let sym = SYMBOLS.iter().find(|sym| text[i..].starts_with(**sym))
+                    .ok_or_else(|| format!("line {}: unexpected character '{}'", line, c))?;
+                for _ in 1..sym.len() {
+                    chars.next();
+                }
+                Token::Sym(sym)
+            }
+        };
+        tokens.push((line, token));
+    }
+    Ok(tokens)
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub(super) enum UnOp {
+    Neg,
+    Not,
+    Invert,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub(super) enum BinOp {
+    Or, And, BitOr, BitXor, BitAnd, Eq, Ne, Lt, Le, Gt, Ge, Shl, Shr, Add, Sub, Mul, Div, Rem,
+}
+
+// Operators from loosest to tightest binding
+const PRECEDENCE: [&[(&str, BinOp)]; 10] = [
+    &[("||", BinOp::Or)],
+    &[("&&", BinOp::And)],
+    &[("|", BinOp::BitOr)],
+    &[("^", BinOp::BitXor)],
+    &[("&", BinOp::BitAnd)],
+    &[("==", BinOp::Eq), ("!=", BinOp::Ne)],
+    &[("<", BinOp::Lt), ("<=", BinOp::Le), (">", BinOp::Gt), (">=", BinOp::Ge)],
+    &[("<<", BinOp::Shl), (">>", BinOp::Shr)],
+    &[("+", BinOp::Add), ("-", BinOp::Sub)],
+    &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Rem)],
+];
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub(super) enum Func {
+    Abs,
+    Int,
+    Log2,
+    Sqrt,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub(super) enum Expr {
+    Int(i64),
+    Hex(String),
+    Bin(String),
+    Aca(String),
+    Var(String),
+    Index(String, Box<Expr>),
+    // The first index is the most significant bit
+    Slice(String, Box<Expr>, Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Call(Func, Box<Expr>),
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub(super) enum Lvalue {
+    Var(String),
+    Index(String, Expr),
+    Slice(String, Expr, Expr),
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub(super) enum Init {
+    // A single value, or for a BOOLEAN array a whole array
+    Value(Expr),
+    // Elements of an INTEGER array
+    List(Vec<Expr>),
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub(super) enum PrintItem {
+    Str(String),
+    Expr(Expr),
+}
+
+/// Whether a procedure listed in an ACTION runs by default
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Inclusion {
+    Required,
+    /// Only runs if enabled with `Player::enable`
+    Optional,
+    /// Runs unless disabled with `Player::enable`
+    Recommended,
+}
+
+/// An ACTION statement: a named sequence of procedures
+#[derive(Clone,Debug,PartialEq)]
+pub struct Action {
+    pub name: String,
+    pub description: Option<String>,
+    pub procedures: Vec<(String, Inclusion)>,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub(super) enum Stmt {
+    Action(Action),
+    Procedure(String),
+    EndProc,
+    Data(String),
+    EndData,
+    Boolean { name: String, size: Option<Expr>, init: Option<Init> },
+    Integer { name: String, size: Option<Expr>, init: Option<Init> },
+    Let(Lvalue, Expr),
+    If(Expr, Box<Stmt>),
+    For { var: String, start: Expr, end: Expr, step: Option<Expr> },
+    Next(String),
+    Goto(String),
+    Call(String),
+    Exit(Expr),
+    Push(Expr),
+    Pop(Lvalue),
+    Print(Vec<PrintItem>),
+    Export(String, Expr),
+    Note(String, String),
+    Crc,
+    Scan { reg: Register, len: Expr, data: Expr, capture: Option<Lvalue>, compare: Option<Box<(Expr, Expr, Lvalue)>> },
+    // PREIR, POSTIR, PREDR or POSTDR
+    Padding { reg: Register, pre: bool, len: Expr, data: Option<Expr> },
+    Stop(Register, JtagState),
+    State(Vec<JtagState>),
+    Wait { state: Option<JtagState>, cycles: Option<Expr>, usec: Option<Expr>, end: Option<JtagState> },
+    Frequency(Option<Expr>),
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub(super) struct Line {
+    pub line: usize,
+    pub stmt: Stmt,
+}
+
+/// A parsed STAPL program
+#[derive(Clone,Debug)]
+pub struct Program {
+    pub(super) lines: Vec<Line>,
+    pub(super) labels: HashMap<String, usize>,
+    // Index of the first statement of each procedure
+    pub(super) procedures: HashMap<String, usize>,
+    // For FOR, PROCEDURE and DATA, the index of the matching NEXT, ENDPROC or ENDDATA
+    pub(super) block_end: HashMap<usize, usize>,
+    pub(super) notes: Vec<(String, String)>,
+    pub(super) actions: Vec<Action>,
+    // A program loaded from byte-code has no statements
+    pub(super) bytecode: Option<ByteCode>,
+}
+
+// Parses the tokens of one statement
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<&Token, String> {
+        let token = self.tokens.get(self.pos).ok_or_else(|| "statement ends too soon".to_string())?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.tokens.len()
+    }
+
+    fn end(&self) -> Result<(), String> {
+        match self.peek() {
+            None => Ok(()),
+            Some(token) => Err(format!("unexpected {:?}", token)),
+        }
+    }
+
+    fn is_sym(&self, sym: &str) -> bool {
+        matches!(self.peek(), Some(Token::Sym(s)) if *s == sym)
+    }
+
+    fn eat_sym(&mut self, sym: &str) -> bool {
+        let found = self.is_sym(sym);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_sym(&mut self, sym: &str) -> Result<(), String> {
+        if !self.eat_sym(sym) {
+            return Err(format!("expected {}", sym));
+        }
+        Ok(())
+    }
+
+    fn is_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(s)) if s == word)
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        let found = self.is_word(word);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_word(&mut self, word: &str) -> Result<(), String> {
+        if !self.eat_word(word) {
+            return Err(format!("expected {}", word));
+        }
+        Ok(())
+    }
+
+    fn ident(&mut self) -> Result<String, String> {
+        match self.next()? {
+            Token::Ident(name) => Ok(name.clone()),
+            token => Err(format!("expected a name, found {:?}", token)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        match self.next()? {
+            Token::Str(s) => Ok(s.clone()),
+            token => Err(format!("expected a string, found {:?}", token)),
+        }
+    }
+
+    fn state(&mut self) -> Result<JtagState, String> {
+        let name = self.ident()?;
+        svf::parse_state(&name)
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        'outer: loop {
+            for (sym, op) in PRECEDENCE[level] {
+                if self.eat_sym(sym) {
+                    let rhs = self.binary(level + 1)?;
+                    lhs = Expr::Binary(*op, Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        for (sym, op) in [("-", UnOp::Neg), ("!", UnOp::Not), ("~", UnOp::Invert)] {
+            if self.eat_sym(sym) {
+                return Ok(Expr::Unary(op, Box::new(self.unary()?)));
+            }
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next()?.clone() {
+            Token::Number(n) => Ok(Expr::Int(n)),
+            Token::Hex(digits) => Ok(Expr::Hex(digits)),
+            Token::Bin(digits) => Ok(Expr::Bin(digits)),
+            Token::Aca(chars) => Ok(Expr::Aca(chars)),
+            Token::Sym("(") => {
+                let expr = self.expr()?;
+                self.expect_sym(")")?;
+                Ok(expr)
+            }
+            Token::Ident(name) => {
+                let func = match name.as_str() {
+                    "ABS" => Some(Func::Abs),
+                    "INT" => Some(Func::Int),
+                    "LOG2" => Some(Func::Log2),
+                    "SQRT" => Some(Func::Sqrt),
+                    _ => None,
+                };
+                if let Some(func) = func.filter(|_| self.is_sym("(")) {
+                    self.expect_sym("(")?;
+                    let arg = self.expr()?;
+                    self.expect_sym(")")?;
+                    return Ok(Expr::Call(func, Box::new(arg)));
+                }
+                if !self.eat_sym("[") {
+                    return Ok(Expr::Var(name));
+                }
+                let first = self.expr()?;
+                let expr = if self.eat_sym("..") {
+                    Expr::Slice(name, Box::new(first), Box::new(self.expr()?))
+                } else {
+                    Expr::Index(name, Box::new(first))
+                };
+                self.expect_sym("]")?;
+                Ok(expr)
+            }
+            token => Err(format!("unexpected {:?}", token)),
+        }
+    }
+
+    fn lvalue(&mut self) -> Result<Lvalue, String> {
+        let name = self.ident()?;
+        if !self.eat_sym("[") {
+            return Ok(Lvalue::Var(name));
+        }
+        let first = self.expr()?;
+        let lvalue = if self.eat_sym("..") {
+            Lvalue::Slice(name, first, self.expr()?)
+        } else {
+            Lvalue::Index(name, first)
+        };
+        self.expect_sym("]")?;
+        Ok(lvalue)
+    }
+
+    fn declaration(&mut self, boolean: bool) -> Result<Stmt, String> {
+        let name = self.ident()?;
+        let size = if self.eat_sym("[") {
+            let size = self.expr()?;
+            self.expect_sym("]")?;
+            Some(size)
+        } else {
+            None
+        };
+        let init = if self.eat_sym("=") {
+            let first = self.expr()?;
+            if self.is_sym(",") {
+                let mut list = vec![first];
+                while self.eat_sym(",") {
+                    list.push(self.expr()?);
+                }
+                Some(Init::List(list))
+            } else {
+                Some(Init::Value(first))
+            }
+        } else {
+            None
+        };
+        Ok(if boolean {
+            Stmt::Boolean { name, size, init }
+        } else {
+            Stmt::Integer { name, size, init }
+        })
+    }
+
+    fn action(&mut self) -> Result<Stmt, String> {
+        let name = self.ident()?;
+        let description = match self.peek() {
+            Some(Token::Str(_)) => Some(self.string()?),
+            _ => None,
+        };
+        self.expect_sym("=")?;
+        let mut procedures = vec![];
+        loop {
+            let procedure = self.ident()?;
+            let inclusion = if self.eat_word("OPTIONAL") {
+                Inclusion::Optional
+            } else if self.eat_word("RECOMMENDED") {
+                Inclusion::Recommended
+            } else {
+                Inclusion::Required
+            };
+            procedures.push((procedure, inclusion));
+            if !self.eat_sym(",") {
+                break;
+            }
+        }
+        Ok(Stmt::Action(Action { name, description, procedures }))
+    }
+
+    fn scan(&mut self, reg: Register) -> Result<Stmt, String> {
+        let len = self.expr()?;
+        self.expect_sym(",")?;
+        let data = self.expr()?;
+        let mut capture = None;
+        let mut compare = None;
+        while self.eat_sym(",") {
+            if self.eat_word("CAPTURE") {
+                capture = Some(self.lvalue()?);
+            } else if self.eat_word("COMPARE") {
+                let expected = self.expr()?;
+                self.expect_sym(",")?;
+                let mask = self.expr()?;
+                self.expect_sym(",")?;
+                compare = Some(Box::new((expected, mask, self.lvalue()?)));
+            } else {
+                return Err("expected CAPTURE or COMPARE".to_string());
+            }
+        }
+        Ok(Stmt::Scan { reg, len, data, capture, compare })
+    }
+
+    fn wait(&mut self) -> Result<Stmt, String> {
+        let mut state = None;
+        let mut cycles = None;
+        let mut usec = None;
+        let mut end = None;
+        loop {
+            let is_state = matches!(self.peek(), Some(Token::Ident(name)) if svf::parse_state(name).is_ok());
+            if is_state {
+                let parsed = self.state()?;
+                if cycles.is_none() && usec.is_none() && state.is_none() {
+                    state = Some(parsed);
+                } else {
+                    end = Some(parsed);
+                }
+            } else {
+                let value = self.expr()?;
+                if self.eat_word("CYCLES") {
+                    cycles = Some(value);
+                } else if self.eat_word("USEC") {
+                    usec = Some(value);
+                } else {
+                    return Err("expected CYCLES or USEC".to_string());
+                }
+            }
+            if !self.eat_sym(",") {
+                break;
+            }
+        }
+        Ok(Stmt::Wait { state, cycles, usec, end })
+    }
+
+    fn statement(&mut self) -> Result<Stmt, String> {
+        let word = match self.peek() {
+            Some(Token::Ident(word)) => word.clone(),
+            Some(token) => return Err(format!("unexpected {:?}", token)),
+            None => return Err("empty statement".to_string()),
+        };
+        // An assignment without LET
+        if matches!(self.tokens.get(self.pos + 1), Some(Token::Sym("=" | "["))) && word != "LET" {
+            let lvalue = self.lvalue()?;
+            self.expect_sym("=")?;
+            return Ok(Stmt::Let(lvalue, self.expr()?));
+        }
+        self.pos += 1;
+        let stmt = match word.as_str() {
+            "ACTION" => self.action()?,
+            "PROCEDURE" => {
+                let name = self.ident()?;
+                // Everything is global, so what the procedure uses doesn't matter
+                if self.eat_word("USES") {
+                    self.pos = self.tokens.len();
+                }
+                Stmt::Procedure(name)
+            }
+            "ENDPROC" => Stmt::EndProc,
+            "DATA" => Stmt::Data(self.ident()?),
+            "ENDDATA" => Stmt::EndData,
+            "BOOLEAN" => self.declaration(true)?,
+            "INTEGER" => self.declaration(false)?,
+            "LET" => {
+                let lvalue = self.lvalue()?;
+                self.expect_sym("=")?;
+                Stmt::Let(lvalue, self.expr()?)
+            }
+            "IF" => {
+                let cond = self.expr()?;
+                self.expect_word("THEN")?;
+                return Ok(Stmt::If(cond, Box::new(self.statement()?)));
+            }
+            "FOR" => {
+                let var = self.ident()?;
+                self.expect_sym("=")?;
+                let start = self.expr()?;
+                self.expect_word("TO")?;
+                let end = self.expr()?;
+                let step = if self.eat_word("STEP") { Some(self.expr()?) } else { None };
+                Stmt::For { var, start, end, step }
+            }
+            "NEXT" => Stmt::Next(self.ident()?),
+            "GOTO" => Stmt::Goto(self.ident()?),
+            "CALL" => Stmt::Call(self.ident()?),
+            "EXIT" => Stmt::Exit(self.expr()?),
+            "PUSH" => Stmt::Push(self.expr()?),
+            "POP" => Stmt::Pop(self.lvalue()?),
+            "PRINT" => {
+                let mut items = vec![];
+                while !self.at_end() {
+                    match self.peek() {
+                        Some(Token::Str(_)) => items.push(PrintItem::Str(self.string()?)),
+                        _ => items.push(PrintItem::Expr(self.expr()?)),
+                    }
+                    if !self.eat_sym(",") {
+                        break;
+                    }
+                }
+                Stmt::Print(items)
+            }
+            "EXPORT" => {
+                let key = self.string()?;
+                self.expect_sym(",")?;
+                Stmt::Export(key, self.expr()?)
+            }
+            "NOTE" => {
+                let key = self.string()?;
+                Stmt::Note(key, self.string()?)
+            }
+            "CRC" => {
+                self.pos = self.tokens.len();
+                Stmt::Crc
+            }
+            "IRSCAN" => self.scan(Register::Instruction)?,
+            "DRSCAN" => self.scan(Register::Data)?,
+            "PREIR" | "POSTIR" | "PREDR" | "POSTDR" => {
+                let reg = if word.ends_with("IR") { Register::Instruction } else { Register::Data };
+                let len = self.expr()?;
+                let data = if self.eat_sym(",") { Some(self.expr()?) } else { None };
+                Stmt::Padding { reg, pre: word.starts_with("PRE"), len, data }
+            }
+            "IRSTOP" => Stmt::Stop(Register::Instruction, self.state()?),
+            "DRSTOP" => Stmt::Stop(Register::Data, self.state()?),
+            "STATE" => {
+                let mut states = vec![self.state()?];
+                while !self.at_end() {
+                    self.eat_sym(",");
+                    states.push(self.state()?);
+                }
+                Stmt::State(states)
+            }
+            "WAIT" => self.wait()?,
+            "FREQUENCY" => Stmt::Frequency(if self.at_end() { None } else { Some(self.expr()?) }),
+            "VECTOR" | "VMAP" => return Err(format!("{} is not supported", word)),
+            _ => return Err(format!("unknown statement {}", word)),
+        };
+        self.end()?;
+        Ok(stmt)
+    }
+}
+
+impl Program {
+    /// Parse STAPL source.  Errors give the line number.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        let mut program = Program {
+            lines: vec![],
+            labels: HashMap::new(),
+            procedures: HashMap::new(),
+            block_end: HashMap::new(),
+            notes: vec![],
+            actions: vec![],
+            bytecode: None,
+        };
+
+        let mut start = 0;
+        for (end, (_, token)) in tokens.iter().enumerate() {
+            if *token != Token::Sym(";") {
+                continue;
+            }
+            let line = tokens[start].0;
+            let mut statement: Vec<Token> = tokens[start..end].iter().map(|(_, t)| t.clone()).collect();
+            start = end + 1;
+            // Labels
+            while let [Token::Ident(label), Token::Sym(":"), ..] = statement.as_slice() {
+                program.labels.insert(label.clone(), program.lines.len());
+                statement.drain(..2);
+            }
+            let mut parser = Parser { tokens: &statement, pos: 0 };
+            let stmt = parser.statement().map_err(|e| format!("line {}: {}", line, e))?;
+            program.lines.push(Line { line, stmt });
+        }
+        if start != tokens.len() {
+            return Err(format!("line {}: missing ; at end of file", tokens[start].0));
+        }
+
+        program.match_blocks()?;
+        Ok(program)
+    }
+
+    // Pair up FOR with NEXT, PROCEDURE with ENDPROC and DATA with ENDDATA, and collect the
+    // procedures, notes and actions
+    fn match_blocks(&mut self) -> Result<(), String> {
+        let mut fors: Vec<(usize, &str)> = vec![];
+        let mut block: Option<usize> = None;
+        for (i, line) in self.lines.iter().enumerate() {
+            let err = |e: String| format!("line {}: {}", line.line, e);
+            match &line.stmt {
+                Stmt::For { var, .. } => fors.push((i, var)),
+                Stmt::Next(var) => {
+                    match fors.pop() {
+                        Some((start, v)) if v == var => {
+                            self.block_end.insert(start, i);
+                        }
+                        _ => return Err(err(format!("NEXT {} without FOR", var))),
+                    }
+                }
+                Stmt::Procedure(name) | Stmt::Data(name) => {
+                    if block.is_some() {
+                        return Err(err(format!("{} starts inside another block", name)));
+                    }
+                    block = Some(i);
+                    if matches!(line.stmt, Stmt::Procedure(_)) &&
+                        self.procedures.insert(name.clone(), i + 1).is_some() {
+                        return Err(err(format!("procedure {} defined twice", name)));
+                    }
+                }
+                Stmt::EndProc | Stmt::EndData => {
+                    let start = block.take().ok_or_else(|| err("end of block without a start".to_string()))?;
+                    let matched = matches!((&self.lines[start].stmt, &line.stmt),
+                                           (Stmt::Procedure(_), Stmt::EndProc) | (Stmt::Data(_), Stmt::EndData));
+                    if !matched {
+                        return Err(err("ENDPROC and ENDDATA don't match the start of the block".to_string()));
+                    }
+                    if let Some((_, var)) = fors.last() {
+                        return Err(err(format!("FOR {} without NEXT", var)));
+                    }
+                    self.block_end.insert(start, i);
+                }
+                Stmt::Note(key, value) => self.notes.push((key.clone(), value.clone())),
+                Stmt::Action(action) => self.actions.push(action.clone()),
+                _ => {}
+            }
+        }
+        if let Some((i, var)) = fors.last() {
+            return Err(format!("line {}: FOR {} without NEXT", self.lines[*i].line, var));
+        }
+        if let Some(i) = block {
+            return Err(format!("line {}: block isn't closed", self.lines[i].line));
+        }
+        for action in &self.actions {
+            for (procedure, _) in &action.procedures {
+                if !self.procedures.contains_key(procedure) {
+                    return Err(format!("action {} uses unknown procedure {}", action.name, procedure));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The NOTE statements, as key and value
+    pub fn notes(&self) -> &[(String, String)] {
+        &self.notes
+    }
+
+    pub fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+}
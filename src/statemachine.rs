@@ -1,15 +1,21 @@
 //! This provides a higher-level interface than the `Cable` trait.  Specifically, it keeps track of
 //! the state of the JTAG state machine, and allows setting the state to any desired state.
 //! `JtagSM` will get to that state by the most efficient path, based on the current state.
+use crate::bits::BitVec;
 use crate::cable::Cable;
 
-#[derive(Clone,Copy,PartialEq)]
+use std::time::Duration;
+
+// Number of TMS values passed to the cable at a time by run_test()
+const RUN_TEST_CHUNK: usize = 4096;
+
+#[derive(Clone,Copy,PartialEq,Debug)]
 pub enum Register {
     Data,
     Instruction
 }
 
-#[derive(Clone,Copy,PartialEq)]
+#[derive(Clone,Copy,PartialEq,Debug)]
 pub enum JtagState {
     Reset = 0,
     Idle = 1,
@@ -29,29 +35,204 @@ pub enum JtagState {
     UpdateIR = 15,
 }
 
-struct Node {
-    edges: Vec<usize>,
+// The states in the order of their numbers, which XSVF and STAPL byte-code use too
+pub(crate) const STATES: [JtagState; 16] = [
+    JtagState::Reset, JtagState::Idle,
+    JtagState::SelectDR, JtagState::CaptureDR, JtagState::ShiftDR, JtagState::Exit1DR,
+    JtagState::PauseDR, JtagState::Exit2DR, JtagState::UpdateDR,
+    JtagState::SelectIR, JtagState::CaptureIR, JtagState::ShiftIR, JtagState::Exit1IR,
+    JtagState::PauseIR, JtagState::Exit2IR, JtagState::UpdateIR,
+];
+
+// Longest path between two states is 7 clocks (e.g. Reset to Exit2IR)
+const MAX_PATH: usize = 8;
+
+#[derive(Clone,Copy)]
+struct TmsPath {
+    tms: [usize; MAX_PATH],
+    len: usize,
 }
 
-impl Node {
-    fn new() -> Self {
-        Self {
-            edges: Vec::new(),
+/// Shortest TMS sequence between every pair of states, indexed by `[from][to]`
+static PATHS: [[TmsPath; 16]; 16] = build_paths();
+
+/// Breadth-first search from every state over the transitions in `JtagState::next`.  Trying TMS
+/// low before TMS high means that, of the shortest paths, the one that spends longest with TMS low
+/// wins.
+const fn build_paths() -> [[TmsPath; 16]; 16] {
+    let mut paths = [[TmsPath { tms: [0; MAX_PATH], len: 0 }; 16]; 16];
+
+    let mut from = 0;
+    while from < 16 {
+        let mut visited = [false; 16];
+        let mut queue = [0; 16];
+        let mut head = 0;
+        let mut tail = 1;
+        queue[0] = from;
+        visited[from] = true;
+
+        while head < tail {
+            let state = queue[head];
+            head += 1;
+
+            let mut tms = 0;
+            while tms < 2 {
+                let next = STATES[state].next(tms != 0) as usize;
+                if !visited[next] {
+                    let mut path = paths[from][state];
+                    assert!(path.len < MAX_PATH);
+                    path.tms[path.len] = tms;
+                    path.len += 1;
+                    paths[from][next] = path;
+
+                    visited[next] = true;
+                    queue[tail] = next;
+                    tail += 1;
+                }
+                tms += 1;
+            }
         }
+        assert!(tail == 16, "unreachable JTAG state");
+        from += 1;
     }
+    paths
 }
 
-#[derive(Clone)]
-struct Path {
-    path: Vec<usize>,
-    state: usize,
+impl JtagState {
+    /// The state the TAP moves to on the next TCK when TMS is `tms`
+    pub const fn next(self, tms: bool) -> JtagState {
+        match (self, tms) {
+            (JtagState::Reset, false)     => JtagState::Idle,
+            (JtagState::Reset, true)      => JtagState::Reset,
+            (JtagState::Idle, false)      => JtagState::Idle,
+            (JtagState::Idle, true)       => JtagState::SelectDR,
+            (JtagState::SelectDR, false)  => JtagState::CaptureDR,
+            (JtagState::SelectDR, true)   => JtagState::SelectIR,
+            (JtagState::CaptureDR, false) => JtagState::ShiftDR,
+            (JtagState::CaptureDR, true)  => JtagState::Exit1DR,
+            (JtagState::ShiftDR, false)   => JtagState::ShiftDR,
+            (JtagState::ShiftDR, true)    => JtagState::Exit1DR,
+            (JtagState::Exit1DR, false)   => JtagState::PauseDR,
+            (JtagState::Exit1DR, true)    => JtagState::UpdateDR,
+            (JtagState::PauseDR, false)   => JtagState::PauseDR,
+            (JtagState::PauseDR, true)    => JtagState::Exit2DR,
+            (JtagState::Exit2DR, false)   => JtagState::ShiftDR,
+            (JtagState::Exit2DR, true)    => JtagState::UpdateDR,
+            (JtagState::UpdateDR, false)  => JtagState::Idle,
+            (JtagState::UpdateDR, true)   => JtagState::SelectDR,
+            (JtagState::SelectIR, false)  => JtagState::CaptureIR,
+            (JtagState::SelectIR, true)   => JtagState::Reset,
+            (JtagState::CaptureIR, false) => JtagState::ShiftIR,
+            (JtagState::CaptureIR, true)  => JtagState::Exit1IR,
+            (JtagState::ShiftIR, false)   => JtagState::ShiftIR,
+            (JtagState::ShiftIR, true)    => JtagState::Exit1IR,
+            (JtagState::Exit1IR, false)   => JtagState::PauseIR,
+            (JtagState::Exit1IR, true)    => JtagState::UpdateIR,
+            (JtagState::PauseIR, false)   => JtagState::PauseIR,
+            (JtagState::PauseIR, true)    => JtagState::Exit2IR,
+            (JtagState::Exit2IR, false)   => JtagState::ShiftIR,
+            (JtagState::Exit2IR, true)    => JtagState::UpdateIR,
+            (JtagState::UpdateIR, false)  => JtagState::Idle,
+            (JtagState::UpdateIR, true)   => JtagState::SelectDR,
+        }
+    }
+
+    /// The shortest sequence of TMS values that takes the TAP from this state to `target`, in the
+    /// form expected by `Cable::change_mode`.  Empty if the two states are the same.
+    pub fn path_to(self, target: JtagState) -> &'static [usize] {
+        let path = &PATHS[self as usize][target as usize];
+        &path.tms[..path.len]
+    }
 }
 
-impl Path {
-    fn new(state: usize) -> Self {
+/// A model of the TAP controller that follows a stream of TMS values.  The state starts out
+/// unknown and the model keeps track of every state the TAP could be in, until the TMS values
+/// narrow it down to one (five clocks with TMS high always do).
+///
+/// Cables use this to trace the TMS values they actually clock, which lets `JtagSM` check in debug
+/// builds that its idea of the current state matches what the hardware was told to do.
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub struct TapModel {
+    // Bit n is set if the TAP could be in the state with value n
+    possible: u16,
+}
+
+impl Default for TapModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TapModel {
+    /// Create a model of a TAP in an unknown state
+    pub fn new() -> Self {
         Self {
-            state,
-            path: Vec::new()
+            possible: 0xffff,
+        }
+    }
+
+    /// Create a model of a TAP known to be in `state`
+    pub fn at(state: JtagState) -> Self {
+        Self {
+            possible: 1 << state as usize,
+        }
+    }
+
+    /// The state of the TAP, or None if the TMS values so far don't determine it
+    pub fn state(&self) -> Option<JtagState> {
+        if self.possible.count_ones() == 1 {
+            Some(STATES[self.possible.trailing_zeros() as usize])
+        } else {
+            None
+        }
+    }
+
+    /// Whether the TAP could be in `state`
+    pub fn could_be(&self, state: JtagState) -> bool {
+        self.possible & (1 << state as usize) != 0
+    }
+
+    /// Follow one clock of TCK with TMS at `tms`
+    pub fn clock(&mut self, tms: bool) {
+        let mut possible = 0;
+        for (i, state) in STATES.iter().enumerate() {
+            if self.possible & (1 << i) != 0 {
+                possible |= 1 << state.next(tms) as usize;
+            }
+        }
+        self.possible = possible;
+    }
+
+    /// Follow a series of TMS values in the form passed to `Cable::change_mode`
+    pub fn clock_tms(&mut self, tms: &[usize]) {
+        for x in tms {
+            self.clock(*x != 0);
+        }
+    }
+
+    /// Follow `bits` TMS values packed into bytes, least significant bit first
+    pub fn clock_bits(&mut self, tms: &[u8], bits: usize) {
+        for i in 0..bits {
+            self.clock(tms[i / 8] & (1 << (i % 8)) != 0);
+        }
+    }
+
+    /// Follow `count` clocks with TMS held at `tms`
+    pub fn clock_held(&mut self, tms: bool, count: usize) {
+        // Holding TMS settles every state within five clocks
+        for _ in 0..std::cmp::min(count, 5) {
+            self.clock(tms);
+        }
+    }
+
+    /// Check that the TAP could be in `expected`
+    pub fn check(&self, expected: JtagState) -> Result<(), String> {
+        if self.could_be(expected) {
+            return Ok(());
+        }
+        match self.state() {
+            Some(state) => Err(format!("TMS left the TAP in {:?}, expected {:?}", state, expected)),
+            None => Err(format!("TMS could not have left the TAP in {:?}", expected)),
         }
     }
 }
@@ -59,7 +240,9 @@ impl Path {
 pub struct JtagSM<T> {
     pub cable: T,
     state: JtagState,
-    graph: Vec<Node>,
+    end_ir: JtagState,
+    end_dr: JtagState,
+    ir_generation: u64,
 }
 
 impl<T, U> JtagSM<T>
@@ -68,100 +251,62 @@ impl<T, U> JtagSM<T>
 {
     /// Create a JTAG state machine using an existing `Cable`
     pub fn new(mut cable: T) -> Self {
-        let mut reset = Node::new();
-        let mut idle = Node::new();
-        let mut selectdr = Node::new();
-        let mut capturedr = Node::new();
-        let mut shiftdr = Node::new();
-        let mut exit1dr = Node::new();
-        let mut pausedr = Node::new();
-        let mut exit2dr = Node::new();
-        let mut updatedr = Node::new();
-        let mut selectir = Node::new();
-        let mut captureir = Node::new();
-        let mut shiftir = Node::new();
-        let mut exit1ir = Node::new();
-        let mut pauseir = Node::new();
-        let mut exit2ir = Node::new();
-        let mut updateir = Node::new();
-
-        reset.edges     = vec![JtagState::Idle as usize, JtagState::Reset as usize];
-        idle.edges      = vec![JtagState::Idle as usize, JtagState::SelectDR as usize];
-        selectdr.edges  = vec![JtagState::CaptureDR as usize,
-                               JtagState::SelectIR as usize];
-        capturedr.edges = vec![JtagState::ShiftDR as usize, JtagState::Exit1DR as usize];
-        shiftdr.edges   = vec![JtagState::ShiftDR as usize, JtagState::Exit1DR as usize];
-        exit1dr.edges   = vec![JtagState::PauseDR as usize, JtagState::UpdateDR as usize];
-        pausedr.edges   = vec![JtagState::PauseDR as usize, JtagState::Exit2DR as usize];
-        exit2dr.edges   = vec![JtagState::ShiftDR as usize, JtagState::UpdateDR as usize];
-        updatedr.edges  = vec![JtagState::Idle as usize, JtagState::SelectDR as usize];
-
-        selectir.edges  = vec![JtagState::CaptureIR as usize,
-                               JtagState::Reset as usize];
-        captureir.edges = vec![JtagState::ShiftIR as usize, JtagState::Exit1IR as usize];
-        shiftir.edges   = vec![JtagState::ShiftIR as usize, JtagState::Exit1IR as usize];
-        exit1ir.edges   = vec![JtagState::PauseIR as usize, JtagState::UpdateIR as usize];
-        pauseir.edges   = vec![JtagState::PauseIR as usize, JtagState::Exit2IR as usize];
-        exit2ir.edges   = vec![JtagState::ShiftIR as usize, JtagState::UpdateIR as usize];
-        updateir.edges  = vec![JtagState::Idle as usize, JtagState::SelectIR as usize];
-
-        let graph = vec![reset, idle,
-            selectdr, capturedr, shiftdr, exit1dr, pausedr, exit2dr, updatedr,
-            selectir, captureir, shiftir, exit1ir, pauseir, exit2ir, updateir,
-        ];
-
-        cable.change_mode(&[1, 1, 1, 1, 1, 0], true);
+        cable.change_mode(&[1, 1, 1, 1, 1], true);
 
-        Self {
+        let sm = Self {
             cable,
             state: JtagState::Reset,
-            graph,
+            end_ir: JtagState::Idle,
+            end_dr: JtagState::Idle,
+            ir_generation: 0,
+        };
+        sm.check_state();
+        sm
+    }
+
+    /// The state the scan chain is in
+    pub fn state(&self) -> JtagState {
+        self.state
+    }
+
+    /// In debug builds, check the state against the TMS values the cable has traced, if any.  This
+    /// catches drivers whose exit sequences have drifted from what `JtagSM` asked for.
+    fn check_state(&self) {
+        if cfg!(debug_assertions) {
+            if let Some(trace) = self.cable.tms_trace() {
+                if let Err(e) = trace.check(self.state) {
+                    panic!("JTAG state out of sync with cable: {}", e);
+                }
+            }
         }
     }
 
+    /// A counter that goes up whenever the instruction registers may have changed: on reset,
+    /// including scans that end in Test-Logic-Reset, on every IR scan and on `shift_vectors`.
+    /// `Taps` uses it to know whether the instructions it loaded are still in place.
+    pub fn ir_generation(&self) -> u64 {
+        self.ir_generation
+    }
+
     /// Reset the scan chain by driving TMS high for 5 clocks
     pub fn mode_reset(&mut self)
     {
-        self.cable.change_mode(&[1, 1, 1, 1, 1, 0], true);
+        self.cable.change_mode(&[1, 1, 1, 1, 1], true);
         self.state = JtagState::Reset;
+        self.ir_generation += 1;
+        self.check_state();
     }
 
-    fn get_path(&mut self, state: JtagState) -> Vec<usize> {
-        let mut paths = Vec::new();
-
-        let mut p = Path::new(self.graph[self.state as usize].edges[0]);
-        p.path = vec![0];
-        paths.push(p);
-
-        let mut p = Path::new(self.graph[self.state as usize].edges[1]);
-        p.path = vec![1];
-        paths.push(p);
-
-        loop {
-            let mut newpaths = Vec::new();
-
-            for p in paths {
-                let mut p1 = p.clone();
-                p1.state = self.graph[p.state].edges[0];
-                p1.path.push(0);
-
-                if p1.state == state as usize {
-                    return p1.path
-                }
-                newpaths.push(p1);
-
-                let mut p2 = p.clone();
-                p2.state = self.graph[p.state].edges[1];
-                p2.path.push(1);
-
-                if p2.state == state as usize {
-                    return p2.path
-                }
-                newpaths.push(p2);
-            }
-            
-            paths = newpaths;
+    /// Reset the scan chain with the TRST line.  Returns false, doing nothing, if the cable
+    /// doesn't have one.
+    pub fn trst(&mut self) -> bool {
+        if !self.cable.trst() {
+            return false;
         }
+        self.state = JtagState::Reset;
+        self.ir_generation += 1;
+        self.check_state();
+        true
     }
 
     /// Use TMS to get into `state` by the most efficient path
@@ -170,18 +315,71 @@ impl<T, U> JtagSM<T>
             return;
         }
 
-        let path = self.get_path(state);
-        //println!("Path from {} to {}: {:?}", self.state as usize, state as usize, path);
-        self.cable.change_mode(&path, true);
+        let path = self.state.path_to(state);
+        self.cable.change_mode(path, true);
         self.state = state;
+        if state == JtagState::Reset {
+            self.ir_generation += 1;
+        }
+        self.check_state();
+    }
+
+    /// Clock out arbitrary TMS and TDI vectors and return what was captured from TDO.  See
+    /// `Cable::shift_vectors`.  The TMS values are followed through the state machine so that later
+    /// mode changes still start from the right place.
+    pub fn shift_vectors(&mut self, tms: &BitVec, tdi: &BitVec) -> BitVec {
+        let tdo = self.cable.shift_vectors(tms, tdi);
+        self.ir_generation += 1;
+
+        for bit in tms.iter() {
+            self.state = self.state.next(bit);
+        }
+        self.check_state();
+        tdo
+    }
+
+    /// Go to Run-Test/Idle and clock TCK `cycles` times while staying there
+    pub fn run_test(&mut self, cycles: usize) {
+        self.wait_in(JtagState::Idle, cycles);
+    }
+
+    /// Go to Run-Test/Idle and stay there for at least `duration`.  Cables that know their TCK
+    /// frequency keep the clock running for the whole time, see `Cable::delay`.
+    pub fn run_test_for(&mut self, duration: Duration) {
+        self.wait_in_for(JtagState::Idle, duration);
+    }
+
+    /// Go to `state` and clock TCK `cycles` times while staying there, like `run_test` but for any
+    /// of the stable states: Reset, Idle, PauseDR or PauseIR.
+    pub fn wait_in(&mut self, state: JtagState, cycles: usize) {
+        let tms = stable_tms(state);
+        self.change_mode(state);
+
+        let tms = vec![tms; std::cmp::min(cycles, RUN_TEST_CHUNK)];
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let len = std::cmp::min(remaining, tms.len());
+            self.cable.change_mode(&tms[..len], true);
+            remaining -= len;
+        }
+        self.check_state();
+    }
+
+    /// Go to `state`, which must be one of the stable states as for `wait_in`, and stay there for
+    /// at least `duration`
+    pub fn wait_in_for(&mut self, state: JtagState, duration: Duration) {
+        stable_tms(state);
+        self.change_mode(state);
+        self.cable.delay(duration);
     }
 
     /// Read `bits` from either the instruction or data register
-    pub fn read_reg(&mut self, reg: Register, bits: usize) -> Vec<u8> {
+    pub fn read_reg(&mut self, reg: Register, bits: usize) -> BitVec {
         if reg == Register::Data {
             self.change_mode(JtagState::ShiftDR);
         } else {
             self.change_mode(JtagState::ShiftIR);
+            self.ir_generation += 1;
         }
         self.cable.read_data(bits)
     }
@@ -192,70 +390,138 @@ impl<T, U> JtagSM<T>
             self.change_mode(JtagState::ShiftDR);
         } else {
             self.change_mode(JtagState::ShiftIR);
+            self.ir_generation += 1;
         }
         self.cable.queue_read(bits)
     }
 
-    /// Write `data` into either the instruction or data register.  `bits` indicates how many bits
-    /// of the last byte should be written (8 indicates that the entire byte should be written).
-    /// The mode will either be ShiftIR / ShiftDR if `pause_after` is false, or PauseIR / PauseDR
-    /// if `pause_after` is true.  This allows for setting the register with multiple calls to
-    /// `write_reg`, which may be more convenient than manual bit-shifting.
-    pub fn write_reg(&mut self, reg: Register, data: &[u8], bits: u8, pause_after: bool) {
+    /// Set the state that `Taps` and other users of `end_state()` leave the chain in after shifting
+    /// the instruction or data register, like SVF's ENDIR and ENDDR.  `state` must be Reset, Idle,
+    /// PauseDR, PauseIR or SelectDR.  Both default to Idle.
+    pub fn set_end_state(&mut self, reg: Register, state: JtagState) {
+        assert!(is_end_state(state), "{:?} is not a valid end state", state);
         if reg == Register::Data {
-            self.change_mode(JtagState::ShiftDR);
+            self.end_dr = state;
         } else {
-            self.change_mode(JtagState::ShiftIR);
-        }
-        self.cable.write_data(data, bits, pause_after);
-        if pause_after {
-            if reg == Register::Data {
-                self.state = JtagState::PauseDR;
-            } else {
-                self.state = JtagState::PauseIR;
-            }
+            self.end_ir = state;
         }
     }
 
-    /// Write `data` into either the instruction or data register.  `bits` indicates how many bits
-    /// of the last byte should be written (8 indicates that the entire byte should be written).
-    /// The mode will either be ShiftIR / ShiftDR if `pause_after` is false, or PauseIR / PauseDR
-    /// if `pause_after` is true.  This allows for setting the register with multiple calls to
-    /// `read_write_reg`, which may be more convenient than manual bit-shifting.
-    ///
-    /// Similar to `write_reg` except it returns the bits that were shifted out during writing.
-    pub fn read_write_reg(&mut self, reg: Register, data: &[u8], bits: u8, pause_after: bool) -> Vec<u8> {
+    /// The state set by `set_end_state()` for `reg`
+    pub fn end_state(&self, reg: Register) -> JtagState {
         if reg == Register::Data {
-            self.change_mode(JtagState::ShiftDR);
+            self.end_dr
         } else {
-            self.change_mode(JtagState::ShiftIR);
+            self.end_ir
         }
-        let data = self.cable.read_write_data(data, bits, pause_after);
-        if pause_after {
-            if reg == Register::Data {
-                self.state = JtagState::PauseDR;
-            } else {
-                self.state = JtagState::PauseIR;
-            }
+    }
+
+    // Record that a scan has left the chain in `end`.  Ending in Test-Logic-Reset loads every
+    // TAP's reset instruction, like `mode_reset`.
+    fn end_shift(&mut self, end: JtagState) {
+        self.state = end;
+        if end == JtagState::Reset {
+            self.ir_generation += 1;
         }
-        data
     }
 
-    pub fn queue_read_write(&mut self, reg: Register, data: &[u8], bits: u8, pause_after: bool) -> bool {
-        if reg == Register::Data {
-            self.change_mode(JtagState::ShiftDR);
+    /// Get into ShiftIR or ShiftDR and work out the TMS sequence that will take the chain from
+    /// there to `end`, starting with the last bit of data.
+    fn start_shift(&mut self, reg: Register, end: JtagState) -> Vec<usize> {
+        let (shift, exit1) = if reg == Register::Data {
+            (JtagState::ShiftDR, JtagState::Exit1DR)
         } else {
-            self.change_mode(JtagState::ShiftIR);
-        }
-        let data = self.cable.queue_read_write(data, bits, pause_after);
-        if pause_after {
-            if reg == Register::Data {
-                self.state = JtagState::PauseDR;
-            } else {
-                self.state = JtagState::PauseIR;
-            }
+            self.ir_generation += 1;
+            (JtagState::ShiftIR, JtagState::Exit1IR)
+        };
+        self.change_mode(shift);
+
+        if end == shift {
+            return vec![];
         }
+        assert!(is_end_state(end), "{:?} is not a valid end state", end);
+        let mut exit = vec![1];
+        exit.extend_from_slice(exit1.path_to(end));
+        exit
+    }
+
+    /// Write `data` into either the instruction or data register.  The mode will either be
+    /// ShiftIR / ShiftDR if `pause_after` is false, or PauseIR / PauseDR if `pause_after` is true.
+    /// This allows for setting the register with multiple calls to `write_reg`, which may be more
+    /// convenient than manual bit-shifting.
+    pub fn write_reg(&mut self, reg: Register, data: &BitVec, pause_after: bool) {
+        let end = pause_or_shift(reg, pause_after);
+        self.write_reg_end(reg, data, end);
+    }
+
+    /// Write `data` into either the instruction or data register, leaving the chain in `end`.  The
+    /// transition to `end` starts with the last bit of data rather than with a separate mode
+    /// change.  `end` may be anything accepted by `set_end_state()`, or ShiftIR / ShiftDR to stay
+    /// in the shift state.
+    pub fn write_reg_end(&mut self, reg: Register, data: &BitVec, end: JtagState) {
+        let exit = self.start_shift(reg, end);
+        self.cable.write_data(data, &exit);
+        self.end_shift(end);
+        self.check_state();
+    }
+
+    /// Write `data` into either the instruction or data register.  The mode will either be
+    /// ShiftIR / ShiftDR if `pause_after` is false, or PauseIR / PauseDR if `pause_after` is true.
+    /// This allows for setting the register with multiple calls to `read_write_reg`, which may be more
+    /// convenient than manual bit-shifting.
+    ///
+    /// Similar to `write_reg` except it returns the bits that were shifted out during writing.
+    pub fn read_write_reg(&mut self, reg: Register, data: &BitVec, pause_after: bool) -> BitVec {
+        let end = pause_or_shift(reg, pause_after);
+        self.read_write_reg_end(reg, data, end)
+    }
+
+    /// Similar to `write_reg_end` except it returns the bits that were shifted out during writing.
+    pub fn read_write_reg_end(&mut self, reg: Register, data: &BitVec, end: JtagState) -> BitVec {
+        let exit = self.start_shift(reg, end);
+        let data = self.cable.read_write_data(data, &exit);
+        self.end_shift(end);
+        self.check_state();
         data
     }
+
+    pub fn queue_read_write(&mut self, reg: Register, data: &BitVec, pause_after: bool) -> bool {
+        let end = pause_or_shift(reg, pause_after);
+        self.queue_read_write_end(reg, data, end)
+    }
+
+    /// Queued version of `read_write_reg_end`.  Returns false, leaving the chain in ShiftIR /
+    /// ShiftDR, if the cable is out of queue space.
+    pub fn queue_read_write_end(&mut self, reg: Register, data: &BitVec, end: JtagState) -> bool {
+        let exit = self.start_shift(reg, end);
+        let queued = self.cable.queue_read_write(data, &exit);
+        if queued {
+            self.end_shift(end);
+        }
+        self.check_state();
+        queued
+    }
 }
 
+fn is_end_state(state: JtagState) -> bool {
+    matches!(state, JtagState::Reset | JtagState::Idle | JtagState::PauseDR | JtagState::PauseIR |
+             JtagState::SelectDR)
+}
+
+// The TMS value that keeps the TAP in `state`, which must be one it can stay in
+fn stable_tms(state: JtagState) -> usize {
+    match state {
+        JtagState::Reset => 1,
+        JtagState::Idle | JtagState::PauseDR | JtagState::PauseIR => 0,
+        _ => panic!("{:?} is not a stable state", state),
+    }
+}
+
+fn pause_or_shift(reg: Register, pause_after: bool) -> JtagState {
+    match (reg, pause_after) {
+        (Register::Data, true) => JtagState::PauseDR,
+        (Register::Data, false) => JtagState::ShiftDR,
+        (Register::Instruction, true) => JtagState::PauseIR,
+        (Register::Instruction, false) => JtagState::ShiftIR,
+    }
+}
@@ -0,0 +1,638 @@
+//! Playing SVF (Serial Vector Format) files, which is how many CPLD and FPGA vendors ship
+//! programming and test sequences.  `parse` turns the text of a file into `Statement`s, and a
+//! `Player` executes them against a `JtagSM`, comparing what comes out of TDO with the expected
+//! values and reporting mismatches with the line they came from.
+//!
+//! Scan data is written in SVF as hex, most significant digit first, with the last bit being the
+//! first shifted, the same way `BitVec::from_hex` reads it.  The header of HIR and HDR is shifted
+//! before the SIR or SDR data, and the trailer of TIR and TDR after it, so the header ends up in
+//! the devices closest to TDO.
+//!
+//! The `record` module goes the other way, writing SVF for whatever a cable shifts.
+//!
+//! PIO and PIOMAP aren't supported.  The TCK frequency of the cable can't be changed, so FREQUENCY
+//! is only used to stretch RUNTEST waits to the time the clock counts would have taken at that
+//! frequency.  SCK counts in RUNTEST are treated as TCK counts.  TRST ON pulses TRST, or resets
+//! the chain with TMS if the cable doesn't have a TRST line, and the other TRST modes do nothing.
+pub mod record;
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::bits::BitVec;
+use crate::cable::Cable;
+use crate::statemachine::{JtagSM, JtagState, Register};
+
+/// The six scan commands
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ScanKind {
+    Hir,
+    Sir,
+    Tir,
+    Hdr,
+    Sdr,
+    Tdr,
+}
+
+impl ScanKind {
+    pub fn register(self) -> Register {
+        match self {
+            ScanKind::Hir | ScanKind::Sir | ScanKind::Tir => Register::Instruction,
+            ScanKind::Hdr | ScanKind::Sdr | ScanKind::Tdr => Register::Data,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// The arguments of a scan command.  Values that aren't given are None, and are carried over
+/// from the last scan of the same kind as the SVF specification describes.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Scan {
+    pub len: usize,
+    pub tdi: Option<BitVec>,
+    pub tdo: Option<BitVec>,
+    pub mask: Option<BitVec>,
+    pub smask: Option<BitVec>,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Trst {
+    On,
+    Off,
+    Z,
+    Absent,
+}
+
+/// The arguments of RUNTEST
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct RunTest {
+    pub run_state: Option<JtagState>,
+    /// Number of TCK (or SCK) cycles to wait for
+    pub cycles: Option<u64>,
+    /// Minimum time to wait in seconds
+    pub min_time: Option<f64>,
+    /// Maximum time in seconds, which is ignored
+    pub max_time: Option<f64>,
+    pub end_state: Option<JtagState>,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Command {
+    Scan(ScanKind, Scan),
+    EndIr(JtagState),
+    EndDr(JtagState),
+    /// Go through the states in turn.  All but the last may be unstable states, in which case
+    /// each has to be one clock from the one before.
+    State(Vec<JtagState>),
+    RunTest(RunTest),
+    /// TCK frequency in hertz, or None to go back to the cable's own
+    Frequency(Option<f64>),
+    Trst(Trst),
+}
+
+/// A command and the line of the file it starts on
+#[derive(Clone,Debug,PartialEq)]
+pub struct Statement {
+    pub line: usize,
+    pub command: Command,
+}
+
+const STATE_NAMES: [(&str, JtagState); 16] = [
+    ("RESET", JtagState::Reset),
+    ("IDLE", JtagState::Idle),
+    ("DRSELECT", JtagState::SelectDR),
+    ("DRCAPTURE", JtagState::CaptureDR),
+    ("DRSHIFT", JtagState::ShiftDR),
+    ("DREXIT1", JtagState::Exit1DR),
+    ("DRPAUSE", JtagState::PauseDR),
+    ("DREXIT2", JtagState::Exit2DR),
+    ("DRUPDATE", JtagState::UpdateDR),
+    ("IRSELECT", JtagState::SelectIR),
+    ("IRCAPTURE", JtagState::CaptureIR),
+    ("IRSHIFT", JtagState::ShiftIR),
+    ("IREXIT1", JtagState::Exit1IR),
+    ("IRPAUSE", JtagState::PauseIR),
+    ("IREXIT2", JtagState::Exit2IR),
+    ("IRUPDATE", JtagState::UpdateIR),
+];
+
+/// The SVF name of `state`
+pub fn state_name(state: JtagState) -> &'static str {
+    STATE_NAMES.iter().find(|(_, s)| *s == state).unwrap().0
+}
+
+pub(crate) fn parse_state(name: &str) -> Result<JtagState, String> {
+    STATE_NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, s)| *s)
+        .ok_or_else(|| format!("unknown state {}", name))
+}
+
+fn is_stable(state: JtagState) -> bool {
+    matches!(state, JtagState::Reset | JtagState::Idle | JtagState::PauseDR | JtagState::PauseIR)
+}
+
+fn parse_stable(name: &str) -> Result<JtagState, String> {
+    let state = parse_state(name)?;
+    if !is_stable(state) {
+        return Err(format!("{} is not a stable state", name));
+    }
+    Ok(state)
+}
+
+#[derive(Debug,PartialEq)]
+enum Token {
+    Word(String),
+    // The contents of parentheses, without whitespace
+    Value(String),
+}
+
+// Split the text into statements, each a list of tokens along with the line it starts on
+fn tokenize(text: &str) -> Result<Vec<(usize, Vec<Token>)>, String> {
+    let mut statements = vec![];
+    let mut tokens = vec![];
+    let mut start = 1;
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            '!' => {
+                while chars.next_if(|c| *c != '\n').is_some() {}
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.next_if(|c| *c != '\n').is_some() {}
+            }
+            ';' => {
+                if tokens.is_empty() {
+                    return Err(format!("line {}: empty statement", line));
+                }
+                statements.push((start, std::mem::take(&mut tokens)));
+            }
+            '(' => {
+                if tokens.is_empty() {
+                    start = line;
+                }
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some(')') => break,
+                        Some('\n') => line += 1,
+                        Some(c) if c.is_whitespace() => {}
+                        Some(c) => value.push(c),
+                        None => return Err(format!("line {}: missing )", line)),
+                    }
+                }
+                tokens.push(Token::Value(value));
+            }
+            c => {
+                if tokens.is_empty() {
+                    start = line;
+                }
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"();!".contains(*c)) {
+                    word.push(c);
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    if !tokens.is_empty() {
+        return Err(format!("line {}: missing ; at end of file", start));
+    }
+    Ok(statements)
+}
+
+fn word(token: Option<&Token>) -> Result<&str, String> {
+    match token {
+        Some(Token::Word(word)) => Ok(word),
+        Some(Token::Value(value)) => Err(format!("unexpected ({})", value)),
+        None => Err("statement ends too soon".to_string()),
+    }
+}
+
+fn number(token: Option<&Token>) -> Result<f64, String> {
+    let word = word(token)?;
+    word.parse().map_err(|_| format!("invalid number {}", word))
+}
+
+fn parse_scan(kind: ScanKind, tokens: &[Token]) -> Result<Scan, String> {
+    let len = number(tokens.first())?;
+    if len < 0.0 || len.fract() != 0.0 {
+        return Err(format!("invalid length {}", len));
+    }
+    let mut scan = Scan {
+        len: len as usize,
+        ..Default::default()
+    };
+    let mut rest = tokens[1..].iter();
+    while let Some(token) = rest.next() {
+        let name = word(Some(token))?.to_ascii_uppercase();
+        let value = match rest.next() {
+            Some(Token::Value(value)) => BitVec::from_hex(value, scan.len)?,
+            _ => return Err(format!("{} needs a value in parentheses", name)),
+        };
+        let field = match name.as_str() {
+            "TDI" => &mut scan.tdi,
+            "TDO" => &mut scan.tdo,
+            "MASK" => &mut scan.mask,
+            "SMASK" => &mut scan.smask,
+            _ => return Err(format!("unknown {:?} parameter {}", kind, name)),
+        };
+        if field.is_some() {
+            return Err(format!("{} given twice", name));
+        }
+        *field = Some(value);
+    }
+    Ok(scan)
+}
+
+fn parse_runtest(tokens: &[Token]) -> Result<RunTest, String> {
+    let mut runtest = RunTest::default();
+    let mut i = 0;
+    if let Some(Token::Word(word)) = tokens.first() {
+        if let Ok(state) = parse_stable(word) {
+            runtest.run_state = Some(state);
+            i = 1;
+        }
+    }
+    while i < tokens.len() {
+        let first = word(tokens.get(i))?;
+        if first.eq_ignore_ascii_case("ENDSTATE") {
+            runtest.end_state = Some(parse_stable(word(tokens.get(i + 1))?)?);
+            i += 2;
+            continue;
+        }
+        let maximum = first.eq_ignore_ascii_case("MAXIMUM");
+        if maximum {
+            i += 1;
+        }
+        let value = number(tokens.get(i))?;
+        let unit = word(tokens.get(i + 1))?.to_ascii_uppercase();
+        let field = match (unit.as_str(), maximum) {
+            ("TCK" | "SCK", false) => {
+                if value < 0.0 {
+                    return Err(format!("invalid count {}", value));
+                }
+                if runtest.cycles.replace(value as u64).is_some() {
+                    return Err("more than one count".to_string());
+                }
+                i += 2;
+                continue;
+            }
+            ("SEC", false) => &mut runtest.min_time,
+            ("SEC", true) => &mut runtest.max_time,
+            _ => return Err(format!("unexpected {}", unit)),
+        };
+        if field.replace(value).is_some() {
+            return Err(format!("more than one {}time", if maximum { "maximum " } else { "" }));
+        }
+        i += 2;
+    }
+    if runtest.cycles.is_none() && runtest.min_time.is_none() {
+        return Err("RUNTEST needs a count or a time".to_string());
+    }
+    Ok(runtest)
+}
+
+fn parse_command(tokens: &[Token]) -> Result<Command, String> {
+    let name = word(tokens.first())?.to_ascii_uppercase();
+    let args = &tokens[1..];
+    let only_arg = || -> Result<&str, String> {
+        if args.len() != 1 {
+            return Err(format!("{} takes one argument", name));
+        }
+        word(args.first())
+    };
+    let scan = |kind| Ok(Command::Scan(kind, parse_scan(kind, args)?));
+    match name.as_str() {
+        "HIR" => scan(ScanKind::Hir),
+        "SIR" => scan(ScanKind::Sir),
+        "TIR" => scan(ScanKind::Tir),
+        "HDR" => scan(ScanKind::Hdr),
+        "SDR" => scan(ScanKind::Sdr),
+        "TDR" => scan(ScanKind::Tdr),
+        "ENDIR" => Ok(Command::EndIr(parse_stable(only_arg()?)?)),
+        "ENDDR" => Ok(Command::EndDr(parse_stable(only_arg()?)?)),
+        "STATE" => {
+            let states = args.iter().map(|t| parse_state(word(Some(t))?)).collect::<Result<Vec<_>, _>>()?;
+            match states.last() {
+                None => Err("STATE needs a state".to_string()),
+                Some(last) if !is_stable(*last) => Err(format!("{} is not a stable state", state_name(*last))),
+                Some(_) => Ok(Command::State(states)),
+            }
+        }
+        "RUNTEST" => Ok(Command::RunTest(parse_runtest(args)?)),
+        "FREQUENCY" => match args {
+            [] => Ok(Command::Frequency(None)),
+            [value, unit] if word(Some(unit))?.eq_ignore_ascii_case("HZ") => {
+                let value = number(Some(value))?;
+                if value <= 0.0 {
+                    return Err(format!("invalid frequency {}", value));
+                }
+                Ok(Command::Frequency(Some(value)))
+            }
+            _ => Err("FREQUENCY takes a value in HZ".to_string()),
+        },
+        "TRST" => match only_arg()?.to_ascii_uppercase().as_str() {
+            "ON" => Ok(Command::Trst(Trst::On)),
+            "OFF" => Ok(Command::Trst(Trst::Off)),
+            "Z" => Ok(Command::Trst(Trst::Z)),
+            "ABSENT" => Ok(Command::Trst(Trst::Absent)),
+            mode => Err(format!("unknown TRST mode {}", mode)),
+        },
+        "PIO" | "PIOMAP" => Err(format!("{} is not supported", name)),
+        _ => Err(format!("unknown command {}", name)),
+    }
+}
+
+/// Parse the text of an SVF file.  Errors give the line number.
+pub fn parse(text: &str) -> Result<Vec<Statement>, String> {
+    tokenize(text)?.into_iter().map(|(line, tokens)| {
+        let command = parse_command(&tokens).map_err(|e| format!("line {}: {}", line, e))?;
+        Ok(Statement { line, command })
+    }).collect()
+}
+
+/// A scan whose TDO didn't match what the file expected
+#[derive(Clone,Debug,PartialEq)]
+pub struct Mismatch {
+    /// Line of the SIR or SDR statement
+    pub line: usize,
+    pub register: Register,
+    /// The whole scan including header and trailer, as are the other values
+    pub captured: BitVec,
+    pub expected: BitVec,
+    pub mask: BitVec,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = if self.register == Register::Instruction { "SIR" } else { "SDR" };
+        write!(f, "line {}: {} captured {}, expected {} with mask {}", self.line, name,
+               self.captured, self.expected, self.mask)
+    }
+}
+
+/// Result of playing an SVF file
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Report {
+    /// Number of statements executed
+    pub statements: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Executes SVF statements, keeping track of the values that carry over between them
+pub struct Player {
+    // The last scan of each kind, indexed by ScanKind.  TDO is only kept for headers and
+    // trailers, which apply to every scan after them.
+    scans: [Scan; 6],
+    run_state: JtagState,
+    end_state: JtagState,
+    frequency: Option<f64>,
+    stop_on_mismatch: bool,
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Player {
+    pub fn new() -> Self {
+        Self {
+            scans: Default::default(),
+            run_state: JtagState::Idle,
+            end_state: JtagState::Idle,
+            frequency: None,
+            stop_on_mismatch: true,
+        }
+    }
+
+    /// Whether to stop at the first TDO mismatch, which is the default, or carry on to the end
+    pub fn set_stop_on_mismatch(&mut self, stop: bool) {
+        self.stop_on_mismatch = stop;
+    }
+
+    /// Execute `statements` in order.  ENDIR and ENDDR set the end states of `sm`, see
+    /// `JtagSM::set_end_state`.  Returns an error, with the line number, if a statement can't be
+    /// executed; TDO mismatches are in the report instead.
+    pub fn play<T, U>(&mut self, sm: &mut JtagSM<T>, statements: &[Statement]) -> Result<Report, String>
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        let mut report = Report::default();
+        for statement in statements {
+            let mismatch = self.execute(sm, statement)
+                .map_err(|e| format!("line {}: {}", statement.line, e))?;
+            report.statements += 1;
+            if let Some(mismatch) = mismatch {
+                report.mismatches.push(mismatch);
+                if self.stop_on_mismatch {
+                    break;
+                }
+            }
+        }
+        sm.cable.flush();
+        Ok(report)
+    }
+
+    fn execute<T, U>(&mut self, sm: &mut JtagSM<T>, statement: &Statement) -> Result<Option<Mismatch>, String>
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        match &statement.command {
+            Command::Scan(kind, scan) => {
+                self.carry_over(*kind, scan)?;
+                if *kind == ScanKind::Sir || *kind == ScanKind::Sdr {
+                    return Ok(self.shift(sm, *kind, scan.tdo.as_ref(), statement.line));
+                }
+            }
+            Command::EndIr(state) => sm.set_end_state(Register::Instruction, *state),
+            Command::EndDr(state) => sm.set_end_state(Register::Data, *state),
+            Command::State(states) => {
+                if states.len() == 1 {
+                    self.go_to(sm, states[0]);
+                } else {
+                    for state in states {
+                        if sm.state().path_to(*state).len() != 1 {
+                            return Err(format!("{} is not one clock from {}", state_name(*state),
+                                               state_name(sm.state())));
+                        }
+                        sm.change_mode(*state);
+                    }
+                }
+            }
+            Command::RunTest(runtest) => self.run_test(sm, runtest),
+            Command::Frequency(frequency) => self.frequency = *frequency,
+            Command::Trst(Trst::On) => {
+                if !sm.trst() {
+                    sm.mode_reset();
+                }
+            }
+            Command::Trst(_) => {}
+        }
+        Ok(None)
+    }
+
+    // Update the values held for `kind` with `scan`
+    fn carry_over(&mut self, kind: ScanKind, scan: &Scan) -> Result<(), String> {
+        let last = &mut self.scans[kind.index()];
+        let same = scan.len == last.len;
+        let tdi = match &scan.tdi {
+            Some(tdi) => tdi.clone(),
+            None if same => last.tdi.clone().unwrap_or_else(|| BitVec::zeros(scan.len)),
+            None if scan.len == 0 => BitVec::new(),
+            None => return Err(format!("TDI must be given when the {:?} length changes", kind)),
+        };
+        let keep = |given: &Option<BitVec>, last: &Option<BitVec>| match (given, last) {
+            (Some(value), _) => value.clone(),
+            (None, Some(value)) if same => value.clone(),
+            (None, _) => BitVec::ones(scan.len),
+        };
+        let keep_tdo = !matches!(kind, ScanKind::Sir | ScanKind::Sdr);
+        *last = Scan {
+            len: scan.len,
+            tdi: Some(tdi),
+            tdo: if keep_tdo { scan.tdo.clone() } else { None },
+            mask: Some(keep(&scan.mask, &last.mask)),
+            smask: Some(keep(&scan.smask, &last.smask)),
+        };
+        Ok(())
+    }
+
+    // Shift the header, the SIR or SDR data and the trailer, comparing TDO if `tdo` is given
+    fn shift<T, U>(&self, sm: &mut JtagSM<T>, kind: ScanKind, tdo: Option<&BitVec>, line: usize) -> Option<Mismatch>
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        let (header, trailer) = if kind == ScanKind::Sir {
+            (ScanKind::Hir, ScanKind::Tir)
+        } else {
+            (ScanKind::Hdr, ScanKind::Tdr)
+        };
+        let parts = [
+            (&self.scans[header.index()], self.scans[header.index()].tdo.as_ref()),
+            (&self.scans[kind.index()], tdo),
+            (&self.scans[trailer.index()], self.scans[trailer.index()].tdo.as_ref()),
+        ];
+        let mut tdi = BitVec::new();
+        for (part, _) in parts {
+            if let Some(part) = &part.tdi {
+                tdi.append(part);
+            }
+        }
+        if tdi.is_empty() {
+            return None;
+        }
+
+        // SVF scans always go through Capture, but JtagSM goes straight from a pause state back to
+        // the shift state
+        if matches!(sm.state(), JtagState::PauseDR | JtagState::PauseIR) {
+            sm.change_mode(JtagState::SelectDR);
+        }
+        let reg = kind.register();
+        let end = sm.end_state(reg);
+        if parts.iter().all(|(_, tdo)| tdo.is_none()) {
+            sm.write_reg_end(reg, &tdi, end);
+            return None;
+        }
+        let captured = sm.read_write_reg_end(reg, &tdi, end);
+
+        // Parts without TDO aren't compared
+        let mut expected = BitVec::new();
+        let mut mask = BitVec::new();
+        for (part, tdo) in parts {
+            match tdo {
+                Some(tdo) => {
+                    expected.append(tdo);
+                    mask.append(part.mask.as_ref().unwrap());
+                }
+                None => {
+                    expected.append(&BitVec::zeros(part.len));
+                    mask.append(&BitVec::zeros(part.len));
+                }
+            }
+        }
+        if captured.masked_eq(&expected, &mask) {
+            return None;
+        }
+        Some(Mismatch {
+            line,
+            register: reg,
+            captured,
+            expected,
+            mask,
+        })
+    }
+
+    fn run_test<T, U>(&mut self, sm: &mut JtagSM<T>, runtest: &RunTest)
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        if let Some(state) = runtest.run_state {
+            self.run_state = state;
+            self.end_state = state;
+        }
+        if let Some(state) = runtest.end_state {
+            self.end_state = state;
+        }
+
+        // Wait for as long as the cycles would have taken at the file's frequency, if that is
+        // longer than they take at the cable's
+        let cycles = runtest.cycles.unwrap_or(0);
+        let mut min_time = runtest.min_time.unwrap_or(0.0);
+        if let Some(frequency) = self.frequency {
+            min_time = min_time.max(cycles as f64 / frequency);
+        }
+        self.go_to(sm, self.run_state);
+        wait(sm, self.run_state, cycles, min_time);
+        self.go_to(sm, self.end_state);
+    }
+
+    // Going to Reset always clocks TMS high, since the file may be relying on it to get the chain
+    // into a known state
+    fn go_to<T, U>(&self, sm: &mut JtagSM<T>, state: JtagState)
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        if state == JtagState::Reset {
+            sm.mode_reset();
+        } else {
+            sm.change_mode(state);
+        }
+    }
+}
+
+/// Clock TCK `cycles` times in the stable state `state`, then stay there for whatever is left of
+/// `min_time` seconds.  Cables that don't know their frequency wait for all of `min_time`.
+pub(crate) fn wait<T, U>(sm: &mut JtagSM<T>, state: JtagState, cycles: u64, min_time: f64)
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    sm.wait_in(state, cycles as usize);
+    let took = match sm.cable.frequency() {
+        Some(frequency) => cycles as f64 / frequency as f64,
+        None => 0.0,
+    };
+    if min_time > took {
+        sm.wait_in_for(state, Duration::from_secs_f64(min_time - took));
+    }
+}
+
+/// Parse and play the text of an SVF file with a new `Player`
+pub fn play<T, U>(sm: &mut JtagSM<T>, text: &str) -> Result<Report, String>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    Player::new().play(sm, &parse(text)?)
+}
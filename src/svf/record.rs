@@ -0,0 +1,310 @@
+//! Recording what a cable shifts as an SVF file, so that a sequence developed with `Taps` on the
+//! bench can be handed to anything that plays SVF.  `Recorder` wraps a cable and follows every
+//! TCK through the TAP state machine, turning the clocks into SIR and SDR scans, RUNTEST waits and
+//! resets.  Scans that are read record what TDO actually returned as the expected value.
+//!
+//! A scan split over several calls by stopping in Pause-DR or Pause-IR is recorded as one SIR or
+//! SDR, since SVF can't express going back to the shift state without a capture.  Clocks spent in
+//! the pause state in between are lost.  Queued reads are done straight away, so batching on the
+//! wrapped cable is lost too.
+use std::collections::VecDeque;
+use std::io::Write;
+use std::time::Duration;
+
+use crate::bits::BitVec;
+use crate::cable::Cable;
+use crate::statemachine::{JtagState, Register, TapModel};
+use super::state_name;
+
+// A scan between Capture and Update
+struct Scan {
+    reg: Register,
+    tdi: BitVec,
+    tdo: BitVec,
+    // Which bits of `tdo` were read
+    known: BitVec,
+}
+
+pub struct Recorder<T, W> {
+    cable: T,
+    out: W,
+    error: Option<String>,
+    // Used until the TMS values so far fix the state
+    model: TapModel,
+    state: Option<JtagState>,
+    scan: Option<Scan>,
+    // Clocks spent in Run-Test/Idle that haven't been written yet
+    idle: u64,
+    end_ir: JtagState,
+    end_dr: JtagState,
+    record_tdo: bool,
+    next_mask: Option<BitVec>,
+    queued: VecDeque<BitVec>,
+}
+
+impl<T, U, W> Recorder<T, W>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized,
+          W: Write
+{
+    /// Wrap `cable`, writing SVF to `out`
+    pub fn new(cable: T, out: W) -> Self {
+        let mut recorder = Self {
+            cable,
+            out,
+            error: None,
+            model: TapModel::new(),
+            state: None,
+            scan: None,
+            idle: 0,
+            end_ir: JtagState::Idle,
+            end_dr: JtagState::Idle,
+            record_tdo: true,
+            next_mask: None,
+            queued: VecDeque::new(),
+        };
+        recorder.emit("! Recorded with jtag_taps::svf::record".to_string());
+        if let Some(frequency) = recorder.cable.frequency() {
+            recorder.emit(format!("FREQUENCY {:E} HZ;", frequency as f64));
+        }
+        recorder
+    }
+
+    /// Whether to write what TDO returned as the expected value of each scan that is read, which
+    /// is the default
+    pub fn set_record_tdo(&mut self, record: bool) {
+        self.record_tdo = record;
+    }
+
+    /// Only compare the bits set in `mask` in the next scan to be written.  Panics then if the
+    /// scan isn't the same length as `mask`.
+    pub fn mask_next_scan(&mut self, mask: BitVec) {
+        self.next_mask = Some(mask);
+    }
+
+    /// The wrapped cable
+    pub fn cable(&mut self) -> &mut U {
+        &mut self.cable
+    }
+
+    pub fn writer(&self) -> &W {
+        &self.out
+    }
+
+    /// Write out any time spent in Run-Test/Idle so far and flush the writer.  Returns the first
+    /// error from writing, if there was one.
+    pub fn flush_svf(&mut self) -> Result<(), String> {
+        self.flush_idle();
+        if let Err(e) = self.out.flush() {
+            self.error.get_or_insert(e.to_string());
+        }
+        match &self.error {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// Write out a scan left in Pause-DR or Pause-IR, flush, and return the writer
+    pub fn finish(mut self) -> Result<W, String> {
+        if let Some(state @ (JtagState::PauseDR | JtagState::PauseIR)) = self.state {
+            self.finish_scan(state);
+        }
+        self.flush_svf()?;
+        Ok(self.out)
+    }
+
+    fn emit(&mut self, line: String) {
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.out, "{}", line) {
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+
+    fn flush_idle(&mut self) {
+        if self.idle > 0 {
+            self.emit(format!("RUNTEST IDLE {} TCK ENDSTATE IDLE;", self.idle));
+            self.idle = 0;
+        }
+    }
+
+    // Follow one TCK.  `tdo` is what was read from TDO, if anything was.
+    fn clock(&mut self, tms: bool, tdi: bool, tdo: Option<bool>) {
+        let prev = match self.state {
+            Some(state) => state,
+            None => {
+                self.model.clock(tms);
+                self.state = self.model.state();
+                if self.state == Some(JtagState::Reset) {
+                    self.emit("STATE RESET;".to_string());
+                }
+                return;
+            }
+        };
+        let next = prev.next(tms);
+
+        if matches!(prev, JtagState::ShiftDR | JtagState::ShiftIR) {
+            if let Some(scan) = &mut self.scan {
+                scan.tdi.push(tdi);
+                scan.tdo.push(tdo.unwrap_or(false));
+                scan.known.push(tdo.is_some());
+            }
+        }
+
+        match (prev, next) {
+            (JtagState::Idle, JtagState::Idle) => self.idle += 1,
+            (JtagState::Reset, JtagState::Reset) => {}
+            (_, JtagState::Reset) => {
+                self.flush_idle();
+                self.scan = None;
+                self.emit("STATE RESET;".to_string());
+            }
+            (_, JtagState::CaptureDR | JtagState::CaptureIR) => {
+                self.flush_idle();
+                self.scan = Some(Scan {
+                    reg: if next == JtagState::CaptureDR { Register::Data } else { Register::Instruction },
+                    tdi: BitVec::new(),
+                    tdo: BitVec::new(),
+                    known: BitVec::new(),
+                });
+            }
+            (JtagState::Exit1DR | JtagState::Exit1IR, JtagState::UpdateDR | JtagState::UpdateIR) => {
+                self.finish_scan(JtagState::Idle);
+            }
+            (JtagState::Exit2DR, JtagState::UpdateDR) => self.finish_scan(JtagState::PauseDR),
+            (JtagState::Exit2IR, JtagState::UpdateIR) => self.finish_scan(JtagState::PauseIR),
+            _ => {}
+        }
+        self.state = Some(next);
+    }
+
+    // Write the scan that has just reached Update, as ending in `end`
+    fn finish_scan(&mut self, end: JtagState) {
+        let scan = match self.scan.take() {
+            Some(scan) if !scan.tdi.is_empty() => scan,
+            _ => return,
+        };
+        let (name, current) = match scan.reg {
+            Register::Data => ("DR", &mut self.end_dr),
+            Register::Instruction => ("IR", &mut self.end_ir),
+        };
+        if *current != end {
+            *current = end;
+            self.emit(format!("END{} {};", name, state_name(end)));
+        }
+
+        let mut line = format!("S{} {} TDI ({})", name, scan.tdi.len(), scan.tdi);
+        let mut mask = scan.known;
+        if let Some(user) = self.next_mask.take() {
+            assert_eq!(user.len(), mask.len(), "scan mask is the wrong length");
+            mask = mask.iter().zip(user.iter()).map(|(a, b)| a && b).collect();
+        }
+        if self.record_tdo && mask.count_ones() > 0 {
+            line += &format!(" TDO ({}) MASK ({})", scan.tdo, mask);
+        }
+        line.push(';');
+        self.emit(line);
+    }
+
+    fn clock_data(&mut self, data: &BitVec, exit: &[usize], tdo: Option<&BitVec>) {
+        for i in 0..data.len() {
+            let tms = i == data.len() - 1 && exit.first().is_some_and(|tms| *tms != 0);
+            self.clock(tms, data.get(i), tdo.map(|tdo| tdo.get(i)));
+        }
+        for tms in exit.iter().skip(1) {
+            self.clock(*tms != 0, true, None);
+        }
+    }
+}
+
+impl<T, U, W> Cable for Recorder<T, W>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized,
+          W: Write
+{
+    fn change_mode(&mut self, tms: &[usize], tdo: bool) {
+        self.cable.change_mode(tms, tdo);
+        for x in tms {
+            self.clock(*x != 0, tdo, None);
+        }
+    }
+
+    fn read_data(&mut self, bits: usize) -> BitVec {
+        let data = self.cable.read_data(bits);
+        for i in 0..bits {
+            self.clock(false, true, Some(data.get(i)));
+        }
+        data
+    }
+
+    fn write_data(&mut self, data: &BitVec, exit: &[usize]) {
+        self.cable.write_data(data, exit);
+        self.clock_data(data, exit, None);
+    }
+
+    fn read_write_data(&mut self, data: &BitVec, exit: &[usize]) -> BitVec {
+        let out = self.cable.read_write_data(data, exit);
+        self.clock_data(data, exit, Some(&out));
+        out
+    }
+
+    fn flush(&mut self) {
+        self.cable.flush();
+    }
+
+    fn queue_read(&mut self, bits: usize) -> bool {
+        let data = self.read_data(bits);
+        self.queued.push_back(data);
+        true
+    }
+
+    fn queue_read_write(&mut self, data: &BitVec, exit: &[usize]) -> bool {
+        let out = self.read_write_data(data, exit);
+        self.queued.push_back(out);
+        true
+    }
+
+    fn finish_read(&mut self, bits: usize) -> BitVec {
+        let data = self.queued.pop_front().expect("no queued read to finish");
+        assert_eq!(data.len(), bits);
+        data
+    }
+
+    fn shift_vectors(&mut self, tms: &BitVec, tdi: &BitVec) -> BitVec {
+        let tdo = self.cable.shift_vectors(tms, tdi);
+        for i in 0..tms.len() {
+            self.clock(tms.get(i), tdi.get(i), Some(tdo.get(i)));
+        }
+        tdo
+    }
+
+    fn trst(&mut self) -> bool {
+        if !self.cable.trst() {
+            return false;
+        }
+        self.flush_idle();
+        self.scan = None;
+        self.state = Some(JtagState::Reset);
+        self.emit("TRST ON;".to_string());
+        self.emit("TRST OFF;".to_string());
+        true
+    }
+
+    fn frequency(&self) -> Option<u32> {
+        self.cable.frequency()
+    }
+
+    fn delay(&mut self, duration: Duration) {
+        self.cable.delay(duration);
+        if let Some(state @ (JtagState::Reset | JtagState::Idle |
+                             JtagState::PauseDR | JtagState::PauseIR)) = self.state {
+            self.flush_idle();
+            self.emit(format!("RUNTEST {} {:E} SEC ENDSTATE {};", state_name(state),
+                              duration.as_secs_f64(), state_name(state)));
+        }
+    }
+
+    fn tms_trace(&self) -> Option<TapModel> {
+        self.cable.tms_trace()
+    }
+}
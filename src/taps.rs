@@ -2,32 +2,375 @@
 //! client to interact with one selected TAP as if it were the only TAP in the chain, so that the
 //! client doesn't have to deal with putting the other TAPs into bypass and shifting data through
 //! the bypass registers.
+pub mod batch;
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+use crate::bits::BitVec;
+use crate::bsdl::Bsdl;
+use crate::idcode::{Database, IdCode};
+use crate::instruction::InstructionSet;
 use crate::statemachine::{JtagSM, JtagState, Register};
 use crate::cable::Cable;
 
-fn add_ones_to_end(input: &[u8], this_len: usize, shift: usize) -> Vec<u8> {
-    let bytes = shift / 8;
-    let mut output = input.to_vec();
+/// What is known about one TAP in the scan chain
+#[derive(Clone,Debug,PartialEq)]
+pub struct TapInfo {
+    /// Index of the TAP in the chain.  TAP 0 is the one closest to TDI.
+    pub position: usize,
+    /// Length of the instruction register in bits
+    pub irlen: usize,
+    /// The IDCODE read after reset, or None if the TAP selected BYPASS at reset instead (or wasn't
+    /// detected)
+    pub idcode: Option<u32>,
+    /// The value captured by the instruction register in Capture-IR, if the TAP was detected
+    pub ir_capture: Option<BitVec>,
+    /// Name to select the TAP by.  Detected TAPs are named after their device if it is in the
+    /// database.
+    pub name: Option<String>,
+    /// The instructions that can be loaded by name
+    pub instructions: InstructionSet,
+}
+
+impl std::fmt::Display for TapInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tap {}", self.position)?;
+        if let Some(name) = &self.name {
+            write!(f, " ({})", name)?;
+        }
+        write!(f, ": irlen {}", self.irlen)?;
+        write!(f, ", {}", describe_idcode(self.idcode))?;
+        if let Some(capture) = &self.ir_capture {
+            write!(f, ", ir capture {:b}", capture)?;
+        }
+        Ok(())
+    }
+}
+
+/// What `Taps::verify_chain` expects to find at one position in the chain
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ExpectedId {
+    /// An IDCODE equal to `value` in the bits set in `mask`
+    Masked { value: u32, mask: u32 },
+    /// A TAP without an IDCODE, which selects BYPASS at reset
+    Bypass,
+    /// Any TAP
+    Any,
+}
+
+impl ExpectedId {
+    /// An IDCODE that has to match exactly
+    pub fn exact(value: u32) -> Self {
+        ExpectedId::Masked { value, mask: 0xffff_ffff }
+    }
+
+    /// An IDCODE of any version, which is the top 4 bits
+    pub fn any_version(value: u32) -> Self {
+        ExpectedId::Masked { value, mask: 0x0fff_ffff }
+    }
+
+    /// Whether a TAP with `idcode`, or None for one that selected BYPASS at reset, is as expected
+    pub fn matches(&self, idcode: Option<u32>) -> bool {
+        match (self, idcode) {
+            (ExpectedId::Masked { value, mask }, Some(idcode)) => (idcode ^ value) & mask == 0,
+            (ExpectedId::Masked { .. }, None) => false,
+            (ExpectedId::Bypass, idcode) => idcode.is_none(),
+            (ExpectedId::Any, _) => true,
+        }
+    }
+}
+
+impl From<u32> for ExpectedId {
+    fn from(value: u32) -> Self {
+        ExpectedId::exact(value)
+    }
+}
+
+impl std::fmt::Display for ExpectedId {
+    /// IDCODEs are shown in hex with an x for each digit that is masked off, or with the mask
+    /// after a slash if it doesn't line up with the digits
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ExpectedId::Masked { value, mask } => {
+                let nibbles = (0..8).rev().map(|i| ((value >> (i * 4)) & 0xf, (mask >> (i * 4)) & 0xf));
+                if nibbles.clone().all(|(_, m)| m == 0 || m == 0xf) {
+                    for (v, m) in nibbles {
+                        if m == 0 {
+                            write!(f, "x")?;
+                        } else {
+                            write!(f, "{:x}", v)?;
+                        }
+                    }
+                    Ok(())
+                } else {
+                    write!(f, "{:08x}/{:08x}", value & mask, mask)
+                }
+            }
+            ExpectedId::Bypass => write!(f, "no idcode"),
+            ExpectedId::Any => write!(f, "any device"),
+        }
+    }
+}
+
+/// A difference found by `Taps::verify_chain` between the chain and what was expected
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum ChainMismatch {
+    /// The TAP at `position` doesn't match.  `found` is None if it has no IDCODE.
+    Wrong { position: usize, expected: ExpectedId, found: Option<u32> },
+    /// The chain ends before `position`
+    Missing { position: usize, expected: ExpectedId },
+    /// There is a TAP at `position` that wasn't expected
+    Extra { position: usize, found: Option<u32> },
+}
+
+fn describe_idcode(idcode: Option<u32>) -> String {
+    match idcode {
+        Some(idcode) => {
+            let idcode = IdCode(idcode);
+            format!("idcode {} ({})", idcode, idcode.manufacturer().unwrap_or("unknown"))
+        }
+        None => "no idcode".to_string(),
+    }
+}
+
+impl std::fmt::Display for ChainMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainMismatch::Wrong { position, expected, found } => {
+                write!(f, "tap {}: expected {}, found {}", position, expected, describe_idcode(*found))
+            }
+            ChainMismatch::Missing { position, expected } => {
+                write!(f, "tap {}: expected {}, but the chain ends", position, expected)
+            }
+            ChainMismatch::Extra { position, found } => {
+                write!(f, "tap {}: unexpected device with {}", position, describe_idcode(*found))
+            }
+        }
+    }
+}
+
+/// Result of `Taps::verify_chain`
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ChainReport {
+    /// The IDCODE of each TAP found, starting from TDI, or None for TAPs that selected BYPASS at
+    /// reset
+    pub found: Vec<Option<u32>>,
+    pub mismatches: Vec<ChainMismatch>,
+}
+
+impl ChainReport {
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// A scan of one TAP's register that captured something other than what was expected
+#[derive(Clone,Debug,PartialEq)]
+pub struct ScanMismatch {
+    pub tap: usize,
+    pub register: Register,
+    pub captured: BitVec,
+    pub expected: BitVec,
+    pub mask: BitVec,
+}
 
-    let top_bits = (1 << (this_len % 8)) - 1;
-    let end = output.len()-1;
-    output[end] |= !top_bits;
+impl ScanMismatch {
+    /// The positions of the bits that differ, starting from bit 0
+    pub fn bits(&self) -> Vec<usize> {
+        self.captured.masked_diff(&self.expected, &self.mask)
+    }
+}
+
+impl std::fmt::Display for ScanMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let register = match self.register {
+            Register::Instruction => "IR",
+            Register::Data => "DR",
+        };
+        let bits: Vec<String> = self.bits().iter().map(|bit| bit.to_string()).collect();
+        write!(f, "tap {}: {} captured {}, expected {} with mask {} (bits {} differ)", self.tap,
+               register, self.captured, self.expected, self.mask, bits.join(", "))
+    }
+}
 
-    let mut pad = vec![0xff; bytes];
-    output.append(&mut pad);
-    output
+// What a queued data register read is expected to capture
+struct Expectation {
+    tap: usize,
+    expected: BitVec,
+    mask: BitVec,
 }
 
-struct Tap {
-    irlen: usize,
+// A queued data register read.  The padding is recorded when the read is queued, since the
+// instructions may have changed by the time it is finished.
+struct QueuedRead {
+    pad_bits: usize,
+    discard_bits: usize,
+    expect: Option<Expectation>,
+}
+
+// The instruction registers of the whole chain as one scan.  The TAP closest to TDO shifts in
+// first.
+fn ir_scan_data(irs: &[BitVec]) -> BitVec {
+    let mut data = BitVec::new();
+    for ir in irs.iter().rev() {
+        data.append(ir);
+    }
+    data
+}
+
+// Length of the data register that instruction `ir` selects in `tap`, if known.  BYPASS is always
+// known.
+fn dr_len(tap: &TapInfo, ir: &BitVec) -> Option<usize> {
+    if ir.count_ones() == ir.len() {
+        return Some(1);
+    }
+    tap.instructions.iter().find(|i| i.opcode == *ir).and_then(|i| i.dr_len)
+}
+
+fn check_capture(tap: usize, register: Register, captured: BitVec, expected: &BitVec, mask: &BitVec)
+    -> Result<BitVec, ScanMismatch>
+{
+    assert_eq!(expected.len(), captured.len());
+    assert_eq!(mask.len(), captured.len());
+    if captured.masked_eq(expected, mask) {
+        return Ok(captured);
+    }
+    Err(ScanMismatch {
+        tap,
+        register,
+        captured,
+        expected: expected.clone(),
+        mask: mask.clone(),
+    })
+}
+
+/// How `Taps::select_tap` treats the TAPs that aren't being selected
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub enum SelectMode {
+    /// Load BYPASS into the other TAPs
+    #[default]
+    Bypass,
+    /// Leave the other TAPs with the instruction set by `Taps::set_parked_ir`, or failing that the
+    /// one they already hold if `Taps` knows it, or BYPASS.  Their data registers are shifted
+    /// through (with ones) whenever the selected TAP's is, so they need instructions whose data
+    /// registers don't mind.  A TAP whose instruction doesn't have a data register length in its
+    /// instruction table is put into BYPASS instead.
+    Preserve,
+    /// Reset the chain with TMS before loading the instruction, then load BYPASS into the other
+    /// TAPs.  This aborts anything the other TAPs are doing.
+    Reset,
+}
+
+/// Largest total instruction register length, and largest number of TAPs, that `Taps::detect`
+/// will look for
+pub const DETECT_MAX_BITS: usize = 1024;
+
+/// Number of bits the streaming data register methods shift at a time, unless changed with
+/// `Taps::set_stream_chunk_bits`
+pub const STREAM_CHUNK_BITS: usize = 32768;
+
+// Collects TDO bits and writes them out a byte at a time, least significant bit first
+struct TdoWriter<W> {
+    out: W,
+    pending: BitVec,
+}
+
+impl<W: Write> TdoWriter<W> {
+    fn push(&mut self, bits: &BitVec) -> Result<(), String> {
+        self.pending.append(bits);
+        let whole = self.pending.len() / 8 * 8;
+        self.out.write_all(self.pending.slice(0..whole).as_bytes()).map_err(|e| e.to_string())?;
+        self.pending = self.pending.slice(whole..self.pending.len());
+        Ok(())
+    }
+
+    // Write out the last partial byte, with the unused bits zero
+    fn finish(mut self) -> Result<(), String> {
+        if !self.pending.is_empty() {
+            self.out.write_all(self.pending.as_bytes()).map_err(|e| e.to_string())?;
+        }
+        self.out.flush().map_err(|e| e.to_string())
+    }
+}
+
+// Result of shifting ones through the whole chain
+struct Flood {
+    // Length of the chain in bits
+    len: usize,
+    // Bits shifted out before the ones came back
+    captured: BitVec,
+}
+
+// Work out the IR length of each TAP from the IR capture values of the whole chain, in the order
+// they were shifted out.  Each capture value starts with a 1 followed by a 0, so TAPs can only
+// start at those points.  `known` has an entry for each TAP, holding its IR length if that is
+// already known from its IDCODE.  Returns an error unless there is exactly one way to split it.
+fn split_ir_capture(capture: &BitVec, known: &[Option<usize>]) -> Result<Vec<usize>, String> {
+    let len = capture.len();
+    let count = known.len();
+    let starts_tap = |i: usize| i + 1 < len && capture.get(i) && !capture.get(i + 1);
+
+    // ways[k][i] is the number of ways (capped at 2) for TAPs k.. to take up the bits from i to
+    // the end, with TAP k starting at i.  suffix[k][i] is the sum of ways[k][i..].
+    let mut ways = vec![vec![0_u8; len + 1]; count + 1];
+    let mut suffix = vec![vec![0_u8; len + 2]; count + 1];
+    ways[count][len] = 1;
+    // Past the last TAP only the end of the capture is a valid start, so its suffix sums are 1
+    // everywhere up to the end
+    suffix[count][..=len].fill(1);
+    for k in (0..count).rev() {
+        for i in (0..len).rev() {
+            if starts_tap(i) {
+                ways[k][i] = match known[k] {
+                    Some(irlen) if i + irlen <= len => ways[k + 1][i + irlen],
+                    Some(_) => 0,
+                    None => suffix[k + 1][i + 2],
+                };
+            }
+            suffix[k][i] = std::cmp::min(2, ways[k][i] + suffix[k][i + 1]);
+        }
+    }
+
+    match ways[0][0] {
+        0 => Err(format!("IR capture {:b} can't be split between {} TAPs", capture, count)),
+        1 => {
+            let mut lens = vec![];
+            let mut i = 0;
+            for k in 0..count {
+                let next = match known[k] {
+                    Some(irlen) => i + irlen,
+                    None => (i + 2..=len).find(|j| ways[k + 1][*j] != 0).unwrap(),
+                };
+                lens.push(next - i);
+                i = next;
+            }
+            Ok(lens)
+        }
+        _ => Err(format!("IR capture {:b} can be split between {} TAPs in more than one way",
+                         capture, count)),
+    }
 }
 
 pub struct Taps<T> {
     pub sm: JtagSM<T>,
-    taps: Vec<Tap>,
+    taps: Vec<TapInfo>,
+    database: Database,
     active: usize,
-    dangling_read: bool,
-    queued_reads: usize
+    // The discard bits queued for a read that didn't fit in the cable's queue, if any
+    dangling_read: usize,
+    queued_reads: usize,
+    select_mode: SelectMode,
+    // Instructions to leave TAPs with in SelectMode::Preserve
+    parked: Vec<Option<BitVec>>,
+    // The instructions last loaded into every TAP, and the IR generation of `sm` at the time if
+    // nothing has gone wrong since
+    irs: Vec<BitVec>,
+    ir_generation: Option<u64>,
+    stream_chunk: usize,
+    // One entry for each queued data register read, in order
+    reads: VecDeque<QueuedRead>,
+    mismatches: Vec<ScanMismatch>,
 }
 
 impl<T, U> Taps<T>
@@ -39,119 +382,581 @@ impl<T, U> Taps<T>
         Self {
             sm,
             taps: Vec::new(),
+            database: Database::builtin(),
             active: 0,
-            dangling_read: false,
+            dangling_read: 0,
             queued_reads: 0,
+            select_mode: SelectMode::default(),
+            parked: Vec::new(),
+            irs: Vec::new(),
+            ir_generation: None,
+            stream_chunk: STREAM_CHUNK_BITS,
+            reads: VecDeque::new(),
+            mismatches: Vec::new(),
         }
     }
 
     /// Add a TAP to the scan chain with the given instruction register length
     pub fn add_tap(&mut self, irlen: usize) {
-        let tap = Tap {
-            irlen
+        let tap = TapInfo {
+            position: self.taps.len(),
+            irlen,
+            idcode: None,
+            ir_capture: None,
+            name: None,
+            instructions: InstructionSet::new(irlen),
         };
         self.taps.push(tap);
+        self.parked.push(None);
+        self.irs.clear();
     }
 
-    /// Attempt to autodetect the number of TAPs on the scan chain and the instruction register
-    /// length for each.
-    pub fn detect(&mut self) {
+    /// Give TAP number `tap` a name to select it by
+    pub fn set_name(&mut self, tap: usize, name: &str) {
+        self.taps[tap].name = Some(name.to_string());
+    }
+
+    /// Replace the instruction table of TAP number `tap`
+    pub fn set_instructions(&mut self, tap: usize, instructions: InstructionSet) -> Result<(), String> {
+        if instructions.irlen() != self.taps[tap].irlen {
+            return Err(format!("TAP {} has a {} bit IR, but the instructions are {} bits", tap,
+                               self.taps[tap].irlen, instructions.irlen()));
+        }
+        self.taps[tap].instructions = instructions;
+        Ok(())
+    }
+
+    /// Take the instructions of TAP number `tap` from `bsdl`, and name it after the entity unless
+    /// it already has a name
+    pub fn load_bsdl(&mut self, tap: usize, bsdl: &Bsdl) -> Result<(), String> {
+        self.set_instructions(tap, InstructionSet::from_bsdl(bsdl))?;
+        if self.taps[tap].name.is_none() {
+            self.taps[tap].name = Some(bsdl.entity.clone());
+        }
+        Ok(())
+    }
+
+    /// Position of the TAP called `name`.  Fails if no TAP, or more than one, has that name.
+    pub fn find(&self, name: &str) -> Result<usize, String> {
+        let mut found = self.taps.iter().filter(|t| t.name.as_deref() == Some(name));
+        match (found.next(), found.next()) {
+            (Some(tap), None) => Ok(tap.position),
+            (Some(_), Some(_)) => Err(format!("more than one TAP is called {}", name)),
+            (None, _) => Err(format!("no TAP called {}", name)),
+        }
+    }
+
+    /// The TAPs in the scan chain, either added with `add_tap` or found by `detect`
+    pub fn taps(&self) -> &[TapInfo] {
+        &self.taps
+    }
+
+    /// The TAP chosen by the last call to `select_tap`
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    /// Replace the database of known devices that `detect` uses to help work out IR lengths.  The
+    /// default is `Database::builtin()`.
+    pub fn set_database(&mut self, database: Database) {
+        self.database = database;
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    /// Attempt to autodetect the number of TAPs on the scan chain, along with the instruction
+    /// register length, IR capture value and IDCODE of each.  The TAPs found replace any that were
+    /// added before, and are also available afterwards from `taps()`.  Gives up if the chain looks
+    /// longer than `DETECT_MAX_BITS`.
+    pub fn detect(&mut self) -> Result<Vec<TapInfo>, String> {
+        self.detect_within(DETECT_MAX_BITS)
+    }
+
+    /// Same as `detect`, but with the total instruction register length and the number of TAPs
+    /// each limited to `max_bits`.  No more than a few times `max_bits` are shifted before giving
+    /// up, so a broken chain gives an error rather than a hang.
+    pub fn detect_within(&mut self, max_bits: usize) -> Result<Vec<TapInfo>, String> {
         self.taps = Vec::new();
+        self.parked = Vec::new();
+        self.irs.clear();
+        let (capture, idcodes) = self.scan_idcodes(max_bits)?;
+
+        // Use the IR lengths of any devices in the database to help split up the IR capture
+        let known: Vec<_> = idcodes.iter().map(|idcode| {
+            idcode.and_then(|idcode| self.database.lookup(IdCode(idcode))).map(|device| device.irlen)
+        }).collect();
+        let irlens = split_ir_capture(&capture, &known)?;
+
+        // The TAP closest to TDO was read first
+        let mut start = 0;
+        let mut taps = vec![];
+        for (irlen, idcode) in irlens.into_iter().zip(idcodes) {
+            let device = idcode.and_then(|idcode| self.database.lookup(IdCode(idcode)));
+            taps.push(TapInfo {
+                position: 0,
+                irlen,
+                idcode,
+                ir_capture: Some(capture.slice(start..start+irlen)),
+                name: device.map(|d| d.name.clone()),
+                instructions: match device {
+                    Some(device) if device.irlen == irlen => InstructionSet::from_device(device),
+                    _ => InstructionSet::new(irlen),
+                },
+            });
+            start += irlen;
+        }
+        taps.reverse();
+        for (i, tap) in taps.iter_mut().enumerate() {
+            tap.position = i;
+        }
+
+        self.parked = vec![None; taps.len()];
+        self.irs.clear();
+        self.taps = taps;
+        Ok(self.taps.clone())
+    }
+
+    /// Read the IDCODE of every TAP on the chain and compare them with `expected`, which starts
+    /// from the TAP closest to TDI.  TAPs that select BYPASS at reset rather than IDCODE are
+    /// found too.  Unlike `detect`, this doesn't need to work out IR lengths, so it works on
+    /// chains that `detect` can't split up, and it leaves the TAPs added to `self` alone.  Returns
+    /// an error if the chain is broken.  Leaves the chain reset.
+    pub fn verify_chain(&mut self, expected: &[ExpectedId]) -> Result<ChainReport, String> {
+        let (_, mut found) = self.scan_idcodes(DETECT_MAX_BITS)?;
+        found.reverse();
         self.sm.mode_reset();
 
-        let mut count: i32 = -1;
-        let mut irlen = vec![];
-        loop {
-            let bit = self.sm.read_reg(Register::Instruction, 1);
-            if bit[0] != 0 {
-                if count > 0 {
-                    println!("found IR len {}", count+1);
-                    irlen.push(count+1)
+        let mut mismatches = vec![];
+        for position in 0..std::cmp::max(found.len(), expected.len()) {
+            match (expected.get(position), found.get(position)) {
+                (Some(expected), Some(found)) if !expected.matches(*found) => {
+                    mismatches.push(ChainMismatch::Wrong { position, expected: *expected, found: *found });
                 }
-                if count == 0 {
-                    break;
+                (Some(expected), None) => {
+                    mismatches.push(ChainMismatch::Missing { position, expected: *expected });
                 }
-                count = 0;
-            } else {
-                count += 1;
+                (None, Some(found)) => {
+                    mismatches.push(ChainMismatch::Extra { position, found: *found });
+                }
+                _ => {}
             }
         }
+        Ok(ChainReport { found, mismatches })
+    }
+
+    // Reset the chain and find the IR capture value of the whole chain and the IDCODE of each TAP,
+    // or None for TAPs that select BYPASS at reset, both in the order they are shifted out
+    fn scan_idcodes(&mut self, max_bits: usize) -> Result<(BitVec, Vec<Option<u32>>), String> {
+        self.sm.mode_reset();
+
+        // Shift the IR captures out, followed by zeros and then enough ones to put every TAP into
+        // BYPASS.  The total IR length is where the ones start coming back out.
+        let out = self.flood(Register::Instruction, max_bits)?;
+        let irlen_total = out.len;
+        let capture = out.captured;
+
+        // Every TAP is in BYPASS now, which is a single bit capturing 0
+        let out = self.flood(Register::Data, max_bits)?;
+        if out.captured.count_ones() != 0 {
+            return Err(format!("BYPASS registers captured {:b} rather than all zeros", out.captured));
+        }
+        let count = out.len;
+        if count == 0 {
+            return Err(format!("IR is {} bits long, but no BYPASS registers found", irlen_total));
+        }
 
+        // After reset, each TAP has selected either IDCODE, which is 32 bits starting with a 1, or
+        // BYPASS, which is a single 0.
         self.sm.mode_reset();
-        let mut ids = vec![];
-        for _ in 0..irlen.len() {
-            let bit = self.sm.read_reg(Register::Data, 1);
-            if bit[0] == 0 {
-                println!("invalid IDCODE of 0");
-                ids.push(0);
+        let ids = self.sm.read_reg(Register::Data, count * 32);
+        let mut idcodes = vec![];
+        let mut pos = 0;
+        for _ in 0..count {
+            if !ids.get(pos) {
+                idcodes.push(None);
+                pos += 1;
+            } else if pos + 32 <= ids.len() {
+                idcodes.push(Some(ids.slice(pos..pos+32).to_u64().unwrap() as u32));
+                pos += 32;
             } else {
-                let bits = self.sm.read_reg(Register::Data, 31);
-                let idcode = u32::from_le_bytes(bits.try_into().unwrap());
-                // Add back the one we read
-                ids.push((idcode << 1) | 1);
+                return Err(format!("IDCODE scan ran out of bits at device {} of {}", idcodes.len(), count));
             }
         }
+        // Past the last TAP are the ones shifted in from TDI
+        if ids.slice(pos..ids.len()).count_ones() != ids.len() - pos {
+            return Err(format!("IDCODE scan found more than {} devices", count));
+        }
 
-        irlen.reverse();
-        ids.reverse();
+        Ok((capture, idcodes))
+    }
 
-        for i in 0..irlen.len() {
-            println!("Adding tap {} idcode {:x}", i, ids[i]);
-            self.add_tap(irlen[i] as usize);
+    // Shift `max_bits` zeros followed by twice as many ones through `reg`, and work out how long
+    // the chain is from where the ones come back out.  The extra ones make it out of a chain that
+    // is too long, so that it can be told apart from TDO stuck low.  This leaves every TAP with
+    // ones in `reg`, and the chain in Run-Test/Idle.
+    fn flood(&mut self, reg: Register, max_bits: usize) -> Result<Flood, String> {
+        let pattern = BitVec::zeros(max_bits).concat(&BitVec::ones(2 * max_bits));
+        let out = self.sm.read_write_reg_end(reg, &pattern, JtagState::Idle);
+
+        if out.count_ones() == out.len() {
+            return Err("TDO is stuck high".to_string());
+        }
+        // The ones come back out as an unbroken run at the end
+        let first_one = (0..out.len()).rev().find(|i| !out.get(*i)).unwrap() + 1;
+        if first_one == out.len() {
+            if out.count_ones() == 0 {
+                return Err("TDO is stuck low or not connected".to_string());
+            }
+            return Err(format!("{:?} chain is longer than {} bits", reg, max_bits));
+        }
+        let len = first_one.checked_sub(max_bits)
+            .ok_or_else(|| format!("{:?} chain returned ones before the zeros shifted in", reg))?;
+        if len >= max_bits {
+            return Err(format!("{:?} chain is longer than {} bits", reg, max_bits));
         }
+
+        // The zeros we shifted in should all have come back out
+        if out.slice(len..first_one).count_ones() != 0 {
+            return Err(format!("{:?} chain returned {:b} rather than the zeros shifted in", reg,
+                               out.slice(len..first_one)));
+        }
+        Ok(Flood {
+            len,
+            captured: out.slice(0..len),
+        })
     }
 
     /// Select which TAP in the scan chain to operate upon.  `ir` will be shifted into its
-    /// instruction register, and the other TAPs put into bypass.
-    pub fn select_tap(&mut self, tap: usize, ir: &[u8]) {
+    /// instruction register, and the other TAPs dealt with as set by `set_select_mode`, which
+    /// by default puts them into BYPASS.  Nothing is shifted if the TAPs are known to hold
+    /// those instructions already, see `current_ir`.
+    pub fn select_tap(&mut self, tap: usize, ir: &BitVec) {
+        self.load_ir(tap, ir, true, true);
+    }
+
+    /// Shift `ir` into the instruction register of the TAP selected by `select_tap`, unless it
+    /// already holds it.  The other TAPs are dealt with as for `select_tap`, except that the
+    /// chain is never reset.
+    pub fn write_ir(&mut self, ir: &BitVec) {
+        self.load_ir(self.active, ir, false, true);
+    }
+
+    // Select `tap` and load `ir` into it, first resetting the chain in `SelectMode::Reset` if
+    // `reset` is set.  What the instruction registers capture is only checked if `check` is set,
+    // since `batch::Batch` can't read while it has reads queued on the cable.
+    pub(crate) fn load_ir(&mut self, tap: usize, ir: &BitVec, reset: bool, check: bool) {
         assert!(tap < self.taps.len());
-        self.sm.mode_reset();
+        assert_eq!(ir.len(), self.taps[tap].irlen);
+        let irs = self.select_irs(tap, ir);
         self.active = tap;
-        self.write_ir(ir);
+        if self.cached_irs() != Some(&irs[..]) {
+            if reset && self.select_mode == SelectMode::Reset {
+                self.sm.mode_reset();
+            }
+            if check {
+                self.scan_ir(irs);
+            } else {
+                self.write_irs(irs);
+            }
+        }
+    }
+
+    /// As `select_tap`, but always scanning the instruction registers, and checking that TAP
+    /// `tap` captured `expected` in the bits set in `mask`.  Returns the captured instruction
+    /// register of the TAP.
+    pub fn select_tap_expect(&mut self, tap: usize, ir: &BitVec, expected: &BitVec, mask: &BitVec)
+        -> Result<BitVec, ScanMismatch>
+    {
+        assert!(tap < self.taps.len());
+        assert_eq!(ir.len(), self.taps[tap].irlen);
+        assert_eq!(self.queued_reads, 0);
+        let irs = self.select_irs(tap, ir);
+        self.active = tap;
+        if self.select_mode == SelectMode::Reset {
+            self.sm.mode_reset();
+        }
+        let captured = self.scan_ir(irs).unwrap();
+
+        // The TAP closest to TDO shifts out first
+        let start = self.taps[tap+1..].iter().map(|t| t.irlen).sum::<usize>();
+        let captured = captured.slice(start..start + ir.len());
+        check_capture(tap, Register::Instruction, captured, expected, mask)
     }
 
-    fn write_ones(&mut self, mut bits: usize) {
-        let bytes = bits / 8;
-        bits %= 8;
+    /// Reset the chain with TMS, which puts every TAP back to its reset instruction.  The data
+    /// register methods load the instructions last chosen again before they shift anything.
+    pub fn reset(&mut self) {
+        self.sm.mode_reset();
+    }
+
+    /// Choose how `select_tap` treats the TAPs that aren't selected
+    pub fn set_select_mode(&mut self, mode: SelectMode) {
+        self.select_mode = mode;
+    }
 
-        if bytes > 0 {
-            let buf = vec![0xff; bytes];
-            self.sm.write_reg(Register::Instruction, &buf, 8, false);
+    pub fn select_mode(&self) -> SelectMode {
+        self.select_mode
+    }
+
+    /// Set the instruction that TAP `tap` is left with while other TAPs are selected in
+    /// `SelectMode::Preserve`, or None to leave it with whatever it holds
+    pub fn set_parked_ir(&mut self, tap: usize, ir: Option<BitVec>) {
+        if let Some(ir) = &ir {
+            assert_eq!(ir.len(), self.taps[tap].irlen);
         }
-        if bits > 0 {
-            let buf = vec![(1 << bits) - 1];
-            self.sm.write_reg(Register::Instruction, &buf, bits as u8, false);
+        self.parked[tap] = ir;
+    }
+
+    // Shift `irs[i]` into the instruction register of TAP i, for every TAP at once, unless they
+    // already hold them
+    pub(crate) fn write_ir_all(&mut self, irs: &[BitVec]) {
+        assert_eq!(irs.len(), self.taps.len());
+        for (t, bits) in self.taps.iter().zip(irs) {
+            assert_eq!(bits.len(), t.irlen);
+        }
+        if self.cached_irs() != Some(irs) {
+            self.scan_ir(irs.to_vec());
         }
     }
 
-    /// Shift `ir` into the instruction register of the TAP selected by `select_tap`
-    pub fn write_ir(&mut self, ir: &[u8]) {
-        assert!(self.active < self.taps.len());
-        let this_irlen = self.taps[self.active].irlen;
-        assert_eq!(ir.len(), (this_irlen + 7) / 8);
+    /// The instruction that TAP `tap` holds, if it was loaded through `Taps` and nothing that
+    /// could have changed it has happened since: a reset, TRST, or an IR scan or `shift_vectors`
+    /// done directly through `sm`
+    pub fn current_ir(&self, tap: usize) -> Option<&BitVec> {
+        self.cached_irs().map(|irs| &irs[tap])
+    }
 
-        // Put downstream taps into BYPASS
-        let mut after_pad = 0;
-        for t in &self.taps[self.active+1..] {
-            after_pad += t.irlen;
+    /// Forget what the instruction registers hold, so that the next `select_tap` or `write_ir`
+    /// scans them whatever happens
+    pub fn invalidate_ir_cache(&mut self) {
+        self.ir_generation = None;
+    }
+
+    fn cached_irs(&self) -> Option<&[BitVec]> {
+        match self.ir_generation {
+            Some(generation) if generation == self.sm.ir_generation() => Some(&self.irs),
+            _ => None,
         }
-        self.write_ones(after_pad);
+    }
 
-        let mut pad_bits = 0;
-        for t in &self.taps[0..self.active] {
-            pad_bits += t.irlen;
+    // `ir` for TAP `tap`, and for the rest whatever the select mode calls for
+    fn select_irs(&self, tap: usize, ir: &BitVec) -> Vec<BitVec> {
+        self.chain_irs(&[(tap, ir.clone())])
+    }
+
+    // The instructions given in `chosen`, and for the rest whatever the select mode calls for
+    fn chain_irs(&self, chosen: &[(usize, BitVec)]) -> Vec<BitVec> {
+        let cached = self.cached_irs();
+        self.taps.iter().map(|t| {
+            if let Some((_, ir)) = chosen.iter().find(|(tap, _)| *tap == t.position) {
+                return ir.clone();
+            }
+            match self.select_mode {
+                SelectMode::Preserve => self.parked[t.position].clone()
+                    .or_else(|| cached.map(|irs| irs[t.position].clone()))
+                    .filter(|ir| dr_len(t, ir).is_some())
+                    .unwrap_or_else(|| BitVec::ones(t.irlen)),
+                SelectMode::Bypass | SelectMode::Reset => BitVec::ones(t.irlen),
+            }
+        }).collect()
+    }
+
+    // Load the instructions last loaded into the TAPs again if they may have changed since, such
+    // as after a reset that left the TAPs holding IDCODE, checking what the instruction registers
+    // capture if `check` is set
+    fn reload_irs(&mut self, check: bool) {
+        if self.cached_irs().is_none() && self.irs.len() == self.taps.len() {
+            let irs = self.irs.clone();
+            if check {
+                self.scan_ir(irs);
+            } else {
+                self.write_irs(irs);
+            }
         }
-        let mut total_bits = (pad_bits + this_irlen) % 8;
-        if total_bits == 0 {
-            total_bits = 8;
+    }
+
+    // Length of the data registers of the TAPs before and after the selected one, which the data
+    // has to be padded out by.  The instructions are loaded again first if they may have changed.
+    // The data can't be lined up with a TAP whose data register length isn't known, which
+    // `write_ir_multi` can leave behind, so any such TAP is put into BYPASS first.  `check` is as
+    // for `reload_irs`.
+    fn dr_pad(&mut self, check: bool) -> (usize, usize) {
+        self.reload_irs(check);
+        let unknown = |taps: &Self, t: &TapInfo| t.position != taps.active && taps.held_dr_len(t).is_none();
+        if self.taps.iter().any(|t| unknown(self, t)) {
+            let irs = self.taps.iter().zip(&self.irs).map(|(t, ir)| {
+                if unknown(self, t) {
+                    BitVec::ones(t.irlen)
+                } else {
+                    ir.clone()
+                }
+            }).collect();
+            if check {
+                self.scan_ir(irs);
+            } else {
+                self.write_irs(irs);
+            }
         }
-        let ir = add_ones_to_end(ir, this_irlen, pad_bits);
-        self.sm.write_reg(Register::Instruction, &ir, total_bits as u8, true);
-        self.sm.change_mode(JtagState::Idle);
+        let len = |t| self.held_dr_len(t).unwrap();
+        let before = self.taps[..self.active].iter().map(len).sum();
+        let after = self.taps[self.active+1..].iter().map(len).sum();
+        (before, after)
+    }
+
+    // Length of the data register selected by the instruction last loaded into `tap`, if known
+    fn held_dr_len(&self, tap: &TapInfo) -> Option<usize> {
+        match self.irs.get(tap.position) {
+            Some(ir) => dr_len(tap, ir),
+            None => Some(1),
+        }
+    }
+
+    // Shift `irs` into the instruction registers and remember them.  Every IR must capture a 1
+    // followed by a 0 in its two least significant bits, and if any doesn't the chain is in an
+    // unknown state so nothing is remembered.  The other bits are often status that changes.
+    // Returns what the whole chain captured, unless a read was queued.
+    fn scan_ir(&mut self, irs: Vec<BitVec>) -> Option<BitVec> {
+        // Reading synchronously would finish someone else's queued read
+        if self.queued_reads > 0 {
+            self.write_irs(irs);
+            return None;
+        }
+
+        let data = ir_scan_data(&irs);
+        let end = self.sm.end_state(Register::Instruction);
+        let captured = self.sm.read_write_reg_end(Register::Instruction, &data, end);
+        // The TAP closest to TDO shifts out first
+        let mut pos = 0;
+        let mut ok = true;
+        for t in self.taps.iter().rev() {
+            ok &= t.irlen < 2 || (captured.get(pos) && !captured.get(pos + 1));
+            pos += t.irlen;
+        }
+        self.ir_generation = ok.then(|| self.sm.ir_generation());
+        self.irs = irs;
+        Some(captured)
+    }
+
+    // Shift `irs` into the instruction registers and remember them, without reading what they
+    // capture
+    fn write_irs(&mut self, irs: Vec<BitVec>) {
+        let end = self.sm.end_state(Register::Instruction);
+        self.sm.write_reg_end(Register::Instruction, &ir_scan_data(&irs), end);
+        self.ir_generation = Some(self.sm.ir_generation());
+        self.irs = irs;
+    }
+
+    // Shift `drs[i]` into the data register of TAP i, for every TAP at once, and return what each
+    // TAP shifted out.  Each register must have the length selected by the current instructions.
+    pub(crate) fn read_write_dr_all(&mut self, drs: &[BitVec]) -> Vec<BitVec> {
+        assert_eq!(drs.len(), self.taps.len());
+        assert_eq!(self.queued_reads, 0);
+        let mut dr = BitVec::new();
+        for bits in drs.iter().rev() {
+            dr.append(bits);
+        }
+        let end = self.sm.end_state(Register::Data);
+        let out = self.sm.read_write_reg_end(Register::Data, &dr, end);
+
+        // The TAP closest to TDO shifts out first
+        let mut ret = vec![BitVec::new(); drs.len()];
+        let mut pos = 0;
+        for (i, bits) in drs.iter().enumerate().rev() {
+            ret[i] = out.slice(pos..pos + bits.len());
+            pos += bits.len();
+        }
+        ret
+    }
+
+    /// Load several TAPs' instructions with a single IR scan.  `irs` lists TAP positions and
+    /// instructions, and the TAPs that aren't listed are dealt with as for `select_tap`, including
+    /// the reset in `SelectMode::Reset`.  Follow with `read_write_dr_multi` to access the data
+    /// registers of the TAPs together.  The single-TAP data register methods still work on the TAP
+    /// chosen by `select_tap`, after putting any other TAP whose instruction doesn't have a data
+    /// register length in its instruction table into BYPASS.
+    pub fn write_ir_multi(&mut self, irs: &[(usize, BitVec)]) {
+        for (i, (tap, ir)) in irs.iter().enumerate() {
+            assert!(*tap < self.taps.len());
+            assert_eq!(ir.len(), self.taps[*tap].irlen);
+            assert!(irs[..i].iter().all(|(t, _)| t != tap), "TAP {} listed twice", tap);
+        }
+        let irs = self.chain_irs(irs);
+        if self.cached_irs() != Some(&irs[..]) {
+            if self.select_mode == SelectMode::Reset {
+                self.sm.mode_reset();
+            }
+            self.scan_ir(irs);
+        }
+    }
+
+    /// Shift data into several TAPs' data registers with a single DR scan, and return what each
+    /// of them shifted out, in the same order as `drs`.  `drs` lists TAP positions and data, which
+    /// must be as long as the data register selected by the TAP's instruction.  The TAPs that
+    /// aren't listed have ones shifted through their data registers, so they need instructions
+    /// whose data register lengths are known, such as BYPASS.
+    pub fn read_write_dr_multi(&mut self, drs: &[(usize, BitVec)]) -> Vec<(usize, BitVec)> {
+        for (i, (tap, _)) in drs.iter().enumerate() {
+            assert!(*tap < self.taps.len());
+            assert!(drs[..i].iter().all(|(t, _)| t != tap), "TAP {} listed twice", tap);
+        }
+        self.reload_irs(true);
+        let all: Vec<BitVec> = self.taps.iter().map(|t| {
+            match drs.iter().find(|(tap, _)| *tap == t.position) {
+                Some((_, dr)) => dr.clone(),
+                None => {
+                    let len = self.held_dr_len(t).unwrap_or_else(|| {
+                        panic!("TAP {} holds an instruction with no known data register length", t.position)
+                    });
+                    BitVec::ones(len)
+                }
+            }
+        }).collect();
+        let out = self.read_write_dr_all(&all);
+        drs.iter().map(|(tap, _)| (*tap, out[*tap].clone())).collect()
+    }
+
+    /// `write_ir_multi` with TAPs and instructions given by name, see `select`
+    pub fn select_multi(&mut self, instructions: &[(&str, &str)]) -> Result<(), String> {
+        let mut irs = vec![];
+        for (name, instruction) in instructions {
+            let tap = self.find(name)?;
+            if irs.iter().any(|(t, _)| *t == tap) {
+                return Err(format!("TAP {} listed twice", name));
+            }
+            irs.push((tap, self.opcode(tap, instruction)?));
+        }
+        self.write_ir_multi(&irs);
+        Ok(())
+    }
+
+    /// Select the TAP called `tap` and load the instruction called `instruction` from its
+    /// instruction table, as for `select_tap`
+    pub fn select(&mut self, tap: &str, instruction: &str) -> Result<(), String> {
+        let tap = self.find(tap)?;
+        let ir = self.opcode(tap, instruction)?;
+        self.select_tap(tap, &ir);
+        Ok(())
+    }
+
+    /// Load the instruction called `instruction` into the TAP selected by `select_tap`, as for
+    /// `write_ir`
+    pub fn write_ir_named(&mut self, instruction: &str) -> Result<(), String> {
+        let ir = self.opcode(self.active, instruction)?;
+        self.write_ir(&ir);
+        Ok(())
+    }
+
+    fn opcode(&self, tap: usize, instruction: &str) -> Result<BitVec, String> {
+        let info = &self.taps[tap];
+        info.instructions.opcode(instruction)
+            .cloned()
+            .map_err(|e| format!("TAP {}: {}", info.name.as_deref().unwrap_or(&tap.to_string()), e))
     }
 
     /// Read the instruction register of the TAP selected by `select_tap`
-    pub fn read_ir(&mut self) -> Vec<u8> {
+    pub fn read_ir(&mut self) -> BitVec {
         assert!(self.active < self.taps.len());
         let this_irlen = self.taps[self.active].irlen;
         let mut pad_bits = 0;
@@ -167,124 +972,293 @@ impl<T, U> Taps<T>
         self.sm.read_reg(Register::Instruction, this_irlen)
     }
 
-    /// Shift `dr` into the data register of the TAP selected by `select_tap`.  `bits` indicates
-    /// how many bits of the final byte should be written (a value of 8 will write the entire byte)
-    pub fn write_dr(&mut self, dr: &[u8], bits: usize) {
-        assert!(self.active < self.taps.len());
-        let this_len = (dr.len() - 1) * 8 + bits;
-        let pad_bits = self.active;
+    /// Shift `dr` into the data register of the TAP selected by `select_tap`
+    pub fn write_dr(&mut self, dr: &BitVec) {
+        let (dr, _) = self.dr_scan(dr, false, true);
+        let end = self.sm.end_state(Register::Data);
+        self.sm.write_reg_end(Register::Data, &dr, end);
+    }
 
-        let mut total_bits = (pad_bits + this_len) % 8;
-        if total_bits == 0 {
-            total_bits = 8;
+    // The scan of the whole chain that shifts `dr` into the data register of the selected TAP,
+    // and where its bits start in what is shifted out.  The bits of the TAPs closer to TDO are
+    // only shifted through if `read` is set.  `check` is as for `dr_pad`.
+    pub(crate) fn dr_scan(&mut self, dr: &BitVec, read: bool, check: bool) -> (BitVec, usize) {
+        assert!(self.active < self.taps.len());
+        let (pad_bits, discard_bits) = self.dr_pad(check);
+        let dr = dr.concat(&BitVec::ones(pad_bits));
+        if read {
+            // The bits for the TAPs closer to TDO come out first
+            (BitVec::ones(discard_bits).concat(&dr), discard_bits)
+        } else {
+            (dr, 0)
         }
-        let dr = add_ones_to_end(dr, this_len, pad_bits);
-        self.sm.write_reg(Register::Data, &dr, total_bits as u8, true);
-        self.sm.change_mode(JtagState::Idle);
     }
 
-    /// Shift `dr` into the data register of the TAP selected by `select_tap`.  `bits` indicates
-    /// how many bits of the final byte should be written (a value of 8 will
-    /// write the entire byte).  Returns the bits that were shifted out while `dr` was
-    /// shifted in.
-    pub fn read_write_dr(&mut self, dr: &[u8], bits: usize) -> Vec<u8> {
+    /// Shift `dr` into the data register of the TAP selected by `select_tap`.  Returns the bits
+    /// that were shifted out while `dr` was shifted in.
+    pub fn read_write_dr(&mut self, dr: &BitVec) -> BitVec {
         assert_eq!(self.queued_reads, 0);
-        self.queue_dr_read_write(dr, bits);
-        let total_bits = (dr.len()-1) * 8 + bits;
-        self.finish_dr_read(total_bits)
+        self.queue_dr(dr);
+        self.finish_dr(dr.len())
+    }
+
+    /// Queue shifting `dr` into the data register of the TAP selected by `select_tap`, to be read
+    /// back with `finish_dr_read`.  Returns false if the cable's queue is full.
+    #[deprecated(note = "queue scans with `batch::Batch`, which hands back a `Capture` for each read")]
+    pub fn queue_dr_read_write(&mut self, dr: &BitVec) -> bool {
+        self.queue_dr(dr)
     }
 
-    pub fn queue_dr_read_write(&mut self, dr: &[u8], bits: usize) -> bool {
+    fn queue_dr(&mut self, dr: &BitVec) -> bool {
         assert!(self.active < self.taps.len());
-        let this_len = (dr.len() - 1) * 8 + bits;
-        let pad_bits = self.active;
-        let discard_bits = self.taps.len() - self.active - 1;
-
-        let mut total_bits = (pad_bits + this_len) % 8;
-        if total_bits == 0 {
-            total_bits = 8;
-        }
-        let dr = add_ones_to_end(dr, this_len, pad_bits);
-        if discard_bits > 0 {
-            if !self.sm.queue_read(Register::Data, discard_bits) {
-                return false;
-            }
+        let (pad_bits, discard_bits) = self.dr_pad(true);
+
+        let dr = dr.concat(&BitVec::ones(pad_bits));
+        if discard_bits > 0 && !self.sm.queue_read(Register::Data, discard_bits) {
+            return false;
         }
-        if self.sm.queue_read_write(Register::Data, &dr, total_bits as u8, true) {
-            self.sm.change_mode(JtagState::Idle);
+        let end = self.sm.end_state(Register::Data);
+        if self.sm.queue_read_write_end(Register::Data, &dr, end) {
             self.queued_reads += 1;
+            self.reads.push_back(QueuedRead { pad_bits, discard_bits, expect: None });
             true
         } else {
-            self.sm.change_mode(JtagState::Idle);
-            self.dangling_read = discard_bits > 0;
+            self.sm.change_mode(end);
+            self.dangling_read = discard_bits;
             false
         }
     }
 
+    /// As `read_write_dr`, checking that the data register captured `expected` in the bits set in
+    /// `mask`
+    pub fn read_write_dr_expect(&mut self, dr: &BitVec, expected: &BitVec, mask: &BitVec)
+        -> Result<BitVec, ScanMismatch>
+    {
+        let captured = self.read_write_dr(dr);
+        check_capture(self.active, Register::Data, captured, expected, mask)
+    }
+
+    /// As `queue_dr_read_write`, with the captured bits compared to `expected` in the bits set
+    /// in `mask` when the read is finished with `finish_dr_read`.  Mismatches are kept until
+    /// `take_mismatches`, so that a whole queue of scans can be checked at the end.
+    #[deprecated(note = "queue scans with `batch::Batch`, which hands back a `Capture` for each read")]
+    pub fn queue_dr_expect(&mut self, dr: &BitVec, expected: &BitVec, mask: &BitVec) -> bool {
+        assert_eq!(expected.len(), dr.len());
+        assert_eq!(mask.len(), dr.len());
+        if !self.queue_dr(dr) {
+            return false;
+        }
+        self.reads.back_mut().unwrap().expect = Some(Expectation {
+            tap: self.active,
+            expected: expected.clone(),
+            mask: mask.clone(),
+        });
+        true
+    }
+
+    /// The mismatches found by the reads queued with `queue_dr_expect` that have been finished
+    /// since the last call
+    #[deprecated(note = "check queued scans with `batch::Batch::read_write_dr_expect`")]
+    pub fn take_mismatches(&mut self) -> Vec<ScanMismatch> {
+        std::mem::take(&mut self.mismatches)
+    }
+
     /// Read the data register of the TAP selected by `select_tap`.  `bits` indicates the length of
     /// the data register for the current instruction.
-    pub fn read_dr(&mut self, bits: usize) -> Vec<u8> {
+    pub fn read_dr(&mut self, bits: usize) -> BitVec {
         assert_eq!(self.queued_reads, 0);
-        self.queue_dr_read(bits);
-        self.finish_dr_read(bits)
+        self.queue_read(bits);
+        self.finish_dr(bits)
     }
 
+    /// Queue reading the data register of the TAP selected by `select_tap`, to be read back with
+    /// `finish_dr_read`.  Returns false if the cable's queue is full.
+    #[deprecated(note = "queue scans with `batch::Batch`, which hands back a `Capture` for each read")]
     pub fn queue_dr_read(&mut self, bits: usize) -> bool {
+        self.queue_read(bits)
+    }
+
+    fn queue_read(&mut self, bits: usize) -> bool {
         assert!(self.active < self.taps.len());
-        let pad_bits = self.active;
-        let discard_bits = self.taps.len() - self.active - 1;
+        let (pad_bits, discard_bits) = self.dr_pad(true);
         let total_bits = pad_bits + bits;
 
         // Discard the bypass bits
         self.sm.change_mode(JtagState::Idle);
-        if discard_bits > 0 {
-            if !self.sm.queue_read(Register::Data, discard_bits) {
-                return false;
-            }
+        if discard_bits > 0 && !self.sm.queue_read(Register::Data, discard_bits) {
+            return false;
         }
         if !self.sm.queue_read(Register::Data, total_bits) {
-            self.dangling_read = discard_bits > 0;
+            self.dangling_read = discard_bits;
             false
         } else {
             self.queued_reads += 1;
+            self.reads.push_back(QueuedRead { pad_bits, discard_bits, expect: None });
             true
         }
     }
 
-    pub fn finish_dr_read(&mut self, bits: usize) -> Vec<u8> {
-        assert!(self.active < self.taps.len());
-        let pad_bits = self.active;
-        let discard_bits = self.taps.len() - self.active - 1;
-        let total_bits = pad_bits + bits;
+    /// The bits captured by the oldest queued data register read, which is `bits` long
+    #[deprecated(note = "queue scans with `batch::Batch`, which hands back a `Capture` for each read")]
+    pub fn finish_dr_read(&mut self, bits: usize) -> BitVec {
+        self.finish_dr(bits)
+    }
+
+    fn finish_dr(&mut self, bits: usize) -> BitVec {
+        let read = self.reads.pop_front().expect("no data register read is queued");
+        let total_bits = read.pad_bits + bits;
 
         // Discard the bypass bits
-        if discard_bits > 0 {
-            self.sm.cable.finish_read(discard_bits);
+        if read.discard_bits > 0 {
+            self.sm.cable.finish_read(read.discard_bits);
         }
         let mut ret = self.sm.cable.finish_read(total_bits);
 
         // Remove the pad bits
-        if pad_bits > 0 {
-            let bytes = (bits + 7) / 8;
-            // Trim off any extra bytes
-            ret.resize(bytes, 0);
+        if read.pad_bits > 0 {
+            ret = ret.slice(0..bits);
+        }
 
-            // Mask off high bits
-            if bits % 8 != 0 {
-                ret[bytes-1] &= (1 << (bits % 8)) - 1;
+        if let Some(expect) = read.expect {
+            let checked = check_capture(expect.tap, Register::Data, ret.clone(), &expect.expected,
+                                        &expect.mask);
+            if let Err(mismatch) = checked {
+                self.mismatches.push(mismatch);
             }
         }
 
         // Handle the case where we were able to queue the read of the discard bits, but not of the
         // interesting data.
         self.queued_reads -= 1;
-        if self.queued_reads == 0 && self.dangling_read {
-            if discard_bits > 0 {
-                self.sm.cable.finish_read(discard_bits);
-            }
-            self.dangling_read = false;
+        if self.queued_reads == 0 && self.dangling_read > 0 {
+            self.sm.cable.finish_read(self.dangling_read);
+            self.dangling_read = 0;
         }
         ret
     }
-}
 
+    /// Set the number of bits the streaming data register methods shift at a time.  Larger chunks
+    /// use more memory but let cables with deep queues keep busy.
+    pub fn set_stream_chunk_bits(&mut self, bits: usize) {
+        assert!(bits > 0);
+        self.stream_chunk = bits;
+    }
+
+    /// Shift `bits` bits read from `tdi` into the data register of the TAP selected by
+    /// `select_tap`, without holding them all in memory.  The bytes are shifted least significant
+    /// bit first, as `BitVec::from_bytes` orders them.  The chain stays in Shift-DR between
+    /// chunks, so the TAP sees a single scan; if reading fails part way, the chain is left in
+    /// Shift-DR.
+    pub fn write_dr_stream<R: Read>(&mut self, mut tdi: R, bits: usize) -> Result<(), String> {
+        let mut next = self.read_chunks(&mut tdi, bits);
+        self.stream_dr(&mut next, None::<TdoWriter<std::io::Sink>>)
+    }
+
+    /// As `write_dr_stream`, writing the bits shifted out of the data register to `tdo` as they
+    /// arrive.  A final partial byte is padded with zeros.
+    pub fn read_write_dr_stream<R: Read, W: Write>(&mut self, mut tdi: R, tdo: W, bits: usize)
+        -> Result<(), String>
+    {
+        let mut next = self.read_chunks(&mut tdi, bits);
+        self.stream_dr(&mut next, Some(TdoWriter { out: tdo, pending: BitVec::new() }))
+    }
+
+    /// As `write_dr_stream`, with the data coming from an iterator.  The pieces can be any
+    /// length; they are gathered up or split to be shifted in chunks.
+    pub fn write_dr_chunks<I: IntoIterator<Item = BitVec>>(&mut self, chunks: I) -> Result<(), String> {
+        let mut next = self.iter_chunks(chunks.into_iter());
+        self.stream_dr(&mut next, None::<TdoWriter<std::io::Sink>>)
+    }
+
+    /// As `read_write_dr_stream`, with the data coming from an iterator
+    pub fn read_write_dr_chunks<I: IntoIterator<Item = BitVec>, W: Write>(&mut self, chunks: I, tdo: W)
+        -> Result<(), String>
+    {
+        let mut next = self.iter_chunks(chunks.into_iter());
+        self.stream_dr(&mut next, Some(TdoWriter { out: tdo, pending: BitVec::new() }))
+    }
+
+    // Chunks of `bits` bits read from `tdi`
+    fn read_chunks<'a, R: Read>(&self, tdi: &'a mut R, bits: usize)
+        -> impl FnMut() -> Result<Option<BitVec>, String> + 'a
+    {
+        let chunk = self.stream_chunk;
+        let mut left = bits;
+        // Bits of the last byte read that didn't fit in the last chunk
+        let mut carry = BitVec::new();
+        move || {
+            if left == 0 {
+                return Ok(None);
+            }
+            let len = left.min(chunk);
+            let mut bytes = vec![0; len.saturating_sub(carry.len()).div_ceil(8)];
+            tdi.read_exact(&mut bytes).map_err(|e| format!("reading data to shift: {}", e))?;
+            let read = carry.concat(&BitVec::from_bytes(&bytes, bytes.len() * 8));
+            carry = read.slice(len..read.len());
+            left -= len;
+            Ok(Some(read.slice(0..len)))
+        }
+    }
+
+    // The bits from `pieces`, in chunks
+    fn iter_chunks<I: Iterator<Item = BitVec>>(&self, mut pieces: I)
+        -> impl FnMut() -> Result<Option<BitVec>, String>
+    {
+        let chunk = self.stream_chunk;
+        let mut buffer = BitVec::new();
+        move || {
+            while buffer.len() < chunk {
+                match pieces.next() {
+                    Some(piece) => buffer.append(&piece),
+                    None => break,
+                }
+            }
+            if buffer.is_empty() {
+                return Ok(None);
+            }
+            let len = buffer.len().min(chunk);
+            let ret = buffer.slice(0..len);
+            buffer = buffer.slice(len..buffer.len());
+            Ok(Some(ret))
+        }
+    }
+
+    // Shift the chunks from `next` as one data register scan, padded for the other TAPs like
+    // `write_dr` and `read_write_dr`.  The chunk after the current one is fetched before it is
+    // shifted, so that the last can take the chain out of Shift-DR.
+    fn stream_dr<W: Write>(&mut self, next: &mut dyn FnMut() -> Result<Option<BitVec>, String>,
+                           mut tdo: Option<TdoWriter<W>>) -> Result<(), String> {
+        assert!(self.active < self.taps.len());
+        assert_eq!(self.queued_reads, 0);
+        let (pad_bits, discard_bits) = self.dr_pad(true);
+        let end = self.sm.end_state(Register::Data);
+
+        let mut chunk = next()?.ok_or_else(|| "no data to shift".to_string())?;
+        if tdo.is_some() && discard_bits > 0 {
+            self.sm.read_reg(Register::Data, discard_bits);
+        }
+        loop {
+            let following = next()?;
+            let len = chunk.len();
+            let (data, state) = match following {
+                Some(_) => (chunk, JtagState::ShiftDR),
+                None => (chunk.concat(&BitVec::ones(pad_bits)), end),
+            };
+            match &mut tdo {
+                Some(tdo) => {
+                    let captured = self.sm.read_write_reg_end(Register::Data, &data, state);
+                    tdo.push(&captured.slice(0..len))?;
+                }
+                None => self.sm.write_reg_end(Register::Data, &data, state),
+            }
+            match following {
+                Some(following) => chunk = following,
+                None => break,
+            }
+        }
+        match tdo {
+            Some(tdo) => tdo.finish(),
+            None => Ok(()),
+        }
+    }
+}
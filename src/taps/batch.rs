@@ -0,0 +1,320 @@
+//! Scans that are queued on the cable together, so that adapters with command queues, such as
+//! MPSSE and J-Link, can do many of them in one round trip.  A `Batch` records scans of the TAPs
+//! of a `Taps`, handing out a `Capture` for each one that reads, and `Batch::run` shifts them all
+//! and returns the `BatchResults` that the captures are redeemed from.  When the cable's queue
+//! fills, the reads queued so far are finished and queueing starts again.  A capture made with
+//! `Batch::read_dr_as` is redeemed as the type it was made for, such as a `u32`.
+//!
+//! ```no_run
+//! use jtag_taps::bits::BitVec;
+//! use jtag_taps::cable;
+//! use jtag_taps::statemachine::JtagSM;
+//! use jtag_taps::taps::Taps;
+//! use jtag_taps::taps::batch::Batch;
+//! let cable = cable::new_from_string("jtagkey", 1_000_000).unwrap();
+//! let mut taps = Taps::new(JtagSM::new(cable));
+//! taps.detect().unwrap();
+//!
+//! let mut batch = Batch::new();
+//! batch.select_tap(0, &BitVec::from_u64(1, 5));
+//! let status: Vec<_> = (0..100).map(|_| batch.read_dr(32)).collect();
+//! let idcode = batch.read_dr_as::<u32>(32);
+//! let mut results = batch.run(&mut taps);
+//! for capture in &status {
+//!     println!("{}", results[capture]);
+//! }
+//! println!("{:08x}", results.take(idcode));
+//! ```
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::ops::Index;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{check_capture, ScanMismatch, Taps};
+use crate::bits::BitVec;
+use crate::cable::Cable;
+use crate::statemachine::Register;
+
+// Tells the batches apart, so that a capture can't be redeemed from the wrong results
+static NEXT_BATCH: AtomicU64 = AtomicU64::new(0);
+
+enum Scan {
+    SelectTap(usize, BitVec),
+    WriteIr(BitVec),
+    // The capture index, if the scan is read, and the expected value and mask
+    Dr { dr: BitVec, capture: Option<usize>, expect: Option<(BitVec, BitVec)> },
+}
+
+/// Handle to the bits captured by one scan of a `Batch`, redeemed as a `T` from the
+/// `BatchResults` that running the batch returns
+#[must_use = "the captured bits can only be had with the capture"]
+#[derive(Debug,PartialEq,Eq)]
+pub struct Capture<T = BitVec> {
+    batch: u64,
+    index: usize,
+    value: PhantomData<fn() -> T>,
+}
+
+/// Types that the bits captured by a scan can be redeemed as
+pub trait FromCapture {
+    /// Most bits a value holds
+    const MAX_BITS: usize;
+
+    fn from_capture(bits: BitVec) -> Self;
+}
+
+impl FromCapture for BitVec {
+    const MAX_BITS: usize = usize::MAX;
+
+    fn from_capture(bits: BitVec) -> Self {
+        bits
+    }
+}
+
+impl FromCapture for bool {
+    const MAX_BITS: usize = 1;
+
+    fn from_capture(bits: BitVec) -> Self {
+        bits.get(0)
+    }
+}
+
+macro_rules! from_capture_uint {
+    ($($t:ty),*) => {$(
+        impl FromCapture for $t {
+            const MAX_BITS: usize = <$t>::BITS as usize;
+
+            fn from_capture(bits: BitVec) -> Self {
+                bits.to_u64().unwrap() as $t
+            }
+        }
+    )*};
+}
+
+from_capture_uint!(u8, u16, u32, u64);
+
+/// A list of scans to run together.  The methods match those of `Taps`, and act on the TAP
+/// selected by the last `select_tap` before them, or if there isn't one the TAP selected in
+/// `Taps` when the batch runs.
+pub struct Batch {
+    id: u64,
+    scans: Vec<Scan>,
+    captures: usize,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the scans of a `Batch` captured
+#[derive(Clone,Debug)]
+pub struct BatchResults {
+    batch: u64,
+    captured: Vec<BitVec>,
+    mismatches: Vec<ScanMismatch>,
+    round_trips: usize,
+}
+
+// A read queued on the cable
+struct Queued {
+    // Length of the whole scan
+    bits: usize,
+    // Where the selected TAP's bits start
+    offset: usize,
+    len: usize,
+    capture: usize,
+    tap: usize,
+    expect: Option<(BitVec, BitVec)>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self {
+            id: NEXT_BATCH.fetch_add(1, Ordering::Relaxed),
+            scans: vec![],
+            captures: 0,
+        }
+    }
+
+    /// Number of scans recorded
+    pub fn len(&self) -> usize {
+        self.scans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scans.is_empty()
+    }
+
+    fn capture<T>(&mut self) -> (usize, Capture<T>) {
+        let index = self.captures;
+        self.captures += 1;
+        (index, Capture { batch: self.id, index, value: PhantomData })
+    }
+
+    /// As `Taps::select_tap`.  The instruction registers aren't checked if reads are queued.
+    pub fn select_tap(&mut self, tap: usize, ir: &BitVec) {
+        self.scans.push(Scan::SelectTap(tap, ir.clone()));
+    }
+
+    /// As `Taps::write_ir`
+    pub fn write_ir(&mut self, ir: &BitVec) {
+        self.scans.push(Scan::WriteIr(ir.clone()));
+    }
+
+    /// As `Taps::write_dr`
+    pub fn write_dr(&mut self, dr: &BitVec) {
+        self.scans.push(Scan::Dr { dr: dr.clone(), capture: None, expect: None });
+    }
+
+    /// As `Taps::read_write_dr`
+    pub fn read_write_dr(&mut self, dr: &BitVec) -> Capture {
+        let (index, capture) = self.capture();
+        self.scans.push(Scan::Dr { dr: dr.clone(), capture: Some(index), expect: None });
+        capture
+    }
+
+    /// Read `bits` bits from the data register, shifting in ones
+    pub fn read_dr(&mut self, bits: usize) -> Capture {
+        self.read_dr_as(bits)
+    }
+
+    /// As `read_dr`, with the capture redeemed as a `T`.  Panics if `T` can't hold `bits` bits.
+    pub fn read_dr_as<T: FromCapture>(&mut self, bits: usize) -> Capture<T> {
+        assert!(bits <= T::MAX_BITS, "{} bits don't fit in the type captured", bits);
+        let (index, capture) = self.capture();
+        self.scans.push(Scan::Dr { dr: BitVec::ones(bits), capture: Some(index), expect: None });
+        capture
+    }
+
+    /// As `read_write_dr`, checking that the data register captured `expected` in the bits set
+    /// in `mask`.  Mismatches are in `BatchResults::mismatches`.
+    pub fn read_write_dr_expect(&mut self, dr: &BitVec, expected: &BitVec, mask: &BitVec) -> Capture {
+        assert_eq!(expected.len(), dr.len());
+        assert_eq!(mask.len(), dr.len());
+        let (index, capture) = self.capture();
+        let expect = Some((expected.clone(), mask.clone()));
+        self.scans.push(Scan::Dr { dr: dr.clone(), capture: Some(index), expect });
+        capture
+    }
+
+    /// Shift the scans, queueing as many reads on the cable at a time as it will take.  Reads
+    /// queued with the deprecated `Taps::queue_dr_read` and friends must have been finished
+    /// first.
+    pub fn run<T, U>(self, taps: &mut Taps<T>) -> BatchResults
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        assert_eq!(taps.queued_reads, 0, "reads queued through Taps must be finished before a batch runs");
+        let mut results = BatchResults {
+            batch: self.id,
+            captured: vec![BitVec::new(); self.captures],
+            mismatches: vec![],
+            round_trips: 0,
+        };
+        let mut queued = VecDeque::new();
+        for scan in self.scans {
+            match scan {
+                // Checking what the instruction registers capture would finish the queued reads
+                Scan::SelectTap(tap, ir) => taps.load_ir(tap, &ir, true, queued.is_empty()),
+                Scan::WriteIr(ir) => taps.load_ir(taps.active(), &ir, false, queued.is_empty()),
+                Scan::Dr { dr, capture, expect } => {
+                    let (data, offset) = taps.dr_scan(&dr, capture.is_some(), queued.is_empty());
+                    let end = taps.sm.end_state(Register::Data);
+                    let Some(capture) = capture else {
+                        taps.sm.write_reg_end(Register::Data, &data, end);
+                        continue;
+                    };
+
+                    let read = Queued {
+                        bits: data.len(),
+                        offset,
+                        len: dr.len(),
+                        capture,
+                        tap: taps.active(),
+                        expect,
+                    };
+                    if taps.sm.queue_read_write_end(Register::Data, &data, end) {
+                        queued.push_back(read);
+                        continue;
+                    }
+                    results.finish(taps, &mut queued);
+                    if taps.sm.queue_read_write_end(Register::Data, &data, end) {
+                        queued.push_back(read);
+                    } else {
+                        // Too big for the queue even when it is empty
+                        let out = taps.sm.read_write_reg_end(Register::Data, &data, end);
+                        results.store(&read, out);
+                    }
+                }
+            }
+        }
+        results.finish(taps, &mut queued);
+        taps.sm.cable.flush();
+        results
+    }
+}
+
+impl BatchResults {
+    // Finish the queued reads, in the order they were queued
+    fn finish<T, U>(&mut self, taps: &mut Taps<T>, queued: &mut VecDeque<Queued>)
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        if queued.is_empty() {
+            return;
+        }
+        self.round_trips += 1;
+        while let Some(read) = queued.pop_front() {
+            let out = taps.sm.cable.finish_read(read.bits);
+            self.store(&read, out);
+        }
+    }
+
+    fn store(&mut self, read: &Queued, out: BitVec) {
+        let captured = out.slice(read.offset..read.offset + read.len);
+        if let Some((expected, mask)) = &read.expect {
+            if let Err(mismatch) = check_capture(read.tap, Register::Data, captured.clone(), expected, mask) {
+                self.mismatches.push(mismatch);
+            }
+        }
+        self.captured[read.capture] = captured;
+    }
+
+    /// The bits captured by the scan that returned `capture`.  Panics if `capture` came from a
+    /// different batch.
+    pub fn get<T>(&self, capture: &Capture<T>) -> &BitVec {
+        assert_eq!(capture.batch, self.batch, "capture from a different batch");
+        &self.captured[capture.index]
+    }
+
+    /// As `get`, taking the bits out of the results as the type the capture was made for
+    pub fn take<T: FromCapture>(&mut self, capture: Capture<T>) -> T {
+        assert_eq!(capture.batch, self.batch, "capture from a different batch");
+        T::from_capture(std::mem::take(&mut self.captured[capture.index]))
+    }
+
+    /// The scans made with `Batch::read_write_dr_expect` that didn't capture what was expected, in
+    /// the order they were made
+    pub fn mismatches(&self) -> &[ScanMismatch] {
+        &self.mismatches
+    }
+
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    /// Number of times queued reads were collected from the cable
+    pub fn round_trips(&self) -> usize {
+        self.round_trips
+    }
+}
+
+impl<T> Index<&Capture<T>> for BatchResults {
+    type Output = BitVec;
+
+    fn index(&self, capture: &Capture<T>) -> &BitVec {
+        self.get(capture)
+    }
+}
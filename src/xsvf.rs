@@ -0,0 +1,345 @@
+//! Playing XSVF files, the compact binary form of SVF described in Xilinx application note
+//! XAPP503 and produced by iMPACT-era tools for CoolRunner and XC9500 parts.
+//!
+//! The only compression XSVF has is XSDRINC, which older tools use for devices programmed one
+//! address at a time.  It shifts a starting value and then, for each step, adds the address mask
+//! set by XSETSDRMASKS to the value and fills the bits set in the data mask with the next piece of
+//! data, as the reference player does.
+//!
+//! Data values are stored most significant byte first, so the last bit of the last byte is the
+//! first shifted.  As in the reference player, XRUNTEST and XWAIT times are in microseconds and
+//! are met by clocking TCK at least once per microsecond, and a TDO mismatch in XSDR or XSDRTDO is
+//! retried up to the XREPEAT count, waiting in Run-Test/Idle for 25% more run-test time each
+//! attempt before the data register is captured and shifted again.
+use crate::bits::BitVec;
+use crate::cable::Cable;
+use crate::statemachine::{JtagSM, JtagState, Register, STATES};
+use crate::svf;
+
+const XCOMPLETE: u8 = 0x00;
+const XTDOMASK: u8 = 0x01;
+const XSIR: u8 = 0x02;
+const XSDR: u8 = 0x03;
+const XRUNTEST: u8 = 0x04;
+const XREPEAT: u8 = 0x07;
+const XSDRSIZE: u8 = 0x08;
+const XSDRTDO: u8 = 0x09;
+const XSETSDRMASKS: u8 = 0x0a;
+const XSDRINC: u8 = 0x0b;
+const XSDRB: u8 = 0x0c;
+const XSDRC: u8 = 0x0d;
+const XSDRE: u8 = 0x0e;
+const XSDRTDOB: u8 = 0x0f;
+const XSDRTDOC: u8 = 0x10;
+const XSDRTDOE: u8 = 0x11;
+const XSTATE: u8 = 0x12;
+const XENDIR: u8 = 0x13;
+const XENDDR: u8 = 0x14;
+const XSIR2: u8 = 0x15;
+const XCOMMENT: u8 = 0x16;
+const XWAIT: u8 = 0x17;
+
+/// Number of times a failed XSDR is retried if the file doesn't say
+pub const DEFAULT_REPEAT: u8 = 32;
+
+/// Result of playing an XSVF file
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Report {
+    /// Number of commands executed, including XCOMPLETE
+    pub commands: usize,
+    /// Number of times a scan had to be repeated because of a TDO mismatch
+    pub retries: usize,
+    /// The text of the XCOMMENT commands
+    pub comments: Vec<String>,
+}
+
+// Reads the parts of a command, keeping track of where it is for error messages
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, count: usize) -> Result<&[u8], String> {
+        if self.data.len() - self.pos < count {
+            return Err("file ends in the middle of a command".to_string());
+        }
+        self.pos += count;
+        Ok(&self.data[self.pos - count..self.pos])
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn number(&mut self, count: usize) -> Result<u32, String> {
+        Ok(self.bytes(count)?.iter().fold(0, |value, b| value << 8 | *b as u32))
+    }
+
+    // A value of `len` bits, most significant byte first
+    fn value(&mut self, len: usize) -> Result<BitVec, String> {
+        let mut bytes = self.bytes(len.div_ceil(8))?.to_vec();
+        bytes.reverse();
+        let bits = BitVec::from_bytes(&bytes, len);
+        if bits.as_bytes() != bytes.as_slice() {
+            return Err(format!("value has bits set beyond its length of {} bits", len));
+        }
+        Ok(bits)
+    }
+
+    fn state(&mut self) -> Result<JtagState, String> {
+        let state = self.byte()?;
+        STATES.get(state as usize).copied().ok_or_else(|| format!("invalid state {}", state))
+    }
+}
+
+struct Player {
+    sdr_size: usize,
+    tdo_mask: Option<BitVec>,
+    // Expected TDO of the last XSDRTDO, which XSDR compares against too
+    tdo_expected: Option<BitVec>,
+    repeat: u8,
+    // Microseconds
+    run_test: u32,
+    // Address and data masks for XSDRINC
+    sdr_masks: Option<(BitVec, BitVec)>,
+    report: Report,
+}
+
+// `value` plus `addend`, dropping any carry out of the top bit
+fn add(value: &BitVec, addend: &BitVec) -> BitVec {
+    let mut carry = false;
+    (0..value.len()).map(|i| {
+        let (a, b) = (value.get(i), addend.get(i));
+        let sum = a ^ b ^ carry;
+        carry = (a && b) || (carry && (a ^ b));
+        sum
+    }).collect()
+}
+
+impl Player {
+    fn mask(&self) -> BitVec {
+        self.tdo_mask.clone().unwrap_or_else(|| BitVec::ones(self.sdr_size))
+    }
+
+    fn execute<T, U>(&mut self, sm: &mut JtagSM<T>, reader: &mut Reader) -> Result<bool, String>
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        let command = reader.byte()?;
+        match command {
+            XCOMPLETE => return Ok(false),
+            XTDOMASK => self.tdo_mask = Some(reader.value(self.sdr_size)?),
+            XSIR | XSIR2 => {
+                let len = reader.number(if command == XSIR { 1 } else { 2 })?;
+                let tdi = reader.value(len as usize)?;
+                if !tdi.is_empty() {
+                    sm.write_reg_end(Register::Instruction, &tdi, sm.end_state(Register::Instruction));
+                }
+                self.wait_after_shift(sm, self.run_test);
+            }
+            XSDR => {
+                let tdi = reader.value(self.sdr_size)?;
+                let expected = self.tdo_expected.clone();
+                self.shift_dr(sm, &tdi, expected.as_ref())?;
+            }
+            XRUNTEST => self.run_test = reader.number(4)?,
+            XREPEAT => self.repeat = reader.byte()?,
+            XSDRSIZE => {
+                self.sdr_size = reader.number(4)? as usize;
+                if self.tdo_mask.as_ref().is_some_and(|mask| mask.len() != self.sdr_size) {
+                    self.tdo_mask = None;
+                }
+                if self.tdo_expected.as_ref().is_some_and(|tdo| tdo.len() != self.sdr_size) {
+                    self.tdo_expected = None;
+                }
+                if self.sdr_masks.as_ref().is_some_and(|(address, _)| address.len() != self.sdr_size) {
+                    self.sdr_masks = None;
+                }
+            }
+            XSDRTDO => {
+                let tdi = reader.value(self.sdr_size)?;
+                let tdo = reader.value(self.sdr_size)?;
+                self.shift_dr(sm, &tdi, Some(&tdo))?;
+                self.tdo_expected = Some(tdo);
+            }
+            XSDRB | XSDRC | XSDRE | XSDRTDOB | XSDRTDOC | XSDRTDOE => {
+                let tdi = reader.value(self.sdr_size)?;
+                let tdo = if command >= XSDRTDOB { Some(reader.value(self.sdr_size)?) } else { None };
+                self.shift_segment(sm, command, &tdi, tdo.as_ref())?;
+            }
+            XSTATE => {
+                let state = reader.state()?;
+                if state == JtagState::Reset {
+                    sm.mode_reset();
+                } else {
+                    sm.change_mode(state);
+                }
+            }
+            XENDIR | XENDDR => {
+                let reg = if command == XENDIR { Register::Instruction } else { Register::Data };
+                let state = match (reader.byte()?, reg) {
+                    (0, _) => JtagState::Idle,
+                    (1, Register::Instruction) => JtagState::PauseIR,
+                    (1, Register::Data) => JtagState::PauseDR,
+                    (other, _) => return Err(format!("invalid end state {}", other)),
+                };
+                sm.set_end_state(reg, state);
+            }
+            XCOMMENT => {
+                let start = reader.pos;
+                while reader.byte()? != 0 {}
+                let text = &reader.data[start..reader.pos - 1];
+                self.report.comments.push(String::from_utf8_lossy(text).into_owned());
+            }
+            XWAIT => {
+                let wait_state = reader.state()?;
+                let end_state = reader.state()?;
+                let usecs = reader.number(4)?;
+                if !matches!(wait_state, JtagState::Reset | JtagState::Idle |
+                             JtagState::PauseDR | JtagState::PauseIR) {
+                    return Err(format!("can't wait in {:?}", wait_state));
+                }
+                sm.change_mode(wait_state);
+                svf::wait(sm, wait_state, usecs as u64, usecs as f64 * 1e-6);
+                sm.change_mode(end_state);
+            }
+            XSETSDRMASKS => {
+                let address = reader.value(self.sdr_size)?;
+                let data = reader.value(self.sdr_size)?;
+                self.sdr_masks = Some((address, data));
+            }
+            XSDRINC => {
+                let (address_mask, data_mask) = self.sdr_masks.clone()
+                    .ok_or_else(|| "XSDRINC without XSETSDRMASKS".to_string())?;
+                let expected = self.tdo_expected.clone();
+                let mut tdi = reader.value(self.sdr_size)?;
+                self.shift_dr(sm, &tdi, expected.as_ref())?;
+
+                let count = reader.byte()?;
+                for _ in 0..count {
+                    let data = reader.value(data_mask.count_ones())?;
+                    tdi = add(&tdi, &address_mask);
+                    // The data fills the bits of the data mask from the least significant up
+                    let fill = (0..tdi.len()).filter(|i| data_mask.get(*i));
+                    for (bit, i) in fill.enumerate() {
+                        tdi.set(i, data.get(bit));
+                    }
+                    self.shift_dr(sm, &tdi, expected.as_ref())?;
+                }
+            }
+            other => return Err(format!("unknown command {:#04x}", other)),
+        }
+        Ok(true)
+    }
+
+    // Shift a whole data register, retrying on a mismatch with `expected`
+    fn shift_dr<T, U>(&mut self, sm: &mut JtagSM<T>, tdi: &BitVec, expected: Option<&BitVec>)
+        -> Result<(), String>
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        let end = sm.end_state(Register::Data);
+        let expected = match expected {
+            Some(expected) if !tdi.is_empty() => expected,
+            _ => {
+                if !tdi.is_empty() {
+                    sm.write_reg_end(Register::Data, tdi, end);
+                }
+                self.wait_after_shift(sm, self.run_test);
+                return Ok(());
+            }
+        };
+        let mask = self.mask();
+
+        let mut run_test = self.run_test;
+        let mut attempt = 0;
+        loop {
+            let captured = sm.read_write_reg_end(Register::Data, tdi, end);
+            if captured.masked_eq(expected, &mask) {
+                break;
+            }
+            if attempt >= self.repeat {
+                return Err(format!("TDO mismatch after {} attempts: captured {}, expected {} with mask {}",
+                                   attempt + 1, captured, expected, mask));
+            }
+            attempt += 1;
+            self.report.retries += 1;
+            run_test += run_test / 4;
+            // The device gets the longer wait to finish with the data before it is captured again
+            sm.change_mode(JtagState::Idle);
+            self.wait_after_shift(sm, run_test);
+        }
+        self.wait_after_shift(sm, run_test);
+        Ok(())
+    }
+
+    // One of XSDRB/C/E or XSDRTDOB/C/E, which shift a register in pieces while staying in
+    // Shift-DR, and aren't retried
+    fn shift_segment<T, U>(&mut self, sm: &mut JtagSM<T>, command: u8, tdi: &BitVec,
+                           expected: Option<&BitVec>) -> Result<(), String>
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        let last = command == XSDRE || command == XSDRTDOE;
+        let end = if last { sm.end_state(Register::Data) } else { JtagState::ShiftDR };
+        if tdi.is_empty() {
+            if last {
+                sm.change_mode(end);
+            }
+            return Ok(());
+        }
+        match expected {
+            Some(expected) => {
+                let captured = sm.read_write_reg_end(Register::Data, tdi, end);
+                let mask = self.mask();
+                if !captured.masked_eq(expected, &mask) {
+                    return Err(format!("TDO mismatch: captured {}, expected {} with mask {}",
+                                       captured, expected, mask));
+                }
+            }
+            None => sm.write_reg_end(Register::Data, tdi, end),
+        }
+        Ok(())
+    }
+
+    // Wait in Run-Test/Idle after a scan if the file asks for it
+    fn wait_after_shift<T, U>(&self, sm: &mut JtagSM<T>, usecs: u32)
+        where T: std::ops::DerefMut<Target=U>,
+              U: Cable + ?Sized
+    {
+        if usecs > 0 {
+            sm.change_mode(JtagState::Idle);
+            svf::wait(sm, JtagState::Idle, usecs as u64, usecs as f64 * 1e-6);
+        }
+    }
+}
+
+/// Play the contents of an XSVF file, stopping at XCOMPLETE or the end of the data.  Errors,
+/// including TDO mismatches once the retries run out, give the offset of the command in the file.
+pub fn play<T, U>(sm: &mut JtagSM<T>, data: &[u8]) -> Result<Report, String>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    let mut player = Player {
+        sdr_size: 0,
+        tdo_mask: None,
+        tdo_expected: None,
+        repeat: DEFAULT_REPEAT,
+        run_test: 0,
+        sdr_masks: None,
+        report: Report::default(),
+    };
+    let mut reader = Reader { data, pos: 0 };
+    while reader.pos < data.len() {
+        let start = reader.pos;
+        let more = player.execute(sm, &mut reader)
+            .map_err(|e| format!("command {:#04x} at offset {:#x}: {}", data[start], start, e))?;
+        player.report.commands += 1;
+        if !more {
+            break;
+        }
+    }
+    sm.cable.flush();
+    Ok(player.report)
+}
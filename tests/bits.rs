@@ -0,0 +1,85 @@
+use jtag_taps::bits::BitVec;
+
+#[test]
+fn u64_round_trip() {
+    let bits = BitVec::from_u64(0x2eb, 10);
+    assert_eq!(bits.len(), 10);
+    assert_eq!(bits.as_bytes(), &[0xeb, 0x02]);
+    assert_eq!(bits.to_u64(), Some(0x2eb));
+    assert!(bits.get(0));
+    assert!(!bits.get(2));
+    assert!(bits.get(9));
+
+    assert_eq!(BitVec::from_u64(u64::MAX, 64).to_u64(), Some(u64::MAX));
+    assert_eq!(BitVec::zeros(65).to_u64(), None);
+}
+
+#[test]
+#[should_panic]
+fn u64_too_wide() {
+    BitVec::from_u64(0x400, 10);
+}
+
+#[test]
+fn from_bytes_masks_unused_bits() {
+    let bits = BitVec::from_bytes(&[0xff, 0xff, 0xff], 12);
+    assert_eq!(bits.as_bytes(), &[0xff, 0x0f]);
+    assert_eq!(bits, BitVec::ones(12));
+    assert_eq!(bits.count_ones(), 12);
+}
+
+#[test]
+fn hex_and_binary_strings() {
+    let bits = BitVec::from_hex("0x2eb", 10).unwrap();
+    assert_eq!(bits.to_u64(), Some(0x2eb));
+    assert_eq!(bits.to_hex(), "2eb");
+    assert_eq!(format!("{:x}", bits), "2eb");
+    assert_eq!(bits.to_bin(), "1011101011");
+    assert_eq!(format!("{:b}", bits), "1011101011");
+    assert_eq!(BitVec::from_bin("10_1110_1011").unwrap(), bits);
+
+    assert_eq!(BitVec::from_hex("0f", 4).unwrap(), BitVec::ones(4));
+    assert!(BitVec::from_hex("1f", 4).is_err());
+    assert!(BitVec::from_hex("0xg", 4).is_err());
+    assert!(BitVec::from_bin("102").is_err());
+}
+
+#[test]
+fn slice_and_concat() {
+    let low = BitVec::from_u64(0x5, 3);
+    let high = BitVec::from_u64(0x1ff, 9);
+    let both = low.concat(&high);
+    assert_eq!(both.len(), 12);
+    assert_eq!(both.to_u64(), Some(0x1ff << 3 | 0x5));
+    assert_eq!(both.slice(0..3), low);
+    assert_eq!(both.slice(3..12), high);
+    assert!(both.slice(5..5).is_empty());
+
+    let aligned = BitVec::from_u64(0xa5, 8).concat(&low);
+    assert_eq!(aligned.to_u64(), Some(0x5a5));
+}
+
+#[test]
+fn push_and_collect() {
+    let mut bits = BitVec::new();
+    for i in 0..20 {
+        bits.push(i % 3 == 0);
+    }
+    let collected: BitVec = bits.iter().collect();
+    assert_eq!(collected, bits);
+    assert_eq!(bits.count_ones(), 7);
+}
+
+#[test]
+fn masked_compare() {
+    let captured = BitVec::from_u64(0b1010_0110, 8);
+    let expected = BitVec::from_u64(0b1001_0110, 8);
+    assert!(captured.masked_eq(&expected, &BitVec::from_u64(0b1000_1111, 8)));
+    assert!(!captured.masked_eq(&expected, &BitVec::ones(8)));
+    // Only the bits all three have are compared
+    assert!(captured.slice(0..4).masked_eq(&expected, &BitVec::ones(8)));
+    assert!(captured.masked_eq(&expected.slice(0..4), &BitVec::ones(12)));
+    assert!(!captured.masked_eq(&expected, &BitVec::ones(6)));
+    assert_eq!(captured.masked_diff(&expected, &BitVec::ones(8)), [4, 5]);
+    assert_eq!(captured.masked_diff(&expected, &BitVec::from_u64(0b0010_0000, 8)), [5]);
+}
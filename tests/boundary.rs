@@ -0,0 +1,102 @@
+use jtag_taps::boundary::BoundaryScan;
+use jtag_taps::cable::sim::{SimChain, SimRegister, SimTap};
+use jtag_taps::statemachine::JtagSM;
+use jtag_taps::taps::Taps;
+
+mod common;
+use common::fixture;
+
+fn mcu() -> BoundaryScan {
+    BoundaryScan::new(fixture("demo_mcu.bsd"), 0).unwrap()
+}
+
+#[test]
+fn starts_with_safe_values() {
+    let bs = mcu();
+    // Every control cell has a safe value of 1, which disables its output
+    assert_eq!(bs.cells().to_bin(), "010100100100");
+    assert!(bs.captured().is_none());
+}
+
+#[test]
+fn drive_and_tristate() {
+    let mut bs = mcu();
+    bs.drive("PA1", true).unwrap();
+    assert!(bs.cells().get(4));
+    assert!(!bs.cells().get(5));
+
+    bs.drive("pb0", false).unwrap();
+    assert!(!bs.cells().get(9));
+    assert!(!bs.cells().get(10));
+
+    bs.tristate("PA1").unwrap();
+    assert!(bs.cells().get(5));
+    // The data cell keeps its value, ready for the next drive
+    assert!(bs.cells().get(4));
+
+    bs.release("PA1").unwrap();
+    bs.release("PB0").unwrap();
+    assert_eq!(bs.cells(), mcu().cells());
+}
+
+#[test]
+fn refuses_input_only_pins() {
+    let mut bs = mcu();
+    assert!(bs.drive("BOOT0", true).unwrap_err().contains("input-only"));
+    assert!(bs.tristate("BOOT0").is_err());
+    assert!(bs.drive("NRST", true).is_err());
+    assert_eq!(bs.cells(), mcu().cells());
+}
+
+#[test]
+fn pins_need_a_scan() {
+    let bs = mcu();
+    assert!(bs.pin("PA0").is_err());
+    assert!(bs.pin("VDD").is_err());
+}
+
+#[test]
+fn sample_and_extest_through_taps() {
+    // The MCU sits behind a TAP in BYPASS, with PA1 wired to PA2
+    let mut chain = SimChain::new(vec![SimTap::new(4, None), SimTap::from_bsdl(&fixture("demo_mcu.bsd"))]);
+    chain.add_net("LOOP", &[(1, "PA1"), (1, "PA2")]);
+    let mut taps = Taps::new(JtagSM::new(Box::new(chain)));
+    taps.add_tap(4);
+    taps.add_tap(5);
+    let mut bs = BoundaryScan::new(fixture("demo_mcu.bsd"), 1).unwrap();
+    assert!(!bs.in_extest(&taps));
+
+    // Nothing drives the net, so it floats high
+    bs.sample(&mut taps).unwrap();
+    assert_eq!(taps.sm.cable.taps()[1].selected(), SimRegister::Sample);
+    assert!(bs.pin("PA2").unwrap());
+
+    // PRELOAD doesn't drive the pins
+    bs.drive("PA1", false).unwrap();
+    bs.preload(&mut taps).unwrap();
+    assert!(!bs.in_extest(&taps));
+    bs.sample(&mut taps).unwrap();
+    assert!(bs.pin("PA2").unwrap());
+
+    // EXTEST does, and the scan after it sees the result
+    bs.apply(&mut taps).unwrap();
+    assert!(bs.in_extest(&taps));
+    let selected: Vec<SimRegister> = taps.sm.cable.taps().iter().map(|t| t.selected()).collect();
+    assert_eq!(selected, [SimRegister::Bypass, SimRegister::Extest]);
+    bs.apply(&mut taps).unwrap();
+    assert!(!bs.pin("PA2").unwrap());
+    assert!(bs.preload(&mut taps).is_err());
+
+    // A reset takes the pins out of EXTEST, and SAMPLE goes back in rather than EXTEST
+    taps.reset();
+    assert!(!bs.in_extest(&taps));
+    bs.sample(&mut taps).unwrap();
+    assert_eq!(taps.sm.cable.taps()[1].selected(), SimRegister::Sample);
+    assert!(bs.pin("PA2").unwrap());
+
+    bs.apply(&mut taps).unwrap();
+    assert!(bs.in_extest(&taps));
+    assert!(taps.sm.trst());
+    assert!(!bs.in_extest(&taps));
+    bs.preload(&mut taps).unwrap();
+}
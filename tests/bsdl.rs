@@ -0,0 +1,146 @@
+use jtag_taps::bits::BitVec;
+use jtag_taps::bsdl::{Bsdl, CellFunction, ControlCell, DisableResult, Pattern};
+
+mod common;
+use common::{fixture, fixture_path};
+
+#[test]
+fn mcu_test_logic() {
+    let bsdl = fixture("demo_mcu.bsd");
+    assert_eq!(bsdl.entity, "DEMO_MCU_LQFP32");
+    assert_eq!(bsdl.irlen, 5);
+    assert_eq!(bsdl.instructions.len(), 5);
+    assert_eq!(bsdl.instruction("bypass"), Some(&BitVec::ones(5)));
+    assert_eq!(bsdl.instruction("SAMPLE"), Some(&BitVec::from_u64(0b00010, 5)));
+    assert_eq!(bsdl.instruction("PRELOAD"), Some(&BitVec::from_u64(0b00010, 5)));
+    assert_eq!(bsdl.instruction("USER1"), None);
+
+    let capture = bsdl.ir_capture.as_ref().unwrap();
+    assert!(capture.matches(&BitVec::from_u64(0b11001, 5)));
+    assert!(!capture.matches(&BitVec::from_u64(0b11011, 5)));
+
+    let idcode = bsdl.idcode.as_ref().unwrap();
+    assert_eq!(idcode.value.to_u64(), Some(0x06410041));
+    assert_eq!(idcode.mask.to_u64(), Some(0x0fffffff));
+    assert!(idcode.matches(&BitVec::from_u64(0x16410041, 32)));
+    assert_eq!(bsdl.usercode, None);
+
+    let boundary = bsdl.register_for("EXTEST").unwrap();
+    assert_eq!(boundary.register, "BOUNDARY");
+    assert_eq!(boundary.length, None);
+    assert_eq!(bsdl.register_for("IDCODE").unwrap().register, "DEVICE_ID");
+}
+
+#[test]
+fn mcu_boundary_register() {
+    let bsdl = fixture("demo_mcu.bsd");
+    assert_eq!(bsdl.boundary_length, 12);
+    assert_eq!(bsdl.boundary.len(), 12);
+    for (i, cell) in bsdl.boundary.iter().enumerate() {
+        assert_eq!(cell.number, i);
+    }
+
+    let observe = &bsdl.boundary[0];
+    assert_eq!(observe.cell_type, "BC_4");
+    assert_eq!(observe.port.as_deref(), Some("PA0"));
+    assert_eq!(observe.function, CellFunction::ObserveOnly);
+
+    let pb0 = &bsdl.boundary[9];
+    assert_eq!(pb0.port.as_deref(), Some("PB0"));
+    assert_eq!(pb0.function, CellFunction::Output3);
+    assert_eq!(pb0.safe, None);
+    assert_eq!(pb0.control, Some(ControlCell {
+        cell: 10,
+        disable_value: true,
+        disable_result: DisableResult::Z,
+    }));
+
+    let control = &bsdl.boundary[10];
+    assert_eq!(control.port, None);
+    assert_eq!(control.function, CellFunction::Control);
+    assert_eq!(control.safe, Some(true));
+}
+
+#[test]
+fn mcu_pin_map() {
+    let bsdl = fixture("demo_mcu.bsd");
+    assert_eq!(bsdl.pins("PA1"), Some(&["7".to_string()][..]));
+    assert_eq!(bsdl.pins("vdd"), Some(&["1".to_string(), "17".to_string()][..]));
+    assert_eq!(bsdl.pins("PC13"), None);
+}
+
+#[test]
+fn cpld_test_logic() {
+    let bsdl = fixture("demo_cpld.bsdl");
+    assert_eq!(bsdl.entity, "demo_cpld_vq44");
+    assert_eq!(bsdl.irlen, 8);
+
+    let ispex = bsdl.instructions.iter().find(|i| i.name == "ISPEX").unwrap();
+    assert_eq!(ispex.opcodes, vec![BitVec::from_u64(0xf0, 8), BitVec::from_u64(0xf1, 8)]);
+    assert_eq!(bsdl.instruction("IDCODE"), Some(&BitVec::from_u64(0xfe, 8)));
+
+    assert_eq!(bsdl.idcode.as_ref().unwrap().value.to_u64(), Some(0x09602093));
+    assert_eq!(bsdl.usercode.as_ref().unwrap().mask, BitVec::zeros(32));
+
+    let status = bsdl.register_for("ISPEX").unwrap();
+    assert_eq!(status.register, "ISPSTATUS");
+    assert_eq!(status.length, Some(2));
+    assert_eq!(bsdl.register_for("CLAMP").unwrap().register, "BYPASS");
+}
+
+#[test]
+fn cpld_boundary_register() {
+    let bsdl = fixture("demo_cpld.bsdl");
+    assert_eq!(bsdl.boundary_length, 13);
+    // Cell 0 is both a control cell and an observe-only input
+    assert_eq!(bsdl.boundary.len(), 14);
+    assert_eq!(bsdl.boundary[0].function, CellFunction::Control);
+    assert_eq!(bsdl.boundary[1].number, 0);
+    assert_eq!(bsdl.boundary[1].function, CellFunction::ObserveOnly);
+
+    let io1 = &bsdl.boundary[2];
+    assert_eq!(io1.port.as_deref(), Some("IO(1)"));
+    assert_eq!(io1.function, CellFunction::Bidir);
+    assert_eq!(io1.control.unwrap().cell, 0);
+
+    let io2 = bsdl.boundary.iter().find(|c| c.number == 3).unwrap();
+    assert_eq!(io2.control.unwrap().disable_result, DisableResult::Weak1);
+    assert_eq!(bsdl.boundary.iter().find(|c| c.number == 8).unwrap().function, CellFunction::ControlR);
+}
+
+#[test]
+fn cpld_default_pin_map() {
+    let bsdl = fixture("demo_cpld.bsdl");
+    // VQ44 is the default package, even though PC44 is listed first
+    assert_eq!(bsdl.pins("TDO"), Some(&["24".to_string()][..]));
+    assert_eq!(bsdl.pins("IO").unwrap().len(), 4);
+    assert_eq!(bsdl.pins("IO").unwrap()[3], "42");
+}
+
+#[test]
+fn errors() {
+    let text = std::fs::read_to_string(fixture_path("demo_mcu.bsd")).unwrap();
+
+    let bad_length = text.replace("entity is 12;", "entity is 13;");
+    assert!(Bsdl::parse(&bad_length).is_err());
+
+    let bad_opcode = text.replace("IDCODE  (00001)", "IDCODE  (0001)");
+    assert!(Bsdl::parse(&bad_opcode).unwrap_err().contains("IDCODE"));
+
+    let bad_function = text.replace("observe_only", "observe");
+    assert!(Bsdl::parse(&bad_function).is_err());
+
+    assert!(Bsdl::parse("-- nothing here").is_err());
+}
+
+#[test]
+fn patterns() {
+    let pattern = Pattern::parse("1x0_1").unwrap();
+    assert_eq!(pattern.len(), 4);
+    assert_eq!(pattern.value.to_u64(), Some(0b1001));
+    assert_eq!(pattern.mask.to_u64(), Some(0b1011));
+    assert!(pattern.matches(&BitVec::from_u64(0b1101, 4)));
+    assert!(!pattern.matches(&BitVec::from_u64(0b1000, 4)));
+    assert!(!pattern.matches(&BitVec::from_u64(0b1001, 5)));
+    assert!(Pattern::parse("10Z").is_err());
+}
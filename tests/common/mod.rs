@@ -0,0 +1,49 @@
+//! Helpers shared by the integration tests.  Each test crate only uses some of them.
+#![allow(dead_code)]
+
+use jtag_taps::bits::BitVec;
+use jtag_taps::bsdl::Bsdl;
+use jtag_taps::cable::sim::{SimChain, SimRegister, SimTap};
+use jtag_taps::statemachine::JtagSM;
+use jtag_taps::taps::Taps;
+
+/// The instruction that selects IDCODE in the TAPs made by `chain`
+pub const IDCODE: u64 = 0xe;
+
+/// Path of the file called `name` in tests/fixtures
+pub fn fixture_path(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// Parse the BSDL file called `name` in tests/fixtures
+pub fn fixture(name: &str) -> Bsdl {
+    Bsdl::from_file(fixture_path(name)).unwrap()
+}
+
+/// The demo MCU closest to TDI, then a 4 bit TAP with the IDCODE 0x0ba00477 whose instruction
+/// 1110 selects `register`
+pub fn mcu_chain(register: SimRegister) -> JtagSM<Box<SimChain>> {
+    let mcu = SimTap::from_bsdl(&fixture("demo_mcu.bsd"));
+    let other = SimTap::new(4, Some(0x0ba00477))
+        .with_instruction(BitVec::from_u64(IDCODE, 4), register);
+    JtagSM::new(Box::new(SimChain::new(vec![mcu, other])))
+}
+
+/// `count` simulated TAPs with 4 bit instruction registers, where 1110 selects IDCODE.  TAP `i`
+/// has the IDCODE 0x0ba00477 with `first_version + i` in the version field.
+pub fn sim_chain(count: usize, first_version: u32) -> Box<SimChain> {
+    let taps = (0..count)
+        .map(|i| SimTap::new(4, Some(0x0ba00477 | (first_version + i as u32) << 28))
+             .with_instruction(BitVec::from_u64(IDCODE, 4), SimRegister::Idcode))
+        .collect();
+    Box::new(SimChain::new(taps))
+}
+
+/// The TAPs of `sim_chain(count, 0)`, added to a `Taps`
+pub fn chain(count: usize) -> Taps<Box<SimChain>> {
+    let mut taps = Taps::new(JtagSM::new(sim_chain(count, 0)));
+    for _ in 0..count {
+        taps.add_tap(4);
+    }
+    taps
+}
@@ -0,0 +1,101 @@
+use jtag_taps::bits::BitVec;
+use jtag_taps::cable::sim::{SimChain, SimTap};
+use jtag_taps::config::ChainConfig;
+use jtag_taps::statemachine::JtagSM;
+use jtag_taps::taps::{SelectMode, Taps};
+
+mod common;
+use common::{fixture, fixture_path};
+
+fn board() -> Taps<Box<SimChain>> {
+    let chain = SimChain::new(vec![
+        SimTap::from_bsdl(&fixture("demo_mcu.bsd")),
+        SimTap::new(3, None),
+        SimTap::from_bsdl(&fixture("demo_cpld.bsdl")),
+    ]);
+    Taps::new(JtagSM::new(Box::new(chain)))
+}
+
+#[test]
+fn configure_from_toml() {
+    let config = ChainConfig::from_file(fixture_path("board.toml")).unwrap();
+    assert_eq!(config.cable.as_deref(), Some("jtagkey"));
+    assert_eq!(config.clock, Some(6_000_000));
+
+    let mut taps = board();
+    config.configure(&mut taps).unwrap();
+    assert_eq!(taps.select_mode(), SelectMode::Preserve);
+    assert_eq!(taps.find("cpld").unwrap(), 2);
+
+    let debug = &taps.taps()[1].instructions;
+    assert_eq!(debug.opcode("DPACC").unwrap(), &BitVec::from_u64(0b010, 3));
+    assert_eq!(debug.get("APACC").unwrap().dr_len, Some(35));
+    assert!(taps.taps()[0].instructions.opcode("SAMPLE").is_ok());
+
+    taps.select("mcu", "IDCODE").unwrap();
+    assert_eq!(taps.read_dr(32).to_u64(), Some(0x06410041));
+}
+
+#[test]
+fn configure_from_json() {
+    let json = r#"{
+        "select": "reset",
+        "taps": [
+            { "name": "mcu", "irlen": 5, "idcode": "0x06410041" },
+            { "name": "debug", "irlen": 3 },
+            { "name": "cpld", "irlen": 8, "idcode": 157294739,
+              "instructions": { "IDCODE": "11111110" } }
+        ]
+    }"#;
+    let config = ChainConfig::from_json(json).unwrap();
+    let mut taps = board();
+    config.configure(&mut taps).unwrap();
+    assert_eq!(taps.select_mode(), SelectMode::Reset);
+    taps.select("cpld", "IDCODE").unwrap();
+    assert_eq!(taps.read_dr(32).to_u64(), Some(0x09602093));
+}
+
+#[test]
+fn chain_mismatch() {
+    let configure = |toml: &str| ChainConfig::from_toml(toml).unwrap().configure(&mut board());
+
+    let err = configure("[[tap]]\nname = \"mcu\"\nirlen = 5\n").unwrap_err();
+    assert!(err.contains("expected 1 TAPs, but found 3"), "{}", err);
+
+    let wrong_irlen = r#"
+        tap = [{ name = "mcu", irlen = 4 }, { name = "debug", irlen = 3 }, { name = "cpld", irlen = 8 }]
+    "#;
+    assert!(configure(wrong_irlen).unwrap_err().contains("4"));
+
+    // Only the masked bits of the IDCODE have to match
+    let idcode = |code: &str| format!(r#"
+        tap = [{{ name = "mcu", irlen = 5 }}, {{ name = "debug", irlen = 3 }},
+               {{ name = "cpld", irlen = 8, idcode = {}, idcode_mask = 0xffff }}]
+    "#, code);
+    assert!(configure(&idcode("0xf0002093")).is_ok());
+    assert!(configure(&idcode("0x09602094")).unwrap_err().contains("IDCODE 09602093"));
+}
+
+#[test]
+fn invalid_descriptions() {
+    assert!(ChainConfig::from_toml("[[tap]]\nname = \"x\"\nirlength = 4\n").is_err());
+    assert!(ChainConfig::from_toml("select = \"sometimes\"\n").unwrap().select_mode().is_err());
+
+    let mut taps = board();
+    let config = ChainConfig::from_toml("[[tap]]\nname = \"x\"\n").unwrap();
+    assert!(config.declare(&mut taps).unwrap_err().contains("no IR length"));
+    let config = ChainConfig::from_toml("[[tap]]\nname = \"x\"\nirlen = 3\ninstructions = { A = 0x10 }\n").unwrap();
+    assert!(config.declare(&mut taps).unwrap_err().contains("fit"));
+}
+
+#[test]
+fn declare_without_detecting() {
+    let config = ChainConfig::from_file(fixture_path("board.toml")).unwrap();
+    let mut taps = board();
+    config.declare(&mut taps).unwrap();
+    let irlens: Vec<usize> = taps.taps().iter().map(|t| t.irlen).collect();
+    assert_eq!(irlens, [5, 3, 8]);
+    assert_eq!(taps.taps()[2].name.as_deref(), Some("cpld"));
+    taps.select("cpld", "IDCODE").unwrap();
+    assert_eq!(taps.read_dr(32).to_u64(), Some(0x09602093));
+}
@@ -0,0 +1,52 @@
+use jtag_taps::idcode::{Database, Device, IdCode};
+
+#[test]
+fn fields() {
+    let id = IdCode(0x3362d093);
+    assert_eq!(id.version(), 3);
+    assert_eq!(id.part(), 0x362d);
+    assert_eq!(id.bank(), 0);
+    assert_eq!(id.manufacturer_id(), 0x49);
+    assert_eq!(id.manufacturer(), Some("Xilinx"));
+    assert!(id.is_valid());
+    assert_eq!(id.to_string(), "3362d093");
+}
+
+#[test]
+fn manufacturers_outside_the_first_bank() {
+    // ARM is in bank 5
+    let id = IdCode(0x4ba00477);
+    assert_eq!(id.bank(), 4);
+    assert_eq!(id.manufacturer_id(), 0x3b);
+    assert_eq!(id.manufacturer(), Some("ARM Ltd"));
+}
+
+#[test]
+fn invalid_idcodes() {
+    assert!(!IdCode(0x0362d092).is_valid());
+    assert!(!IdCode(0xffffffff).is_valid());
+}
+
+#[test]
+fn builtin_lookup() {
+    let db = Database::builtin();
+    let device = db.lookup(IdCode(0x3362d093)).unwrap();
+    assert_eq!(device.name, "XC7A35T");
+    assert_eq!(device.irlen, 6);
+    assert_eq!(device.instruction("IDCODE"), Some(0x09));
+    assert_eq!(device.instruction("NOPE"), None);
+    assert_eq!(IdCode(0x3362d093).describe(&db), "Xilinx XC7A35T rev 3");
+
+    // The version nibble is part of the ECP5 IDCODE
+    assert_eq!(db.lookup(IdCode(0x41112043)).unwrap().name, "LFE5U-45F");
+    assert!(db.lookup(IdCode(0x51112043)).is_none());
+
+    assert_eq!(IdCode(0x1234509b).describe(&db), "ABLIC part 2345 rev 1");
+}
+
+#[test]
+fn later_devices_take_priority() {
+    let mut db = Database::builtin();
+    db.add(Device::new("board FPGA", 0x0362d093, 0x0fffffff, 6, &[("USER1", 0x02)]));
+    assert_eq!(db.lookup(IdCode(0x1362d093)).unwrap().name, "board FPGA");
+}
@@ -0,0 +1,73 @@
+use jtag_taps::bits::BitVec;
+use jtag_taps::cable::sim::{SimChain, SimRegister, SimTap};
+use jtag_taps::idcode::{Database, IdCode};
+use jtag_taps::instruction::InstructionSet;
+use jtag_taps::statemachine::JtagSM;
+use jtag_taps::taps::Taps;
+
+mod common;
+use common::fixture;
+
+#[test]
+fn standard_instructions() {
+    let mut set = InstructionSet::new(6);
+    assert_eq!(set.opcode("bypass"), Ok(&BitVec::ones(6)));
+    assert_eq!(set.get("BYPASS").unwrap().dr_len, Some(1));
+
+    set.add("IDCODE", BitVec::from_u64(0x09, 6), None).unwrap();
+    set.add("USER1", BitVec::from_u64(0x02, 6), Some(64)).unwrap();
+    assert_eq!(set.get("idcode").unwrap().dr_len, Some(32));
+    assert_eq!(set.get("USER1").unwrap().dr_len, Some(64));
+    assert_eq!(set.name_of(&BitVec::from_u64(0x02, 6)), Some("USER1"));
+
+    assert!(set.add("USER2", BitVec::from_u64(0x03, 5), None).is_err());
+    assert!(set.opcode("USER2").unwrap_err().contains("USER1"));
+}
+
+#[test]
+fn from_bsdl_and_database() {
+    let set = InstructionSet::from_bsdl(&fixture("demo_mcu.bsd"));
+    assert_eq!(set.irlen(), 5);
+    assert_eq!(set.get("EXTEST").unwrap().dr_len, Some(12));
+    assert_eq!(set.get("SAMPLE").unwrap().opcode, BitVec::from_u64(0b00010, 5));
+    assert_eq!(set.get("IDCODE").unwrap().dr_len, Some(32));
+
+    let cpld = InstructionSet::from_bsdl(&fixture("demo_cpld.bsdl"));
+    assert_eq!(cpld.get("ISPEX").unwrap().dr_len, Some(2));
+    assert_eq!(cpld.get("CLAMP").unwrap().dr_len, Some(1));
+
+    let db = Database::builtin();
+    let set = InstructionSet::from_device(db.lookup(IdCode(0x0362d093)).unwrap());
+    assert_eq!(set.opcode("USER1"), Ok(&BitVec::from_u64(0x02, 6)));
+    assert_eq!(set.get("USER1").unwrap().dr_len, None);
+}
+
+#[test]
+fn select_by_name() {
+    let chain = SimChain::new(vec![
+        SimTap::from_bsdl(&fixture("demo_mcu.bsd")),
+        SimTap::from_bsdl(&fixture("demo_cpld.bsdl")),
+    ]);
+    let mut taps = Taps::new(JtagSM::new(Box::new(chain)));
+    let found = taps.detect().unwrap();
+    assert_eq!(found[0].name.as_deref(), Some("STM32F1 medium density"));
+    assert_eq!(found[1].name.as_deref(), Some("XC9536XL"));
+
+    taps.set_name(1, "cpld");
+    taps.select("cpld", "SAMPLE").unwrap();
+    assert_eq!(taps.sm.cable.taps()[1].selected(), SimRegister::Sample);
+    assert_eq!(taps.sm.cable.taps()[0].selected(), SimRegister::Bypass);
+    taps.write_ir_named("idcode").unwrap();
+    assert_eq!(taps.read_dr(32).to_u64(), Some(0x09602093));
+
+    assert!(taps.select("cpld", "USER1").is_err());
+    assert!(taps.select("fpga", "BYPASS").is_err());
+    assert!(taps.write_ir_named("JPROGRAM").is_err());
+
+    // Instructions from BSDL replace the ones from the database
+    taps.load_bsdl(0, &fixture("demo_mcu.bsd")).unwrap();
+    assert_eq!(taps.taps()[0].name.as_deref(), Some("STM32F1 medium density"));
+    assert!(taps.load_bsdl(1, &fixture("demo_mcu.bsd")).is_err());
+    taps.set_name(0, "cpld");
+    assert!(taps.select("cpld", "BYPASS").unwrap_err().contains("more than one"));
+}
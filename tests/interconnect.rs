@@ -0,0 +1,124 @@
+use jtag_taps::bits::BitVec;
+use jtag_taps::boundary::BoundaryScan;
+use jtag_taps::cable::sim::{SimChain, SimFault, SimTap};
+use jtag_taps::interconnect::{self, Algorithm, Fault, Netlist, Pin};
+use jtag_taps::statemachine::JtagSM;
+use jtag_taps::taps::Taps;
+
+mod common;
+use common::fixture;
+
+const NETS: &[(&str, &[(usize, &str)])] = &[
+    ("N0", &[(0, "PA0"), (1, "IO(1)")]),
+    ("N1", &[(0, "PA1"), (1, "IO(2)")]),
+    ("N2", &[(0, "PA2"), (1, "IO(3)")]),
+    ("N3", &[(1, "IO(4)"), (0, "BOOT0")]),
+    ("CLK", &[(0, "PB0"), (1, "GCK")]),
+];
+
+// The MCU is closest to TDI, followed by the CPLD, plus a TAP that isn't under test
+fn board(nets: &[(&str, &[(usize, &str)])], faults: &[SimFault])
+    -> (Taps<Box<SimChain>>, Vec<BoundaryScan>, Netlist)
+{
+    let mcu = fixture("demo_mcu.bsd");
+    let cpld = fixture("demo_cpld.bsdl");
+    let mut chain = SimChain::new(vec![
+        SimTap::from_bsdl(&mcu),
+        SimTap::from_bsdl(&cpld),
+        SimTap::new(4, Some(0x4ba00477)),
+    ]);
+    let mut netlist = Netlist::new();
+    for (name, pins) in nets {
+        chain.add_net(name, pins);
+        netlist.add_net(name, pins);
+    }
+    netlist.add_net("UNTESTED", &[(0, "BOOT0"), (2, "SWDIO")]);
+    for fault in faults {
+        chain.inject(fault.clone());
+    }
+
+    let mut taps = Taps::new(JtagSM::new(Box::new(chain)));
+    taps.add_tap(5);
+    taps.add_tap(8);
+    taps.add_tap(4);
+    let devices = vec![BoundaryScan::new(mcu, 0).unwrap(), BoundaryScan::new(cpld, 1).unwrap()];
+    (taps, devices, netlist)
+}
+
+fn run(faults: &[SimFault], algorithm: Algorithm) -> interconnect::Report {
+    let (mut taps, mut devices, netlist) = board(NETS, faults);
+    let before: Vec<BitVec> = devices.iter().map(|d| d.cells().clone()).collect();
+    let report = interconnect::run(&mut taps, &mut devices, &netlist, algorithm).unwrap();
+    let after: Vec<BitVec> = devices.iter().map(|d| d.cells().clone()).collect();
+    assert_eq!(before, after);
+    assert_eq!(report.untested, vec!["UNTESTED".to_string()]);
+    report
+}
+
+#[test]
+fn vectors() {
+    let counting = interconnect::vectors(Algorithm::CountingSequence, 5);
+    // Net 0 is the least significant bit
+    let codes: Vec<String> = counting.iter().map(|v| v.to_bin()).collect();
+    assert_eq!(codes, ["10101", "00110", "11000", "01010", "11001", "00111"]);
+
+    let walking = interconnect::vectors(Algorithm::WalkingOnes, 3);
+    assert_eq!(walking, vec![BitVec::from_u64(1, 3), BitVec::from_u64(2, 3), BitVec::from_u64(4, 3)]);
+    assert!(interconnect::vectors(Algorithm::CountingSequence, 0).is_empty());
+}
+
+#[test]
+fn good_board_passes() {
+    let report = run(&[], Algorithm::CountingSequence);
+    assert_eq!(report.vectors, 6);
+    assert!(report.passed(), "{:?}", report.faults);
+
+    let report = run(&[], Algorithm::WalkingOnes);
+    assert_eq!(report.vectors, 5);
+    assert!(report.passed(), "{:?}", report.faults);
+}
+
+#[test]
+fn stuck_at() {
+    let report = run(&[SimFault::StuckAt { net: "N1".to_string(), value: false }], Algorithm::CountingSequence);
+    assert_eq!(report.faults, vec![Fault::StuckAt { net: "N1".to_string(), value: false }]);
+    assert_eq!(report.faults[0].to_string(), "N1 stuck at 0");
+}
+
+#[test]
+fn open() {
+    // With a second receiver on N3, an open on one of them can be located
+    let nets: &[(&str, &[(usize, &str)])] = &[("N3", &[(1, "IO(4)"), (0, "BOOT0"), (0, "PA2")])];
+    let (mut taps, mut devices, netlist) = board(nets, &[SimFault::Open { tap: 0, port: "BOOT0".to_string() }]);
+    let report = interconnect::run(&mut taps, &mut devices, &netlist, Algorithm::CountingSequence).unwrap();
+    assert_eq!(report.faults, vec![Fault::Open {
+        net: "N3".to_string(),
+        pins: vec![Pin { tap: 0, port: "BOOT0".to_string() }],
+    }]);
+    assert_eq!(report.faults[0].to_string(), "N3 open at BOOT0 on TAP 0");
+
+    // With only one receiver, an open reads as the pin floating high
+    let report = run(&[SimFault::Open { tap: 1, port: "IO(2)".to_string() }], Algorithm::CountingSequence);
+    assert_eq!(report.faults, vec![Fault::StuckAt { net: "N1".to_string(), value: true }]);
+}
+
+#[test]
+fn short() {
+    let fault = SimFault::Short("N0".to_string(), "CLK".to_string());
+    for algorithm in [Algorithm::CountingSequence, Algorithm::WalkingOnes] {
+        let report = run(std::slice::from_ref(&fault), algorithm);
+        assert_eq!(report.faults, vec![Fault::Short { nets: vec!["N0".to_string(), "CLK".to_string()] }]);
+    }
+}
+
+#[test]
+fn errors() {
+    let (mut taps, mut devices, mut netlist) = board(NETS, &[]);
+    netlist.add_net("BAD", &[(0, "PC13"), (1, "IO(1)")]);
+    assert!(interconnect::run(&mut taps, &mut devices, &netlist, Algorithm::WalkingOnes).unwrap_err().contains("PC13"));
+
+    let (mut taps, mut devices, netlist) = board(NETS, &[]);
+    let mcu = devices[0].bsdl().clone();
+    devices.push(BoundaryScan::new(mcu, 1).unwrap());
+    assert!(interconnect::run(&mut taps, &mut devices, &netlist, Algorithm::WalkingOnes).is_err());
+}
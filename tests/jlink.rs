@@ -0,0 +1,110 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use jtag_taps::bits::BitVec;
+use jtag_taps::cable::Cable;
+use jtag_taps::cable::jlink::{BulkTransfer, JLink};
+use jtag_taps::statemachine::{JtagSM, JtagState, Register, TapModel};
+
+// Most bytes of TMS (and of TDI) that fit in one EMU_CMD_HW_JTAG3 command
+const SEQUENCE_MAX: usize = 389;
+
+#[derive(Default)]
+struct Seen {
+    clocks: usize,
+    sequences: usize,
+    longest: usize,
+    tap: TapModel,
+}
+
+// Stands in for the adapter, answering the commands the driver sends.  TDO is looped back from
+// TDI.
+struct Adapter {
+    seen: Rc<RefCell<Seen>>,
+    responses: Vec<u8>,
+}
+
+impl BulkTransfer for Adapter {
+    fn read_bulk(&mut self, _endpoint: u8, buf: &mut [u8], _timeout: Duration) -> Result<usize, rusb::Error> {
+        let len = std::cmp::min(buf.len(), self.responses.len());
+        buf[..len].copy_from_slice(&self.responses[..len]);
+        self.responses.drain(..len);
+        Ok(len)
+    }
+
+    fn write_bulk(&mut self, _endpoint: u8, buf: &[u8], _timeout: Duration) -> Result<usize, rusb::Error> {
+        let mut seen = self.seen.borrow_mut();
+        let mut i = 0;
+        while i < buf.len() {
+            match buf[i] {
+                // Get status, with Vref at 3.3V
+                0x07 => {
+                    self.responses.extend([0xe4, 0x0c, 0, 0, 0, 0, 0, 0]);
+                    i += 1;
+                }
+                // Set clock
+                0x05 => i += 3,
+                // Select interface
+                0xc7 => {
+                    self.responses.extend([0; 4]);
+                    i += 2;
+                }
+                // Reset lines
+                0xdc..=0xdf => i += 1,
+                // Clock TMS and TDI
+                0xcd => {
+                    let bits = buf[i + 1] as usize | (buf[i + 2] as usize) << 8;
+                    let bytes = bits.div_ceil(8);
+                    assert!(bytes <= SEQUENCE_MAX, "{} bytes in one sequence", bytes);
+                    let tms = &buf[i + 3..i + 3 + bytes];
+                    let tdi = &buf[i + 3 + bytes..i + 3 + 2 * bytes];
+                    seen.tap.clock_bits(tms, bits);
+                    seen.clocks += bits;
+                    seen.sequences += 1;
+                    seen.longest = std::cmp::max(seen.longest, bytes);
+                    self.responses.extend_from_slice(tdi);
+                    i += 3 + 2 * bytes;
+                }
+                cmd => panic!("unexpected command {:02x}", cmd),
+            }
+        }
+        Ok(buf.len())
+    }
+}
+
+fn jlink() -> (JtagSM<Box<JLink<Adapter>>>, Rc<RefCell<Seen>>) {
+    let seen = Rc::new(RefCell::new(Seen::default()));
+    let adapter = Adapter {
+        seen: seen.clone(),
+        responses: vec![],
+    };
+    let cable = JLink::from_device(adapter, 0x81, 0x01, 1_000_000);
+    (JtagSM::new(Box::new(cable)), seen)
+}
+
+#[test]
+fn long_waits_fit_the_adapter_buffer() {
+    let (mut sm, seen) = jlink();
+    sm.change_mode(JtagState::Idle);
+    sm.cable.flush();
+    let start = seen.borrow().clocks;
+
+    // More clocks than fit in one command, both from run_test and from a delay at 1MHz
+    sm.run_test(10_000);
+    sm.run_test_for(Duration::from_millis(10));
+    sm.cable.flush();
+    {
+        let seen = seen.borrow();
+        assert_eq!(seen.clocks - start, 20_000);
+        assert!(seen.sequences > 2);
+        assert_eq!(seen.longest, SEQUENCE_MAX);
+        assert_eq!(seen.tap.state(), Some(JtagState::Idle));
+    }
+
+    // Reads still line up with the data after a long wait
+    sm.run_test(5_000);
+    let data = BitVec::from_u64(0x5a5a, 16);
+    assert_eq!(sm.read_write_reg_end(Register::Data, &data, JtagState::Idle), data);
+    assert_eq!(seen.borrow().tap.state(), Some(JtagState::Idle));
+}
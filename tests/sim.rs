@@ -0,0 +1,238 @@
+use jtag_taps::bits::BitVec;
+use jtag_taps::boundary::BoundaryScan;
+use jtag_taps::cable::sim::{SimChain, SimFault, SimRegister, SimTap};
+use jtag_taps::idcode::{Database, Device, IdCode};
+use jtag_taps::statemachine::{JtagSM, JtagState, Register};
+use jtag_taps::taps::Taps;
+
+mod common;
+use common::fixture;
+
+fn taps(chain: SimChain) -> Taps<Box<SimChain>> {
+    Taps::new(JtagSM::new(Box::new(chain)))
+}
+
+#[test]
+fn shift_vectors() {
+    let chain = SimChain::new(vec![SimTap::new(4, Some(0x0ba00477)), SimTap::new(5, None)]);
+    let mut sm = JtagSM::new(Box::new(chain));
+    sm.change_mode(JtagState::Idle);
+    let start = sm.cable.clocks();
+
+    // Into Shift-DR, then BYPASS and the IDCODE with TMS high on the last bit, then back to Idle
+    let mut tms: BitVec = [true, false, false].into_iter().collect();
+    tms.append(&BitVec::zeros(32));
+    tms.append(&[true, true, false].into_iter().collect());
+    let tdo = sm.shift_vectors(&tms, &BitVec::ones(38));
+    assert_eq!(sm.cable.clocks() - start, 38);
+    assert_eq!(sm.cable.state(), JtagState::Idle);
+    assert_eq!(sm.state(), JtagState::Idle);
+    assert!(!tdo.get(3));
+    assert_eq!(tdo.slice(4..36).to_u64(), Some(0x0ba00477));
+
+    // The state machine followed the vectors
+    sm.change_mode(JtagState::ShiftIR);
+    assert_eq!(sm.cable.state(), JtagState::ShiftIR);
+}
+
+#[test]
+fn end_states() {
+    let tap = SimTap::new(4, Some(0x0ba00477))
+        .with_instruction(BitVec::from_u64(0xe, 4), SimRegister::Idcode);
+    let mut sm = JtagSM::new(Box::new(SimChain::new(vec![tap])));
+    sm.change_mode(JtagState::Idle);
+
+    // Four clocks to Shift-IR, then the exit TMS goes out with the last of the four bits
+    let start = sm.cable.clocks();
+    sm.write_reg_end(Register::Instruction, &BitVec::from_u64(0x3, 4), JtagState::PauseIR);
+    assert_eq!(sm.cable.state(), JtagState::PauseIR);
+    assert_eq!(sm.cable.clocks() - start, 4 + 4 + 1);
+    sm.write_reg_end(Register::Instruction, &BitVec::from_u64(0xe, 4), JtagState::PauseIR);
+
+    // The instruction is updated on the way to Shift-DR
+    sm.set_end_state(Register::Data, JtagState::PauseDR);
+    let end = sm.end_state(Register::Data);
+    let start = sm.cable.clocks();
+    let tdo = sm.read_write_reg_end(Register::Data, &BitVec::zeros(32), end);
+    assert_eq!(tdo.to_u64(), Some(0x0ba00477));
+    assert_eq!(sm.cable.taps()[0].ir(), &BitVec::from_u64(0xe, 4));
+    assert_eq!(sm.cable.state(), JtagState::PauseDR);
+    assert_eq!(sm.cable.clocks() - start, 5 + 32 + 1);
+
+    // An empty exit stays in Shift-DR, and the state machine knows where the chain is
+    sm.write_reg_end(Register::Data, &BitVec::zeros(1), JtagState::ShiftDR);
+    assert_eq!(sm.cable.state(), JtagState::ShiftDR);
+    assert!(sm.queue_read_write_end(Register::Data, &BitVec::zeros(1), JtagState::Reset));
+    assert_eq!(sm.cable.state(), JtagState::Reset);
+    sm.write_reg_end(Register::Instruction, &BitVec::from_u64(0x3, 4), JtagState::SelectDR);
+    assert_eq!(sm.cable.state(), JtagState::SelectDR);
+    sm.change_mode(JtagState::Idle);
+    assert_eq!(sm.cable.state(), JtagState::Idle);
+}
+
+#[test]
+fn tap_info() {
+    let chain = SimChain::new(vec![SimTap::new(5, None), SimTap::new(4, Some(0x1ba00477))]);
+    let mut taps = taps(chain);
+    taps.add_tap(8);
+    let info = &taps.taps()[0];
+    assert_eq!(info.position, 0);
+    assert_eq!(info.irlen, 8);
+    assert_eq!(info.idcode, None);
+    assert_eq!(info.ir_capture, None);
+    assert_eq!(info.to_string(), "tap 0: irlen 8, no idcode");
+
+    // The TAPs found replace the ones added, and stay available
+    let found = taps.detect().unwrap();
+    assert_eq!(taps.taps(), &found[..]);
+    let positions: Vec<usize> = found.iter().map(|t| t.position).collect();
+    assert_eq!(positions, [0, 1]);
+    assert_eq!(found[0].irlen, 5);
+    assert_eq!(found[0].idcode, None);
+    assert_eq!(found[1].idcode, Some(0x1ba00477));
+    assert_eq!(found[1].ir_capture, Some(BitVec::from_u64(1, 4)));
+    assert_eq!(found[1].to_string(), "tap 1 (ARM JTAG-DP): irlen 4, idcode 1ba00477 (ARM Ltd), ir capture 0001");
+}
+
+#[test]
+fn detect() {
+    let chain = SimChain::new(vec![
+        SimTap::from_bsdl(&fixture("demo_mcu.bsd")),
+        SimTap::new(3, None),
+        SimTap::from_bsdl(&fixture("demo_cpld.bsdl")),
+    ]);
+    let mut taps = taps(chain);
+    let found = taps.detect().unwrap();
+    let irlens: Vec<usize> = found.iter().map(|t| t.irlen).collect();
+    assert_eq!(irlens, [5, 3, 8]);
+    let idcodes: Vec<Option<u32>> = found.iter().map(|t| t.idcode).collect();
+    assert_eq!(idcodes, [Some(0x06410041), None, Some(0x09602093)]);
+    assert_eq!(found[2].ir_capture, Some(BitVec::from_u64(1, 8)));
+}
+
+#[test]
+fn detect_ambiguous() {
+    // The captured 010101 could be split as 2+4 or 4+2
+    let chain = SimChain::new(vec![
+        SimTap::new(4, Some(0x12345001)).with_ir_capture(BitVec::from_u64(0b0101, 4)),
+        SimTap::new(2, Some(0x12346001)),
+    ]);
+    let err = taps(chain).detect().unwrap_err();
+    assert!(err.contains("more than one way"), "{}", err);
+}
+
+#[test]
+fn detect_unknown_irlens() {
+    // The IR lengths come from the IR captures alone
+    let chain = SimChain::new(vec![SimTap::new(4, Some(0x12345001)), SimTap::new(6, None)]);
+    let found = taps(chain).detect().unwrap();
+    let irlens: Vec<usize> = found.iter().map(|t| t.irlen).collect();
+    assert_eq!(irlens, [4, 6]);
+
+    let chain = SimChain::new(vec![SimTap::new(2, None), SimTap::new(7, None), SimTap::new(3, None)]);
+    let found = taps(chain).detect().unwrap();
+    let irlens: Vec<usize> = found.iter().map(|t| t.irlen).collect();
+    assert_eq!(irlens, [2, 7, 3]);
+}
+
+#[test]
+fn detect_with_database() {
+    // The split of 010101 is ambiguous, until the IDCODE of the first TAP gives its IR length
+    let chain = || SimChain::new(vec![
+        SimTap::new(4, Some(0x0ba00477)).with_ir_capture(BitVec::from_u64(0b0101, 4)),
+        SimTap::new(2, Some(0x12346001)),
+    ]);
+    let mut known = taps(chain());
+    let found = known.detect().unwrap();
+    let irlens: Vec<usize> = found.iter().map(|t| t.irlen).collect();
+    assert_eq!(irlens, [4, 2]);
+    let device = known.database().lookup(IdCode(found[0].idcode.unwrap())).unwrap();
+    assert_eq!(device.name, "ARM JTAG-DP");
+    assert_eq!(device.instruction("APACC"), Some(0xb));
+    assert_eq!(found[0].name.as_deref(), Some("ARM JTAG-DP"));
+    assert_eq!(found[0].instructions.opcode("APACC"), Ok(&BitVec::from_u64(0xb, 4)));
+    assert_eq!(found[1].name, None);
+    assert!(known.database().lookup(IdCode(found[1].idcode.unwrap())).is_none());
+
+    // Without the database it can't be split
+    let mut unknown = taps(chain());
+    unknown.set_database(Database::new());
+    assert!(unknown.detect().is_err());
+
+    // Or the second TAP can be the one that is known
+    let mut db = Database::new();
+    db.add(Device::new("widget", 0x12346001, 0xffffffff, 2, &[("HALT", 0x2)]));
+    let mut custom = taps(chain());
+    custom.set_database(db);
+    let found = custom.detect().unwrap();
+    let irlens: Vec<usize> = found.iter().map(|t| t.irlen).collect();
+    assert_eq!(irlens, [4, 2]);
+}
+
+#[test]
+fn detect_broken_chain() {
+    let mut chain = SimChain::new(vec![SimTap::new(4, None)]);
+    chain.inject(SimFault::TdoStuck(true));
+    assert!(taps(chain).detect().unwrap_err().contains("stuck high"));
+
+    let mut chain = SimChain::new(vec![SimTap::new(4, None)]);
+    chain.inject(SimFault::TdoStuck(false));
+    assert!(taps(chain).detect().unwrap_err().contains("stuck low"));
+}
+
+#[test]
+fn detect_long_chain() {
+    // 24 bits of IR, more than is allowed for but short enough for the ones to make it through
+    let chain = || SimChain::new(vec![SimTap::new(8, None), SimTap::new(8, None), SimTap::new(8, None)]);
+    let err = taps(chain()).detect_within(16).unwrap_err();
+    assert!(err.contains("longer than 16 bits"), "{}", err);
+    let err = taps(chain()).detect_within(24).unwrap_err();
+    assert!(err.contains("longer than 24 bits"), "{}", err);
+    assert_eq!(taps(chain()).detect_within(25).unwrap().len(), 3);
+
+    // Too long for even those
+    let err = taps(chain()).detect_within(8).unwrap_err();
+    assert!(err.contains("longer than 8 bits"), "{}", err);
+}
+
+#[test]
+fn instructions() {
+    let tap = SimTap::new(4, Some(0x0ba00477))
+        .with_instruction(BitVec::from_u64(0xe, 4), SimRegister::Idcode);
+    assert_eq!(tap.selected(), SimRegister::Idcode);
+    assert_eq!(tap.ir(), &BitVec::from_u64(0xe, 4));
+
+    let mut taps = taps(SimChain::new(vec![tap, SimTap::new(5, None)]));
+    taps.add_tap(4);
+    taps.add_tap(5);
+    taps.select_tap(0, &BitVec::from_u64(0xe, 4));
+    assert_eq!(taps.read_dr(32).to_u64(), Some(0x0ba00477));
+    taps.select_tap(0, &BitVec::from_u64(0x3, 4));
+    assert_eq!(taps.sm.cable.taps()[0].selected(), SimRegister::Bypass);
+    assert_eq!(taps.read_dr(1).to_u64(), Some(0));
+}
+
+#[test]
+fn extest_loopback() {
+    // PA1 drives PA2 on the same device
+    let bsdl = fixture("demo_mcu.bsd");
+    let mut chain = SimChain::new(vec![SimTap::from_bsdl(&bsdl)]);
+    chain.add_net("LOOP", &[(0, "PA1"), (0, "PA2")]);
+    let mut taps = taps(chain);
+    taps.add_tap(5);
+    let mut bs = BoundaryScan::new(bsdl, 0).unwrap();
+
+    bs.sample(&mut taps).unwrap();
+    assert!(bs.pin("PA2").unwrap());
+
+    bs.drive("PA1", false).unwrap();
+    bs.apply(&mut taps).unwrap();
+    assert_eq!(taps.sm.cable.taps()[0].selected(), SimRegister::Extest);
+    bs.apply(&mut taps).unwrap();
+    assert!(!bs.pin("PA2").unwrap());
+
+    bs.tristate("PA1").unwrap();
+    bs.apply(&mut taps).unwrap();
+    bs.apply(&mut taps).unwrap();
+    assert!(bs.pin("PA2").unwrap());
+}
@@ -0,0 +1,453 @@
+use jtag_taps::bits::BitVec;
+use jtag_taps::cable::sim::{SimChain, SimRegister};
+use jtag_taps::statemachine::{JtagSM, JtagState};
+use jtag_taps::stapl::{self, Inclusion, Player, Program};
+
+mod common;
+
+const OP_ADD: u8 = 0x03;
+const OP_DIV: u8 = 0x06;
+const OP_RET: u8 = 0x11;
+const OP_PINT: u8 = 0x13;
+const OP_PRNT: u8 = 0x14;
+const OP_DSSC: u8 = 0x16;
+const OP_ISS: u8 = 0x17;
+const OP_DPR: u8 = 0x1a;
+const OP_IPR: u8 = 0x1e;
+const OP_EXIT: u8 = 0x23;
+const OP_PSH0: u8 = 0x2b;
+const OP_PSHL: u8 = 0x40;
+const OP_PSHV: u8 = 0x41;
+const OP_NEXT: u8 = 0x44;
+const OP_PSTR: u8 = 0x45;
+const OP_ISTP: u8 = 0x49;
+const OP_POPV: u8 = 0x4d;
+const OP_JMPZ: u8 = 0x50;
+const OP_EXPT: u8 = 0x57;
+const OP_PSHA: u8 = 0x59;
+const OP_EXPV: u8 = 0x5c;
+const OP_DSC: u8 = 0x82;
+const OP_WAIT: u8 = 0x84;
+const OP_CMPA: u8 = 0xc0;
+
+fn chain() -> JtagSM<Box<SimChain>> {
+    common::mcu_chain(SimRegister::Idcode)
+}
+
+const CHECK_ID: &str = "
+' Read and check the IDCODE of the MCU, with the other TAP in BYPASS
+NOTE \"DEVICE\" \"DEMO_MCU\";
+ACTION VERIFY \"Check the IDCODE\" = CHECK_ID, DUMP OPTIONAL, COUNT RECOMMENDED;
+
+DATA IDS;
+    BOOLEAN expected[32] = $06410041;
+    INTEGER masks[2] = $0FFFFFFF, -1;
+ENDDATA;
+
+PROCEDURE CHECK_ID USES IDS;
+    BOOLEAN id[32];
+    BOOLEAN ok;
+    PREIR 4;
+    PREDR 1;
+    IRSTOP IRPAUSE;
+    IRSCAN 5, #00001;
+    DRSCAN 32, $00000000, CAPTURE id[31..0], COMPARE expected, masks[0], ok;
+    EXPORT \"IDCODE\", id;
+    IF !ok THEN EXIT 6;
+    COMPARE_ALL: DRSCAN 32, 0, COMPARE expected, masks[1], ok;
+    PRINT \"exact \", ok;
+ENDPROC;
+
+PROCEDURE DUMP;
+    PRINT \"dump\";
+ENDPROC;
+
+PROCEDURE COUNT;
+    INTEGER i;
+    INTEGER total = 0;
+    FOR i = 1 TO 10 STEP 3;
+        total = total + i;
+    NEXT i;
+    PRINT \"total \", total;
+ENDPROC;
+";
+
+#[test]
+fn actions() {
+    let program = Program::parse(CHECK_ID).unwrap();
+    assert_eq!(program.notes(), [("DEVICE".to_string(), "DEMO_MCU".to_string())]);
+    let action = &program.actions()[0];
+    assert_eq!(action.name, "VERIFY");
+    assert_eq!(action.description.as_deref(), Some("Check the IDCODE"));
+    assert_eq!(action.procedures[1], ("DUMP".to_string(), Inclusion::Optional));
+
+    let mut sm = chain();
+    let report = Player::new().run(&mut sm, &program, Some("verify")).unwrap();
+    assert!(report.passed());
+    assert_eq!(report.exports, [("IDCODE".to_string(), "06410041".to_string())]);
+    assert_eq!(report.output, ["exact 1", "total 22"]);
+    assert_eq!(sm.cable.taps()[1].ir(), &BitVec::ones(4));
+    assert_eq!(sm.state(), JtagState::Idle);
+
+    let mut player = Player::new();
+    player.enable("DUMP", true);
+    player.enable("COUNT", false);
+    let report = player.run(&mut sm, &program, Some("VERIFY")).unwrap();
+    assert_eq!(report.output[1], "dump");
+    assert_eq!(report.output.len(), 2);
+    assert!(player.run(&mut sm, &program, Some("ERASE")).is_err());
+
+    // The IDCODE isn't there when the PREDR bit is missing
+    let program = Program::parse(&CHECK_ID.replace("PREDR 1;", "")).unwrap();
+    let report = Player::new().run(&mut sm, &program, Some("VERIFY")).unwrap();
+    assert_eq!(report.exit_code, 6);
+    assert_eq!(report.output, Vec::<String>::new());
+}
+
+#[test]
+fn statements() {
+    let program = "
+        INTEGER a[4] = 3, 1, 4, 1;
+        INTEGER i;
+        INTEGER sum = 0;
+        BOOLEAN b = 5;
+        BOOLEAN bits[8] = #1010;
+        ' The first index of a slice is the most significant bit
+        LET bits[7..4] = bits[0..3];
+        FOR i = 0 TO 3;
+            sum = sum + a[i];
+        NEXT i;
+        PUSH sum;
+        POP i;
+        DOWN: i = i - 2;
+        IF i > 0 THEN GOTO DOWN;
+        STATE IDLE;
+        STATE RESET IDLE;
+        WAIT IDLE, 10 CYCLES, 5 USEC, DRPAUSE;
+        PRINT bits, \" \", sum, \" \", i, \" \", b, \" \", LOG2(9), \" \", SQRT(10), \" \", 7 % 3 << 2;
+        FOR i = 5 TO 1;
+            EXIT 1;
+        NEXT i;
+    ";
+    let mut sm = chain();
+    let resets = sm.cable.resets();
+    let report = stapl::play(&mut sm, program, None).unwrap();
+    assert!(report.passed());
+    assert_eq!(report.output, ["90 9 -1 1 4 3 4"]);
+    assert_eq!(sm.state(), JtagState::PauseDR);
+    assert_eq!(sm.cable.resets(), resets + 1);
+}
+
+// The bits of (value, bits) fields, least significant first
+fn aca_bits(fields: &[(usize, usize)]) -> Vec<bool> {
+    fields.iter().flat_map(|&(value, len)| (0..len).map(move |i| value >> i & 1 == 1)).collect()
+}
+
+// Pack (value, bits) fields least significant bit first, six bits to a character
+fn aca(fields: &[(usize, usize)]) -> String {
+    const CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz_@";
+    aca_bits(fields).chunks(6).map(|chunk| {
+        let value: usize = chunk.iter().enumerate().map(|(i, bit)| (*bit as usize) << i).sum();
+        CHARS[value] as char
+    }).collect()
+}
+
+#[test]
+fn compressed_array() {
+    // Eight bytes: three literals, then five copied from three back
+    let data = aca(&[(8, 32), (0, 1), (0x12, 8), (0x34, 8), (0x56, 8), (1, 1), (3, 2), (5, 8)]);
+    let program = format!("BOOLEAN c[64] = @{};\nEXPORT \"C\", c;\n", data);
+    let report = stapl::play(&mut chain(), &program, None).unwrap();
+    assert_eq!(report.exports[0].1, "3412563412563412");
+
+    let program = format!("BOOLEAN c[72] = @{};\n", data);
+    assert!(stapl::play(&mut chain(), &program, None).unwrap_err().starts_with("line 1:"));
+}
+
+#[test]
+fn errors() {
+    let err = Program::parse("INTEGER i;\nLET i = (1;\n").unwrap_err();
+    assert!(err.starts_with("line 2:"), "{}", err);
+    assert!(Program::parse("FOR i = 1 TO 2;\n").unwrap_err().contains("without NEXT"));
+    assert!(Program::parse("VECTOR 1;").unwrap_err().contains("not supported"));
+    assert!(Program::parse("ACTION A = MISSING;").unwrap_err().contains("MISSING"));
+    assert!(Program::parse("EXIT 0").unwrap_err().contains("missing ;"));
+
+    let err = stapl::play(&mut chain(), "INTEGER i;\n\nLET j = 1;\n", None).unwrap_err();
+    assert_eq!(err, "line 3: unknown variable J");
+    let err = stapl::load(b"JAM\x01\x00\x00\x00\x00").unwrap_err();
+    assert!(err.contains("ends before"), "{}", err);
+}
+
+// Assembles STAPL byte-code, with a procedure for each of its actions' procedures
+#[derive(Default)]
+struct Jbc {
+    strings: Vec<u8>,
+    note_strings: Vec<u8>,
+    notes: Vec<(u32, u32)>,
+    actions: Vec<(u32, u32, u32)>,
+    // Name, next and attribute, then the code offset
+    procedures: Vec<(u32, u32, u8, u32)>,
+    symbols: Vec<(u8, u32, u32)>,
+    data: Vec<u8>,
+    code: Vec<u8>,
+}
+
+impl Jbc {
+    fn string(&mut self, s: &str) -> u32 {
+        let id = self.strings.len() as u32;
+        self.strings.extend(s.bytes().chain([0]));
+        id
+    }
+
+    fn note(&mut self, key: &str, value: &str) {
+        let key_id = self.note_strings.len() as u32;
+        self.note_strings.extend(key.bytes().chain([0]));
+        let value_id = self.note_strings.len() as u32;
+        self.note_strings.extend(value.bytes().chain([0]));
+        self.notes.push((key_id, value_id));
+    }
+
+    // Procedures are (name, attribute, code offset)
+    fn action(&mut self, name: &str, description: &str, procedures: &[(&str, u8, u32)]) {
+        let (name, description) = (self.string(name), self.string(description));
+        let first = self.procedures.len() as u32;
+        self.actions.push((name, description, first));
+        for (i, (procedure, attr, offset)) in procedures.iter().enumerate() {
+            let next = if i + 1 < procedures.len() { first + i as u32 + 1 } else { 0 };
+            let name = self.string(procedure);
+            self.procedures.push((name, next, *attr, *offset));
+        }
+    }
+
+    fn symbol(&mut self, attr: u8, value: u32, size: u32) -> u32 {
+        self.symbols.push((attr, value, size));
+        self.symbols.len() as u32 - 1
+    }
+
+    fn op(&mut self, op: u8, args: &[u32]) {
+        assert_eq!(args.len(), (op >> 6) as usize);
+        self.code.push(op);
+        for arg in args {
+            self.code.extend(arg.to_be_bytes());
+        }
+    }
+
+    // Point the jump at the end of the code `at` to the end of the code now
+    fn patch(&mut self, at: usize) {
+        let to = (self.code.len() as u32).to_be_bytes();
+        self.code[at + 1..at + 5].copy_from_slice(&to);
+    }
+
+    fn build(&self, version: u8) -> Vec<u8> {
+        let delta = version as usize * 8;
+        let mut out = vec![0; 52 + 2 * delta];
+        out[..4].copy_from_slice(&[b'J', b'A', b'M', version]);
+        fn put(out: &mut [u8], at: usize, value: usize) {
+            out[at..at + 4].copy_from_slice(&(value as u32).to_be_bytes());
+        }
+        // Append a section, with its offset in the header at `at`
+        fn section(out: &mut Vec<u8>, at: usize, bytes: &[u8]) {
+            let start = out.len();
+            put(out, at, start);
+            out.extend(bytes);
+        }
+        section(&mut out, 4 + delta, &self.strings);
+        section(&mut out, 8 + delta, &self.note_strings);
+        let mut notes = vec![];
+        for (key, value) in &self.notes {
+            notes.extend(key.to_be_bytes().into_iter().chain(value.to_be_bytes()));
+        }
+        section(&mut out, 12 + delta, &notes);
+        if version > 0 {
+            let mut actions = vec![];
+            for (name, description, first) in &self.actions {
+                actions.extend([name, description, first].iter().flat_map(|word| word.to_be_bytes()));
+            }
+            section(&mut out, 4, &actions);
+            let mut procedures = vec![];
+            for (name, next, attr, offset) in &self.procedures {
+                procedures.extend([name, next].iter().flat_map(|word| word.to_be_bytes()));
+                procedures.push(*attr);
+                procedures.extend(offset.to_be_bytes());
+            }
+            section(&mut out, 8, &procedures);
+        }
+        let mut symbols = vec![];
+        for (attr, value, size) in &self.symbols {
+            symbols.push(*attr);
+            symbols.extend(vec![0; 2 + delta]);
+            symbols.extend([value, size].iter().flat_map(|word| word.to_be_bytes()));
+        }
+        section(&mut out, 16 + delta, &symbols);
+        section(&mut out, 20 + delta, &self.data);
+        section(&mut out, 24 + delta, &self.code);
+        let end = out.len();
+        put(&mut out, 28 + delta, end);
+        if version > 0 {
+            put(&mut out, 40 + delta, self.actions.len());
+            put(&mut out, 44 + delta, self.procedures.len());
+        }
+        put(&mut out, 44 + 2 * delta, self.notes.len());
+        put(&mut out, 48 + 2 * delta, self.symbols.len());
+        out
+    }
+}
+
+const IRPAUSE: u32 = 13;
+
+#[test]
+fn bytecode() {
+    // CHECK_ID compiled by hand, without the second compare
+    let mut jbc = Jbc::default();
+    jbc.note("DEVICE", "DEMO_MCU");
+    jbc.data.extend(0x06410041u32.to_le_bytes());
+    jbc.data.extend(0x0fffffffu32.to_le_bytes());
+    let expected = jbc.symbol(0x0c, 0, 32);
+    let id = jbc.symbol(0x08, 0, 32);
+    let mask = jbc.symbol(0x0c, 4, 32);
+    let ok = jbc.symbol(0x00, 0, 0);
+    let i = jbc.symbol(0x00, 0, 0);
+    let total = jbc.symbol(0x04, 0, 0);
+    let (exact, idcode, raw) = (jbc.string("exact "), jbc.string("IDCODE"), jbc.string("RAW"));
+
+    let check_id = jbc.code.len() as u32;
+    jbc.op(OP_PSHL, &[4]);
+    jbc.op(OP_IPR, &[]);
+    jbc.op(OP_PSHL, &[1]);
+    jbc.op(OP_DPR, &[]);
+    jbc.op(OP_ISTP, &[IRPAUSE]);
+    for value in [5, 1] {
+        jbc.op(OP_PSHL, &[value]);
+    }
+    jbc.op(OP_ISS, &[]);
+    // DRSCAN 32, id[31..0], CAPTURE id[31..0]
+    for value in [32, 31, 0, 31, 0] {
+        jbc.op(OP_PSHL, &[value]);
+    }
+    jbc.op(OP_DSC, &[id, id]);
+    for value in [31, 0, 31, 0, 31, 0] {
+        jbc.op(OP_PSHL, &[value]);
+    }
+    jbc.op(OP_CMPA, &[id, expected, mask]);
+    jbc.op(OP_POPV, &[ok]);
+    for value in [31, 0, id] {
+        jbc.op(OP_PSHL, &[value]);
+    }
+    jbc.op(OP_EXPV, &[idcode]);
+    jbc.op(OP_PSHV, &[ok]);
+    let skip = jbc.code.len();
+    jbc.op(OP_JMPZ, &[0]);
+    // The IDCODE again, as an integer
+    jbc.op(OP_PSHL, &[32]);
+    jbc.op(OP_PSH0, &[]);
+    jbc.op(OP_DSSC, &[]);
+    jbc.op(OP_EXPT, &[raw]);
+    jbc.op(OP_PSTR, &[exact]);
+    jbc.op(OP_PSHV, &[ok]);
+    jbc.op(OP_PINT, &[]);
+    jbc.op(OP_PRNT, &[]);
+    jbc.op(OP_RET, &[]);
+    jbc.patch(skip);
+    jbc.op(OP_PSHL, &[6]);
+    jbc.op(OP_EXIT, &[]);
+
+    let dump = jbc.code.len() as u32;
+    let text = jbc.string("dump");
+    jbc.op(OP_PSTR, &[text]);
+    jbc.op(OP_PRNT, &[]);
+    jbc.op(OP_RET, &[]);
+
+    // FOR i = 1 TO 10 STEP 3; total = total + i; NEXT i;
+    let count = jbc.code.len() as u32;
+    jbc.op(OP_PSHL, &[1]);
+    jbc.op(OP_POPV, &[i]);
+    let body = jbc.code.len() as u32 + 15;
+    for value in [body, 10, 3] {
+        jbc.op(OP_PSHL, &[value]);
+    }
+    jbc.op(OP_PSHV, &[total]);
+    jbc.op(OP_PSHV, &[i]);
+    jbc.op(OP_ADD, &[]);
+    jbc.op(OP_POPV, &[total]);
+    jbc.op(OP_NEXT, &[i]);
+    let text = jbc.string("total ");
+    jbc.op(OP_PSTR, &[text]);
+    jbc.op(OP_PSHV, &[total]);
+    jbc.op(OP_PINT, &[]);
+    jbc.op(OP_PRNT, &[]);
+    jbc.op(OP_RET, &[]);
+    let procedures = [("CHECK_ID", 0, check_id), ("DUMP", 1, dump), ("COUNT", 2, count)];
+    jbc.action("VERIFY", "Check the IDCODE", &procedures);
+
+    let program = stapl::load(&jbc.build(1)).unwrap();
+    assert_eq!(program.notes(), [("DEVICE".to_string(), "DEMO_MCU".to_string())]);
+    let action = &program.actions()[0];
+    assert_eq!(action.name, "VERIFY");
+    assert_eq!(action.description.as_deref(), Some("Check the IDCODE"));
+    assert_eq!(action.procedures[1], ("DUMP".to_string(), Inclusion::Optional));
+    assert_eq!(action.procedures[2], ("COUNT".to_string(), Inclusion::Recommended));
+
+    let mut sm = chain();
+    let report = Player::new().run(&mut sm, &program, Some("verify")).unwrap();
+    assert!(report.passed());
+    assert_eq!(report.exports, [("IDCODE".to_string(), "06410041".to_string()),
+                                ("RAW".to_string(), 0x06410041.to_string())]);
+    assert_eq!(report.output, ["exact 1", "total 22"]);
+    assert_eq!(sm.cable.taps()[1].ir(), &BitVec::ones(4));
+    assert_eq!(sm.state(), JtagState::Idle);
+
+    let mut player = Player::new();
+    player.enable("DUMP", true);
+    player.enable("COUNT", false);
+    let report = player.run(&mut sm, &program, Some("VERIFY")).unwrap();
+    assert_eq!(report.output, ["exact 1", "dump"]);
+    assert!(player.run(&mut sm, &program, None).is_err());
+
+    // The IDCODE doesn't match with a wrong bit expected
+    jbc.data[0] ^= 1;
+    let program = stapl::load(&jbc.build(1)).unwrap();
+    let report = Player::new().run(&mut sm, &program, Some("VERIFY")).unwrap();
+    assert_eq!(report.exit_code, 6);
+}
+
+#[test]
+fn bytecode_version_0() {
+    // A compressed array, as in compressed_array, read 32 bits at a time
+    let mut jbc = Jbc::default();
+    let bits = aca_bits(&[(8, 32), (0, 1), (0x12, 8), (0x34, 8), (0x56, 8), (1, 1), (3, 2), (5, 8)]);
+    jbc.data = bits.chunks(8)
+        .map(|chunk| chunk.iter().enumerate().map(|(i, bit)| (*bit as u8) << i).sum())
+        .collect();
+    let c = jbc.symbol(0x0e, 0, jbc.data.len() as u32);
+    let (low, high) = (jbc.string("LOW"), jbc.string("HIGH"));
+    for (index, key) in [(0, low), (32, high)] {
+        jbc.op(OP_PSHL, &[index]);
+        jbc.op(OP_PSHL, &[32]);
+        jbc.op(OP_PSHA, &[c]);
+        jbc.op(OP_EXPT, &[key]);
+    }
+    jbc.op(OP_PSHL, &[5]);
+    jbc.op(OP_PSHL, &[10]);
+    jbc.op(OP_WAIT, &[1, 6]);
+    jbc.op(OP_PSHL, &[3]);
+    jbc.op(OP_EXIT, &[]);
+
+    let mut sm = chain();
+    let program = stapl::load(&jbc.build(0)).unwrap();
+    assert!(program.actions().is_empty());
+    let report = Player::new().run(&mut sm, &program, None).unwrap();
+    assert_eq!(report.exit_code, 3);
+    assert_eq!(report.exports, [("LOW".to_string(), 0x12563412.to_string()),
+                                ("HIGH".to_string(), 0x34125634.to_string())]);
+    assert_eq!(sm.state(), JtagState::PauseDR);
+
+    // Errors give the offset of the instruction in the file
+    let mut jbc = Jbc::default();
+    jbc.op(OP_PSHL, &[1]);
+    jbc.op(OP_PSH0, &[]);
+    jbc.op(OP_DIV, &[]);
+    let program = stapl::load(&jbc.build(0)).unwrap();
+    let err = Player::new().run(&mut sm, &program, None).unwrap_err();
+    assert_eq!(err, "opcode 0x06 at offset 0x3a: division by zero");
+}
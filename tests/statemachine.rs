@@ -0,0 +1,110 @@
+use jtag_taps::statemachine::{JtagState, TapModel};
+
+const STATES: [JtagState; 16] = [
+    JtagState::Reset, JtagState::Idle,
+    JtagState::SelectDR, JtagState::CaptureDR, JtagState::ShiftDR, JtagState::Exit1DR,
+    JtagState::PauseDR, JtagState::Exit2DR, JtagState::UpdateDR,
+    JtagState::SelectIR, JtagState::CaptureIR, JtagState::ShiftIR, JtagState::Exit1IR,
+    JtagState::PauseIR, JtagState::Exit2IR, JtagState::UpdateIR,
+];
+
+// Independent model of the IEEE 1149.1 TAP controller: (next state on TMS=0, next state on TMS=1)
+fn step(state: JtagState, tms: usize) -> JtagState {
+    use JtagState::*;
+    let (low, high) = match state {
+        Reset => (Idle, Reset),
+        Idle => (Idle, SelectDR),
+        SelectDR => (CaptureDR, SelectIR),
+        CaptureDR => (ShiftDR, Exit1DR),
+        ShiftDR => (ShiftDR, Exit1DR),
+        Exit1DR => (PauseDR, UpdateDR),
+        PauseDR => (PauseDR, Exit2DR),
+        Exit2DR => (ShiftDR, UpdateDR),
+        UpdateDR => (Idle, SelectDR),
+        SelectIR => (CaptureIR, Reset),
+        CaptureIR => (ShiftIR, Exit1IR),
+        ShiftIR => (ShiftIR, Exit1IR),
+        Exit1IR => (PauseIR, UpdateIR),
+        PauseIR => (PauseIR, Exit2IR),
+        Exit2IR => (ShiftIR, UpdateIR),
+        UpdateIR => (Idle, SelectDR),
+    };
+    if tms != 0 { high } else { low }
+}
+
+fn replay(from: JtagState, tms: &[usize]) -> JtagState {
+    tms.iter().fold(from, |state, &x| step(state, x))
+}
+
+#[test]
+fn next_matches_model() {
+    for &state in &STATES {
+        assert_eq!(state.next(false), step(state, 0));
+        assert_eq!(state.next(true), step(state, 1));
+    }
+}
+
+#[test]
+fn every_path_reaches_target() {
+    for &from in &STATES {
+        for &to in &STATES {
+            let path = from.path_to(to);
+            assert_eq!(replay(from, path), to, "path {:?} from {:?}", path, from);
+            if from == to {
+                assert!(path.is_empty());
+            }
+        }
+    }
+}
+
+#[test]
+fn every_path_is_shortest() {
+    for &from in &STATES {
+        for &to in &STATES {
+            let len = from.path_to(to).len();
+            for shorter in 0..len {
+                for bits in 0..(1usize << shorter) {
+                    let tms: Vec<usize> = (0..shorter).map(|i| (bits >> i) & 1).collect();
+                    assert_ne!(replay(from, &tms), to, "{:?} is shorter than {:?}", tms, from.path_to(to));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn tap_model_resets_from_unknown() {
+    let mut model = TapModel::new();
+    assert_eq!(model.state(), None);
+    assert!(model.check(JtagState::ShiftDR).is_ok());
+
+    model.clock_tms(&[1, 1, 1, 1]);
+    assert_eq!(model.state(), None);
+    model.clock(true);
+    assert_eq!(model.state(), Some(JtagState::Reset));
+    assert!(model.check(JtagState::Idle).is_err());
+}
+
+#[test]
+fn tap_model_follows_paths() {
+    for &from in &STATES {
+        for &to in &STATES {
+            let mut model = TapModel::at(from);
+            model.clock_tms(from.path_to(to));
+            assert_eq!(model.state(), Some(to));
+        }
+    }
+}
+
+#[test]
+fn tap_model_follows_shift_with_pause() {
+    // Five bits of data with the last one clocked with TMS high, then one clock into PauseDR
+    let mut model = TapModel::at(JtagState::ShiftDR);
+    model.clock_bits(&[0x10], 6);
+    assert_eq!(model.state(), Some(JtagState::PauseDR));
+
+    model.clock_held(false, 1000);
+    assert_eq!(model.state(), Some(JtagState::PauseDR));
+    model.clock_held(true, 1000);
+    assert_eq!(model.state(), Some(JtagState::Reset));
+}
@@ -0,0 +1,112 @@
+use jtag_taps::bits::BitVec;
+use jtag_taps::cable::sim::{SimChain, SimRegister};
+use jtag_taps::statemachine::{JtagSM, JtagState, Register};
+use jtag_taps::svf::{self, Command, Player, ScanKind};
+
+mod common;
+
+fn chain() -> JtagSM<Box<SimChain>> {
+    common::mcu_chain(SimRegister::Bypass)
+}
+
+const READ_IDCODE: &str = "
+! Read the IDCODE of the MCU with the other TAP in BYPASS
+TRST OFF;
+ENDIR IDLE;
+ENDDR DRPAUSE;
+STATE RESET;
+HIR 4 TDI (f);
+TIR 0;
+HDR 1 TDI (0) TDO (0);
+TDR 0;
+SIR 5 TDI (01);
+SDR 32 TDI (00000000)
+       TDO (06410041) MASK (0fffffff);
+// Version doesn't matter, and TDI carries over
+SDR 32 TDO (f6410041);
+RUNTEST 100 TCK ENDSTATE IDLE;
+FREQUENCY 1.0E+06 HZ;
+RUNTEST IDLE 20 TCK 1E-4 SEC MAXIMUM 1 SEC;
+";
+
+#[test]
+fn parse() {
+    let statements = svf::parse(READ_IDCODE).unwrap();
+    assert_eq!(statements.len(), 14);
+    assert_eq!(statements[9].line, 12);
+    match &statements[9].command {
+        Command::Scan(ScanKind::Sdr, scan) => {
+            assert_eq!(scan.len, 32);
+            assert_eq!(scan.tdo, Some(BitVec::from_u64(0x06410041, 32)));
+            assert_eq!(scan.mask, Some(BitVec::from_u64(0x0fffffff, 32)));
+        }
+        other => panic!("{:?}", other),
+    }
+    match &statements[13].command {
+        Command::RunTest(runtest) => {
+            assert_eq!(runtest.run_state, Some(JtagState::Idle));
+            assert_eq!(runtest.cycles, Some(20));
+            assert_eq!(runtest.min_time, Some(1e-4));
+            assert_eq!(runtest.max_time, Some(1.0));
+        }
+        other => panic!("{:?}", other),
+    }
+
+    let err = svf::parse("SIR 4 TDI (f);\nSDR 8 TDI (100);\n").unwrap_err();
+    assert!(err.starts_with("line 2:"), "{}", err);
+    assert!(svf::parse("ENDDR DRSHIFT;").is_err());
+    assert!(svf::parse("PIOMAP (IN A);").unwrap_err().contains("not supported"));
+    assert!(svf::parse("SIR 4 TDI (f)").unwrap_err().contains("missing ;"));
+}
+
+#[test]
+fn play() {
+    let mut sm = chain();
+    let report = svf::play(&mut sm, READ_IDCODE).unwrap();
+    assert!(report.passed(), "{}", report.mismatches[0]);
+    assert_eq!(report.statements, 14);
+    assert_eq!(sm.state(), JtagState::Idle);
+    assert_eq!(sm.end_state(Register::Data), JtagState::PauseDR);
+    assert_eq!(sm.cable.taps()[0].selected(), SimRegister::Idcode);
+    assert_eq!(sm.cable.taps()[1].ir(), &BitVec::ones(4));
+}
+
+#[test]
+fn mismatches() {
+    let svf = "
+        SIR 9 TDI (1ff);
+        SDR 2 TDI (0) TDO (3);
+        SDR 2 TDO (0) MASK (2);
+        SDR 2 TDO (2);
+    ";
+    let mut sm = chain();
+    let report = svf::play(&mut sm, svf).unwrap();
+    assert_eq!(report.statements, 2);
+    assert_eq!(report.mismatches.len(), 1);
+    assert_eq!(report.mismatches[0].line, 3);
+    assert_eq!(report.mismatches[0].to_string(), "line 3: SDR captured 0, expected 3 with mask 3");
+
+    let mut player = Player::new();
+    player.set_stop_on_mismatch(false);
+    let report = player.play(&mut sm, &svf::parse(svf).unwrap()).unwrap();
+    assert_eq!(report.statements, 4);
+    let lines: Vec<usize> = report.mismatches.iter().map(|m| m.line).collect();
+    assert_eq!(lines, [3, 5]);
+}
+
+#[test]
+fn states() {
+    let mut sm = chain();
+    svf::play(&mut sm, "STATE IDLE DRSELECT DRCAPTURE DREXIT1 DRPAUSE;").unwrap();
+    assert_eq!(sm.cable.state(), JtagState::PauseDR);
+
+    let err = svf::play(&mut sm, "STATE IDLE IRPAUSE;").unwrap_err();
+    assert!(err.contains("not one clock"), "{}", err);
+
+    let resets = sm.cable.resets();
+    svf::play(&mut sm, "TRST ON;\nSTATE IDLE;\nSTATE RESET;").unwrap();
+    assert_eq!(sm.cable.resets(), resets + 2);
+
+    // The length changes without TDI
+    assert!(svf::play(&mut sm, "SDR 4 TDI (0);\nSDR 5;").unwrap_err().starts_with("line 2:"));
+}
@@ -0,0 +1,314 @@
+use jtag_taps::bits::BitVec;
+use jtag_taps::cable::sim::{SimChain, SimRegister, SimTap};
+use jtag_taps::statemachine::{JtagSM, JtagState, Register};
+use jtag_taps::instruction::InstructionSet;
+use jtag_taps::taps::{ChainMismatch, ExpectedId, SelectMode, Taps};
+
+mod common;
+use common::{chain, IDCODE};
+
+// Poll the IDCODE of TAP 3 ten times, and return the number of clocks it took
+fn poll(taps: &mut Taps<Box<SimChain>>, cache: bool) -> usize {
+    let start = taps.sm.cable.clocks();
+    for _ in 0..10 {
+        if !cache {
+            taps.invalidate_ir_cache();
+        }
+        taps.select_tap(3, &BitVec::from_u64(IDCODE, 4));
+        assert_eq!(taps.read_dr(32).to_u64(), Some(0x3ba00477));
+    }
+    taps.sm.cable.clocks() - start
+}
+
+#[test]
+fn ir_cache_skips_scans() {
+    let mut taps = chain(8);
+    let uncached = poll(&mut taps, false);
+    taps.invalidate_ir_cache();
+    let cached = poll(&mut taps, true);
+    assert!(cached * 10 < uncached * 6, "{} vs {} clocks", cached, uncached);
+
+    assert_eq!(taps.current_ir(3), Some(&BitVec::from_u64(IDCODE, 4)));
+    assert_eq!(taps.current_ir(4), Some(&BitVec::ones(4)));
+
+    // Writing the same instruction again doesn't shift anything
+    let start = taps.sm.cable.clocks();
+    taps.write_ir(&BitVec::from_u64(IDCODE, 4));
+    assert_eq!(taps.sm.cable.clocks(), start);
+}
+
+#[test]
+fn ir_cache_invalidation() {
+    let mut taps = chain(2);
+    let idcode = BitVec::from_u64(IDCODE, 4);
+    taps.select_tap(1, &idcode);
+    assert!(taps.current_ir(1).is_some());
+
+    taps.sm.mode_reset();
+    assert_eq!(taps.current_ir(1), None);
+    taps.select_tap(1, &idcode);
+    assert_eq!(taps.sm.cable.taps()[0].selected(), SimRegister::Bypass);
+
+    assert!(taps.sm.trst());
+    assert_eq!(taps.current_ir(1), None);
+    assert_eq!(taps.sm.cable.taps()[0].selected(), SimRegister::Idcode);
+
+    // An IR scan behind the back of `Taps` also makes it forget
+    taps.select_tap(1, &idcode);
+    taps.sm.write_reg(Register::Instruction, &BitVec::zeros(8), true);
+    assert_eq!(taps.current_ir(1), None);
+
+    // So does a scan that ends in Test-Logic-Reset
+    taps.reset();
+    taps.select_tap(1, &idcode);
+    assert!(taps.current_ir(1).is_some());
+    taps.sm.set_end_state(Register::Data, JtagState::Reset);
+    taps.write_dr(&BitVec::zeros(32));
+    assert_eq!(taps.current_ir(1), None);
+    taps.sm.set_end_state(Register::Data, JtagState::Idle);
+    taps.select_tap(1, &idcode);
+    assert_eq!(taps.sm.cable.taps()[0].selected(), SimRegister::Bypass);
+}
+
+#[test]
+fn data_after_reset() {
+    // After a reset every TAP holds IDCODE, so the others' 32 bit registers would be in the way
+    let mut taps = chain(3);
+    let idcode = BitVec::from_u64(IDCODE, 4);
+    taps.select_tap(1, &idcode);
+    taps.reset();
+    assert_eq!(taps.read_dr(32).to_u64(), Some(0x1ba00477));
+    assert_eq!(taps.current_ir(0), Some(&BitVec::ones(4)));
+
+    assert!(taps.sm.trst());
+    assert_eq!(taps.read_dr(32).to_u64(), Some(0x1ba00477));
+
+    // Likewise for the whole-chain scans
+    taps.write_ir_multi(&[(0, idcode.clone()), (2, idcode.clone())]);
+    taps.reset();
+    let out = taps.read_write_dr_multi(&[(0, BitVec::zeros(32)), (2, BitVec::zeros(32))]);
+    assert_eq!(out[0].1.to_u64(), Some(0x0ba00477));
+    assert_eq!(out[1].1.to_u64(), Some(0x2ba00477));
+}
+
+#[test]
+fn ir_capture_mismatch() {
+    // TAP 0 captures 00 where 1149.1 requires 01
+    let bad = SimTap::new(4, None).with_ir_capture(BitVec::zeros(4));
+    let chain = SimChain::new(vec![bad, SimTap::new(4, None)]);
+    let mut taps = Taps::new(JtagSM::new(Box::new(chain)));
+    taps.add_tap(4);
+    taps.add_tap(4);
+    taps.select_tap(1, &BitVec::ones(4));
+    assert_eq!(taps.current_ir(1), None);
+}
+
+#[test]
+fn select_without_reset() {
+    let mut taps = chain(3);
+    let idcode = BitVec::from_u64(IDCODE, 4);
+    let resets = taps.sm.cable.resets();
+    taps.select_tap(0, &idcode);
+    taps.select_tap(2, &idcode);
+    assert_eq!(taps.sm.cable.resets(), resets);
+    assert_eq!(taps.sm.cable.taps()[0].selected(), SimRegister::Bypass);
+    assert_eq!(taps.read_dr(32).to_u64(), Some(0x2ba00477));
+
+    taps.set_select_mode(SelectMode::Reset);
+    taps.select_tap(1, &idcode);
+    assert_eq!(taps.sm.cable.resets(), resets + 1);
+    taps.reset();
+    assert_eq!(taps.sm.cable.resets(), resets + 2);
+    assert_eq!(taps.current_ir(1), None);
+}
+
+#[test]
+fn select_preserving_others() {
+    let mut taps = chain(3);
+    let idcode = BitVec::from_u64(IDCODE, 4);
+    for tap in 0..3 {
+        let mut set = InstructionSet::new(4);
+        set.add("IDCODE", idcode.clone(), None).unwrap();
+        taps.set_instructions(tap, set).unwrap();
+    }
+    taps.set_select_mode(SelectMode::Preserve);
+
+    // TAP 1 keeps IDCODE while TAP 0 and then TAP 2 are selected, and the 32 bits of its data
+    // register are skipped over
+    taps.select_tap(1, &idcode);
+    taps.select_tap(0, &idcode);
+    assert_eq!(taps.read_dr(32).to_u64(), Some(0x0ba00477));
+    taps.set_parked_ir(0, Some(BitVec::ones(4)));
+    taps.select_tap(2, &idcode);
+    let chain = taps.sm.cable.taps();
+    let selected: Vec<SimRegister> = chain.iter().map(|t| t.selected()).collect();
+    assert_eq!(selected, [SimRegister::Bypass, SimRegister::Idcode, SimRegister::Idcode]);
+    assert_eq!(taps.read_dr(32).to_u64(), Some(0x2ba00477));
+}
+
+#[test]
+fn preserve_unknown_dr_lengths() {
+    // Only BYPASS is in the instruction tables, so the IDCODE left in TAP 1 is replaced
+    let mut taps = chain(3);
+    let idcode = BitVec::from_u64(IDCODE, 4);
+    taps.set_select_mode(SelectMode::Preserve);
+    taps.select_tap(1, &idcode);
+    taps.select_tap(2, &idcode);
+    assert_eq!(taps.current_ir(1), Some(&BitVec::ones(4)));
+    assert_eq!(taps.read_dr(32).to_u64(), Some(0x2ba00477));
+
+    // As are instructions loaded with write_ir_multi, before a single-TAP scan
+    taps.write_ir_multi(&[(0, idcode.clone()), (1, idcode.clone()), (2, idcode.clone())]);
+    assert_eq!(taps.current_ir(0), Some(&idcode));
+    taps.write_dr(&BitVec::zeros(32));
+    let selected: Vec<SimRegister> = taps.sm.cable.taps().iter().map(|t| t.selected()).collect();
+    assert_eq!(selected, [SimRegister::Bypass, SimRegister::Bypass, SimRegister::Idcode]);
+    assert_eq!(taps.read_dr(32).to_u64(), Some(0x2ba00477));
+}
+
+#[test]
+#[allow(deprecated)]
+fn select_between_queued_reads() {
+    // Each read is finished with the padding it was queued with
+    let mut taps = chain(3);
+    let idcode = BitVec::from_u64(IDCODE, 4);
+    taps.select_tap(1, &idcode);
+    assert!(taps.queue_dr_read(32));
+    taps.select_tap(2, &idcode);
+    assert!(taps.queue_dr_read(32));
+    taps.select_tap(0, &idcode);
+    assert!(taps.queue_dr_read(32));
+    assert_eq!(taps.finish_dr_read(32).to_u64(), Some(0x1ba00477));
+    assert_eq!(taps.finish_dr_read(32).to_u64(), Some(0x2ba00477));
+    assert_eq!(taps.finish_dr_read(32).to_u64(), Some(0x0ba00477));
+}
+
+#[test]
+fn multi_tap_scans() {
+    let mut taps = chain(3);
+    let idcode = BitVec::from_u64(IDCODE, 4);
+    taps.write_ir_multi(&[(2, idcode.clone()), (0, idcode.clone())]);
+    let selected: Vec<SimRegister> = taps.sm.cable.taps().iter().map(|t| t.selected()).collect();
+    assert_eq!(selected, [SimRegister::Idcode, SimRegister::Bypass, SimRegister::Idcode]);
+
+    let out = taps.read_write_dr_multi(&[(2, BitVec::zeros(32)), (0, BitVec::zeros(32))]);
+    assert_eq!(out, vec![(2, BitVec::from_u64(0x2ba00477, 32)), (0, BitVec::from_u64(0x0ba00477, 32))]);
+
+    // Unlisted TAPs need known data register lengths
+    taps.write_ir_multi(&[(0, idcode.clone())]);
+    let out = taps.read_write_dr_multi(&[(0, BitVec::zeros(32))]);
+    assert_eq!(out[0].1.to_u64(), Some(0x0ba00477));
+}
+
+#[test]
+fn multi_tap_by_name() {
+    let mut taps = chain(3);
+    for (tap, name) in ["cpu0", "fpga", "cpu1"].iter().enumerate() {
+        taps.set_name(tap, name);
+        let mut set = InstructionSet::new(4);
+        set.add("IDCODE", BitVec::from_u64(IDCODE, 4), None).unwrap();
+        taps.set_instructions(tap, set).unwrap();
+    }
+    taps.select_multi(&[("cpu0", "IDCODE"), ("cpu1", "IDCODE")]).unwrap();
+    let out = taps.read_write_dr_multi(&[(0, BitVec::zeros(32)), (2, BitVec::zeros(32))]);
+    assert_eq!(out[1].1.to_u64(), Some(0x2ba00477));
+
+    assert!(taps.select_multi(&[("cpu0", "IDCODE"), ("cpu0", "BYPASS")]).is_err());
+    assert!(taps.select_multi(&[("cpu0", "HALT")]).is_err());
+}
+
+#[test]
+fn verify_chain() {
+    // The IR captures can't be split up, but the IDCODEs can still be checked
+    let chain = SimChain::new(vec![
+        SimTap::new(4, Some(0x3ba00477)).with_ir_capture(BitVec::from_u64(0b0101, 4)),
+        SimTap::new(2, None),
+        SimTap::new(2, Some(0x06410041)),
+    ]);
+    let mut taps = Taps::new(JtagSM::new(Box::new(chain)));
+    assert!(taps.detect().is_err());
+
+    let good = [ExpectedId::any_version(0x0ba00477), ExpectedId::Bypass, 0x06410041.into()];
+    let report = taps.verify_chain(&good).unwrap();
+    assert!(report.passed());
+    assert_eq!(report.found, [Some(0x3ba00477), None, Some(0x06410041)]);
+
+    let report = taps.verify_chain(&[ExpectedId::exact(0x0ba00477), ExpectedId::Any]).unwrap();
+    assert_eq!(report.mismatches, [
+        ChainMismatch::Wrong { position: 0, expected: ExpectedId::exact(0x0ba00477), found: Some(0x3ba00477) },
+        ChainMismatch::Extra { position: 2, found: Some(0x06410041) },
+    ]);
+
+    let mut long = good.to_vec();
+    long.push(ExpectedId::Masked { value: 0x00000041, mask: 0x0000f0ff });
+    let report = taps.verify_chain(&long).unwrap();
+    assert_eq!(report.mismatches, [ChainMismatch::Missing { position: 3, expected: long[3] }]);
+    assert_eq!(report.mismatches[0].to_string(), "tap 3: expected xxxx0x41, but the chain ends");
+    assert_eq!(ExpectedId::any_version(0x0ba00477).to_string(), "xba00477");
+    assert_eq!(ExpectedId::Masked { value: 0x10, mask: 0x11 }.to_string(), "00000010/00000011");
+}
+
+#[test]
+fn streaming_dr() {
+    let mut taps = chain(3);
+    let idcode = BitVec::from_u64(IDCODE, 4);
+    taps.select_tap(1, &idcode);
+    taps.set_stream_chunk_bits(20);
+
+    // The IDCODE comes out first, then what went in 34 bits earlier, after the bypass registers
+    let tdi: Vec<u8> = (0..12u8).map(|i| i.wrapping_mul(37) ^ 0x5a).collect();
+    let mut tdo = vec![];
+    taps.read_write_dr_stream(&tdi[..], &mut tdo, 96).unwrap();
+    let sent = BitVec::from_bytes(&tdi, 96);
+    let got = BitVec::from_bytes(&tdo, 96);
+    assert_eq!(got.slice(0..32).to_u64(), Some(0x1ba00477));
+    assert_eq!(got.slice(34..96), sent.slice(0..62));
+    assert_eq!(taps.sm.state(), JtagState::Idle);
+
+    // Pieces of any length make the same scan, and a partial last byte is padded
+    let pieces = (0..10).map(|i| sent.slice(i * 9..i * 9 + 9));
+    let mut tdo = vec![];
+    taps.read_write_dr_chunks(pieces, &mut tdo).unwrap();
+    assert_eq!(tdo.len(), 12);
+    let got = BitVec::from_bytes(&tdo, 90);
+    assert_eq!(got.slice(0..32).to_u64(), Some(0x1ba00477));
+    assert_eq!(got.slice(34..90), sent.slice(0..56));
+    assert_eq!(tdo[11] >> 2, 0);
+
+    let clocks = taps.sm.cable.clocks();
+    taps.write_dr_chunks([BitVec::zeros(40)]).unwrap();
+    assert!(taps.sm.cable.clocks() > clocks + 40);
+    assert!(taps.write_dr_chunks([]).is_err());
+    assert!(taps.write_dr_stream(&tdi[..4], 64).is_err());
+}
+
+#[test]
+#[allow(deprecated)]
+fn expected_captures() {
+    let mut taps = chain(3);
+    let idcode = BitVec::from_u64(IDCODE, 4);
+    let ir = taps.select_tap_expect(1, &idcode, &BitVec::from_u64(0b01, 4), &BitVec::from_u64(0b11, 4)).unwrap();
+    assert_eq!(ir.len(), 4);
+    let mismatch = taps.select_tap_expect(1, &idcode, &BitVec::zeros(4), &BitVec::from_u64(0b11, 4)).unwrap_err();
+    assert_eq!(mismatch.register, Register::Instruction);
+    assert_eq!(mismatch.bits(), [0]);
+
+    let mask = BitVec::from_u64(0x0fffffff, 32);
+    let dr = taps.read_write_dr_expect(&BitVec::zeros(32), &BitVec::from_u64(0x0ba00477, 32), &mask).unwrap();
+    assert_eq!(dr.to_u64(), Some(0x1ba00477));
+    let mismatch = taps.read_write_dr_expect(&BitVec::zeros(32), &BitVec::from_u64(0x0ba00476, 32), &mask).unwrap_err();
+    assert_eq!(mismatch.to_string(), "tap 1: DR captured 1ba00477, expected 0ba00476 with mask 0fffffff (bits 0 differ)");
+
+    // Checked as the queued reads are finished
+    for expected in [0x0ba00477, 0x0ba10477, 0x0ba00477] {
+        assert!(taps.queue_dr_expect(&BitVec::zeros(32), &BitVec::from_u64(expected, 32), &mask));
+    }
+    assert!(taps.queue_dr_read(32));
+    for _ in 0..4 {
+        taps.finish_dr_read(32);
+    }
+    let mismatches = taps.take_mismatches();
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(mismatches[0].bits(), [16]);
+    assert!(taps.take_mismatches().is_empty());
+}
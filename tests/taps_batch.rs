@@ -0,0 +1,91 @@
+use jtag_taps::bits::BitVec;
+use jtag_taps::statemachine::{JtagState, Register};
+use jtag_taps::taps::batch::Batch;
+
+mod common;
+use common::{chain, IDCODE};
+
+#[test]
+fn batch_splits_when_queue_fills() {
+    let mut taps = chain(3);
+    taps.sm.cable.set_queue_limit(Some(2));
+    let idcode = BitVec::from_u64(IDCODE, 4);
+    let mask = BitVec::from_u64(0x0fffffff, 32);
+
+    let mut batch = Batch::new();
+    batch.select_tap(2, &idcode);
+    let first = batch.read_dr(32);
+    batch.select_tap(0, &idcode);
+    let second = batch.read_write_dr(&BitVec::zeros(32));
+    let wrong = batch.read_write_dr_expect(&BitVec::zeros(32), &BitVec::from_u64(0x0ba00476, 32), &mask);
+    batch.write_dr(&BitVec::zeros(32));
+    let right = batch.read_write_dr_expect(&BitVec::zeros(32), &BitVec::from_u64(0x0ba00477, 32), &mask);
+    assert_eq!(batch.len(), 7);
+
+    let mut results = batch.run(&mut taps);
+    assert_eq!(results[&first].to_u64(), Some(0x2ba00477));
+    assert_eq!(results.get(&second).to_u64(), Some(0x0ba00477));
+    assert_eq!(results[&wrong].to_u64(), Some(0x0ba00477));
+    assert_eq!(results.take(right).to_u64(), Some(0x0ba00477));
+    assert_eq!(results.round_trips(), 2);
+    assert!(!results.passed());
+    assert_eq!(results.mismatches().len(), 1);
+    assert_eq!(results.mismatches()[0].register, Register::Data);
+    assert_eq!(results.mismatches()[0].bits(), [0]);
+
+    // The IR scan between the reads still counts
+    assert_eq!(taps.current_ir(0), Some(&idcode));
+    assert_eq!(taps.sm.state(), JtagState::Idle);
+}
+
+#[test]
+fn batch_without_queue() {
+    // Every read has to be done on its own
+    let mut taps = chain(2);
+    taps.sm.cable.set_queue_limit(Some(0));
+    taps.select_tap(1, &BitVec::from_u64(IDCODE, 4));
+    let mut batch = Batch::new();
+    let reads: Vec<_> = (0..3).map(|_| batch.read_dr(32)).collect();
+    let results = batch.run(&mut taps);
+    for read in &reads {
+        assert_eq!(results[read].to_u64(), Some(0x1ba00477));
+    }
+    assert_eq!(results.round_trips(), 0);
+    assert!(results.passed());
+}
+
+#[test]
+fn typed_captures() {
+    let mut taps = chain(2);
+    taps.select_tap(1, &BitVec::from_u64(IDCODE, 4));
+    let mut batch = Batch::new();
+    let idcode = batch.read_dr_as::<u32>(32);
+    let low = batch.read_dr_as::<u8>(8);
+    let first = batch.read_dr_as::<bool>(1);
+    let bits = batch.read_dr(32);
+    let mut results = batch.run(&mut taps);
+    assert_eq!(results[&idcode].to_u64(), Some(0x1ba00477));
+    assert_eq!(results.take(idcode), 0x1ba00477);
+    assert_eq!(results.take(low), 0x77);
+    assert!(results.take(first));
+    assert_eq!(results.take(bits), BitVec::from_u64(0x1ba00477, 32));
+}
+
+#[test]
+#[should_panic(expected = "don't fit")]
+fn capture_too_big_for_type() {
+    let _ = Batch::new().read_dr_as::<u16>(32);
+}
+
+#[test]
+#[should_panic(expected = "different batch")]
+fn capture_from_other_batch() {
+    let mut taps = chain(1);
+    taps.select_tap(0, &BitVec::from_u64(IDCODE, 4));
+    let mut first = Batch::new();
+    let capture = first.read_dr(32);
+    let mut second = Batch::new();
+    let _ = second.read_dr(32);
+    let results = second.run(&mut taps);
+    let _ = &results[&capture];
+}
@@ -0,0 +1,119 @@
+use jtag_taps::cable::sim::{SimChain, SimRegister};
+use jtag_taps::statemachine::{JtagSM, JtagState};
+use jtag_taps::svf::record::Recorder;
+use jtag_taps::xsvf;
+
+mod common;
+
+fn chain() -> JtagSM<Box<SimChain>> {
+    common::mcu_chain(SimRegister::Idcode)
+}
+
+// `value` as `bytes` bytes, most significant first
+fn be(value: u64, bytes: usize) -> Vec<u8> {
+    value.to_be_bytes()[8 - bytes..].to_vec()
+}
+
+#[test]
+fn read_idcodes() {
+    let mut file = vec![0x12, 0x00, 0x13, 0x00, 0x14, 0x00];
+    // IDCODE in both TAPs
+    file.extend([0x02, 9, 0x00, 0x1e]);
+    // Each IDCODE in its own segment, staying in Shift-DR between them
+    file.push(0x08);
+    file.extend(be(32, 4));
+    file.push(0x0f);
+    file.extend(be(0, 4));
+    file.extend(be(0x0ba00477, 4));
+    file.push(0x11);
+    file.extend(be(0, 4));
+    file.extend(be(0x06410041, 4));
+    // Both at once, ignoring the versions, then again with XSDR
+    file.push(0x08);
+    file.extend(be(64, 4));
+    file.push(0x01);
+    file.extend(be(0x0fffffff_0fffffff, 8));
+    file.push(0x09);
+    file.extend(be(0, 8));
+    file.extend(be(0xf6410041_fba00477, 8));
+    file.push(0x03);
+    file.extend(be(0, 8));
+    file.extend(b"\x16hello\0");
+    file.extend([0x17, 0x01, 0x06]);
+    file.extend(be(10, 4));
+    // Anything after XCOMPLETE is ignored
+    file.extend([0x00, 0xff]);
+
+    let mut sm = chain();
+    let report = xsvf::play(&mut sm, &file).unwrap();
+    assert_eq!(report.commands, 14);
+    assert_eq!(report.retries, 0);
+    assert_eq!(report.comments, ["hello"]);
+    assert_eq!(sm.cable.state(), JtagState::PauseDR);
+}
+
+#[test]
+fn retries() {
+    // EXTEST in the MCU, driving PA0 high.  The first attempt captures the pins before the update,
+    // and the retry captures them again after going through Update-DR to Run-Test/Idle.  The
+    // other TAP's BYPASS bit and the inputs that aren't driven are masked out.
+    let mut file = vec![0x02, 9, 0x00, 0x0f, 0x08];
+    file.extend(be(13, 4));
+    file.extend([0x01, 0x0f, 0x6e, 0x09, 0x0a, 0x44, 0x0a, 0x46]);
+    let report = xsvf::play(&mut chain(), &file).unwrap();
+    assert_eq!(report.retries, 1);
+
+    // Both TAPs in BYPASS, which always capture zeros, waiting 100us and then 25% longer each
+    // retry
+    let mut file = vec![0x02, 9, 0x01, 0xff, 0x07, 0x02, 0x04];
+    file.extend(be(100, 4));
+    file.push(0x08);
+    file.extend(be(2, 4));
+    file.extend([0x09, 0x03, 0x03]);
+    let mut sm = chain();
+    let err = xsvf::play(&mut sm, &file).unwrap_err();
+    assert!(err.starts_with("command 0x09 at offset 0x10:"), "{}", err);
+    assert!(err.contains("after 3 attempts"), "{}", err);
+    assert!(sm.cable.clocks() >= 125 + 156, "{}", sm.cable.clocks());
+}
+
+#[test]
+fn increments() {
+    // SAMPLE/PRELOAD in the MCU, stepping the address in bit 8 and filling bits 4-7 with data
+    let mut file = vec![0x02, 9, 0x00, 0x2f, 0x08];
+    file.extend(be(13, 4));
+    file.push(0x0a);
+    file.extend(be(0x0100, 2));
+    file.extend(be(0x00f0, 2));
+    file.push(0x0b);
+    file.extend(be(0x1005, 2));
+    file.extend([3, 0x0a, 0x03, 0x0f]);
+
+    let mut recorder = Recorder::new(chain().cable, Vec::new());
+    recorder.set_record_tdo(false);
+    let mut sm = JtagSM::new(Box::new(recorder));
+    let report = xsvf::play(&mut sm, &file).unwrap();
+    assert_eq!(report.commands, 4);
+    let svf = String::from_utf8(sm.cable.finish().unwrap()).unwrap();
+    let scans: Vec<&str> = svf.lines().filter(|line| line.starts_with("SDR")).collect();
+    assert_eq!(scans, [
+        "SDR 13 TDI (1005);",
+        "SDR 13 TDI (11a5);",
+        "SDR 13 TDI (1235);",
+        "SDR 13 TDI (13f5);",
+    ]);
+
+    // The masks have to come first
+    let mut file = vec![0x08];
+    file.extend(be(13, 4));
+    file.push(0x0b);
+    assert!(xsvf::play(&mut chain(), &file).unwrap_err().contains("without XSETSDRMASKS"));
+}
+
+#[test]
+fn invalid() {
+    assert!(xsvf::play(&mut chain(), &[0x02, 9, 0x01]).unwrap_err().contains("ends in the middle"));
+    assert!(xsvf::play(&mut chain(), &[0x02, 4, 0x1f]).unwrap_err().contains("beyond"));
+    assert!(xsvf::play(&mut chain(), &[0x05]).unwrap_err().contains("unknown command 0x05"));
+    assert!(xsvf::play(&mut chain(), &[0x12, 0x10]).unwrap_err().contains("invalid state"));
+}